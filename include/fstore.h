@@ -0,0 +1,43 @@
+/* Copyright 2021 Matthew Petricone
+ *
+ * C interface to fstore, built with `cargo build --features capi`.
+ * Hand-maintained: kept in step with src/capi.rs by hand, and compared
+ * with it by the capi tests (`cargo test --features capi`).
+ */
+#ifndef FSTORE_H
+#define FSTORE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define FSTORE_OK 0
+#define FSTORE_ERR_NULL -1
+#define FSTORE_ERR_IO -2
+#define FSTORE_ERR_BOUNDS -3
+#define FSTORE_ERR_CORRUPT -4
+#define FSTORE_ERR_LOCKED -5
+#define FSTORE_ERR_READONLY -6
+#define FSTORE_ERR_BUFFER -7
+#define FSTORE_ERR_PATH -8
+#define FSTORE_ERR_NOT_LIVE -9
+#define FSTORE_ERR_PANIC -10
+
+typedef struct FstoreHandle FstoreHandle;
+
+int32_t fstore_open(const char *path, int32_t writable, FstoreHandle **out);
+int32_t fstore_create(const char *path, FstoreHandle **out);
+int32_t fstore_write(FstoreHandle *handle, const uint8_t *data, size_t len, uint64_t *out_index);
+int32_t fstore_read(FstoreHandle *handle, uint64_t index, uint8_t *buf, size_t cap, size_t *out_len);
+int32_t fstore_len(const FstoreHandle *handle, uint64_t *out);
+int32_t fstore_delete(FstoreHandle *handle, uint64_t index);
+int32_t fstore_close(FstoreHandle *handle);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
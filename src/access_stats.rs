@@ -0,0 +1,154 @@
+//Copyright 2021 Matthew Petricone
+use crate::platform;
+use crate::store::BlockId;
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Marks an access stats sidecar file
+static ACCESS_STATS_MAGIC: &[u8; 8] = b"FSTACC01";
+static ERROR_ACCESS_STATS_INVALID: &str = "Invalid access stats file.";
+
+/// Reads of one block
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BlockAccess {
+    /// number of times the block was read
+    pub reads: u64,
+    /// when it was last read, to the second
+    pub last_access: Option<SystemTime>,
+}
+
+/// Per block read counts, kept in a sidecar file next to the store
+///
+/// The sidecar is named after the store with ".access" appended, and holds
+/// the magic, a u64 count, then u64 reads and u64 last access (unix seconds,
+/// 0 for never) per block.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AccessStats {
+    blocks: Vec<BlockAccess>,
+}
+
+impl AccessStats {
+    /// Sidecar file name for a store
+    pub fn sidecar_path(store_path: &str) -> String {
+        format!("{}.access", store_path)
+    }
+
+    /// Load stats for a store, empty if there is no sidecar yet
+    pub fn load(store_path: &str) -> Result<AccessStats, Error> {
+        let data = match fs::read(AccessStats::sidecar_path(store_path)) {
+            Ok(d) => d,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(AccessStats::default()),
+            Err(e) => return Err(e),
+        };
+        let invalid = || Error::new(ErrorKind::InvalidData, ERROR_ACCESS_STATS_INVALID);
+        if data.len() < 16 || &data[0..8] != ACCESS_STATS_MAGIC {
+            return Err(invalid());
+        }
+        let count = u64::from_le_bytes(data[8..16].try_into().unwrap());
+        if count.checked_mul(16).and_then(|c| c.checked_add(16)) != Some(data.len() as u64) {
+            return Err(invalid());
+        }
+        let blocks = data[16..]
+            .chunks_exact(16)
+            .map(|c| {
+                let secs = u64::from_le_bytes(c[8..16].try_into().unwrap());
+                BlockAccess {
+                    reads: u64::from_le_bytes(c[0..8].try_into().unwrap()),
+                    last_access: if secs == 0 {
+                        None
+                    } else {
+                        Some(UNIX_EPOCH + Duration::from_secs(secs))
+                    },
+                }
+            })
+            .collect();
+        Ok(AccessStats { blocks })
+    }
+
+    /// Write the sidecar, replacing the old one in one rename
+    pub fn save(&self, store_path: &str) -> Result<(), Error> {
+        let mut out = Vec::with_capacity(16 + self.blocks.len() * 16);
+        out.extend_from_slice(ACCESS_STATS_MAGIC);
+        out.extend_from_slice(&u64::try_from(self.blocks.len()).unwrap().to_le_bytes());
+        for b in &self.blocks {
+            let secs = b
+                .last_access
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            out.extend_from_slice(&b.reads.to_le_bytes());
+            out.extend_from_slice(&secs.to_le_bytes());
+        }
+        platform::write_replace(&AccessStats::sidecar_path(store_path), &out)
+    }
+
+    /// Count a read of index now
+    pub fn record(&mut self, index: BlockId) {
+        if self.blocks.len() <= index {
+            self.blocks.resize(index + 1, BlockAccess::default());
+        }
+        let b = &mut self.blocks[index];
+        b.reads += 1;
+        b.last_access = Some(SystemTime::now());
+    }
+
+    /// Stats for index, default if it was never read
+    pub fn get(&self, index: BlockId) -> BlockAccess {
+        self.blocks.get(index).copied().unwrap_or_default()
+    }
+
+    /// Stats for every block read so far, by index
+    pub fn blocks(&self) -> &[BlockAccess] {
+        &self.blocks
+    }
+
+    /// Stats moved to new indexes, remap[old] is the new index or None
+    pub(crate) fn remap(&self, remap: &[Option<BlockId>]) -> AccessStats {
+        let mut out = AccessStats::default();
+        for (old, b) in self.blocks.iter().enumerate() {
+            if let Some(Some(new)) = remap.get(old) {
+                if out.blocks.len() <= *new {
+                    out.blocks.resize(new + 1, BlockAccess::default());
+                }
+                out.blocks[*new] = *b;
+            }
+        }
+        out
+    }
+
+    /// Blocks read at least once, fewest reads first, ties broken by oldest access.
+    ///
+    /// The front of the list is what an LFU cache would evict first.
+    pub fn least_frequently_used(&self) -> Vec<BlockId> {
+        let mut ids: Vec<BlockId> = (0..self.blocks.len()).filter(|i| self.blocks[*i].reads > 0).collect();
+        ids.sort_by_key(|i| (self.blocks[*i].reads, self.blocks[*i].last_access));
+        ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sidecar_round_trip() {
+        fs::create_dir_all("testout").unwrap();
+        let path = "testout/access_stats_unit";
+        let _ = fs::remove_file(AccessStats::sidecar_path(path));
+        assert_eq!(AccessStats::load(path).unwrap(), AccessStats::default());
+
+        let mut st = AccessStats::default();
+        st.record(2);
+        st.record(2);
+        st.record(0);
+        st.save(path).unwrap();
+        let st2 = AccessStats::load(path).unwrap();
+        assert_eq!(st2.get(2).reads, 2);
+        assert_eq!(st2.get(1), BlockAccess::default());
+        assert!(st2.get(0).last_access.is_some());
+        assert_eq!(st2.least_frequently_used(), vec![0, 2]);
+    }
+}
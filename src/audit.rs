@@ -0,0 +1,202 @@
+//Copyright 2021 Matthew Petricone
+//! An audit trail of what was done to a store, kept next to the store.
+//!
+//! With Store::set_audit_label, every live block appended, every batch of
+//! deletes and every compaction through that handle is followed by a
+//! record saying what was done, when, and under the label. Records go to
+//! a sidecar named after the store with ".audit" appended, not to blocks,
+//! so they take no block indexes, aren't seen by iter or get, can't be
+//! deleted through the store, and stay with the file name through
+//! compaction. Store::create leaves an existing sidecar alone, as a trail
+//! isn't dropped quietly. Store::audit_log reads them back.
+//!
+//! The sidecar is AUDIT_MAGIC, then per record a u32 length and the
+//! record: a u8 action, a u64 time in unix seconds, a u16 label length and
+//! the label, then for AUDIT_WRITE the u64 block index, for AUDIT_DELETE a
+//! u32 count and the u64 indexes, and for AUDIT_COMPACT the u64 bytes
+//! reclaimed. A record cut short by a crash while it was appended ends the
+//! log.
+use crate::crypto::BlockHasher;
+use crate::store::{BlockId, Store};
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Marks an audit sidecar file
+static AUDIT_MAGIC: &[u8; 8] = b"FSTAUD01";
+
+const AUDIT_WRITE: u8 = 0;
+const AUDIT_DELETE: u8 = 1;
+const AUDIT_COMPACT: u8 = 2;
+
+static ERROR_AUDIT_RECORD: &str = "Invalid audit record.";
+
+/// What an audit record says was done
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuditAction {
+    /// a block was appended, or rewritten in place by swap
+    Write(BlockId),
+    /// blocks were deleted in one batch, or discarded by truncate_to
+    Delete(Vec<BlockId>),
+    /// the store was compacted; indexes in earlier records are from before
+    Compact { bytes_reclaimed: u64 },
+}
+
+/// One entry of Store::audit_log
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    /// to the second
+    pub time: SystemTime,
+    /// the label of the handle that did it
+    pub label: String,
+    pub action: AuditAction,
+}
+
+impl AuditRecord {
+    /// Sidecar file name for a store
+    pub fn sidecar_path(store_path: &str) -> String {
+        format!("{}.audit", store_path)
+    }
+
+    /// Append the record to the sidecar of the store at store_path, and sync it
+    pub(crate) fn append(&self, store_path: &str) -> Result<(), Error> {
+        let mut file = OpenOptions::new().create(true).append(true).open(AuditRecord::sidecar_path(store_path))?;
+        let record = self.encode();
+        let mut out = Vec::with_capacity(AUDIT_MAGIC.len() + 4 + record.len());
+        if file.metadata()?.len() == 0 {
+            out.extend_from_slice(AUDIT_MAGIC);
+        }
+        out.extend_from_slice(&u32::try_from(record.len()).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?.to_le_bytes());
+        out.extend_from_slice(&record);
+        file.write_all(&out)?;
+        file.sync_data()
+    }
+
+    /// The record as kept in the sidecar, without its length
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let (kind, data) = match &self.action {
+            AuditAction::Write(b) => (AUDIT_WRITE, (*b as u64).to_le_bytes().to_vec()),
+            AuditAction::Delete(blocks) => {
+                let mut d = (blocks.len() as u32).to_le_bytes().to_vec();
+                for b in blocks {
+                    d.extend_from_slice(&(*b as u64).to_le_bytes());
+                }
+                (AUDIT_DELETE, d)
+            }
+            AuditAction::Compact { bytes_reclaimed } => (AUDIT_COMPACT, bytes_reclaimed.to_le_bytes().to_vec()),
+        };
+        out.push(kind);
+        let secs = self.time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        out.extend_from_slice(&secs.to_le_bytes());
+        // labels are cut to what the length holds
+        let label = &self.label.as_bytes()[..self.label.len().min(u16::MAX as usize)];
+        out.extend_from_slice(&(label.len() as u16).to_le_bytes());
+        out.extend_from_slice(label);
+        out.extend_from_slice(&data);
+        out
+    }
+
+    /// A record from what encode gave
+    fn decode(data: &[u8]) -> Option<AuditRecord> {
+        let u64_at = |pos: usize| -> Option<u64> { Some(u64::from_le_bytes(data.get(pos..pos + 8)?.try_into().ok()?)) };
+        let kind = *data.first()?;
+        let time = UNIX_EPOCH + Duration::from_secs(u64_at(1)?);
+        let label_len = usize::from(u16::from_le_bytes(data.get(9..11)?.try_into().ok()?));
+        let label = String::from_utf8_lossy(data.get(11..11 + label_len)?).into_owned();
+        let pos = 11 + label_len;
+        let action = match kind {
+            AUDIT_WRITE => AuditAction::Write(usize::try_from(u64_at(pos)?).ok()?),
+            AUDIT_DELETE => {
+                let n = u32::from_le_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize;
+                let mut blocks = Vec::with_capacity(n.min(data.len() / 8));
+                for i in 0..n {
+                    blocks.push(usize::try_from(u64_at(pos + 4 + i * 8)?).ok()?);
+                }
+                AuditAction::Delete(blocks)
+            }
+            AUDIT_COMPACT => AuditAction::Compact { bytes_reclaimed: u64_at(pos)? },
+            _ => return None,
+        };
+        Some(AuditRecord { time, label, action })
+    }
+}
+
+impl<T: BlockHasher> Store<T> {
+    /// Every audit record, oldest first, see the module docs
+    pub fn audit_log(&mut self) -> Result<Vec<AuditRecord>, Box<dyn std::error::Error>> {
+        let data = match fs::read(AuditRecord::sidecar_path(self.path())) {
+            Ok(d) => d,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Box::new(e)),
+        };
+        if data.get(..AUDIT_MAGIC.len()) != Some(&AUDIT_MAGIC[..]) {
+            return Err(ERROR_AUDIT_RECORD.into());
+        }
+        let mut out = Vec::new();
+        let mut pos = AUDIT_MAGIC.len();
+        while let Some(len) = data.get(pos..pos + 4) {
+            let len = u32::from_le_bytes(len.try_into()?) as usize;
+            let record = match data.get(pos + 4..pos + 4 + len) {
+                Some(r) => r,
+                // cut short by a crash
+                None => break,
+            };
+            out.push(AuditRecord::decode(record).ok_or(ERROR_AUDIT_RECORD)?);
+            pos += 4 + len;
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+    use crate::store::StoreIO;
+
+    #[test]
+    fn actions_are_recorded() {
+        std::fs::create_dir_all("testout").unwrap();
+        let _ = fs::remove_file(AuditRecord::sidecar_path("testout/audit.st"));
+        let mut s = Store::<B3BlockHasher>::create("testout/audit.st".to_string()).unwrap();
+        let quiet = s.put(b"before").unwrap();
+        s.set_audit_label(Some("alice"));
+        let a = s.put(b"a").unwrap();
+        let b = s.put(b"b").unwrap();
+        s.delete_many(&[quiet, a]).unwrap();
+        s.set_audit_label(Some("compactor"));
+        s.compact().unwrap();
+        s.set_audit_label(None);
+        s.put(b"after").unwrap();
+
+        let log = s.audit_log().unwrap();
+        let actions: Vec<(&str, AuditAction)> = log.iter().map(|r| (r.label.as_str(), r.action.clone())).collect();
+        assert!(matches!(actions[3], ("compactor", AuditAction::Compact { bytes_reclaimed }) if bytes_reclaimed > 0));
+        assert_eq!(
+            actions[..3],
+            [
+                ("alice", AuditAction::Write(a)),
+                ("alice", AuditAction::Write(b)),
+                ("alice", AuditAction::Delete(vec![quiet, a])),
+            ]
+        );
+        assert_eq!(actions.len(), 4);
+        assert!(log[0].time.elapsed().unwrap() < Duration::from_secs(60));
+        // the records take no blocks
+        assert!(s.column_families().unwrap().is_empty());
+        assert_eq!(s.len(), 2);
+        s.close().unwrap();
+
+        // a torn last record is left out
+        let path = AuditRecord::sidecar_path("testout/audit.st");
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(&100u32.to_le_bytes()).unwrap();
+        f.write_all(b"torn").unwrap();
+        drop(f);
+        let mut s = Store::<B3BlockHasher>::new("testout/audit.st".to_string()).unwrap();
+        assert_eq!(s.audit_log().unwrap(), log);
+    }
+}
@@ -0,0 +1,692 @@
+//Copyright 2021 Matthew Petricone
+//! Command line tool for store files
+use fstore::crypto::B3BlockHasher;
+use fstore::format;
+use fstore::frames::FramePrefix;
+use fstore::manifest::from_hex;
+use fstore::store::{Store, StoreIO};
+use std::env;
+use std::io::{IsTerminal, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use std::process;
+
+static USAGE: &str = "usage:
+  fstore spec [codec id]
+  fstore manifest [--json] <store>
+  fstore cat [--type] <store> <index>
+  fstore grep [--hex] <pattern> <store>
+  fstore extract <store> (--index <n> | --range <a>..<b>) --out <path>
+  fstore fsck [--repair] <store>
+  fstore verify [--json] <store>
+  fstore compact [--dry-run] <store>
+  fstore import [--prefix <p>] <store>  (length prefixed frames from stdin)
+  fstore export [--prefix <p>] <store>  (length prefixed frames to stdout)
+                                     (p: u32be, the default, u32le, u64be or u64le)
+  fstore browse <store>              (tui feature)";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(|a| a.as_str()) {
+        Some("spec") => spec(&args[1..]),
+        Some("manifest") => manifest(&args[1..]),
+        Some("cat") => cat(&args[1..]),
+        Some("grep") => grep(&args[1..]),
+        Some("extract") => extract(&args[1..]),
+        Some("fsck") => fsck(&args[1..]),
+        Some("verify") => verify(&args[1..]),
+        Some("compact") => compact(&args[1..]),
+        Some("import") => import(&args[1..]),
+        Some("export") => export(&args[1..]),
+        #[cfg(feature = "tui")]
+        Some("browse") => browse(&args[1..]),
+        _ => usage(),
+    }
+}
+
+/// Print the on disk layout as JSON
+fn spec(args: &[String]) {
+    let codec = match args.first().map(|a| a.parse::<u32>()) {
+        None => 0,
+        Some(Ok(c)) => c,
+        Some(Err(_)) => usage(),
+    };
+    match format::describe_for::<B3BlockHasher>(codec) {
+        Some(d) => println!("{}", d.to_json()),
+        None => {
+            eprintln!("unknown codec {}", codec);
+            process::exit(1);
+        }
+    }
+}
+
+/// Print every block of a store
+fn manifest(args: &[String]) {
+    let json = args.iter().any(|a| a == "--json");
+    let path = match args.iter().find(|a| !a.starts_with("--")) {
+        Some(p) => p.clone(),
+        None => usage(),
+    };
+    let result = Store::<B3BlockHasher>::new(path).and_then(|mut s| s.manifest());
+    match result {
+        Ok(m) if json => println!("{}", m.to_json()),
+        Ok(m) => print!("{}", m.to_text()),
+        Err(e) => fail(e),
+    }
+}
+
+/// Write a block's payload to stdout, or with --type its content type.
+///
+/// Payloads that aren't known to be text aren't written to a terminal.
+fn cat(args: &[String]) {
+    let show_type = args.iter().any(|a| a == "--type");
+    let rest: Vec<&String> = args.iter().filter(|a| !a.starts_with("--")).collect();
+    let (path, index) = match rest.as_slice() {
+        [p, i] => match i.parse::<usize>() {
+            Ok(i) => ((*p).clone(), i),
+            Err(_) => usage(),
+        },
+        _ => usage(),
+    };
+    let mut s = Store::<B3BlockHasher>::new(path).unwrap_or_else(|e| fail(e));
+    let content_type = s.content_type(index).unwrap_or_else(|e| fail(e));
+    if show_type {
+        println!("{}", content_type);
+        return;
+    }
+    let data = s.get(index).unwrap_or_else(|e| fail(e));
+    let mut out = std::io::stdout();
+    if out.is_terminal() && !content_type.is_text() && std::str::from_utf8(&data).is_err() {
+        eprintln!("fstore: block {} is {} ({} bytes), redirect to a file", index, content_type, data.len());
+        process::exit(1);
+    }
+    if let Err(e) = out.write_all(&data).and_then(|_| out.flush()) {
+        fail(e.into());
+    }
+}
+
+/// Print the block index and offset of every match of a pattern, given as
+/// text or with --hex as hex digits. Exits 1 if nothing matched, like grep.
+fn grep(args: &[String]) {
+    let hex = args.iter().any(|a| a == "--hex");
+    let rest: Vec<&String> = args.iter().filter(|a| !a.starts_with("--")).collect();
+    let (pattern, path) = match rest.as_slice() {
+        [p, s] if hex => (from_hex(p).unwrap_or_else(|| usage()), (*s).clone()),
+        [p, s] => (p.as_bytes().to_vec(), (*s).clone()),
+        _ => usage(),
+    };
+    let result = Store::<B3BlockHasher>::new(path).and_then(|mut s| s.search(&pattern));
+    match result {
+        Ok(hits) if hits.is_empty() => process::exit(1),
+        Ok(hits) => hits.iter().for_each(|h| println!("{} {}", h.index, h.offset)),
+        Err(e) => fail(e),
+    }
+}
+
+/// Write verified payloads to files: one block to the file given by --out,
+/// or the live blocks of a range to <index>.<extension> in the directory
+/// given by --out, the extension coming from the block's content type.
+fn extract(args: &[String]) {
+    let mut path = None;
+    let mut index = None;
+    let mut range = None;
+    let mut out = None;
+    let mut it = args.iter();
+    while let Some(a) = it.next() {
+        match a.as_str() {
+            "--index" => index = Some(it.next().and_then(|v| v.parse::<usize>().ok()).unwrap_or_else(|| usage())),
+            "--range" => range = Some(it.next().and_then(|v| parse_range(v)).unwrap_or_else(|| usage())),
+            "--out" => out = Some(it.next().unwrap_or_else(|| usage()).clone()),
+            p if path.is_none() && !p.starts_with("--") => path = Some(p.to_string()),
+            _ => usage(),
+        }
+    }
+    let (path, out) = match (path, out) {
+        (Some(p), Some(o)) => (p, o),
+        _ => usage(),
+    };
+    let mut s = Store::<B3BlockHasher>::new(path).unwrap_or_else(|e| fail(e));
+    let written = match (index, range) {
+        (Some(i), None) => s.get(i).and_then(|data| Ok(std::fs::write(&out, data)?)).map(|_| 1),
+        (None, Some((start, end))) => extract_range(&mut s, start..end.unwrap_or(usize::MAX), Path::new(&out)),
+        _ => usage(),
+    };
+    match written {
+        Ok(n) => eprintln!("extracted {} block(s)", n),
+        Err(e) => fail(e),
+    }
+}
+
+/// Write the live blocks of range into dir, returning how many
+fn extract_range(s: &mut Store<B3BlockHasher>, range: Range<usize>, dir: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+    std::fs::create_dir_all(dir)?;
+    let mut n = 0;
+    for i in range.start..range.end.min(s.len()) {
+        if !s.is_live(i)? {
+            continue;
+        }
+        let ext = s.content_type(i)?.extension().unwrap_or("bin");
+        std::fs::write(dir.join(format!("{}.{}", i, ext)), s.get(i)?)?;
+        n += 1;
+    }
+    Ok(n)
+}
+
+/// a..b, a.. or ..b, the end None if left open
+fn parse_range(s: &str) -> Option<(usize, Option<usize>)> {
+    let (a, b) = s.split_once("..")?;
+    let start = if a.is_empty() { 0 } else { a.parse().ok()? };
+    let end = if b.is_empty() { None } else { Some(b.parse().ok()?) };
+    Some((start, end))
+}
+
+/// Check a store and print a summary, repairing it with --repair.
+///
+/// Exits 0 if the store is clean, 1 if problems were fixed or are contained
+/// in quarantine, 4 if problems are left and 8 if the check couldn't run.
+fn fsck(args: &[String]) {
+    let repair = args.iter().any(|a| a == "--repair");
+    let path = match args.iter().find(|a| !a.starts_with("--")) {
+        Some(p) => p.clone(),
+        None => usage(),
+    };
+    let opened = if repair { Store::<B3BlockHasher>::open_for_write(path.clone()) } else { Store::<B3BlockHasher>::new(path.clone()) };
+    let report = match opened.and_then(|mut s| {
+        let report = s.fsck()?;
+        s.close()?;
+        Ok(report)
+    }) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("fstore: {}", e);
+            process::exit(8);
+        }
+    };
+    let (state, code) = if report.is_clean() {
+        ("clean", 0)
+    } else if report.is_consistent() {
+        ("consistent", 1)
+    } else {
+        ("damaged", 4)
+    };
+    println!("{}: {}", path, state);
+    println!("headers: {}, delete journals: {}", report.headers_checked, report.journal_blocks);
+    println!("blocks verified: {} ({} bytes)", report.scrub.blocks_checked, report.scrub.bytes_checked);
+    let lists = [
+        ("repaired", &report.scrub.repaired),
+        ("newly quarantined", &report.quarantined),
+        ("failed verification", &report.scrub.failed),
+    ];
+    for (what, blocks) in lists.iter().filter(|(_, b)| !b.is_empty()) {
+        println!("{}: {:?}", what, blocks);
+    }
+    if report.recovered {
+        println!("recovered from an unclean close");
+    }
+    if report.dirty {
+        println!("not closed cleanly, run with --repair to recover");
+    }
+    if report.journal_pending {
+        println!("in place compaction was interrupted, run with --repair to finish it");
+    }
+    for e in &report.chain_errors {
+        println!("header chain: {}", e);
+    }
+    process::exit(code);
+}
+
+/// Verify every payload of a store opened read only, with a progress bar
+/// or with --json printing the status of each block and the totals.
+///
+/// Exits 0 if every block verified, 1 if any failed and 2 if the store
+/// couldn't be read through.
+fn verify(args: &[String]) {
+    let json = args.iter().any(|a| a == "--json");
+    let path = match args.iter().filter(|a| a.as_str() != "--json").collect::<Vec<_>>().as_slice() {
+        [p] if !p.starts_with("--") => (*p).clone(),
+        _ => usage(),
+    };
+    let mut bar = Progress::new("verify");
+    let result = Store::<B3BlockHasher>::new(path.clone()).and_then(|mut s| {
+        let report = if json { s.scrub()? } else { s.scrub_with_progress(|d, t| bar.update(d, t))? };
+        let mut deleted = Vec::with_capacity(s.len());
+        for i in 0..s.len() {
+            deleted.push(s.block_header(i)?.is_deleted());
+        }
+        Ok((report, deleted))
+    });
+    bar.finish();
+    let (r, deleted) = match result {
+        Ok(found) => found,
+        Err(e) if json => {
+            println!(r#"{{"path":{},"status":"unreadable","error":{}}}"#, json_string(&path), json_string(&e.to_string()));
+            process::exit(2);
+        }
+        Err(e) => {
+            eprintln!("fstore: {}", e);
+            process::exit(2);
+        }
+    };
+    let status = if r.failed.is_empty() { "ok" } else { "corrupt" };
+    if json {
+        let blocks: Vec<String> = deleted
+            .iter()
+            .enumerate()
+            .map(|(i, &d)| {
+                let s = if r.failed.contains(&i) { "corrupt" } else if d { "deleted" } else { "ok" };
+                format!(r#"{{"index":{},"status":"{}"}}"#, i, s)
+            })
+            .collect();
+        println!(
+            r#"{{"path":{},"status":"{}","blocks_checked":{},"bytes_checked":{},"failed":{:?},"blocks":[{}]}}"#,
+            json_string(&path),
+            status,
+            r.blocks_checked,
+            r.bytes_checked,
+            r.failed,
+            blocks.join(",")
+        );
+    } else {
+        println!("{} blocks, {} bytes verified", r.blocks_checked, r.bytes_checked);
+        if !r.failed.is_empty() {
+            println!("failed verification: {:?}", r.failed);
+        }
+    }
+    if !r.failed.is_empty() {
+        process::exit(1);
+    }
+}
+
+/// s as a quoted JSON string
+fn json_string(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Compact a store with a progress bar, or with --dry-run say what it would reclaim
+fn compact(args: &[String]) {
+    let dry_run = args.iter().any(|a| a == "--dry-run");
+    let path = match args.iter().find(|a| !a.starts_with("--")) {
+        Some(p) => p.clone(),
+        None => usage(),
+    };
+    let result = if dry_run {
+        Store::<B3BlockHasher>::new(path).and_then(|mut s| s.compact_dry_run())
+    } else {
+        let mut bar = Progress::new("compact");
+        let r = Store::<B3BlockHasher>::open_for_write(path).and_then(|mut s| {
+            let r = s.compact_with_progress(|d, t| bar.update(d, t))?;
+            s.close()?;
+            Ok(r)
+        });
+        bar.finish();
+        r
+    };
+    match result {
+        Ok(r) => {
+            let dropped = r.remap.iter().filter(|m| m.is_none()).count();
+            let verb = if dry_run { "would reclaim" } else { "reclaimed" };
+            println!("{} {} bytes, dropping {} of {} blocks", verb, r.bytes_reclaimed, dropped, r.remap.len());
+        }
+        Err(e) => fail(e),
+    }
+}
+
+/// Append length prefixed frames read from stdin as blocks, creating the
+/// store if there is none, see Store::import_frames
+fn import(args: &[String]) {
+    let (path, prefix) = frame_args(args);
+    let opened = if Path::new(&path).exists() {
+        Store::<B3BlockHasher>::open_for_write(path)
+    } else {
+        Store::<B3BlockHasher>::create(path).map_err(|e| e.into())
+    };
+    let result = opened.and_then(|mut s| {
+        let ids = s.import_frames(std::io::stdin().lock(), prefix);
+        s.close()?;
+        ids
+    });
+    match result {
+        Ok(ids) => eprintln!("{} blocks imported", ids.len()),
+        Err(e) => fail(e),
+    }
+}
+
+/// Write the live blocks to stdout as length prefixed frames, see Store::export_frames
+fn export(args: &[String]) {
+    let (path, prefix) = frame_args(args);
+    let result = Store::<B3BlockHasher>::new(path).and_then(|mut s| s.export_frames(std::io::stdout().lock(), prefix));
+    if let Err(e) = result {
+        fail(e);
+    }
+}
+
+/// The store and --prefix of import and export
+fn frame_args(args: &[String]) -> (String, FramePrefix) {
+    let mut path = None;
+    let mut prefix = FramePrefix::U32Be;
+    let mut it = args.iter();
+    while let Some(a) = it.next() {
+        match a.as_str() {
+            "--prefix" => prefix = it.next().and_then(|v| v.parse().ok()).unwrap_or_else(|| usage()),
+            p if path.is_none() && !p.starts_with("--") => path = Some(p.to_string()),
+            _ => usage(),
+        }
+    }
+    (path.unwrap_or_else(|| usage()), prefix)
+}
+
+/// Progress bar on stderr with rate and time left
+struct Progress {
+    label: &'static str,
+    start: Instant,
+    drawn: Option<Instant>,
+}
+
+impl Progress {
+    fn new(label: &'static str) -> Progress {
+        Progress { label, start: Instant::now(), drawn: None }
+    }
+
+    /// Redraw for done of total bytes, at most ten times a second; always true, to carry on
+    fn update(&mut self, done: u64, total: u64) -> bool {
+        let now = Instant::now();
+        if self.drawn.is_some_and(|t| now - t < Duration::from_millis(100)) && done < total {
+            return true;
+        }
+        self.drawn = Some(now);
+        let secs = (now - self.start).as_secs_f64().max(1e-3);
+        let rate = done as f64 / secs;
+        let share = if total == 0 { 1.0 } else { done as f64 / total as f64 };
+        let eta = if rate > 0.0 { (total - done.min(total)) as f64 / rate } else { 0.0 };
+        let filled = (share.min(1.0) * 30.0) as usize;
+        eprint!(
+            "\r{} [{}{}] {:>3.0}% {:.1} MB/s ETA {:.0}s ",
+            self.label,
+            "#".repeat(filled),
+            "-".repeat(30 - filled),
+            share * 100.0,
+            rate / 1e6,
+            eta
+        );
+        true
+    }
+
+    /// End the bar's line, if one was drawn
+    fn finish(&self) {
+        if self.drawn.is_some() {
+            eprintln!();
+        }
+    }
+}
+
+/// Block rows shown at once by browse
+#[cfg(feature = "tui")]
+const BROWSE_ROWS: usize = 16;
+/// Payload bytes shown in the preview pane of browse
+#[cfg(feature = "tui")]
+const BROWSE_PREVIEW: usize = 256;
+
+/// Which block browse has selected, and the first one on screen
+#[cfg(feature = "tui")]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct BrowseView {
+    selected: usize,
+    top: usize,
+    /// block index typed so far, jumped to on enter
+    typed: Option<usize>,
+}
+
+#[cfg(feature = "tui")]
+impl BrowseView {
+    /// Act on key for a store of len blocks, false if it was q
+    fn key(&mut self, key: u8, len: usize) -> bool {
+        let last = len.saturating_sub(1);
+        match key {
+            b'q' => return false,
+            b'j' => self.selected = (self.selected + 1).min(last),
+            b'k' => self.selected = self.selected.saturating_sub(1),
+            b'n' => self.selected = (self.selected + BROWSE_ROWS).min(last),
+            b'p' => self.selected = self.selected.saturating_sub(BROWSE_ROWS),
+            b'0'..=b'9' => {
+                let digit = usize::from(key - b'0');
+                self.typed = Some(self.typed.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+                return true;
+            }
+            b'\r' | b'\n' => {
+                if let Some(i) = self.typed {
+                    self.selected = i.min(last);
+                }
+            }
+            _ => {}
+        }
+        self.typed = None;
+        if self.selected < self.top {
+            self.top = self.selected;
+        } else if self.selected >= self.top + BROWSE_ROWS {
+            self.top = self.selected + 1 - BROWSE_ROWS;
+        }
+        true
+    }
+}
+
+/// The terminal settings browse needs, through the C library's termios
+#[cfg(all(feature = "tui", target_os = "linux"))]
+mod term {
+    use std::os::raw::{c_int, c_uint};
+
+    const NCCS: usize = 32;
+    const ICANON: c_uint = 0o2;
+    const ECHO: c_uint = 0o10;
+    const VTIME: usize = 5;
+    const VMIN: usize = 6;
+    const TCSANOW: c_int = 0;
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Termios {
+        c_iflag: c_uint,
+        c_oflag: c_uint,
+        c_cflag: c_uint,
+        c_lflag: c_uint,
+        c_line: u8,
+        c_cc: [u8; NCCS],
+        c_ispeed: c_uint,
+        c_ospeed: c_uint,
+    }
+
+    extern "C" {
+        fn tcgetattr(fd: c_int, termios: *mut Termios) -> c_int;
+        fn tcsetattr(fd: c_int, optional_actions: c_int, termios: *const Termios) -> c_int;
+    }
+
+    /// stdin reading a key at a time without echo, until dropped
+    pub(crate) struct RawMode {
+        saved: Termios,
+    }
+
+    impl RawMode {
+        /// None if stdin isn't a terminal
+        pub(crate) fn enable() -> Option<RawMode> {
+            let mut saved = std::mem::MaybeUninit::<Termios>::uninit();
+            // tcgetattr fills the struct when it succeeds
+            let saved = unsafe {
+                if tcgetattr(0, saved.as_mut_ptr()) != 0 {
+                    return None;
+                }
+                saved.assume_init()
+            };
+            let mut raw = saved;
+            raw.c_lflag &= !(ICANON | ECHO);
+            raw.c_cc[VMIN] = 1;
+            raw.c_cc[VTIME] = 0;
+            if unsafe { tcsetattr(0, TCSANOW, &raw) } != 0 {
+                return None;
+            }
+            Some(RawMode { saved })
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            unsafe {
+                tcsetattr(0, TCSANOW, &self.saved);
+            }
+        }
+    }
+}
+
+/// Elsewhere keys arrive a line at a time
+#[cfg(all(feature = "tui", not(target_os = "linux")))]
+mod term {
+    pub(crate) struct RawMode;
+
+    impl RawMode {
+        pub(crate) fn enable() -> Option<RawMode> {
+            None
+        }
+    }
+}
+
+/// Scroll through the blocks of a store, with a hex and text preview of the
+/// selected one.
+///
+/// The screen is drawn with ANSI escapes. On a Linux terminal each key acts
+/// as soon as it is pressed, and the terminal is put back on exit: j/k to
+/// move, n/p to page, a block index and enter to jump to it, q to quit.
+/// Elsewhere keys act once enter is pressed.
+#[cfg(feature = "tui")]
+fn browse(args: &[String]) {
+    use std::io::Read;
+    let path = match args.first() {
+        Some(p) => p.clone(),
+        None => usage(),
+    };
+    let mut s = Store::<B3BlockHasher>::new(path.clone()).unwrap_or_else(|e| fail(e));
+    let manifest = s.manifest().unwrap_or_else(|e| fail(e));
+    let blocks = &manifest.blocks;
+    let mut view = BrowseView::default();
+    let raw = term::RawMode::enable();
+    let mut keys = std::io::stdin().lock().bytes();
+    loop {
+        let mut screen = format!("\x1b[2J\x1b[H{} - {} blocks\n\n", path, blocks.len());
+        screen.push_str("  index        size  flags       hash\n");
+        for b in blocks.iter().skip(view.top).take(BROWSE_ROWS) {
+            let mark = if b.index == view.selected { '>' } else { ' ' };
+            let hash = &b.hash[..b.hash.len().min(16)];
+            screen.push_str(&format!("{} {:>5} {:>11}  {:#010x}  {}\n", mark, b.index, b.size, b.flags, hash));
+        }
+        screen.push('\n');
+        if view.selected < blocks.len() {
+            let kind = match s.content_type(view.selected) {
+                Ok(t) if t.0 != 0 => t.to_string(),
+                _ => String::new(),
+            };
+            screen.push_str(&format!("block {} {}\n", view.selected, kind));
+            match s.get(view.selected) {
+                Ok(data) => screen.push_str(&hex_preview(&data[..data.len().min(BROWSE_PREVIEW)])),
+                Err(e) => screen.push_str(&format!("{}\n", e)),
+            }
+        }
+        screen.push_str("\nj/k move, n/p page, <index> enter jump, q quit: ");
+        if let Some(i) = view.typed {
+            screen.push_str(&i.to_string());
+        }
+        print!("{}", screen);
+        let _ = std::io::stdout().flush();
+        let key = match keys.next() {
+            Some(Ok(k)) => k,
+            _ => break,
+        };
+        if !view.key(key, blocks.len()) {
+            break;
+        }
+    }
+    drop(raw);
+    println!();
+}
+
+/// Hex dump of data, 16 bytes a line with printable ASCII alongside
+#[cfg(feature = "tui")]
+fn hex_preview(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let text: String = line.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+        out.push_str(&format!("{:08x}  {:<47}  {}\n", i * 16, hex.join(" "), text));
+    }
+    out
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn fail(e: Box<dyn std::error::Error>) -> ! {
+    eprintln!("fstore: {}", e);
+    process::exit(1);
+}
+
+#[cfg(all(test, feature = "tui"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_preview_shows_hex_and_text() {
+        let data: Vec<u8> = (b'a'..=b'q').chain([0, b' ']).collect();
+        let preview = hex_preview(&data);
+        let lines: Vec<&str> = preview.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "00000000  61 62 63 64 65 66 67 68 69 6a 6b 6c 6d 6e 6f 70  abcdefghijklmnop");
+        assert_eq!(lines[1], format!("00000010  {:<47}  q. ", "71 00 20"));
+        assert_eq!(hex_preview(&[]), "");
+    }
+
+    #[test]
+    fn browse_keys_move_the_selection_and_scroll() {
+        let mut view = BrowseView::default();
+        let len = BROWSE_ROWS * 2 + 3;
+        assert!(view.key(b'k', len));
+        assert_eq!((view.selected, view.top), (0, 0));
+        for _ in 0..BROWSE_ROWS {
+            view.key(b'j', len);
+        }
+        // one past the last row on screen scrolls by one
+        assert_eq!((view.selected, view.top), (BROWSE_ROWS, 1));
+        view.key(b'n', len);
+        view.key(b'n', len);
+        assert_eq!((view.selected, view.top), (len - 1, len - BROWSE_ROWS));
+        view.key(b'p', len);
+        assert_eq!((view.selected, view.top), (len - 1 - BROWSE_ROWS, len - 1 - BROWSE_ROWS));
+
+        for &k in b"12" {
+            view.key(k, len);
+        }
+        assert_eq!(view.typed, Some(12));
+        view.key(b'\n', len);
+        assert_eq!((view.selected, view.top, view.typed), (12, 12, None));
+        for &k in b"999\r" {
+            view.key(k, len);
+        }
+        assert_eq!(view.selected, len - 1);
+        // a stray enter does nothing
+        view.key(b'\n', len);
+        assert_eq!(view.selected, len - 1);
+        assert!(!view.key(b'q', len));
+
+        let mut empty = BrowseView::default();
+        empty.key(b'j', 0);
+        assert_eq!((empty.selected, empty.top), (0, 0));
+    }
+}
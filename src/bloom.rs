@@ -0,0 +1,157 @@
+//Copyright 2021 Matthew Petricone
+use std::convert::TryFrom;
+use std::convert::TryInto;
+
+/// bits per expected item, gives roughly a 1% false positive rate
+const BLOOM_BITS_PER_ITEM: u64 = 10;
+/// bit positions per item
+const BLOOM_HASHES: u32 = 7;
+/// smallest filter we bother with
+const BLOOM_MIN_CAPACITY: u64 = 128;
+
+/// Bloom filter keyed by block checksums
+///
+/// Checksums are already uniformly distributed, so bit positions are taken
+/// straight from their bytes rather than hashing again.
+/// An empty key can't be placed, so it is always reported as maybe present.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    /// number of keys inserted
+    items: u64,
+    /// number of keys the filter was sized for
+    capacity: u64,
+}
+
+impl BloomFilter {
+    /// Create an empty filter sized for capacity keys
+    pub fn with_capacity(capacity: u64) -> BloomFilter {
+        let capacity = capacity.max(BLOOM_MIN_CAPACITY);
+        let words = BloomFilter::words_for(capacity).unwrap();
+        BloomFilter {
+            bits: vec![0; usize::try_from(words).unwrap()],
+            items: 0,
+            capacity,
+        }
+    }
+
+    /// Number of u64 words of bits for a capacity, None on overflow
+    fn words_for(capacity: u64) -> Option<u64> {
+        Some(capacity.checked_mul(BLOOM_BITS_PER_ITEM)?.div_ceil(64))
+    }
+
+    /// Add key to the filter
+    pub fn insert(&mut self, key: &[u8]) {
+        if key.is_empty() {
+            return;
+        }
+        for bit in self.positions(key) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.items += 1;
+    }
+
+    /// false if key was definitely never inserted
+    pub fn maybe_contains(&self, key: &[u8]) -> bool {
+        if key.is_empty() {
+            return true;
+        }
+        self.positions(key).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// true once more keys have been inserted than the filter was sized for
+    pub fn is_full(&self) -> bool {
+        self.items > self.capacity
+    }
+
+    /// Number of keys inserted
+    pub fn len(&self) -> u64 {
+        self.items
+    }
+
+    /// true if no keys have been inserted
+    pub fn is_empty(&self) -> bool {
+        self.items == 0
+    }
+
+    /// bit positions for key, by double hashing on its first 16 bytes
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let mut b = [0u8; 16];
+        let n = key.len().min(16);
+        b[..n].copy_from_slice(&key[..n]);
+        let h1 = u64::from_le_bytes(b[0..8].try_into().unwrap());
+        let h2 = if n > 8 {
+            u64::from_le_bytes(b[8..16].try_into().unwrap())
+        } else {
+            h1.rotate_left(32)
+        } | 1;
+        let nbits = self.bits.len() as u64 * 64;
+        (0..u64::from(BLOOM_HASHES)).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % nbits) as usize)
+    }
+
+    /// Serialize as u64 items, u64 capacity, then the bit words
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(16 + self.bits.len() * 8);
+        out.extend_from_slice(&self.items.to_le_bytes());
+        out.extend_from_slice(&self.capacity.to_le_bytes());
+        for w in &self.bits {
+            out.extend_from_slice(&w.to_le_bytes());
+        }
+        out
+    }
+
+    /// Inverse of to_bytes, None if data isn't a filter
+    pub fn from_bytes(data: &[u8]) -> Option<BloomFilter> {
+        if data.len() < 16 {
+            return None;
+        }
+        let items = u64::from_le_bytes(data[0..8].try_into().ok()?);
+        let capacity = u64::from_le_bytes(data[8..16].try_into().ok()?);
+        // check the size before allocating anything
+        let words = BloomFilter::words_for(capacity)?;
+        if capacity < BLOOM_MIN_CAPACITY || Some(data.len() as u64) != words.checked_mul(8)?.checked_add(16) {
+            return None;
+        }
+        let bits = data[16..]
+            .chunks_exact(8)
+            .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        Some(BloomFilter { bits, items, capacity })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{B3BlockHasher, BlockHasher};
+
+    #[test]
+    fn inserted_keys_are_found() {
+        let mut bf = BloomFilter::with_capacity(100);
+        let mut h = B3BlockHasher::create();
+        for i in 0..100u32 {
+            bf.insert(h.hash(&i.to_le_bytes()));
+        }
+        for i in 0..100u32 {
+            assert!(bf.maybe_contains(h.hash(&i.to_le_bytes())));
+        }
+        let misses = (1000..2000u32).filter(|i| !bf.maybe_contains(h.hash(&i.to_le_bytes()))).count();
+        assert!(misses > 950);
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let mut bf = BloomFilter::with_capacity(10);
+        bf.insert(&[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        let bf2 = BloomFilter::from_bytes(&bf.to_bytes()).unwrap();
+        assert_eq!(bf, bf2);
+        assert!(BloomFilter::from_bytes(&bf.to_bytes()[1..]).is_none());
+    }
+
+    #[test]
+    fn empty_key_is_maybe_present() {
+        let bf = BloomFilter::with_capacity(10);
+        assert!(bf.maybe_contains(&[]));
+        assert!(!bf.maybe_contains(&[1]));
+    }
+}
@@ -0,0 +1,346 @@
+//Copyright 2021 Matthew Petricone
+//! C interface, built with the capi feature.
+//!
+//! Stores use B3BlockHasher. Every function returns FSTORE_OK or a negative
+//! error code; results come back through out pointers. A panic doesn't
+//! unwind into C, the function returns FSTORE_ERR_PANIC instead.
+//! The matching header is include/fstore.h, kept in step by hand and
+//! checked against this file by the tests.
+use crate::crypto::B3BlockHasher;
+use crate::store::{Store, StoreError, StoreErrorKind, StoreIO};
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::io::ErrorKind;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+pub const FSTORE_OK: i32 = 0;
+/// a required pointer was null
+pub const FSTORE_ERR_NULL: i32 = -1;
+/// any other failure reading or writing the file
+pub const FSTORE_ERR_IO: i32 = -2;
+/// no block at that index
+pub const FSTORE_ERR_BOUNDS: i32 = -3;
+/// the block failed verification
+pub const FSTORE_ERR_CORRUPT: i32 = -4;
+/// another writer has the store open
+pub const FSTORE_ERR_LOCKED: i32 = -5;
+/// the store was opened read only
+pub const FSTORE_ERR_READONLY: i32 = -6;
+/// the buffer is too small, the size needed is in out_len
+pub const FSTORE_ERR_BUFFER: i32 = -7;
+/// the path is not valid UTF-8
+pub const FSTORE_ERR_PATH: i32 = -8;
+/// the block was deleted or quarantined
+pub const FSTORE_ERR_NOT_LIVE: i32 = -9;
+/// fstore panicked; the handle may be in any state, only close it
+pub const FSTORE_ERR_PANIC: i32 = -10;
+
+/// Opaque store handle for C
+pub struct FstoreHandle {
+    store: Store<B3BlockHasher>,
+}
+
+/// Map an error to a code
+fn error_code(e: &(dyn std::error::Error + 'static)) -> i32 {
+    if let Some(se) = StoreError::find(e) {
+        return match se.kind() {
+            StoreErrorKind::Checksum => FSTORE_ERR_CORRUPT,
+            StoreErrorKind::NotLive => FSTORE_ERR_NOT_LIVE,
+            StoreErrorKind::ReadOnly => FSTORE_ERR_READONLY,
+            StoreErrorKind::Locked => FSTORE_ERR_LOCKED,
+            StoreErrorKind::OutOfBounds => FSTORE_ERR_BOUNDS,
+            _ => FSTORE_ERR_IO,
+        };
+    }
+    match e.downcast_ref::<std::io::Error>().map(|io| io.kind()) {
+        Some(ErrorKind::WouldBlock) => FSTORE_ERR_LOCKED,
+        Some(ErrorKind::PermissionDenied) => FSTORE_ERR_READONLY,
+        _ => FSTORE_ERR_IO,
+    }
+}
+
+/// Run the body of a C function, returning FSTORE_ERR_PANIC if it panics
+fn guard(body: impl FnOnce() -> i32) -> i32 {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or(FSTORE_ERR_PANIC)
+}
+
+/// Convert a C path
+unsafe fn path_arg(path: *const c_char) -> Result<String, i32> {
+    if path.is_null() {
+        return Err(FSTORE_ERR_NULL);
+    }
+    CStr::from_ptr(path).to_str().map(|p| p.to_string()).map_err(|_| FSTORE_ERR_PATH)
+}
+
+/// Hand a new store to C
+unsafe fn give(result: Result<Store<B3BlockHasher>, Box<dyn std::error::Error>>, out: *mut *mut FstoreHandle) -> i32 {
+    match result {
+        Ok(store) => {
+            *out = Box::into_raw(Box::new(FstoreHandle { store }));
+            FSTORE_OK
+        }
+        Err(e) => error_code(e.as_ref()),
+    }
+}
+
+/// Open an existing store, for appending if writable is non zero.
+///
+/// # Safety
+/// path must be a null terminated string and out a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn fstore_open(path: *const c_char, writable: i32, out: *mut *mut FstoreHandle) -> i32 {
+    guard(|| {
+        if out.is_null() {
+            return FSTORE_ERR_NULL;
+        }
+        let path = match path_arg(path) {
+            Ok(p) => p,
+            Err(code) => return code,
+        };
+        let result = if writable != 0 {
+            Store::<B3BlockHasher>::open_for_write(path)
+        } else {
+            Store::<B3BlockHasher>::new(path)
+        };
+        give(result, out)
+    })
+}
+
+/// Create a store, replacing any file at path.
+///
+/// # Safety
+/// path must be a null terminated string and out a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn fstore_create(path: *const c_char, out: *mut *mut FstoreHandle) -> i32 {
+    guard(|| {
+        if out.is_null() {
+            return FSTORE_ERR_NULL;
+        }
+        let path = match path_arg(path) {
+            Ok(p) => p,
+            Err(code) => return code,
+        };
+        give(Store::<B3BlockHasher>::create(path).map_err(|e| e.into()), out)
+    })
+}
+
+/// Append len bytes of data as a new block, its index goes to out_index if not null.
+///
+/// # Safety
+/// handle must come from fstore_open or fstore_create, data must point to len bytes.
+#[no_mangle]
+pub unsafe extern "C" fn fstore_write(handle: *mut FstoreHandle, data: *const u8, len: usize, out_index: *mut u64) -> i32 {
+    guard(|| {
+        if handle.is_null() || (data.is_null() && len > 0) {
+            return FSTORE_ERR_NULL;
+        }
+        let data = if len == 0 { &[][..] } else { slice::from_raw_parts(data, len) };
+        match (*handle).store.put(data) {
+            Ok(i) => {
+                if !out_index.is_null() {
+                    *out_index = i as u64;
+                }
+                FSTORE_OK
+            }
+            Err(e) => error_code(e.as_ref()),
+        }
+    })
+}
+
+/// Copy the verified payload of block index into buf.
+///
+/// out_len gets the payload size; if it is more than cap nothing is copied
+/// and FSTORE_ERR_BUFFER is returned, so call again with a bigger buffer.
+///
+/// # Safety
+/// handle must be valid, buf must have room for cap bytes, out_len must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn fstore_read(handle: *mut FstoreHandle, index: u64, buf: *mut u8, cap: usize, out_len: *mut usize) -> i32 {
+    guard(|| {
+        if handle.is_null() || out_len.is_null() {
+            return FSTORE_ERR_NULL;
+        }
+        let store = &mut (*handle).store;
+        let index = match usize::try_from(index) {
+            Ok(i) if store.contains(i) => i,
+            _ => return FSTORE_ERR_BOUNDS,
+        };
+        match store.get(index) {
+            Ok(data) => {
+                *out_len = data.len();
+                if data.len() > cap {
+                    return FSTORE_ERR_BUFFER;
+                }
+                if !data.is_empty() {
+                    if buf.is_null() {
+                        return FSTORE_ERR_NULL;
+                    }
+                    ptr::copy_nonoverlapping(data.as_ptr(), buf, data.len());
+                }
+                FSTORE_OK
+            }
+            Err(e) => error_code(e.as_ref()),
+        }
+    })
+}
+
+/// Number of blocks, deleted ones included.
+///
+/// # Safety
+/// handle and out must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn fstore_len(handle: *const FstoreHandle, out: *mut u64) -> i32 {
+    guard(|| {
+        if handle.is_null() || out.is_null() {
+            return FSTORE_ERR_NULL;
+        }
+        *out = (*handle).store.len() as u64;
+        FSTORE_OK
+    })
+}
+
+/// Delete block index.
+///
+/// # Safety
+/// handle must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn fstore_delete(handle: *mut FstoreHandle, index: u64) -> i32 {
+    guard(|| {
+        if handle.is_null() {
+            return FSTORE_ERR_NULL;
+        }
+        let store = &mut (*handle).store;
+        let index = match usize::try_from(index) {
+            Ok(i) if store.contains(i) => i,
+            _ => return FSTORE_ERR_BOUNDS,
+        };
+        match store.delete_block(index) {
+            Ok(()) => FSTORE_OK,
+            Err(e) => error_code(e.as_ref()),
+        }
+    })
+}
+
+/// Close the store and free the handle, which must not be used again.
+///
+/// The handle is freed even if closing fails. A null handle is ignored.
+///
+/// # Safety
+/// handle must be null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn fstore_close(handle: *mut FstoreHandle) -> i32 {
+    guard(|| {
+        if handle.is_null() {
+            return FSTORE_OK;
+        }
+        match Box::from_raw(handle).store.close() {
+            Ok(()) => FSTORE_OK,
+            Err(e) => error_code(e.as_ref()),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    #[test]
+    fn c_round_trip() {
+        std::fs::create_dir_all("testout").unwrap();
+        let path = CString::new("testout/capi.st").unwrap();
+        unsafe {
+            let mut h = ptr::null_mut();
+            assert_eq!(fstore_create(path.as_ptr(), &mut h), FSTORE_OK);
+            let mut index = 99;
+            assert_eq!(fstore_write(h, [1u8, 2, 3].as_ptr(), 3, &mut index), FSTORE_OK);
+            assert_eq!(index, 0);
+            assert_eq!(fstore_close(h), FSTORE_OK);
+
+            assert_eq!(fstore_open(path.as_ptr(), 0, &mut h), FSTORE_OK);
+            let mut buf = [0u8; 2];
+            let mut len = 0;
+            assert_eq!(fstore_read(h, 0, buf.as_mut_ptr(), buf.len(), &mut len), FSTORE_ERR_BUFFER);
+            assert_eq!(len, 3);
+            let mut buf = [0u8; 3];
+            assert_eq!(fstore_read(h, 0, buf.as_mut_ptr(), buf.len(), &mut len), FSTORE_OK);
+            assert_eq!(buf, [1, 2, 3]);
+            assert_eq!(fstore_read(h, 1, buf.as_mut_ptr(), buf.len(), &mut len), FSTORE_ERR_BOUNDS);
+            assert_eq!(fstore_write(h, buf.as_ptr(), 1, ptr::null_mut()), FSTORE_ERR_READONLY);
+            assert_eq!(fstore_close(h), FSTORE_OK);
+            assert_eq!(fstore_open(ptr::null(), 0, &mut h), FSTORE_ERR_NULL);
+        }
+    }
+
+    #[test]
+    fn panics_stay_on_the_rust_side() {
+        assert_eq!(guard(|| panic!("in fstore")), FSTORE_ERR_PANIC);
+        assert_eq!(guard(|| FSTORE_OK), FSTORE_OK);
+    }
+
+    /// C spelling of a parameter type used in this file
+    fn c_type(rust: &str) -> String {
+        match rust.strip_prefix("*const ").map(|t| ("const ", t)).or_else(|| rust.strip_prefix("*mut ").map(|t| ("", t))) {
+            Some((qualifier, pointee)) => {
+                let inner = c_type(pointee);
+                if inner.ends_with('*') {
+                    format!("{}{}*", qualifier, inner)
+                } else {
+                    format!("{}{} *", qualifier, inner)
+                }
+            }
+            None => match rust {
+                "c_char" => "char",
+                "i32" => "int32_t",
+                "u8" => "uint8_t",
+                "u64" => "uint64_t",
+                "usize" => "size_t",
+                other => other,
+            }
+            .to_string(),
+        }
+    }
+
+    #[test]
+    fn header_matches_this_file() {
+        let header = include_str!("../include/fstore.h");
+        let source = include_str!("capi.rs");
+
+        let mut consts = 0;
+        for line in source.lines().filter_map(|l| l.strip_prefix("pub const ")) {
+            let (name, value) = line.split_once(": i32 = ").unwrap();
+            assert!(header.contains(&format!("#define {} {}\n", name, value.trim_end_matches(';'))), "{}", name);
+            consts += 1;
+        }
+        // the include guard has no value
+        let defines = header.lines().filter(|l| l.starts_with("#define FSTORE_") && l.split(' ').count() == 3);
+        assert_eq!(defines.count(), consts);
+
+        let mut fns = 0;
+        for line in source.lines().filter_map(|l| l.strip_prefix("pub unsafe extern \"C\" fn ")) {
+            let (name, rest) = line.split_once('(').unwrap();
+            let params: Vec<String> = rest
+                .split_once(") -> i32")
+                .unwrap()
+                .0
+                .split(", ")
+                .map(|p| {
+                    let (arg, ty) = p.split_once(": ").unwrap();
+                    let ty = c_type(ty);
+                    if ty.ends_with('*') {
+                        format!("{}{}", ty, arg)
+                    } else {
+                        format!("{} {}", ty, arg)
+                    }
+                })
+                .collect();
+            let prototype = format!("int32_t {}({});", name, params.join(", "));
+            assert!(header.contains(&prototype), "{}", prototype);
+            fns += 1;
+        }
+        assert_eq!(header.matches("int32_t fstore_").count(), fns);
+    }
+}
@@ -0,0 +1,214 @@
+//Copyright 2021 Matthew Petricone
+//! Content defined chunking for large payloads.
+//!
+//! A payload is cut where a gear rolling hash of the last bytes hits a
+//! pattern, so an edit only changes the chunks around it. Stored with
+//! Store::put_chunked, each chunk is its own block and the payload is a
+//! chunk list block naming them; putting a new version against the old one
+//! stores only the chunks that changed.
+//!
+//! A chunk list payload is CHUNK_LIST_MAGIC, u64 total length, u64 chunk
+//! count, then the u64 block index of each chunk.
+use crate::crypto::BlockHasher;
+use crate::store::{BlockId, Store};
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::ops::Range;
+
+/// Marks a chunk list block
+pub static CHUNK_LIST_MAGIC: &[u8; 8] = b"FSTCHK01";
+static ERROR_CHUNK_LIST: &str = "Block is not a chunk list.";
+
+/// Chunk size limits, avg_size must be a power of two
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkParams {
+    pub min_size: usize,
+    pub avg_size: usize,
+    pub max_size: usize,
+}
+
+impl Default for ChunkParams {
+    fn default() -> ChunkParams {
+        ChunkParams {
+            min_size: 2 * 1024,
+            avg_size: 8 * 1024,
+            max_size: 64 * 1024,
+        }
+    }
+}
+
+/// Result of Store::put_chunked
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ChunkedPut {
+    /// the chunk list block, pass this to get_chunked
+    pub id: BlockId,
+    /// chunks written
+    pub new_chunks: usize,
+    /// chunks shared with the base version
+    pub reused_chunks: usize,
+}
+
+/// Random u64 per byte value, fixed so chunk boundaries never change
+fn gear_table() -> [u64; 256] {
+    // splitmix64
+    let mut table = [0u64; 256];
+    let mut x: u64 = 0x9E37_79B9_7F4A_7C15;
+    for t in table.iter_mut() {
+        x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = x;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        *t = z ^ (z >> 31);
+    }
+    table
+}
+
+/// Split data into chunks
+pub fn chunk(data: &[u8], params: &ChunkParams) -> Vec<Range<usize>> {
+    let gear = gear_table();
+    let mask = (params.avg_size.max(1).next_power_of_two() - 1) as u64;
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let end = (start + params.max_size.max(1)).min(data.len());
+        let mut cut = end;
+        let mut h: u64 = 0;
+        for (i, b) in data[start..end].iter().enumerate() {
+            h = (h << 1).wrapping_add(gear[usize::from(*b)]);
+            if i + 1 >= params.min_size && h & mask == 0 {
+                cut = start + i + 1;
+                break;
+            }
+        }
+        chunks.push(start..cut);
+        start = cut;
+    }
+    chunks
+}
+
+impl<T: BlockHasher> Store<T> {
+    /// Store data as chunks and a chunk list.
+    ///
+    /// Chunks of base, an earlier chunk list, that are unchanged are shared
+    /// instead of written again.
+    pub fn put_chunked(
+        &mut self,
+        data: &[u8],
+        base: Option<BlockId>,
+        params: &ChunkParams,
+    ) -> Result<ChunkedPut, Box<dyn std::error::Error>> {
+        let mut known = Vec::new();
+        if let Some(b) = base {
+            for id in self.chunk_ids(b)? {
+                known.push((self.block_header(id)?.fields().checksum, id));
+            }
+        }
+        let ranges = chunk(data, params);
+        let mut list = CHUNK_LIST_MAGIC.to_vec();
+        list.extend_from_slice(&u64::try_from(data.len())?.to_le_bytes());
+        list.extend_from_slice(&u64::try_from(ranges.len())?.to_le_bytes());
+        let mut put = ChunkedPut::default();
+        for r in ranges {
+            let c = &data[r];
+            let hash = self.hash(c);
+            let id = match known.iter().find(|(sum, _)| !sum.is_empty() && hash.starts_with(sum)) {
+                Some((_, id)) => {
+                    put.reused_chunks += 1;
+                    *id
+                }
+                None => {
+                    put.new_chunks += 1;
+                    self.put(c)?
+                }
+            };
+            list.extend_from_slice(&u64::try_from(id)?.to_le_bytes());
+        }
+        put.id = self.put(&list)?;
+        Ok(put)
+    }
+
+    /// Reassemble a payload stored with put_chunked
+    pub fn get_chunked(&mut self, id: BlockId) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let (total, ids) = parse_chunk_list(&self.get(id)?)?;
+        let mut out = Vec::new();
+        for c in ids {
+            out.extend_from_slice(&self.get(c)?);
+        }
+        if out.len() as u64 != total {
+            return Err(ERROR_CHUNK_LIST.into());
+        }
+        Ok(out)
+    }
+
+    /// Block indexes of the chunks named by the chunk list at id
+    pub fn chunk_ids(&mut self, id: BlockId) -> Result<Vec<BlockId>, Box<dyn std::error::Error>> {
+        Ok(parse_chunk_list(&self.get(id)?)?.1)
+    }
+}
+
+/// Total length and chunk indexes of a chunk list payload
+fn parse_chunk_list(list: &[u8]) -> Result<(u64, Vec<BlockId>), Box<dyn std::error::Error>> {
+    if list.len() < 24 || &list[0..8] != CHUNK_LIST_MAGIC {
+        return Err(ERROR_CHUNK_LIST.into());
+    }
+    let total = u64::from_le_bytes(list[8..16].try_into()?);
+    let count = u64::from_le_bytes(list[16..24].try_into()?);
+    if count.checked_mul(8).and_then(|c| c.checked_add(24)) != Some(list.len() as u64) {
+        return Err(ERROR_CHUNK_LIST.into());
+    }
+    let ids = list[24..]
+        .chunks_exact(8)
+        .map(|c| u64::from_le_bytes(c.try_into().unwrap()) as BlockId)
+        .collect();
+    Ok((total, ids))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+
+    fn pseudo_random(len: usize, seed: u64) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chunks_respect_limits() {
+        let p = ChunkParams { min_size: 64, avg_size: 256, max_size: 1024 };
+        let data = pseudo_random(100_000, 1);
+        let chunks = chunk(&data, &p);
+        assert_eq!(chunks.first().unwrap().start, 0);
+        assert_eq!(chunks.last().unwrap().end, data.len());
+        for w in chunks.windows(2) {
+            assert_eq!(w[0].end, w[1].start);
+        }
+        assert!(chunks[..chunks.len() - 1].iter().all(|c| c.len() >= 64 && c.len() <= 1024));
+        assert!(chunk(&[], &p).is_empty());
+    }
+
+    #[test]
+    fn edit_stores_only_changed_chunks() {
+        std::fs::create_dir_all("testout").unwrap();
+        let mut s = Store::<B3BlockHasher>::create("testout/chunked.st".to_string()).unwrap();
+        let p = ChunkParams { min_size: 64, avg_size: 256, max_size: 1024 };
+        let v1 = pseudo_random(50_000, 7);
+        let first = s.put_chunked(&v1, None, &p).unwrap();
+        assert_eq!(first.reused_chunks, 0);
+        let mut v2 = v1.clone();
+        v2.splice(20_000..20_010, [1u8, 2, 3].iter().copied());
+        let second = s.put_chunked(&v2, Some(first.id), &p).unwrap();
+        assert!(second.new_chunks <= 3);
+        assert!(second.reused_chunks > first.new_chunks - 5);
+        assert_eq!(s.get_chunked(first.id).unwrap(), v1);
+        assert_eq!(s.get_chunked(second.id).unwrap(), v2);
+        assert!(s.get_chunked(0).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//Copyright 2021 Matthew Petricone
+//! Named column families sharing one store file.
+//!
+//! A block put through Store::cf belongs to that family: its payload is
+//! CF_MAGIC, u16 name length and the name before the data. Each family reads
+//! and iterates over its own blocks only, and has its own CfOptions. Blocks
+//! put straight into the store belong to no family.
+//!
+//! Every family uses the store's hasher and header codec, as those are fixed
+//! for a file; options are limited to what can differ between blocks.
+use crate::crypto::BlockHasher;
+use crate::store::{BlockId, Store};
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::convert::TryInto;
+
+/// Marks a block belonging to a column family
+pub static CF_MAGIC: &[u8; 8] = b"FSTCF001";
+
+static ERROR_CF_NAME: &str = "Column family names are 1 to 255 bytes.";
+static ERROR_CF_OTHER: &str = "Block is not in this column family.";
+static ERROR_CF_TOOLARGE: &str = "Block larger than the column family allows.";
+
+/// Options of one column family
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CfOptions {
+    max_block_size: Option<u64>,
+}
+
+impl CfOptions {
+    /// Default options, no limits
+    pub fn new() -> CfOptions {
+        CfOptions::default()
+    }
+
+    /// Largest payload put through the family, on top of the store's own limit
+    pub fn max_block_size(mut self, bytes: u64) -> CfOptions {
+        self.max_block_size = Some(bytes);
+        self
+    }
+}
+
+/// Block index and payload of a block in a family
+pub type CfBlock = (BlockId, Vec<u8>);
+
+/// A column family of a store, see Store::cf
+pub struct ColumnFamily<'a, T: BlockHasher> {
+    store: &'a mut Store<T>,
+    /// CF_MAGIC, name length and name
+    prefix: Vec<u8>,
+    options: CfOptions,
+}
+
+/// CF_MAGIC, name length and name, what blocks of family name start with
+pub(crate) fn cf_prefix(name: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if name.is_empty() || name.len() > 255 {
+        return Err(ERROR_CF_NAME.into());
+    }
+    let mut prefix = CF_MAGIC.to_vec();
+    prefix.extend_from_slice(&u16::try_from(name.len())?.to_le_bytes());
+    prefix.extend_from_slice(name.as_bytes());
+    Ok(prefix)
+}
+
+/// Family name of a block payload, None if it is in no family
+fn family_of(payload: &[u8]) -> Option<&[u8]> {
+    let rest = payload.strip_prefix(&CF_MAGIC[..])?;
+    let len = usize::from(u16::from_le_bytes(rest.get(0..2)?.try_into().ok()?));
+    rest.get(2..2 + len)
+}
+
+impl<T: BlockHasher> Store<T> {
+    /// Column family name with default options
+    pub fn cf(&mut self, name: &str) -> Result<ColumnFamily<'_, T>, Box<dyn std::error::Error>> {
+        self.cf_with(name, CfOptions::default())
+    }
+
+    /// Column family name with options
+    pub fn cf_with(&mut self, name: &str, options: CfOptions) -> Result<ColumnFamily<'_, T>, Box<dyn std::error::Error>> {
+        Ok(ColumnFamily { store: self, prefix: cf_prefix(name)?, options })
+    }
+
+    /// Names of the column families with live blocks, sorted
+    pub fn column_families(&mut self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut names = BTreeSet::new();
+        for block in self.iter() {
+            let (_, data) = block?;
+            if let Some(name) = family_of(&data) {
+                names.insert(String::from_utf8_lossy(name).into_owned());
+            }
+        }
+        Ok(names.into_iter().collect())
+    }
+}
+
+impl<T: BlockHasher> ColumnFamily<'_, T> {
+    /// Name of the family
+    pub fn name(&self) -> &str {
+        std::str::from_utf8(&self.prefix[CF_MAGIC.len() + 2..]).unwrap()
+    }
+
+    /// Options of the family
+    pub fn options(&self) -> CfOptions {
+        self.options
+    }
+
+    /// Append data to the family, returning its block index in the store
+    pub fn put(&mut self, data: &[u8]) -> Result<BlockId, Box<dyn std::error::Error>> {
+        if self.options.max_block_size.is_some_and(|max| data.len() as u64 > max) {
+            return Err(ERROR_CF_TOOLARGE.into());
+        }
+        let mut block = self.prefix.clone();
+        block.extend_from_slice(data);
+        self.store.put(&block)
+    }
+
+    /// Payload of the block at index, which must be in the family
+    pub fn get(&mut self, index: BlockId) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut data = self.store.get(index)?;
+        if !data.starts_with(&self.prefix) {
+            return Err(ERROR_CF_OTHER.into());
+        }
+        data.drain(..self.prefix.len());
+        Ok(data)
+    }
+
+    /// Delete the block at index, which must be in the family
+    pub fn delete(&mut self, index: BlockId) -> Result<(), Box<dyn std::error::Error>> {
+        self.get(index)?;
+        self.store.delete_many(&[index])
+    }
+
+    /// Block index and payload of every live block of the family, in order
+    pub fn iter(&mut self) -> Result<Vec<CfBlock>, Box<dyn std::error::Error>> {
+        let mut out = Vec::new();
+        for block in self.store.iter() {
+            let (index, mut data) = block?;
+            if data.starts_with(&self.prefix) {
+                data.drain(..self.prefix.len());
+                out.push((index, data));
+            }
+        }
+        Ok(out)
+    }
+
+    /// Number of live blocks in the family
+    pub fn len(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
+        Ok(self.iter()?.len())
+    }
+
+    /// true if the family has no live blocks
+    pub fn is_empty(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self.len()? == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+
+    #[test]
+    fn families_are_kept_apart() {
+        std::fs::create_dir_all("testout").unwrap();
+        let mut s = Store::<B3BlockHasher>::create("testout/column.st".to_string()).unwrap();
+        let plain = s.put(b"plain").unwrap();
+        let e1 = s.cf("events").unwrap().put(b"started").unwrap();
+        let i1 = s.cf("index").unwrap().put(b"k1").unwrap();
+        let e2 = s.cf("events").unwrap().put(b"stopped").unwrap();
+
+        let mut events = s.cf("events").unwrap();
+        assert_eq!(events.name(), "events");
+        assert_eq!(events.iter().unwrap(), vec![(e1, b"started".to_vec()), (e2, b"stopped".to_vec())]);
+        assert_eq!(events.get(e2).unwrap(), b"stopped");
+        assert!(events.get(i1).is_err());
+        assert!(events.get(plain).is_err());
+        assert!(events.delete(i1).is_err());
+        events.delete(e1).unwrap();
+        assert_eq!(events.len().unwrap(), 1);
+
+        let mut small = s.cf_with("small", CfOptions::new().max_block_size(2)).unwrap();
+        assert!(small.put(b"abc").is_err());
+        small.put(b"ab").unwrap();
+        assert!(s.cf("").is_err());
+        assert_eq!(s.column_families().unwrap(), vec!["events", "index", "small"]);
+        assert_eq!(s.get(plain).unwrap(), b"plain");
+    }
+}
@@ -0,0 +1,140 @@
+//Copyright 2021 Matthew Petricone
+//! Content types of block payloads.
+//!
+//! A store whose header codec records them (FieldsHeaderCodec with
+//! HEADER_FIELD_CONTENT_TYPE) keeps a u16 content type in every header, set
+//! by Store::put_typed. Codes below USER_START are a registry of common MIME
+//! types, so generic tools can pick how to show or name a payload; codes from
+//! USER_START up are the application's own and mean nothing to fstore.
+use std::fmt;
+
+/// Content type code of a block payload
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ContentType(pub u16);
+
+/// Registered code, MIME type and file extension
+static REGISTRY: &[(u16, &str, &str)] = &[
+    (1, "application/octet-stream", "bin"),
+    (2, "text/plain", "txt"),
+    (3, "application/json", "json"),
+    (4, "application/cbor", "cbor"),
+    (5, "application/xml", "xml"),
+    (6, "text/html", "html"),
+    (7, "text/csv", "csv"),
+    (8, "image/png", "png"),
+    (9, "image/jpeg", "jpg"),
+    (10, "application/pdf", "pdf"),
+    (11, "application/gzip", "gz"),
+    (12, "application/zstd", "zst"),
+    (13, "application/vnd.apache.arrow.file", "arrow"),
+    (14, "application/vnd.apache.arrow.stream", "arrows"),
+    (15, "application/vnd.apache.parquet", "parquet"),
+];
+
+impl ContentType {
+    /// No content type recorded
+    pub const UNKNOWN: ContentType = ContentType(0);
+    pub const OCTET_STREAM: ContentType = ContentType(1);
+    pub const TEXT: ContentType = ContentType(2);
+    pub const JSON: ContentType = ContentType(3);
+    pub const CBOR: ContentType = ContentType(4);
+    pub const XML: ContentType = ContentType(5);
+    pub const HTML: ContentType = ContentType(6);
+    pub const CSV: ContentType = ContentType(7);
+    pub const PNG: ContentType = ContentType(8);
+    pub const JPEG: ContentType = ContentType(9);
+    pub const PDF: ContentType = ContentType(10);
+    pub const GZIP: ContentType = ContentType(11);
+    pub const ZSTD: ContentType = ContentType(12);
+    pub const ARROW_FILE: ContentType = ContentType(13);
+    pub const ARROW_STREAM: ContentType = ContentType(14);
+    pub const PARQUET: ContentType = ContentType(15);
+    /// First code of the range left to applications
+    pub const USER_START: u16 = 0x8000;
+
+    /// Application defined content type n, None if n is out of the user range
+    pub fn user(n: u16) -> Option<ContentType> {
+        ContentType::USER_START.checked_add(n).map(ContentType)
+    }
+
+    /// true for codes in the range left to applications
+    pub fn is_user(&self) -> bool {
+        self.0 >= ContentType::USER_START
+    }
+
+    /// MIME type of a registered code
+    pub fn mime(&self) -> Option<&'static str> {
+        REGISTRY.iter().find(|r| r.0 == self.0).map(|r| r.1)
+    }
+
+    /// File extension for a registered code, without the dot
+    pub fn extension(&self) -> Option<&'static str> {
+        REGISTRY.iter().find(|r| r.0 == self.0).map(|r| r.2)
+    }
+
+    /// Registered code of a MIME type, ignoring parameters such as charset
+    pub fn from_mime(mime: &str) -> Option<ContentType> {
+        let essence = mime.split(';').next().unwrap_or("").trim();
+        REGISTRY
+            .iter()
+            .find(|r| r.1.eq_ignore_ascii_case(essence))
+            .map(|r| ContentType(r.0))
+    }
+
+    /// true if the payload is meant to be read as text
+    pub fn is_text(&self) -> bool {
+        self.mime().is_some_and(|m| m.starts_with("text/") || m == "application/json" || m == "application/xml")
+    }
+}
+
+impl fmt::Display for ContentType {
+    /// The MIME type if registered, else the code in hex
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mime() {
+            Some(m) => write!(f, "{}", m),
+            None => write!(f, "{:#06x}", self.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+    use crate::data_header::{FieldsHeaderCodec, HEADER_FIELD_CONTENT_TYPE};
+    use crate::store::{Store, StoreError, StoreErrorKind, StoreIO};
+
+    #[test]
+    fn content_types_are_recorded_and_checked() {
+        assert_eq!(ContentType::from_mime("Text/Plain; charset=utf-8"), Some(ContentType::TEXT));
+        assert_eq!(ContentType::JSON.extension(), Some("json"));
+        assert_eq!(ContentType::user(1).unwrap().to_string(), "0x8001");
+        assert!(ContentType::user(u16::MAX).is_none());
+        assert!(ContentType::CSV.is_text() && !ContentType::PNG.is_text());
+
+        std::fs::create_dir_all("testout").unwrap();
+        let mut plain = Store::<B3BlockHasher>::create("testout/content_type_plain.st".to_string()).unwrap();
+        assert!(plain.put_typed(b"{}", ContentType::JSON).is_err());
+        assert!(plain.is_empty());
+
+        let mut s = Store::<B3BlockHasher>::create_with_codec("testout/content_type.st".to_string(), Box::new(FieldsHeaderCodec { fields: HEADER_FIELD_CONTENT_TYPE })).unwrap();
+        let json = s.put_typed(b"{}", ContentType::JSON).unwrap();
+        let raw = s.put(b"raw").unwrap();
+        let mine = s.put_typed(b"mine", ContentType::user(7).unwrap()).unwrap();
+        assert_eq!(s.content_type(json).unwrap(), ContentType::JSON);
+        assert_eq!(s.content_type(raw).unwrap(), ContentType::UNKNOWN);
+        assert!(s.content_type(mine).unwrap().is_user());
+        assert_eq!(s.get_typed(json, ContentType::JSON).unwrap(), b"{}");
+        let e = s.get_typed(json, ContentType::TEXT).err().unwrap();
+        assert_eq!(
+            e.downcast_ref::<StoreError>().unwrap().kind(),
+            StoreErrorKind::ContentType { expected: 2, found: 3 }
+        );
+
+        s.swap(&[(json, b"[]")]).unwrap();
+        s.delete_many(&[raw]).unwrap();
+        let report = s.compact().unwrap();
+        let json = report.remap[json].unwrap();
+        assert_eq!(s.get_typed(json, ContentType::JSON).unwrap(), b"[]");
+    }
+}
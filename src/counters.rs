@@ -0,0 +1,207 @@
+//Copyright 2021 Matthew Petricone
+//! Counts of the I/O a Store does, see Store::io_counters.
+#[cfg(feature = "fault-injection")]
+use crate::fault::{Fault, FaultState, WriteLog};
+use std::cell::Cell;
+use std::fs::{File, Metadata};
+use std::io::{Read, Result, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
+
+/// I/O done through one Store handle since it was opened or counters were reset
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct IoCounters {
+    /// bytes read from the file
+    pub bytes_read: u64,
+    /// bytes written to the file
+    pub bytes_written: u64,
+    /// reads, writes, seeks, syncs and other calls on the file
+    pub syscalls: u64,
+    /// seeks, including asking for the position
+    pub seeks: u64,
+    /// lookups answered from memory without touching the file, such as
+    /// maybe_contains ruling a hash out
+    pub cache_hits: u64,
+}
+
+/// File that counts what is done with it, and with the fault-injection
+/// feature can be made to fail
+///
+/// Derefs to the File for everything it doesn't count.
+#[derive(Debug)]
+pub(crate) struct CountingFile {
+    file: File,
+    /// a Cell, so lookups through &Store can count too
+    counters: Cell<IoCounters>,
+    /// bytes written since opened, never reset
+    total_written: u64,
+    #[cfg(feature = "fault-injection")]
+    faults: Option<FaultState>,
+    /// where writes are recorded, if anywhere
+    #[cfg(feature = "fault-injection")]
+    log: Option<WriteLog>,
+}
+
+impl CountingFile {
+    pub(crate) fn new(file: File) -> CountingFile {
+        CountingFile {
+            file,
+            counters: Cell::new(IoCounters::default()),
+            total_written: 0,
+            #[cfg(feature = "fault-injection")]
+            faults: None,
+            #[cfg(feature = "fault-injection")]
+            log: None,
+        }
+    }
+
+    /// Faults to inject from now on, None for none
+    #[cfg(feature = "fault-injection")]
+    pub(crate) fn set_faults(&mut self, faults: Option<FaultState>) {
+        self.faults = faults;
+    }
+
+    /// Record writes in log from now on, None to stop
+    #[cfg(feature = "fault-injection")]
+    pub(crate) fn set_log(&mut self, log: Option<WriteLog>) {
+        self.log = log;
+    }
+
+    /// Faults being injected
+    #[cfg(feature = "fault-injection")]
+    pub(crate) fn faults(&self) -> Option<&FaultState> {
+        self.faults.as_ref()
+    }
+
+    /// Count an operation against the fault plan, true if it should be cut short
+    #[cfg(feature = "fault-injection")]
+    fn inject(&mut self) -> Result<bool> {
+        match self.faults.as_mut() {
+            Some(f) => Ok(matches!(f.next()?, Some(Fault::ShortRead) | Some(Fault::ShortWrite))),
+            None => Ok(false),
+        }
+    }
+
+    #[cfg(not(feature = "fault-injection"))]
+    #[inline]
+    fn inject(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Counts so far
+    pub(crate) fn counters(&self) -> IoCounters {
+        self.counters.get()
+    }
+
+    /// Bytes written since the file was opened, whatever reset the counts
+    pub(crate) fn total_written(&self) -> u64 {
+        self.total_written
+    }
+
+    /// Replace the counts
+    pub(crate) fn set_counters(&self, counters: IoCounters) {
+        self.counters.set(counters);
+    }
+
+    /// Change the counts with f
+    pub(crate) fn count<F: FnOnce(&mut IoCounters)>(&self, f: F) {
+        let mut c = self.counters.get();
+        f(&mut c);
+        self.counters.set(c);
+    }
+
+    pub(crate) fn sync_data(&mut self) -> Result<()> {
+        self.inject()?;
+        self.count(|c| c.syscalls += 1);
+        self.file.sync_data()
+    }
+
+    pub(crate) fn sync_all(&mut self) -> Result<()> {
+        self.inject()?;
+        self.count(|c| c.syscalls += 1);
+        self.file.sync_all()
+    }
+
+    pub(crate) fn set_len(&mut self, size: u64) -> Result<()> {
+        self.inject()?;
+        self.count(|c| c.syscalls += 1);
+        self.file.set_len(size)?;
+        #[cfg(feature = "fault-injection")]
+        if let Some(log) = &self.log {
+            log.set_len(size);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn metadata(&self) -> Result<Metadata> {
+        self.count(|c| c.syscalls += 1);
+        self.file.metadata()
+    }
+}
+
+impl Read for CountingFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let short = self.inject()?;
+        let n = if short && buf.len() > 1 {
+            let half = buf.len() / 2;
+            self.file.read(&mut buf[..half])?
+        } else {
+            self.file.read(buf)?
+        };
+        self.count(|c| {
+            c.syscalls += 1;
+            c.bytes_read += n as u64;
+        });
+        Ok(n)
+    }
+}
+
+impl Write for CountingFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let short = self.inject()?;
+        let n = if short && buf.len() > 1 {
+            self.file.write(&buf[..buf.len() / 2])?
+        } else {
+            self.file.write(buf)?
+        };
+        #[cfg(feature = "fault-injection")]
+        if let Some(log) = &self.log {
+            let end = self.file.stream_position()?;
+            log.write(end - n as u64, &buf[..n]);
+        }
+        self.total_written += n as u64;
+        self.count(|c| {
+            c.syscalls += 1;
+            c.bytes_written += n as u64;
+        });
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for CountingFile {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.inject()?;
+        self.count(|c| {
+            c.syscalls += 1;
+            c.seeks += 1;
+        });
+        self.file.seek(pos)
+    }
+}
+
+impl Deref for CountingFile {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl DerefMut for CountingFile {
+    fn deref_mut(&mut self) -> &mut File {
+        &mut self.file
+    }
+}
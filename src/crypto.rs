@@ -10,30 +10,147 @@ pub trait BlockHasher {
     fn create() -> Self;
     /// Generate hash from input
     fn hash(&mut self, input: &[u8]) -> &[u8];
+    /// Hash of the parts one after another, as hash of them joined would be.
+    ///
+    /// The default joins them; hashers that can take input in pieces should
+    /// override it.
+    fn hash_parts(&mut self, parts: &[&[u8]]) -> &[u8] {
+        self.hash(&parts.concat())
+    }
     /// Size of hash
     fn size() -> usize;
+    /// Id recorded in headers that name their hasher, 0 if it has none.
+    ///
+    /// Built in hashers with an id can verify blocks in a store of any hasher.
+    fn id() -> u8 {
+        0
+    }
+    /// Create an instance whose hashes depend on context, so stores given
+    /// different contexts never agree on a hash. None if the hasher can't.
+    fn create_personalized(_context: &str) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        None
+    }
+}
+
+/// Hash input with the built in hasher with id, None if there isn't one
+pub fn hash_with_id(id: u8, input: &[u8]) -> Option<Vec<u8>> {
+    match id {
+        B3_HASHER_ID => Some(B3BlockHasher::create().hash(input).to_vec()),
+        CRC32_HASHER_ID => Some(Crc32BlockHasher::create().hash(input).to_vec()),
+        _ => None,
+    }
 }
 
+const B3_HASHER_ID: u8 = 1;
+const CRC32_HASHER_ID: u8 = 2;
+
 /// Blake3 Hasher
 #[derive(Default, Debug, PartialEq)]
 pub struct B3BlockHasher {
     /// Stores the value of hash as bytes, not aligned.
     pub hash_value: [u8;  32],
+    /// context for blake3's derive_key mode, see BlockHasher::create_personalized
+    context: Option<String>,
 }
 
 impl BlockHasher for B3BlockHasher {
 
     fn create() -> Self {
-        B3BlockHasher { hash_value: [0; 32] }
+        B3BlockHasher { hash_value: [0; 32], context: None }
     }
     fn hash(&mut self, input: &[u8]) -> &[u8] {
-        self.hash_value = *blake3::hash(input).as_bytes();
+        self.hash_value = match &self.context {
+            Some(c) => *blake3::Hasher::new_derive_key(c).update(input).finalize().as_bytes(),
+            None => *blake3::hash(input).as_bytes(),
+        };
+        &self.hash_value
+    }
+
+    fn hash_parts(&mut self, parts: &[&[u8]]) -> &[u8] {
+        let mut hasher = match &self.context {
+            Some(c) => blake3::Hasher::new_derive_key(c),
+            None => blake3::Hasher::new(),
+        };
+        for p in parts {
+            hasher.update(p);
+        }
+        self.hash_value = *hasher.finalize().as_bytes();
         &self.hash_value
     }
 
     fn size() -> usize {
         32
     }
+
+    fn id() -> u8 {
+        B3_HASHER_ID
+    }
+
+    fn create_personalized(context: &str) -> Option<Self> {
+        Some(B3BlockHasher {
+            hash_value: [0; 32],
+            context: Some(context.to_string()),
+        })
+    }
+}
+
+#[cfg(feature = "secure-memory")]
+impl Drop for B3BlockHasher {
+    /// Wipe the last hash and the personalization
+    fn drop(&mut self) {
+        crate::zeroize::zeroize(&mut self.hash_value);
+        if let Some(c) = self.context.take() {
+            crate::zeroize::zeroize(&mut c.into_bytes());
+        }
+    }
+}
+
+/// CRC-32 (IEEE), fast but only good for catching accidental damage
+#[derive(Default, Debug, PartialEq)]
+pub struct Crc32BlockHasher {
+    /// Stores the value of hash as little endian bytes
+    pub hash_value: [u8; 4],
+}
+
+impl BlockHasher for Crc32BlockHasher {
+    fn create() -> Self {
+        Crc32BlockHasher { hash_value: [0; 4] }
+    }
+
+    fn hash(&mut self, input: &[u8]) -> &[u8] {
+        self.hash_parts(&[input])
+    }
+
+    fn hash_parts(&mut self, parts: &[&[u8]]) -> &[u8] {
+        let mut crc = !0u32;
+        for b in parts.iter().flat_map(|p| p.iter()) {
+            crc ^= u32::from(*b);
+            for _ in 0..8 {
+                crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+            }
+        }
+        self.hash_value = (!crc).to_le_bytes();
+        &self.hash_value
+    }
+
+    fn size() -> usize {
+        4
+    }
+
+    fn id() -> u8 {
+        CRC32_HASHER_ID
+    }
+}
+
+#[cfg(feature = "secure-memory")]
+impl Drop for Crc32BlockHasher {
+    /// Wipe the last hash
+    fn drop(&mut self) {
+        crate::zeroize::zeroize(&mut self.hash_value);
+    }
 }
 
 #[derive(Default)]
@@ -42,6 +159,33 @@ pub struct NullBlockHasher {
 
 impl BlockHasher for NullBlockHasher {
     fn create() -> Self { NullBlockHasher {} }
-    fn hash(&mut self, _input: &[u8]) -> &[u8] { &[0] }
+    fn hash(&mut self, _input: &[u8]) -> &[u8] { &[] }
     fn size() -> usize { 0 }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        let mut h = Crc32BlockHasher::create();
+        assert_eq!(h.hash(b"123456789"), &0xCBF4_3926u32.to_le_bytes());
+        assert_eq!(hash_with_id(Crc32BlockHasher::id(), b"123456789").unwrap(), 0xCBF4_3926u32.to_le_bytes());
+        assert!(hash_with_id(0, b"").is_none());
+        assert_eq!(h.hash_parts(&[b"1234", b"", b"56789"]), &0xCBF4_3926u32.to_le_bytes());
+        let whole = B3BlockHasher::create().hash(b"123456789").to_vec();
+        assert_eq!(B3BlockHasher::create().hash_parts(&[b"12", b"3456789"]), &whole[..]);
+    }
+
+    #[test]
+    fn personalized_hashes_differ() {
+        let plain = B3BlockHasher::create().hash(b"data").to_vec();
+        let a = B3BlockHasher::create_personalized("deploy a").unwrap().hash(b"data").to_vec();
+        let b = B3BlockHasher::create_personalized("deploy b").unwrap().hash(b"data").to_vec();
+        assert_ne!(plain, a);
+        assert_ne!(a, b);
+        assert_eq!(a, B3BlockHasher::create_personalized("deploy a").unwrap().hash(b"data"));
+        assert!(Crc32BlockHasher::create_personalized("deploy a").is_none());
+    }
+}
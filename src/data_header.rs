@@ -4,20 +4,30 @@ use std::convert::TryInto;
 use std::error::Error;
 use std::mem::size_of;
 use std::marker::PhantomData;
-use crate::crypto::{BlockHasher};
+use crate::crypto::{hash_with_id, BlockHasher};
 
 
 const STATE_FLAG_ALLOC: u32 = 0b0;
-const STATE_FLAG_DELETE: u32 = 0b1;
+pub(crate) const STATE_FLAG_DELETE: u32 = 0b1;
+pub(crate) const STATE_FLAG_INDEX: u32 = 0b10;
+pub(crate) const STATE_FLAG_CORRUPT: u32 = 0b100;
+pub(crate) const STATE_FLAG_PARITY: u32 = 0b1000;
+pub(crate) const STATE_FLAG_JOURNAL: u32 = 0b10000;
+pub(crate) const STATE_FLAG_FILLER: u32 = 0b100000;
+pub(crate) const STATE_FLAG_REWRITTEN: u32 = 0b1000000;
+pub(crate) const STATE_FLAG_DIGEST: u32 = 0b10000000;
 const DEFAULT_ADDR_NEXT: u64 = 0;
 
+static ERROR_PERSONALIZATION: &str = "Hasher can't be personalized.";
+static ERROR_PAYLOAD_SIZE: &str = "Payload too large.";
+
 /// Trait for preparing a DataHeader for writing to stream
 pub trait BlockSerializer {
     /// Create a vector of data ready to be written
     ///
     fn serialize(&mut self, data: &[u8]) -> Result<&Vec<u8>, Box<dyn Error>>;
 
-    fn deserialize(&mut self, data: &Vec<u8>) -> Result<(), Box<dyn Error>>;
+    fn deserialize(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>>;
     
     fn verify(&self, data: &[u8]) -> bool;
 
@@ -30,7 +40,361 @@ pub trait BlockSerializer {
     fn delete_offset() -> usize;
 
     /// gets the amount to seek to next DataHeader
-    fn read_ahead(_buffer: &Vec<u8>) -> Result<i64, Box<dyn Error>>;
+    ///
+    /// buffer must hold at least read_ahead_size() bytes from the start of the header
+    fn read_ahead(buffer: &[u8]) -> Result<i64, Box<dyn Error>>;
+}
+
+/// Header fields independent of their layout on disk
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct HeaderFields {
+    pub size_data: u64,
+    pub state_flag: u32,
+    pub address_next: u64,
+    pub checksum: Vec<u8>,
+    /// BlockHasher::id of the hasher that made checksum, 0 for the store's own
+    pub hash_id: u8,
+    /// ContentType code of the payload, 0 for unknown
+    pub content_type: u16,
+    /// owner of the block, for an access policy to judge, 0 for none
+    pub owner: u32,
+    /// permission bits of the block, meaning whatever the access policy says
+    pub permissions: u16,
+    /// write session the block was put in, 0 for none, see Store::begin_session
+    pub session: u64,
+}
+
+/// Layout of a DataHeader on disk
+///
+/// Every header in a store has the same size; the store records the id
+/// of its codec in the file descriptor.
+pub trait HeaderCodec: Send + Sync {
+    /// id recorded in the file descriptor
+    fn id(&self) -> u32;
+
+    /// Number of checksum bytes kept from a hash of hash_size bytes
+    fn checksum_size(&self, hash_size: usize) -> usize {
+        hash_size
+    }
+
+    /// size in bytes of an encoded header
+    fn size(&self, hash_size: usize) -> usize;
+
+    /// true if the codec keeps HeaderFields::hash_id
+    fn records_hash_id(&self) -> bool {
+        false
+    }
+
+    /// true if the codec keeps HeaderFields::content_type
+    fn records_content_type(&self) -> bool {
+        false
+    }
+
+    /// true if the codec keeps HeaderFields::owner and permissions
+    fn records_access(&self) -> bool {
+        false
+    }
+
+    /// true if the codec keeps HeaderFields::session
+    fn records_session(&self) -> bool {
+        false
+    }
+
+    /// Append the encoded fields to out
+    ///
+    /// checksum is already cut to checksum_size
+    fn encode(&self, fields: &HeaderFields, out: &mut Vec<u8>) -> Result<(), Box<dyn Error>>;
+
+    /// Decode fields from exactly size() bytes
+    fn decode(&self, data: &[u8]) -> Result<HeaderFields, Box<dyn Error>>;
+}
+
+/// The original header layout.
+///
+/// u64 size, u32 state flags, u64 next address, then the full hash.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct BinaryHeaderCodec;
+
+impl HeaderCodec for BinaryHeaderCodec {
+    fn id(&self) -> u32 {
+        0
+    }
+
+    fn size(&self, hash_size: usize) -> usize {
+        (size_of::<u64>() * 2) + size_of::<u32>() + hash_size
+    }
+
+    fn encode(&self, fields: &HeaderFields, out: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        out.extend_from_slice(&fields.size_data.to_le_bytes());
+        out.extend_from_slice(&fields.state_flag.to_le_bytes());
+        out.extend_from_slice(&fields.address_next.to_le_bytes());
+        out.extend_from_slice(&fields.checksum);
+        Ok(())
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<HeaderFields, Box<dyn Error>> {
+        Ok(HeaderFields {
+            size_data: u64::from_le_bytes(data[0..8].try_into()?),
+            state_flag: u32::from_le_bytes(data[8..12].try_into()?),
+            address_next: u64::from_le_bytes(data[12..20].try_into()?),
+            checksum: data[20..].to_vec(),
+            hash_id: 0,
+            content_type: 0,
+            owner: 0,
+            permissions: 0,
+            session: 0,
+        })
+    }
+}
+
+/// Small header layout for stores of many tiny blocks.
+///
+/// u32 size, u8 state flags, then the hash, optionally truncated to 8 bytes.
+/// There is no next address, and blocks are limited to u32::MAX bytes.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct CompactHeaderCodec {
+    /// keep only the first 8 bytes of the hash
+    pub truncate_hash: bool,
+}
+
+pub(crate) const COMPACT_TRUNCATED_HASH_SIZE: usize = 8;
+
+impl HeaderCodec for CompactHeaderCodec {
+    fn id(&self) -> u32 {
+        if self.truncate_hash {
+            2
+        } else {
+            1
+        }
+    }
+
+    fn checksum_size(&self, hash_size: usize) -> usize {
+        if self.truncate_hash {
+            hash_size.min(COMPACT_TRUNCATED_HASH_SIZE)
+        } else {
+            hash_size
+        }
+    }
+
+    fn size(&self, hash_size: usize) -> usize {
+        size_of::<u32>() + size_of::<u8>() + self.checksum_size(hash_size)
+    }
+
+    fn encode(&self, fields: &HeaderFields, out: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        out.extend_from_slice(&u32::try_from(fields.size_data)?.to_le_bytes());
+        out.push(u8::try_from(fields.state_flag)?);
+        out.extend_from_slice(&fields.checksum);
+        Ok(())
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<HeaderFields, Box<dyn Error>> {
+        Ok(HeaderFields {
+            size_data: u64::from(u32::from_le_bytes(data[0..4].try_into()?)),
+            state_flag: u32::from(data[4]),
+            address_next: DEFAULT_ADDR_NEXT,
+            checksum: data[5..].to_vec(),
+            hash_id: 0,
+            content_type: 0,
+            owner: 0,
+            permissions: 0,
+            session: 0,
+        })
+    }
+}
+
+/// Header layout naming the hasher of each block, so hashers can be mixed.
+///
+/// u64 size, u32 state flags, u8 hasher id, u8 checksum length, then the
+/// checksum zero padded to TAGGED_MAX_HASH_SIZE bytes (or the store's hash
+/// size if that is bigger). A store can be reopened with a different
+/// BlockHasher: new blocks use it and old ones still verify with theirs,
+/// as long as both are built in hashers with an id.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct TaggedHeaderCodec;
+
+/// Room kept for the checksum in a TaggedHeaderCodec header
+pub const TAGGED_MAX_HASH_SIZE: usize = 32;
+
+impl HeaderCodec for TaggedHeaderCodec {
+    fn id(&self) -> u32 {
+        3
+    }
+
+    fn size(&self, hash_size: usize) -> usize {
+        FieldsHeaderCodec::default().size(hash_size)
+    }
+
+    fn records_hash_id(&self) -> bool {
+        true
+    }
+
+    fn encode(&self, fields: &HeaderFields, out: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        FieldsHeaderCodec::default().encode(fields, out)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<HeaderFields, Box<dyn Error>> {
+        FieldsHeaderCodec::default().decode(data)
+    }
+}
+
+/// Optional header field: the payload's u16 content type, see ContentType
+pub const HEADER_FIELD_CONTENT_TYPE: u32 = 0b1;
+/// Optional header field: the block's u32 owner and u16 permissions, see
+/// Store::put_owned and StoreOptions::access_policy
+pub const HEADER_FIELD_ACCESS: u32 = 0b10;
+/// Optional header field: the u64 write session the block was put in, see
+/// Store::begin_session
+pub const HEADER_FIELD_SESSION: u32 = 0b100;
+
+/// Name, size and type of a value in a header, as format::FieldSpec has them
+pub(crate) type HeaderValue = (&'static str, usize, &'static str);
+
+/// The optional header fields in the order they are laid out, each with
+/// the values it holds
+pub(crate) const HEADER_OPTIONAL_FIELDS: [(u32, &[HeaderValue]); 3] = [
+    (HEADER_FIELD_CONTENT_TYPE, &[("content_type", 2, "u16")]),
+    (HEADER_FIELD_ACCESS, &[("owner", 4, "u32"), ("permissions", 2, "u16")]),
+    (HEADER_FIELD_SESSION, &[("session", 8, "u64")]),
+];
+
+/// Low byte of a FieldsHeaderCodec id
+const FIELDS_CODEC_ID: u32 = 4;
+
+/// TaggedHeaderCodec with the optional fields named by a bitmask.
+///
+/// u64 size, u32 state flags, u8 hasher id, u8 checksum length, the fields
+/// whose HEADER_FIELD_ bits are set in bit order, then the checksum padded
+/// as for TaggedHeaderCodec. The id is 4 with the mask above the low byte,
+/// so the file descriptor says which fields a store's headers hold; with
+/// no fields it is TaggedHeaderCodec's.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct FieldsHeaderCodec {
+    /// HEADER_FIELD_ bits of the fields present
+    pub fields: u32,
+}
+
+impl FieldsHeaderCodec {
+    fn has(&self, field: u32) -> bool {
+        self.fields & field != 0
+    }
+
+    /// Bytes from the start of a header to the first present field whose
+    /// bit isn't below field, or to the checksum if there is none
+    fn offset(&self, field: u32) -> usize {
+        let before: usize = HEADER_OPTIONAL_FIELDS
+            .iter()
+            .filter(|(bit, _)| *bit < field && self.has(*bit))
+            .map(|(_, values)| values.iter().map(|(_, size, _)| size).sum::<usize>())
+            .sum();
+        size_of::<u64>() + size_of::<u32>() + 2 + before
+    }
+}
+
+impl HeaderCodec for FieldsHeaderCodec {
+    fn id(&self) -> u32 {
+        if self.fields == 0 {
+            // the same layout
+            TaggedHeaderCodec.id()
+        } else {
+            FIELDS_CODEC_ID | self.fields << 8
+        }
+    }
+
+    fn size(&self, hash_size: usize) -> usize {
+        self.offset(u32::MAX) + hash_size.max(TAGGED_MAX_HASH_SIZE)
+    }
+
+    fn records_hash_id(&self) -> bool {
+        true
+    }
+
+    fn records_content_type(&self) -> bool {
+        self.has(HEADER_FIELD_CONTENT_TYPE)
+    }
+
+    fn records_access(&self) -> bool {
+        self.has(HEADER_FIELD_ACCESS)
+    }
+
+    fn records_session(&self) -> bool {
+        self.has(HEADER_FIELD_SESSION)
+    }
+
+    fn encode(&self, fields: &HeaderFields, out: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let start = out.len();
+        out.extend_from_slice(&fields.size_data.to_le_bytes());
+        out.extend_from_slice(&fields.state_flag.to_le_bytes());
+        out.push(fields.hash_id);
+        out.push(u8::try_from(fields.checksum.len())?);
+        if self.has(HEADER_FIELD_CONTENT_TYPE) {
+            out.extend_from_slice(&fields.content_type.to_le_bytes());
+        }
+        if self.has(HEADER_FIELD_ACCESS) {
+            out.extend_from_slice(&fields.owner.to_le_bytes());
+            out.extend_from_slice(&fields.permissions.to_le_bytes());
+        }
+        if self.has(HEADER_FIELD_SESSION) {
+            out.extend_from_slice(&fields.session.to_le_bytes());
+        }
+        out.extend_from_slice(&fields.checksum);
+        out.resize(start + self.size(fields.checksum.len()), 0);
+        Ok(())
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<HeaderFields, Box<dyn Error>> {
+        let len = usize::from(data[13]);
+        let sum = self.offset(u32::MAX);
+        let mut fields = HeaderFields {
+            size_data: u64::from_le_bytes(data[0..8].try_into()?),
+            state_flag: u32::from_le_bytes(data[8..12].try_into()?),
+            address_next: DEFAULT_ADDR_NEXT,
+            checksum: data.get(sum..sum + len).ok_or("checksum length out of range")?.to_vec(),
+            hash_id: data[12],
+            ..HeaderFields::default()
+        };
+        if self.has(HEADER_FIELD_CONTENT_TYPE) {
+            let at = self.offset(HEADER_FIELD_CONTENT_TYPE);
+            fields.content_type = u16::from_le_bytes(data[at..at + 2].try_into()?);
+        }
+        if self.has(HEADER_FIELD_ACCESS) {
+            let at = self.offset(HEADER_FIELD_ACCESS);
+            fields.owner = u32::from_le_bytes(data[at..at + 4].try_into()?);
+            fields.permissions = u16::from_le_bytes(data[at + 4..at + 6].try_into()?);
+        }
+        if self.has(HEADER_FIELD_SESSION) {
+            let at = self.offset(HEADER_FIELD_SESSION);
+            fields.session = u64::from_le_bytes(data[at..at + 8].try_into()?);
+        }
+        Ok(fields)
+    }
+}
+
+/// Look up a built in codec by the id stored in a file descriptor
+pub fn header_codec(id: u32) -> Option<Box<dyn HeaderCodec>> {
+    match id {
+        0 => Some(Box::new(BinaryHeaderCodec)),
+        1 => Some(Box::new(CompactHeaderCodec { truncate_hash: false })),
+        2 => Some(Box::new(CompactHeaderCodec { truncate_hash: true })),
+        3 => Some(Box::new(TaggedHeaderCodec)),
+        id if id & 0xff == FIELDS_CODEC_ID => {
+            let known = HEADER_OPTIONAL_FIELDS.iter().fold(0, |mask, (bit, _)| mask | bit);
+            let fields = id >> 8;
+            if fields != 0 && fields & !known == 0 {
+                Some(Box::new(FieldsHeaderCodec { fields }))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// T, personalized by context if there is one, None if T can't be
+fn personalized_hasher<T: BlockHasher>(context: Option<&str>) -> Option<T> {
+    match context {
+        Some(c) => T::create_personalized(c),
+        None => Some(T::create()),
+    }
 }
 
 /// interface with block flags
@@ -38,6 +402,20 @@ pub trait BlockFlags {
     /// Get the positive flag value
     fn delete_flag() -> u32;
     fn set_delete_flag(value: bool, flags: u32) -> u32;
+    /// Flag marking a block written by the store itself (index footer)
+    fn index_flag() -> u32;
+    /// Flag marking a block quarantined after failing verification
+    fn corrupt_flag() -> u32;
+    /// Flag marking a block of parity for the block before it
+    fn parity_flag() -> u32;
+    /// Flag marking a journal entry of blocks being deleted
+    fn journal_flag() -> u32;
+    /// Flag marking space left behind by in place compaction
+    fn filler_flag() -> u32;
+    /// Flag marking a block whose payload was replaced in place
+    fn rewritten_flag() -> u32;
+    /// Flag marking a strong digest of the block before it and its parity
+    fn digest_flag() -> u32;
 }
 
 /// A DataHeader, minus the data.debuggers
@@ -53,6 +431,16 @@ pub struct DataHeader<T: BlockHasher> {
     /// address of next DataHeader in file containing appended data
     address_next: u64,
     checksum: Vec<u8>,
+    /// hasher that made checksum, see HeaderFields::hash_id
+    hash_id: u8,
+    /// see HeaderFields::content_type
+    content_type: u16,
+    /// see HeaderFields::owner
+    owner: u32,
+    /// see HeaderFields::permissions
+    permissions: u16,
+    /// see HeaderFields::session
+    session: u64,
     /// Vector of DataHeader header
     header: Vec<u8>,
     phantom: PhantomData<T>,
@@ -67,6 +455,11 @@ impl<T: BlockHasher > DataHeader<T> {
             address_next: DEFAULT_ADDR_NEXT,
             header: vec![0],
             checksum: vec![0],
+            hash_id: 0,
+            content_type: 0,
+            owner: 0,
+            permissions: 0,
+            session: 0,
             phantom: PhantomData,
         })
     }
@@ -74,6 +467,191 @@ impl<T: BlockHasher > DataHeader<T> {
     pub fn data_size(&self) -> Result<usize, Box<dyn std::error::Error>> {
         Ok(usize::try_from(self.size_data)?)
     }
+
+    /// true if this block was written by the store rather than the user
+    pub fn is_index(&self) -> bool {
+        self.state_flag & STATE_FLAG_INDEX != 0
+    }
+
+    /// true if this block holds parity for the block before it
+    pub fn is_parity(&self) -> bool {
+        self.state_flag & STATE_FLAG_PARITY != 0
+    }
+
+    /// true if this block is a journal entry of deletes
+    pub fn is_journal(&self) -> bool {
+        self.state_flag & STATE_FLAG_JOURNAL != 0
+    }
+
+    /// true if this block holds a strong digest of the block before it
+    pub fn is_digest(&self) -> bool {
+        self.state_flag & STATE_FLAG_DIGEST != 0
+    }
+
+    /// true for blocks the store writes for itself, which are not indexed
+    pub fn is_system(&self) -> bool {
+        self.state_flag & (STATE_FLAG_INDEX | STATE_FLAG_PARITY | STATE_FLAG_JOURNAL | STATE_FLAG_FILLER | STATE_FLAG_DIGEST) != 0
+    }
+
+    /// true if the block is neither deleted nor quarantined
+    pub fn is_live(&self) -> bool {
+        self.state_flag & (STATE_FLAG_DELETE | STATE_FLAG_CORRUPT) == 0
+    }
+
+    /// true if the block was deleted
+    pub fn is_deleted(&self) -> bool {
+        self.state_flag & STATE_FLAG_DELETE != 0
+    }
+
+    /// true if the block was quarantined
+    pub fn is_corrupt(&self) -> bool {
+        self.state_flag & STATE_FLAG_CORRUPT != 0
+    }
+
+    /// true if the block's payload was replaced in place, losing the old one
+    pub fn is_rewritten(&self) -> bool {
+        self.state_flag & STATE_FLAG_REWRITTEN != 0
+    }
+
+    /// ContentType code of the payload, 0 if unknown or not recorded
+    pub fn content_type(&self) -> u16 {
+        self.content_type
+    }
+
+    /// Set the content type written by the next serialize, kept only by
+    /// codecs that record it
+    pub fn set_content_type(&mut self, content_type: u16) {
+        self.content_type = content_type;
+    }
+
+    /// Owner of the block, 0 if none or not recorded
+    pub fn owner(&self) -> u32 {
+        self.owner
+    }
+
+    /// Permission bits of the block, 0 if none or not recorded
+    pub fn permissions(&self) -> u16 {
+        self.permissions
+    }
+
+    /// Set the owner and permissions written by the next serialize, kept
+    /// only by codecs that record them
+    pub fn set_access(&mut self, owner: u32, permissions: u16) {
+        self.owner = owner;
+        self.permissions = permissions;
+    }
+
+    /// Write session of the block, 0 if none or not recorded
+    pub fn session(&self) -> u64 {
+        self.session
+    }
+
+    /// Set the session written by the next serialize, kept only by codecs
+    /// that record it
+    pub fn set_session(&mut self, session: u64) {
+        self.session = session;
+    }
+
+    /// Claim a payload size without hashing one, for blocks whose payload is never read
+    pub(crate) fn set_data_size(&mut self, size: u64) {
+        self.size_data = size;
+    }
+
+    /// Copy of the header fields
+    pub fn fields(&self) -> HeaderFields {
+        HeaderFields {
+            size_data: self.size_data,
+            state_flag: self.state_flag,
+            address_next: self.address_next,
+            checksum: self.checksum.clone(),
+            hash_id: self.hash_id,
+            content_type: self.content_type,
+            owner: self.owner,
+            permissions: self.permissions,
+            session: self.session,
+        }
+    }
+
+    /// Hash data and encode the header for it with codec
+    pub fn serialize_with(&mut self, codec: &dyn HeaderCodec, data: &[u8]) -> Result<&Vec<u8>, Box<dyn Error>> {
+        self.serialize_personalized(codec, data, None)
+    }
+
+    /// serialize_with, hashing with the hasher personalized by context if there is one
+    pub fn serialize_personalized(
+        &mut self,
+        codec: &dyn HeaderCodec,
+        data: &[u8],
+        context: Option<&str>,
+    ) -> Result<&Vec<u8>, Box<dyn Error>> {
+        self.serialize_parts(codec, &[data], context)
+    }
+
+    /// serialize_personalized, for a payload of parts one after another
+    pub fn serialize_parts(
+        &mut self,
+        codec: &dyn HeaderCodec,
+        parts: &[&[u8]],
+        context: Option<&str>,
+    ) -> Result<&Vec<u8>, Box<dyn Error>> {
+        self.size_data = parts
+            .iter()
+            .try_fold(0u64, |n, p| n.checked_add(u64::try_from(p.len()).ok()?))
+            .ok_or(ERROR_PAYLOAD_SIZE)?;
+        let mut hasher = personalized_hasher::<T>(context).ok_or(ERROR_PERSONALIZATION)?;
+        let hash = hasher.hash_parts(parts);
+        self.checksum = hash[..codec.checksum_size(hash.len())].to_vec();
+        self.hash_id = if codec.records_hash_id() { T::id() } else { 0 };
+        self.encode_with(codec)
+    }
+
+    /// verify, for a block hashed with the hasher personalized by context.
+    ///
+    /// With a context, blocks made by another hasher never verify.
+    pub fn verify_personalized(&self, data: &[u8], context: Option<&str>) -> bool {
+        let other;
+        let mut hasher = match personalized_hasher::<T>(context) {
+            Some(h) => h,
+            None => return false,
+        };
+        let hash = if self.hash_id == 0 || self.hash_id == T::id() {
+            hasher.hash(data)
+        } else if context.is_some() {
+            return false;
+        } else {
+            match hash_with_id(self.hash_id, data) {
+                Some(h) => {
+                    other = h;
+                    &other[..]
+                }
+                None => return false,
+            }
+        };
+        hash.len() >= self.checksum.len() && hash[..self.checksum.len()] == self.checksum[..]
+    }
+
+    /// Encode the current fields with codec, without rehashing
+    pub fn encode_with(&mut self, codec: &dyn HeaderCodec) -> Result<&Vec<u8>, Box<dyn Error>> {
+        let fields = self.fields();
+        self.header.clear();
+        codec.encode(&fields, &mut self.header)?;
+        Ok(&self.header)
+    }
+
+    /// Fill struct from binary data encoded with codec
+    pub fn deserialize_with(&mut self, codec: &dyn HeaderCodec, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let fields = codec.decode(data)?;
+        self.size_data = fields.size_data;
+        self.state_flag = fields.state_flag;
+        self.address_next = fields.address_next;
+        self.checksum = fields.checksum;
+        self.hash_id = fields.hash_id;
+        self.content_type = fields.content_type;
+        self.owner = fields.owner;
+        self.permissions = fields.permissions;
+        self.session = fields.session;
+        Ok(())
+    }
 }
 
 impl<T: BlockHasher> BlockFlags for DataHeader<T> {
@@ -83,49 +661,72 @@ impl<T: BlockHasher> BlockFlags for DataHeader<T> {
     }
 
     fn set_delete_flag(value: bool,mut  flags: u32 ) -> u32 {
-        flags = flags|STATE_FLAG_DELETE;
+        flags |= STATE_FLAG_DELETE;
         if !value {
-            flags = flags^STATE_FLAG_DELETE;
+            flags ^= STATE_FLAG_DELETE;
         }
         flags
     }
+
+    #[inline]
+    fn index_flag() -> u32 {
+        STATE_FLAG_INDEX
+    }
+
+    #[inline]
+    fn corrupt_flag() -> u32 {
+        STATE_FLAG_CORRUPT
+    }
+
+    #[inline]
+    fn parity_flag() -> u32 {
+        STATE_FLAG_PARITY
+    }
+
+    #[inline]
+    fn journal_flag() -> u32 {
+        STATE_FLAG_JOURNAL
+    }
+
+    #[inline]
+    fn filler_flag() -> u32 {
+        STATE_FLAG_FILLER
+    }
+
+    #[inline]
+    fn rewritten_flag() -> u32 {
+        STATE_FLAG_REWRITTEN
+    }
+
+    #[inline]
+    fn digest_flag() -> u32 {
+        STATE_FLAG_DIGEST
+    }
 }
 
 impl<T: BlockHasher> BlockSerializer for DataHeader<T> {
-    /// Return vector serialized DataHeader
+    /// Return vector serialized DataHeader, using BinaryHeaderCodec
     fn serialize(&mut self, data: &[u8]) -> Result<&Vec<u8>, Box<dyn Error>> {
-        self.header.clear();
-        self.size_data = u64::try_from(data.len())?;
-        self.header
-            .append(&mut self.size_data.to_le_bytes().to_vec());
-        self.header
-            .append(&mut self.state_flag.to_le_bytes().to_vec());
-        self.header
-            .append(&mut self.address_next.to_le_bytes().to_vec());
-        let mut hasher = T::create();
-        self.header
-            .append(&mut hasher.hash(data).to_vec());
-        Ok(&self.header)
+        self.serialize_with(&BinaryHeaderCodec, data)
     }
 
-    /// Fill struct from binary data
+    /// Fill struct from binary data, using BinaryHeaderCodec
     ///
     /// Assumes correct size of data for the Block
-    fn deserialize(&mut self, data: &Vec<u8>) -> Result<(), Box<dyn Error>> {
-        self.size_data = u64::from_le_bytes(data[0..8].try_into()?);
-        self.state_flag = u32::from_le_bytes(data[8..12].try_into()?);
-        self.address_next = u64::from_le_bytes(data[12..20].try_into()?);
-        self.checksum = data[20..].to_vec();
-        Ok(())
+    fn deserialize(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.deserialize_with(&BinaryHeaderCodec, data)
     }
 
+    /// Checks data against the checksum, which may be a truncated hash
+    ///
+    /// A block made by another built in hasher is checked with that one.
     fn verify(&self, data: &[u8]) -> bool {
-        T::create().hash(data)==self.checksum
+        self.verify_personalized(data, None)
     }
 
     #[inline]
     fn size() -> usize {
-        (size_of::<u64>() * 2) + size_of::<u32>() + T::size()
+        BinaryHeaderCodec.size(T::size())
     }
 
     #[inline]
@@ -133,10 +734,11 @@ impl<T: BlockHasher> BlockSerializer for DataHeader<T> {
         size_of::<u64>()
     }
 
-    fn read_ahead(_buffer: &Vec<u8>) -> Result<i64, Box<dyn Error>> {
-        //TODO: WTF was supposed to happen here?
-        let mds = i64::try_from(size_of::<u64>() + size_of::<u32>() + T::size() )?;
-        Ok(mds)
+    /// Distance from the end of the read ahead data to the next DataHeader
+    fn read_ahead(buffer: &[u8]) -> Result<i64, Box<dyn Error>> {
+        let size_data = u64::from_le_bytes(buffer[0..8].try_into()?);
+        let mds = i64::try_from(size_of::<u64>() + size_of::<u32>() + T::size())?;
+        Ok(mds + i64::try_from(size_data)?)
     }
 
     #[inline]
@@ -148,7 +750,7 @@ impl<T: BlockHasher> BlockSerializer for DataHeader<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::crypto::{B3BlockHasher};
+    use crate::crypto::{B3BlockHasher, Crc32BlockHasher};
 
     #[test]
     fn can_create_data_block() {
@@ -181,4 +783,139 @@ mod tests {
         assert_eq!(DataHeader::<B3BlockHasher>::set_delete_flag(false, tflag), 0);
         assert_eq!(DataHeader::<B3BlockHasher>::set_delete_flag(true, tflag), 1);
     }
+
+    #[test]
+    fn binary_codec_matches_serialize() {
+        let data = [9, 8, 7];
+        let mut dh = DataHeader::<B3BlockHasher>::new().unwrap();
+        dh.state_flag = 1;
+        let plain = dh.serialize(&data).unwrap().clone();
+        let coded = dh.serialize_with(&BinaryHeaderCodec, &data).unwrap().clone();
+        assert_eq!(plain, coded);
+        assert_eq!(coded.len(), BinaryHeaderCodec.size(B3BlockHasher::size()));
+
+        let mut db2 = DataHeader::<B3BlockHasher>::new().unwrap();
+        db2.deserialize_with(header_codec(0).unwrap().as_ref(), &coded).unwrap();
+        assert_eq!(db2.fields(), dh.fields());
+        assert!(header_codec(1000).is_none());
+    }
+
+    #[test]
+    fn compact_codec_round_trip() {
+        let data = [1, 2, 3, 4];
+        for codec in [CompactHeaderCodec { truncate_hash: false }, CompactHeaderCodec { truncate_hash: true }] {
+            let mut dh = DataHeader::<B3BlockHasher>::new().unwrap();
+            let coded = dh.serialize_with(&codec, &data).unwrap().clone();
+            assert_eq!(coded.len(), codec.size(B3BlockHasher::size()));
+            assert_eq!(header_codec(codec.id()).unwrap().size(B3BlockHasher::size()), coded.len());
+
+            let mut db2 = DataHeader::<B3BlockHasher>::new().unwrap();
+            db2.deserialize_with(&codec, &coded).unwrap();
+            assert_eq!(db2.data_size().unwrap(), data.len());
+            assert!(db2.verify(&data));
+            assert!(!db2.verify(&[1, 2, 3, 5]));
+        }
+        assert_eq!(CompactHeaderCodec { truncate_hash: true }.size(B3BlockHasher::size()), 13);
+    }
+
+    #[test]
+    fn compact_codec_rejects_wide_flags() {
+        let mut dh = DataHeader::<B3BlockHasher>::new().unwrap();
+        dh.state_flag = 0x100;
+        assert!(dh.serialize_with(&CompactHeaderCodec::default(), &[1]).is_err());
+    }
+
+    #[test]
+    fn read_ahead_skips_payload() {
+        let data = [1, 2, 3, 4, 5];
+        let mut dh = DataHeader::<B3BlockHasher>::new().unwrap();
+        let sd = dh.serialize(&data).unwrap().clone();
+        let skip = DataHeader::<B3BlockHasher>::read_ahead(&sd).unwrap();
+        let expected = DataHeader::<B3BlockHasher>::size() - DataHeader::<B3BlockHasher>::read_ahead_size() + data.len();
+        assert_eq!(skip, expected as i64);
+    }
+
+    #[test]
+    fn tagged_codec_verifies_other_hashers() {
+        let data = [5, 6, 7];
+        let mut crc = DataHeader::<Crc32BlockHasher>::new().unwrap();
+        let coded = crc.serialize_with(&TaggedHeaderCodec, &data).unwrap().clone();
+        assert_eq!(coded.len(), TaggedHeaderCodec.size(B3BlockHasher::size()));
+
+        let mut b3 = DataHeader::<B3BlockHasher>::new().unwrap();
+        b3.deserialize_with(&TaggedHeaderCodec, &coded).unwrap();
+        assert_eq!(b3.fields().hash_id, Crc32BlockHasher::id());
+        assert_eq!(b3.fields().checksum.len(), 4);
+        assert!(b3.verify(&data));
+        assert!(!b3.verify(&[5, 6]));
+    }
+
+    #[test]
+    fn fields_codec_keeps_content_type() {
+        let data = [1, 2, 3];
+        let typed = FieldsHeaderCodec { fields: HEADER_FIELD_CONTENT_TYPE };
+        let mut dh = DataHeader::<B3BlockHasher>::new().unwrap();
+        dh.set_content_type(0x8001);
+        let coded = dh.serialize_with(&typed, &data).unwrap().clone();
+        assert_eq!(coded.len(), typed.size(B3BlockHasher::size()));
+        assert_eq!(coded.len(), TaggedHeaderCodec.size(B3BlockHasher::size()) + 2);
+
+        let mut back = DataHeader::<B3BlockHasher>::new().unwrap();
+        back.deserialize_with(header_codec(typed.id()).unwrap().as_ref(), &coded).unwrap();
+        assert_eq!(back.content_type(), 0x8001);
+        assert!(back.verify(&data));
+        let tagged = dh.serialize_with(&TaggedHeaderCodec, &data).unwrap().clone();
+        back.deserialize_with(&TaggedHeaderCodec, &tagged).unwrap();
+        assert_eq!(back.content_type(), 0);
+
+        // without the field the layout is TaggedHeaderCodec's
+        assert_eq!(dh.serialize_with(&FieldsHeaderCodec::default(), &data).unwrap().clone(), tagged);
+        assert_eq!(FieldsHeaderCodec::default().id(), TaggedHeaderCodec.id());
+        assert!(header_codec(FIELDS_CODEC_ID).is_none());
+        assert!(header_codec(FIELDS_CODEC_ID | 0x8000 << 8).is_none());
+    }
+
+    #[test]
+    fn fields_codec_keeps_owner_and_permissions() {
+        let data = [1, 2, 3];
+        let mut dh = DataHeader::<Crc32BlockHasher>::new().unwrap();
+        dh.set_content_type(2);
+        dh.set_access(0xdead_beef, 0o640);
+        let both = FieldsHeaderCodec { fields: HEADER_FIELD_CONTENT_TYPE | HEADER_FIELD_ACCESS };
+        let coded = dh.serialize_with(&both, &data).unwrap().clone();
+        assert_eq!(coded.len(), both.size(Crc32BlockHasher::size()));
+
+        let mut back = DataHeader::<Crc32BlockHasher>::new().unwrap();
+        back.deserialize_with(header_codec(both.id()).unwrap().as_ref(), &coded).unwrap();
+        assert_eq!((back.owner(), back.permissions(), back.content_type()), (0xdead_beef, 0o640, 2));
+        assert!(back.verify(&data));
+
+        // each field moves up into the room of any absent before it
+        let access = FieldsHeaderCodec { fields: HEADER_FIELD_ACCESS };
+        let coded = dh.serialize_with(&access, &data).unwrap().clone();
+        assert_eq!(coded[14..18], 0xdead_beef_u32.to_le_bytes());
+        back.deserialize_with(&access, &coded).unwrap();
+        assert_eq!((back.owner(), back.permissions(), back.content_type()), (0xdead_beef, 0o640, 0));
+        assert!(back.verify(&data));
+    }
+
+    #[test]
+    fn fields_codec_keeps_session() {
+        let data = [4, 5, 6];
+        let mut dh = DataHeader::<B3BlockHasher>::new().unwrap();
+        dh.set_access(7, 0o600);
+        dh.set_session(u64::MAX - 1);
+        let all = FieldsHeaderCodec { fields: HEADER_FIELD_CONTENT_TYPE | HEADER_FIELD_ACCESS | HEADER_FIELD_SESSION };
+        let coded = dh.serialize_with(&all, &data).unwrap().clone();
+        assert_eq!(coded.len(), all.size(B3BlockHasher::size()));
+
+        let mut back = DataHeader::<B3BlockHasher>::new().unwrap();
+        back.deserialize_with(header_codec(all.id()).unwrap().as_ref(), &coded).unwrap();
+        assert_eq!((back.session(), back.owner(), back.permissions()), (u64::MAX - 1, 7, 0o600));
+        assert!(back.verify(&data));
+        let access = FieldsHeaderCodec { fields: HEADER_FIELD_CONTENT_TYPE | HEADER_FIELD_ACCESS };
+        let coded = dh.serialize_with(&access, &data).unwrap().clone();
+        back.deserialize_with(&access, &coded).unwrap();
+        assert_eq!(back.session(), 0);
+    }
 }
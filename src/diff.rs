@@ -0,0 +1,176 @@
+//Copyright 2021 Matthew Petricone
+//! Compare two stores by the checksums of their live blocks.
+//!
+//! Blocks are matched by checksum, so the same payload at different indexes
+//! still matches, and duplicates are paired off in order.
+//!
+//! A patch is an ordinary store whose blocks each start with an op byte:
+//! PATCH_OP_ADD followed by a payload to append, or PATCH_OP_REMOVE
+//! followed by the checksum of a block to delete. Store::apply_patch
+//! applies one, so a store can be brought up to date by shipping only that.
+use crate::crypto::BlockHasher;
+use crate::store::{BlockId, Store, StoreIO};
+use std::collections::{HashMap, VecDeque};
+
+/// Patch block holding a payload to append
+pub const PATCH_OP_ADD: u8 = 0;
+/// Patch block holding the checksum of a block to delete
+pub const PATCH_OP_REMOVE: u8 = 1;
+
+static ERROR_PATCH_OP: &str = "Unknown patch op.";
+
+/// Result of Store::apply_patch
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PatchReport {
+    /// blocks appended
+    pub added: Vec<BlockId>,
+    /// blocks deleted
+    pub removed: Vec<BlockId>,
+    /// removals with no live block of that checksum, already applied or never there
+    pub missing: usize,
+}
+/// Result of diff
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct StoreDiff {
+    /// live blocks of a with no match in b
+    pub only_in_a: Vec<BlockId>,
+    /// live blocks of b with no match in a
+    pub only_in_b: Vec<BlockId>,
+    /// pairs of (index in a, index in b) with the same checksum
+    pub matching: Vec<(BlockId, BlockId)>,
+}
+
+/// Compare the live blocks of a and b
+pub fn diff<T: BlockHasher>(a: &mut Store<T>, b: &mut Store<T>) -> Result<StoreDiff, Box<dyn std::error::Error>> {
+    let mut in_b: HashMap<Vec<u8>, VecDeque<BlockId>> = HashMap::new();
+    for (i, sum) in live_checksums(b)? {
+        in_b.entry(sum).or_default().push_back(i);
+    }
+    let mut d = StoreDiff::default();
+    for (i, sum) in live_checksums(a)? {
+        match in_b.get_mut(&sum).and_then(|q| q.pop_front()) {
+            Some(j) => d.matching.push((i, j)),
+            None => d.only_in_a.push(i),
+        }
+    }
+    d.only_in_b = in_b.into_values().flatten().collect();
+    d.only_in_b.sort_unstable();
+    Ok(d)
+}
+
+/// A block index and its checksum
+type BlockSum = (BlockId, Vec<u8>);
+
+/// Index and checksum of every live block
+fn live_checksums<T: BlockHasher>(s: &mut Store<T>) -> Result<Vec<BlockSum>, Box<dyn std::error::Error>> {
+    let mut out = Vec::new();
+    for i in 0..s.len() {
+        let dh = s.block_header(i)?;
+        if dh.is_live() {
+            out.push((i, dh.fields().checksum));
+        }
+    }
+    Ok(out)
+}
+
+impl StoreDiff {
+    /// true if both stores hold the same payloads
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty()
+    }
+
+    /// Write a patch store at path that turns a into b
+    pub fn write_patch<T: BlockHasher>(
+        &self,
+        a: &mut Store<T>,
+        b: &mut Store<T>,
+        path: String,
+    ) -> Result<Store<T>, Box<dyn std::error::Error>> {
+        let mut patch = Store::<T>::create(path)?;
+        for i in &self.only_in_a {
+            let mut block = vec![PATCH_OP_REMOVE];
+            block.extend_from_slice(&a.block_header(*i)?.fields().checksum);
+            patch.put(&block)?;
+        }
+        for i in &self.only_in_b {
+            let mut block = vec![PATCH_OP_ADD];
+            block.extend_from_slice(&b.get(*i)?);
+            patch.put(&block)?;
+        }
+        Ok(patch)
+    }
+}
+
+impl<T: BlockHasher> Store<T> {
+    /// Apply a patch written by StoreDiff::write_patch.
+    ///
+    /// Removals delete one live block with the checksum each, all in one
+    /// journal entry, then additions are appended in order.
+    /// A patch is meant for the store it was made from; applying it twice
+    /// appends its additions twice.
+    pub fn apply_patch(&mut self, patch: &mut Store<T>) -> Result<PatchReport, Box<dyn std::error::Error>> {
+        let mut by_sum: HashMap<Vec<u8>, VecDeque<BlockId>> = HashMap::new();
+        for (i, sum) in live_checksums(self)? {
+            by_sum.entry(sum).or_default().push_back(i);
+        }
+        let mut report = PatchReport::default();
+        let mut adds = Vec::new();
+        for i in 0..patch.len() {
+            if !patch.is_live(i)? {
+                continue;
+            }
+            let block = patch.get(i)?;
+            match block.first() {
+                Some(&PATCH_OP_ADD) => adds.push(i),
+                Some(&PATCH_OP_REMOVE) => match by_sum.get_mut(&block[1..]).and_then(|q| q.pop_front()) {
+                    Some(j) => report.removed.push(j),
+                    None => report.missing += 1,
+                },
+                _ => return Err(ERROR_PATCH_OP.into()),
+            }
+        }
+        self.delete_many(&report.removed)?;
+        for i in adds {
+            report.added.push(self.put(&patch.get(i)?[1..])?);
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+
+    fn store(name: &str, blocks: &[&[u8]]) -> Store<B3BlockHasher> {
+        std::fs::create_dir_all("testout").unwrap();
+        let mut s = Store::<B3BlockHasher>::create(format!("testout/{}", name)).unwrap();
+        for b in blocks {
+            s.put(b).unwrap();
+        }
+        s
+    }
+
+    #[test]
+    fn diff_by_checksum() {
+        let mut a = store("diff_a.st", &[&[1], &[2], &[3], &[2]]);
+        let mut b = store("diff_b.st", &[&[3], &[2], &[4], &[9]]);
+        b.delete_block(3).unwrap();
+        let d = diff(&mut a, &mut b).unwrap();
+        assert_eq!(d.matching, vec![(1, 1), (2, 0)]);
+        assert_eq!(d.only_in_a, vec![0, 3]);
+        assert_eq!(d.only_in_b, vec![2]);
+        assert!(!d.is_empty());
+
+        let mut patch = d.write_patch(&mut a, &mut b, "testout/diff_patch.st".to_string()).unwrap();
+        assert_eq!(patch.len(), 3);
+        assert_eq!(patch.get(2).unwrap(), vec![PATCH_OP_ADD, 4]);
+
+        let report = a.apply_patch(&mut patch).unwrap();
+        assert_eq!(report.removed, vec![0, 1]);
+        assert_eq!(report.added, vec![4]);
+        assert!(diff(&mut a, &mut b).unwrap().is_empty());
+        let mut a2 = a.try_clone().unwrap();
+        assert!(diff(&mut a, &mut a2).unwrap().is_empty());
+    }
+}
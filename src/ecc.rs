@@ -0,0 +1,305 @@
+//Copyright 2021 Matthew Petricone
+//! Reed-Solomon erasure coding over GF(256), used for block parity.
+//!
+//! Data is split into data_shards equal shards (the last one zero padded) and
+//! parity_shards parity shards are computed with a Cauchy matrix, so any
+//! data_shards of the shards are enough to rebuild the data.
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+
+/// x^8 + x^4 + x^3 + x^2 + 1
+const GF_POLY: u16 = 0x11d;
+
+static ERROR_ECC_SHARDS: &str = "Invalid shard counts.";
+static ERROR_ECC_TOO_MANY_LOST: &str = "Too many shards lost to reconstruct.";
+
+/// Errors from the ecc module
+#[derive(Debug)]
+pub struct EccError {
+    error: String,
+}
+
+impl EccError {
+    fn new(error: &str) -> EccError {
+        EccError { error: error.to_string() }
+    }
+}
+
+impl fmt::Display for EccError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl Error for EccError {}
+
+/// log and exp tables for GF(256)
+struct Gf {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+impl Gf {
+    fn new() -> Gf {
+        let mut gf = Gf { exp: [0; 512], log: [0; 256] };
+        let mut x: u16 = 1;
+        for i in 0..255 {
+            gf.exp[i] = x as u8;
+            gf.log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= GF_POLY;
+            }
+        }
+        for i in 255..512 {
+            gf.exp[i] = gf.exp[i - 255];
+        }
+        gf
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            return 0;
+        }
+        self.exp[self.log[a as usize] as usize + self.log[b as usize] as usize]
+    }
+
+    fn inv(&self, a: u8) -> u8 {
+        self.exp[255 - self.log[a as usize] as usize]
+    }
+}
+
+/// Shard counts for a Reed-Solomon code
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EccConfig {
+    pub data_shards: u8,
+    pub parity_shards: u8,
+}
+
+impl Default for EccConfig {
+    /// Survives the loss of any 2 of 10 shards
+    fn default() -> EccConfig {
+        EccConfig {
+            data_shards: 8,
+            parity_shards: 2,
+        }
+    }
+}
+
+/// Reed-Solomon encoder/decoder for one EccConfig
+pub struct ReedSolomon {
+    config: EccConfig,
+    gf: Gf,
+}
+
+impl ReedSolomon {
+    /// data_shards and parity_shards must be at least 1, and total no more than 256
+    pub fn new(config: EccConfig) -> Result<ReedSolomon, Box<dyn Error>> {
+        if config.data_shards == 0 || config.parity_shards == 0 || u16::from(config.data_shards) + u16::from(config.parity_shards) > 256 {
+            return Err(Box::new(EccError::new(ERROR_ECC_SHARDS)));
+        }
+        Ok(ReedSolomon { config, gf: Gf::new() })
+    }
+
+    /// Shard counts
+    pub fn config(&self) -> EccConfig {
+        self.config
+    }
+
+    /// Size of each shard for data of len bytes
+    pub fn shard_len(&self, len: usize) -> usize {
+        len.div_ceil(usize::from(self.config.data_shards))
+    }
+
+    /// Split data into padded data shards
+    pub fn split(&self, data: &[u8]) -> Vec<Vec<u8>> {
+        let sl = self.shard_len(data.len());
+        (0..usize::from(self.config.data_shards))
+            .map(|i| {
+                let mut shard = vec![0u8; sl];
+                let start = (i * sl).min(data.len());
+                let end = ((i + 1) * sl).min(data.len());
+                shard[..end - start].copy_from_slice(&data[start..end]);
+                shard
+            })
+            .collect()
+    }
+
+    /// generator row for shard i, identity for data shards, Cauchy for parity
+    fn row(&self, i: usize) -> Vec<u8> {
+        let k = usize::from(self.config.data_shards);
+        if i < k {
+            (0..k).map(|j| u8::from(i == j)).collect()
+        } else {
+            (0..k).map(|j| self.gf.inv((i as u8) ^ (j as u8))).collect()
+        }
+    }
+
+    /// Compute the parity shards for data
+    pub fn encode(&self, data: &[u8]) -> Vec<Vec<u8>> {
+        let shards = self.split(data);
+        let k = usize::from(self.config.data_shards);
+        let sl = self.shard_len(data.len());
+        (k..k + usize::from(self.config.parity_shards))
+            .map(|i| {
+                let row = self.row(i);
+                let mut parity = vec![0u8; sl];
+                for (c, shard) in row.iter().zip(&shards) {
+                    for (p, d) in parity.iter_mut().zip(shard) {
+                        *p ^= self.gf.mul(*c, *d);
+                    }
+                }
+                parity
+            })
+            .collect()
+    }
+
+    /// Rebuild data of len bytes from shards, data shards first then parity.
+    ///
+    /// None marks a lost shard; at most parity_shards may be lost.
+    pub fn reconstruct(&self, shards: &[Option<Vec<u8>>], len: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let k = usize::from(self.config.data_shards);
+        let present: Vec<usize> = (0..shards.len()).filter(|i| shards[*i].is_some()).take(k).collect();
+        if present.len() < k {
+            return Err(Box::new(EccError::new(ERROR_ECC_TOO_MANY_LOST)));
+        }
+        let sl = self.shard_len(len);
+        // invert the generator rows of the shards we have
+        let mut m: Vec<Vec<u8>> = present.iter().map(|i| self.row(*i)).collect();
+        let mut inv: Vec<Vec<u8>> = (0..k).map(|i| (0..k).map(|j| u8::from(i == j)).collect()).collect();
+        for col in 0..k {
+            let pivot = (col..k)
+                .find(|r| m[*r][col] != 0)
+                .ok_or_else(|| EccError::new(ERROR_ECC_TOO_MANY_LOST))?;
+            m.swap(col, pivot);
+            inv.swap(col, pivot);
+            let scale = self.gf.inv(m[col][col]);
+            for j in 0..k {
+                m[col][j] = self.gf.mul(m[col][j], scale);
+                inv[col][j] = self.gf.mul(inv[col][j], scale);
+            }
+            for r in 0..k {
+                if r != col && m[r][col] != 0 {
+                    let f = m[r][col];
+                    for j in 0..k {
+                        m[r][j] ^= self.gf.mul(f, m[col][j]);
+                        inv[r][j] ^= self.gf.mul(f, inv[col][j]);
+                    }
+                }
+            }
+        }
+        let mut data = Vec::with_capacity(k * sl);
+        for row in inv.iter() {
+            let mut shard = vec![0u8; sl];
+            for (c, i) in row.iter().zip(&present) {
+                let src = shards[*i].as_ref().unwrap();
+                for (d, s) in shard.iter_mut().zip(src) {
+                    *d ^= self.gf.mul(*c, *s);
+                }
+            }
+            data.extend_from_slice(&shard);
+        }
+        data.truncate(len);
+        Ok(data)
+    }
+}
+
+/// Parity stored alongside a block
+///
+/// Serialized as u8 data shards, u8 parity shards, u32 shard length,
+/// a checksum for every shard, then the parity shards.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parity {
+    pub config: EccConfig,
+    /// checksums of the data shards then the parity shards
+    pub checksums: Vec<[u8; PARITY_CHECKSUM_SIZE]>,
+    pub shards: Vec<Vec<u8>>,
+}
+
+/// bytes of hash kept per shard, enough to tell which shards went bad
+pub const PARITY_CHECKSUM_SIZE: usize = 8;
+
+impl Parity {
+    /// Serialize for storage
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let sl = self.shards.first().map(|s| s.len()).unwrap_or(0);
+        let mut out = vec![self.config.data_shards, self.config.parity_shards];
+        out.extend_from_slice(&(sl as u32).to_le_bytes());
+        for c in &self.checksums {
+            out.extend_from_slice(c);
+        }
+        for s in &self.shards {
+            out.extend_from_slice(s);
+        }
+        out
+    }
+
+    /// Inverse of to_bytes, None if data isn't parity
+    pub fn from_bytes(data: &[u8]) -> Option<Parity> {
+        if data.len() < 6 {
+            return None;
+        }
+        let config = EccConfig {
+            data_shards: data[0],
+            parity_shards: data[1],
+        };
+        let sl = usize::try_from(u32::from_le_bytes([data[2], data[3], data[4], data[5]])).ok()?;
+        let total = usize::from(config.data_shards) + usize::from(config.parity_shards);
+        let sums_end = 6 + total * PARITY_CHECKSUM_SIZE;
+        if data.len() != sums_end + usize::from(config.parity_shards) * sl {
+            return None;
+        }
+        let checksums = data[6..sums_end]
+            .chunks_exact(PARITY_CHECKSUM_SIZE)
+            .map(|c| {
+                let mut a = [0u8; PARITY_CHECKSUM_SIZE];
+                a.copy_from_slice(c);
+                a
+            })
+            .collect();
+        let shards = if sl == 0 {
+            vec![Vec::new(); usize::from(config.parity_shards)]
+        } else {
+            data[sums_end..].chunks_exact(sl).map(|c| c.to_vec()).collect()
+        };
+        Some(Parity { config, checksums, shards })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gf_inverse() {
+        let gf = Gf::new();
+        for a in 1..=255u8 {
+            assert_eq!(gf.mul(a, gf.inv(a)), 1);
+        }
+    }
+
+    #[test]
+    fn reconstruct_lost_shards() {
+        let rs = ReedSolomon::new(EccConfig { data_shards: 4, parity_shards: 2 }).unwrap();
+        let data: Vec<u8> = (0..103u32).map(|i| (i * 7) as u8).collect();
+        let mut shards: Vec<Option<Vec<u8>>> = rs.split(&data).into_iter().map(Some).collect();
+        shards.extend(rs.encode(&data).into_iter().map(Some));
+        shards[1] = None;
+        shards[3] = None;
+        assert_eq!(rs.reconstruct(&shards, data.len()).unwrap(), data);
+        shards[4] = None;
+        assert!(rs.reconstruct(&shards, data.len()).is_err());
+    }
+
+    #[test]
+    fn parity_bytes_round_trip() {
+        let p = Parity {
+            config: EccConfig { data_shards: 2, parity_shards: 1 },
+            checksums: vec![[1; 8], [2; 8], [3; 8]],
+            shards: vec![vec![9, 9, 9]],
+        };
+        assert_eq!(Parity::from_bytes(&p.to_bytes()).unwrap(), p);
+        assert!(Parity::from_bytes(&p.to_bytes()[1..]).is_none());
+    }
+}
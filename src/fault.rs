@@ -0,0 +1,332 @@
+//Copyright 2021 Matthew Petricone
+//! Fault injection, for testing that code using a Store survives failures.
+//!
+//! A FaultPlan names faults to inject by operation count. Every read,
+//! write, seek, sync and truncate on the store's file is one operation,
+//! counted from when the plan is set. Asking for metadata is not counted.
+//!
+//! A WriteLog records what a store writes, so the file can be rebuilt as
+//! it would be had power failed after any number of bytes reached it.
+//!
+//! Only built with the fault-injection feature.
+use crate::crypto::BlockHasher;
+use crate::store::Store;
+use std::convert::TryFrom;
+use std::io::Error;
+use std::sync::{Arc, Mutex};
+
+static ERROR_FAULT_IO: &str = "Injected I/O error.";
+static ERROR_FAULT_CRASH: &str = "Injected crash.";
+
+/// Something to go wrong with one operation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// a read returns at most half the bytes asked for
+    ShortRead,
+    /// a write takes at most half the bytes given
+    ShortWrite,
+    /// the operation fails
+    IoError,
+    /// the operation and every one after it fails without touching the
+    /// file, as if the process died there
+    Crash,
+}
+
+/// Faults to inject, by operation count from 0
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FaultPlan {
+    faults: Vec<(u64, Fault)>,
+}
+
+impl FaultPlan {
+    /// Plan with no faults
+    pub fn new() -> FaultPlan {
+        FaultPlan::default()
+    }
+
+    /// Inject fault in operation op
+    pub fn at(mut self, op: u64, fault: Fault) -> FaultPlan {
+        self.faults.push((op, fault));
+        self
+    }
+}
+
+/// A plan being carried out
+#[derive(Debug, Clone)]
+pub(crate) struct FaultState {
+    plan: FaultPlan,
+    ops: u64,
+    crashed: bool,
+}
+
+impl FaultState {
+    pub(crate) fn new(plan: FaultPlan) -> FaultState {
+        FaultState {
+            plan,
+            ops: 0,
+            crashed: false,
+        }
+    }
+
+    /// Operations seen so far
+    pub(crate) fn ops(&self) -> u64 {
+        self.ops
+    }
+
+    /// true once a Crash fault was injected
+    pub(crate) fn crashed(&self) -> bool {
+        self.crashed
+    }
+
+    /// Count an operation, failing it if the plan says so.
+    ///
+    /// Returns the short read or write to do instead, if any.
+    pub(crate) fn next(&mut self) -> Result<Option<Fault>, Error> {
+        if self.crashed {
+            return Err(Error::other(ERROR_FAULT_CRASH));
+        }
+        let op = self.ops;
+        self.ops += 1;
+        match self.plan.faults.iter().find(|(o, _)| *o == op).map(|(_, f)| *f) {
+            Some(Fault::Crash) => {
+                self.crashed = true;
+                Err(Error::other(ERROR_FAULT_CRASH))
+            }
+            Some(Fault::IoError) => Err(Error::other(ERROR_FAULT_IO)),
+            f => Ok(f),
+        }
+    }
+}
+
+/// One change to a file
+#[derive(Debug, Clone, PartialEq)]
+enum FileEvent {
+    /// bytes written at an offset
+    Write(u64, Vec<u8>),
+    /// file truncated or extended to a length
+    SetLen(u64),
+}
+
+/// Writes made to a store's file, in order, see Store::record_writes
+///
+/// Clones share the same log.
+#[derive(Debug, Default, Clone)]
+pub struct WriteLog {
+    events: Arc<Mutex<Vec<FileEvent>>>,
+}
+
+impl WriteLog {
+    /// Empty log
+    pub fn new() -> WriteLog {
+        WriteLog::default()
+    }
+
+    /// Record data written at offset
+    pub(crate) fn write(&self, offset: u64, data: &[u8]) {
+        self.events().push(FileEvent::Write(offset, data.to_vec()));
+    }
+
+    /// Record the file's length being set
+    pub(crate) fn set_len(&self, len: u64) {
+        self.events().push(FileEvent::SetLen(len));
+    }
+
+    fn events(&self) -> std::sync::MutexGuard<'_, Vec<FileEvent>> {
+        self.events.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Bytes written in all
+    pub fn total_bytes(&self) -> u64 {
+        self.events()
+            .iter()
+            .map(|e| match e {
+                FileEvent::Write(_, d) => d.len() as u64,
+                FileEvent::SetLen(_) => 0,
+            })
+            .sum()
+    }
+
+    /// The file as it was before the log began, base, with the first bytes
+    /// of the writes applied.
+    ///
+    /// Changes of length are applied once every byte written before them is.
+    pub fn image_at(&self, base: &[u8], bytes: u64) -> Vec<u8> {
+        let mut image = base.to_vec();
+        let mut left = bytes;
+        for e in self.events().iter() {
+            match e {
+                FileEvent::SetLen(len) => image.resize(usize::try_from(*len).unwrap(), 0),
+                FileEvent::Write(offset, data) => {
+                    if left == 0 {
+                        break;
+                    }
+                    let n = data.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+                    let start = usize::try_from(*offset).unwrap();
+                    if image.len() < start + n {
+                        image.resize(start + n, 0);
+                    }
+                    image[start..start + n].copy_from_slice(&data[..n]);
+                    left -= n as u64;
+                }
+            }
+        }
+        image
+    }
+}
+
+/// What happened in run_with_faults
+#[derive(Debug)]
+pub struct FaultRun<R> {
+    /// what the closure returned
+    pub result: R,
+    /// operations the closure did
+    pub ops: u64,
+    /// a Crash fault was injected
+    pub crashed: bool,
+}
+
+/// Run f against store with plan's faults injected.
+///
+/// If the run crashed the store is left unable to do any more I/O, so
+/// dropping it leaves the file as the crash did; reopen it to check
+/// recovery. Otherwise the faults are cleared afterwards.
+pub fn run_with_faults<T, R, F>(store: &mut Store<T>, plan: FaultPlan, f: F) -> FaultRun<R>
+where
+    T: BlockHasher,
+    F: FnOnce(&mut Store<T>) -> R,
+{
+    store.inject_faults(plan);
+    let result = f(store);
+    let ops = store.fault_ops();
+    let crashed = store.crashed();
+    if !crashed {
+        store.clear_faults();
+    }
+    FaultRun { result, ops, crashed }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+    use crate::store::StoreIO;
+
+    fn fresh_store(path: &str) {
+        std::fs::create_dir_all("testout").unwrap();
+        let mut s = Store::<B3BlockHasher>::create(path.to_string()).unwrap();
+        s.put(&[1; 100]).unwrap();
+        s.put(&[2; 100]).unwrap();
+        s.close().unwrap();
+    }
+
+    #[test]
+    fn crash_at_every_op_of_a_put_recovers() {
+        let path = "testout/fault_crash.st";
+        let mut op = 0;
+        loop {
+            fresh_store(path);
+            let mut s = Store::<B3BlockHasher>::open_for_write(path.to_string()).unwrap();
+            let run = run_with_faults(&mut s, FaultPlan::new().at(op, Fault::Crash), |s| s.put(&[3; 100]).map(|_| ()));
+            drop(s);
+            let mut s = Store::<B3BlockHasher>::open_for_write(path.to_string()).unwrap();
+            assert_eq!(s.get(0).unwrap(), vec![1; 100]);
+            assert_eq!(s.get(1).unwrap(), vec![2; 100]);
+            if s.len() == 3 {
+                assert_eq!(s.get(2).unwrap(), vec![3; 100]);
+            }
+            if !run.crashed {
+                assert!(run.result.is_ok());
+                assert_eq!(s.len(), 3);
+                break;
+            }
+            assert!(run.result.is_err());
+            op += 1;
+        }
+        assert!(op > 2);
+    }
+
+    /// Check that a crash after any number of bytes written by workload
+    /// leaves a store that opens, read only and for writing, without
+    /// returning a payload other than the one expected at its index.
+    ///
+    /// The store starts with expected[0] in it.
+    fn assert_survives_power_failure<F>(name: &str, expected: &[Vec<u8>], workload: F)
+    where
+        F: FnOnce(&mut Store<B3BlockHasher>),
+    {
+        std::fs::create_dir_all("testout").unwrap();
+        let path = format!("testout/{}.st", name);
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        s.put(&expected[0]).unwrap();
+        s.close().unwrap();
+        let mut s = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        let base = std::fs::read(&path).unwrap();
+        let log = WriteLog::new();
+        s.record_writes(log.clone());
+        workload(&mut s);
+        s.close().unwrap();
+
+        let crashed = format!("testout/{}_crashed.st", name);
+        let check = |s: &mut Store<B3BlockHasher>, at: u64| {
+            assert!(s.len() <= expected.len(), "{} blocks after {} bytes", s.len(), at);
+            for (i, want) in expected.iter().enumerate().take(s.len()) {
+                if let Ok(data) = s.get(i) {
+                    assert_eq!(&data, want, "block {} after {} bytes", i, at);
+                }
+            }
+        };
+        for at in 0..=log.total_bytes() {
+            let image = log.image_at(&base, at);
+            std::fs::write(&crashed, &image).unwrap();
+            if let Ok(mut s) = Store::<B3BlockHasher>::new(crashed.clone()) {
+                check(&mut s, at);
+            }
+            std::fs::write(&crashed, &image).unwrap();
+            if let Ok(mut s) = Store::<B3BlockHasher>::open_for_write(crashed.clone()) {
+                check(&mut s, at);
+                // a writer always gets back at least what was there before
+                assert!(s.len() >= 1, "recovery lost blocks after {} bytes", at);
+            }
+        }
+    }
+
+    #[test]
+    fn power_failure_during_writes_and_deletes() {
+        let expected = vec![vec![1; 40], vec![2; 30], vec![3; 50], vec![4; 20]];
+        let blocks = expected.clone();
+        assert_survives_power_failure("power_fail", &expected, move |s| {
+            s.put(&blocks[1]).unwrap();
+            s.put(&blocks[2]).unwrap();
+            s.delete_many(&[0]).unwrap();
+            s.put(&blocks[3]).unwrap();
+        });
+    }
+
+    #[test]
+    fn power_failure_during_reopen_and_close() {
+        let expected = vec![vec![1; 10], vec![], vec![2; 10]];
+        let blocks = expected.clone();
+        assert_survives_power_failure("power_fail_empty", &expected, move |s| {
+            s.put(&blocks[1]).unwrap();
+            s.put(&blocks[2]).unwrap();
+            s.quarantine(1).unwrap();
+        });
+    }
+
+    #[test]
+    fn short_reads_and_errors() {
+        let path = "testout/fault_short.st";
+        fresh_store(path);
+        let mut s = Store::<B3BlockHasher>::new(path.to_string()).unwrap();
+        let plan = (0..20).fold(FaultPlan::new(), |p, op| p.at(op, Fault::ShortRead));
+        let run = run_with_faults(&mut s, plan, |s| s.get(1));
+        assert_eq!(run.result.unwrap(), vec![2; 100]);
+        assert!(!run.crashed);
+
+        let run = run_with_faults(&mut s, FaultPlan::new().at(0, Fault::IoError), |s| s.get(0));
+        assert!(run.result.is_err());
+        assert_eq!(run.ops, 1);
+        assert_eq!(s.get(0).unwrap(), vec![1; 100]);
+        assert_eq!(s.len(), 2);
+    }
+}
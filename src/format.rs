@@ -0,0 +1,216 @@
+//Copyright 2021 Matthew Petricone
+//! Description of the on disk layout, for tools written in other languages.
+//!
+//! All integers are little endian. A store is the file descriptor, then
+//! blocks of a header and payload one after another, optionally ending in an
+//! index footer block. describe().to_json() gives the details.
+use crate::crypto::{B3BlockHasher, BlockHasher};
+use crate::data_header::{
+    header_codec, COMPACT_TRUNCATED_HASH_SIZE, HEADER_OPTIONAL_FIELDS, STATE_FLAG_CORRUPT, STATE_FLAG_DELETE, STATE_FLAG_DIGEST,
+    STATE_FLAG_FILLER, STATE_FLAG_INDEX, STATE_FLAG_JOURNAL, STATE_FLAG_PARITY, STATE_FLAG_REWRITTEN,
+};
+use crate::store::{
+    DESCRIPTOR_FLAG_DIRTY, FEATURES_REQUIRED_MASK, FEATURE_INDEX_FOOTER, FEATURE_PERSONALIZED, FOOTER_SECTION_BLOOM,
+    FOOTER_SECTION_DELETE_TIMES, FOOTER_SECTION_LIFETIME, FOOTER_SECTION_QUARANTINE, FOOTER_SECTION_SCRUB,
+    FOOTER_SECTION_TIMES, INDEX_FOOTER_MAGIC, STORE_VERSIONNUM, STORE_VERSIONTAG,
+};
+use std::fmt::Write;
+
+/// One field of a fixed layout
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldSpec {
+    pub name: &'static str,
+    /// bytes from the start of the structure
+    pub offset: usize,
+    /// bytes
+    pub size: usize,
+    /// "u8", "u16", "u32", "u64" or "bytes"
+    pub kind: &'static str,
+}
+
+/// A named bit or tag value
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlagSpec {
+    pub name: &'static str,
+    pub value: u64,
+}
+
+/// Layout of a store with a given header codec and hasher
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatDescription {
+    pub version: u32,
+    pub version_tag: &'static str,
+    pub endianness: &'static str,
+    /// the file descriptor at offset 0
+    pub descriptor: Vec<FieldSpec>,
+    /// where the first block starts, unless the personalized feature adds
+    /// u16 length and the personalization to the descriptor
+    pub data_start: usize,
+    pub descriptor_flags: Vec<FlagSpec>,
+    pub features: Vec<FlagSpec>,
+    /// feature bits a reader must understand to open the store
+    pub features_required_mask: u64,
+    pub codec_id: u32,
+    /// the header before every block payload
+    pub header: Vec<FieldSpec>,
+    pub header_size: usize,
+    /// BlockHasher::id, 0 if the hasher has none
+    pub hasher_id: u8,
+    pub hash_size: usize,
+    pub state_flags: Vec<FlagSpec>,
+    pub footer_magic: &'static str,
+    /// tags of the sections in the index footer payload
+    pub footer_sections: Vec<FlagSpec>,
+}
+
+/// Layout of a store created with Store::create and B3BlockHasher
+pub fn describe() -> FormatDescription {
+    describe_for::<B3BlockHasher>(0).unwrap()
+}
+
+/// Layout of a store with header codec codec_id and hasher T, None for an unknown codec
+pub fn describe_for<T: BlockHasher>(codec_id: u32) -> Option<FormatDescription> {
+    let codec = header_codec(codec_id)?;
+    let hash_size = T::size();
+    let header = match codec_id {
+        0 => fields(&[("size_data", 8, "u64"), ("state_flag", 4, "u32"), ("address_next", 8, "u64"), ("checksum", hash_size, "bytes")]),
+        1 | 2 => {
+            let sum = if codec_id == 2 { hash_size.min(COMPACT_TRUNCATED_HASH_SIZE) } else { hash_size };
+            fields(&[("size_data", 4, "u32"), ("state_flag", 1, "u8"), ("checksum", sum, "bytes")])
+        }
+        _ => {
+            // TaggedHeaderCodec, or FieldsHeaderCodec with its optional fields above the id's low byte
+            let present = codec_id >> 8;
+            let mut spec = vec![("size_data", 8, "u64"), ("state_flag", 4, "u32"), ("hash_id", 1, "u8"), ("checksum_length", 1, "u8")];
+            for (_, values) in HEADER_OPTIONAL_FIELDS.iter().filter(|(bit, _)| present & bit != 0) {
+                spec.extend_from_slice(values);
+            }
+            let before: usize = spec.iter().map(|(_, size, _)| size).sum();
+            spec.push(("checksum", codec.size(hash_size) - before, "bytes"));
+            fields(&spec)
+        }
+    };
+    let descriptor = fields(&[
+        ("version", 4, "u32"),
+        ("version_tag_length", 8, "u64"),
+        ("version_tag", STORE_VERSIONTAG.len(), "bytes"),
+        ("codec_id", 4, "u32"),
+        ("flags", 8, "u64"),
+        ("features", 8, "u64"),
+    ]);
+    let data_start = descriptor.iter().map(|f| f.size).sum();
+    Some(FormatDescription {
+        version: STORE_VERSIONNUM,
+        version_tag: STORE_VERSIONTAG,
+        endianness: "little",
+        descriptor,
+        data_start,
+        descriptor_flags: flags(&[("dirty", DESCRIPTOR_FLAG_DIRTY)]),
+        features: flags(&[("personalized", FEATURE_PERSONALIZED), ("index_footer", FEATURE_INDEX_FOOTER)]),
+        features_required_mask: FEATURES_REQUIRED_MASK,
+        codec_id,
+        header,
+        header_size: codec.size(hash_size),
+        hasher_id: T::id(),
+        hash_size,
+        state_flags: flags(&[
+            ("delete", u64::from(STATE_FLAG_DELETE)),
+            ("index", u64::from(STATE_FLAG_INDEX)),
+            ("corrupt", u64::from(STATE_FLAG_CORRUPT)),
+            ("parity", u64::from(STATE_FLAG_PARITY)),
+            ("journal", u64::from(STATE_FLAG_JOURNAL)),
+            ("filler", u64::from(STATE_FLAG_FILLER)),
+            ("rewritten", u64::from(STATE_FLAG_REWRITTEN)),
+            ("digest", u64::from(STATE_FLAG_DIGEST)),
+        ]),
+        footer_magic: std::str::from_utf8(INDEX_FOOTER_MAGIC).unwrap(),
+        footer_sections: flags(&[
+            ("bloom", u64::from(FOOTER_SECTION_BLOOM)),
+            ("quarantine", u64::from(FOOTER_SECTION_QUARANTINE)),
+            ("scrub_position", u64::from(FOOTER_SECTION_SCRUB)),
+            ("append_times", u64::from(FOOTER_SECTION_TIMES)),
+            ("delete_times", u64::from(FOOTER_SECTION_DELETE_TIMES)),
+            ("lifetime", u64::from(FOOTER_SECTION_LIFETIME)),
+        ]),
+    })
+}
+
+/// Lay out fields one after another
+fn fields(spec: &[(&'static str, usize, &'static str)]) -> Vec<FieldSpec> {
+    let mut offset = 0;
+    spec.iter()
+        .map(|(name, size, kind)| {
+            let f = FieldSpec { name, offset, size: *size, kind };
+            offset += size;
+            f
+        })
+        .collect()
+}
+
+fn flags(spec: &[(&'static str, u64)]) -> Vec<FlagSpec> {
+    spec.iter().map(|(name, value)| FlagSpec { name, value: *value }).collect()
+}
+
+impl FormatDescription {
+    /// The description as a JSON object
+    pub fn to_json(&self) -> String {
+        let field_list = |fs: &[FieldSpec]| {
+            let items: Vec<String> = fs
+                .iter()
+                .map(|f| format!(r#"{{"name":"{}","offset":{},"size":{},"type":"{}"}}"#, f.name, f.offset, f.size, f.kind))
+                .collect();
+            format!("[{}]", items.join(","))
+        };
+        let flag_list = |fs: &[FlagSpec]| {
+            let items: Vec<String> = fs.iter().map(|f| format!(r#"{{"name":"{}","value":{}}}"#, f.name, f.value)).collect();
+            format!("[{}]", items.join(","))
+        };
+        let mut out = String::from("{");
+        // none of the strings need escaping
+        write!(out, r#""version":{},"version_tag":"{}","endianness":"{}","#, self.version, self.version_tag, self.endianness).unwrap();
+        write!(out, r#""descriptor":{},"data_start":{},"#, field_list(&self.descriptor), self.data_start).unwrap();
+        write!(out, r#""descriptor_flags":{},"features":{},"#, flag_list(&self.descriptor_flags), flag_list(&self.features)).unwrap();
+        write!(out, r#""features_required_mask":{},"codec_id":{},"#, self.features_required_mask, self.codec_id).unwrap();
+        write!(out, r#""header":{},"header_size":{},"#, field_list(&self.header), self.header_size).unwrap();
+        write!(out, r#""hasher_id":{},"hash_size":{},"state_flags":{},"#, self.hasher_id, self.hash_size, flag_list(&self.state_flags)).unwrap();
+        write!(out, r#""footer_magic":"{}","footer_sections":{}}}"#, self.footer_magic, flag_list(&self.footer_sections)).unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Crc32BlockHasher;
+    use crate::data_header::{
+        BlockSerializer, DataHeader, FieldsHeaderCodec, HeaderCodec, HEADER_FIELD_ACCESS, HEADER_FIELD_CONTENT_TYPE,
+        HEADER_FIELD_SESSION,
+    };
+    use crate::store::{Store, StoreIO};
+
+    #[test]
+    fn description_matches_files() {
+        let d = describe();
+        assert_eq!(d.header_size, DataHeader::<B3BlockHasher>::size());
+        assert_eq!(d.header.iter().map(|f| f.size).sum::<usize>(), d.header_size);
+        std::fs::create_dir_all("testout").unwrap();
+        let mut s = Store::<B3BlockHasher>::create("testout/format.st".to_string()).unwrap();
+        s.put(&[1]).unwrap();
+        assert_eq!(s.block_address(0), Some(d.data_start as u64));
+
+        let typed = FieldsHeaderCodec { fields: HEADER_FIELD_CONTENT_TYPE }.id();
+        let access = FieldsHeaderCodec { fields: HEADER_FIELD_CONTENT_TYPE | HEADER_FIELD_ACCESS }.id();
+        let all = FieldsHeaderCodec { fields: HEADER_FIELD_CONTENT_TYPE | HEADER_FIELD_ACCESS | HEADER_FIELD_SESSION }.id();
+        for id in [0, 1, 2, 3, typed, access, all] {
+            let d = describe_for::<Crc32BlockHasher>(id).unwrap();
+            assert_eq!(d.header.iter().map(|f| f.size).sum::<usize>(), d.header_size);
+        }
+        let header = describe_for::<B3BlockHasher>(typed).unwrap().header;
+        assert_eq!(header.iter().find(|f| f.name == "content_type").unwrap().offset, 14);
+        assert_eq!(header.last().unwrap().offset, 16);
+        assert!(describe_for::<B3BlockHasher>(99).is_none());
+        let json = d.to_json();
+        assert!(json.starts_with('{') && json.ends_with('}'));
+        assert!(json.contains(r#""name":"checksum","offset":20,"size":32"#));
+    }
+}
@@ -0,0 +1,171 @@
+//Copyright 2021 Matthew Petricone
+//! Length prefixed frame streams, for moving payloads in and out of other
+//! systems.
+//!
+//! A frame stream is a run of payloads each preceded by its length, a u32
+//! or u64 in either byte order, with nothing before, between or after
+//! them. Kafka dumps use big endian u32 lengths, and many delimited record
+//! files one of the other forms. Store::import_frames appends every frame
+//! as a block, Store::export_frames writes the live blocks as frames.
+use crate::crypto::BlockHasher;
+use crate::store::{BlockId, Store};
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::str::FromStr;
+
+static ERROR_FRAMES_TRUNCATED: &str = "Frame stream ends part way through a frame.";
+static ERROR_FRAMES_TOO_LARGE: &str = "Payload is too large for the frame length prefix.";
+static ERROR_FRAMES_PREFIX: &str = "Unknown frame prefix, expected u32be, u32le, u64be or u64le.";
+
+/// Length prefix of each frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramePrefix {
+    U32Be,
+    U32Le,
+    U64Be,
+    U64Le,
+}
+
+impl FramePrefix {
+    /// Bytes in the prefix
+    pub fn size(self) -> usize {
+        match self {
+            FramePrefix::U32Be | FramePrefix::U32Le => 4,
+            FramePrefix::U64Be | FramePrefix::U64Le => 8,
+        }
+    }
+
+    /// The length in a prefix of size() bytes
+    fn decode(self, b: &[u8]) -> u64 {
+        match self {
+            FramePrefix::U32Be => u32::from_be_bytes(b.try_into().unwrap()) as u64,
+            FramePrefix::U32Le => u32::from_le_bytes(b.try_into().unwrap()) as u64,
+            FramePrefix::U64Be => u64::from_be_bytes(b.try_into().unwrap()),
+            FramePrefix::U64Le => u64::from_le_bytes(b.try_into().unwrap()),
+        }
+    }
+
+    /// The prefix for a payload of len bytes, None if it doesn't fit
+    fn encode(self, len: u64) -> Option<Vec<u8>> {
+        Some(match self {
+            FramePrefix::U32Be => u32::try_from(len).ok()?.to_be_bytes().to_vec(),
+            FramePrefix::U32Le => u32::try_from(len).ok()?.to_le_bytes().to_vec(),
+            FramePrefix::U64Be => len.to_be_bytes().to_vec(),
+            FramePrefix::U64Le => len.to_le_bytes().to_vec(),
+        })
+    }
+}
+
+impl FromStr for FramePrefix {
+    type Err = Error;
+
+    /// u32be, u32le, u64be or u64le
+    fn from_str(s: &str) -> Result<FramePrefix, Error> {
+        match s {
+            "u32be" => Ok(FramePrefix::U32Be),
+            "u32le" => Ok(FramePrefix::U32Le),
+            "u64be" => Ok(FramePrefix::U64Be),
+            "u64le" => Ok(FramePrefix::U64Le),
+            _ => Err(Error::new(ErrorKind::InvalidInput, ERROR_FRAMES_PREFIX)),
+        }
+    }
+}
+
+/// Fill buf from r, false if r was already at its end. Ending part way
+/// through buf is an error.
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<bool, Error> {
+    let mut got = 0;
+    while got < buf.len() {
+        match r.read(&mut buf[got..]) {
+            Ok(0) if got == 0 => return Ok(false),
+            Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, ERROR_FRAMES_TRUNCATED)),
+            Ok(n) => got += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+impl<T: BlockHasher> Store<T> {
+    /// Append every frame read from r as a block, returning their indexes.
+    ///
+    /// Reading stops at the end of r. A frame cut short fails with
+    /// ErrorKind::UnexpectedEof, after the whole frames before it were
+    /// appended. A length over the store's maximum block size fails before
+    /// its payload is read, so a bad prefix can't make it allocate.
+    pub fn import_frames<R: Read>(&mut self, mut r: R, prefix: FramePrefix) -> Result<Vec<BlockId>, Box<dyn std::error::Error>> {
+        let mut ids = Vec::new();
+        let mut len_buf = vec![0u8; prefix.size()];
+        let mut payload = Vec::new();
+        while read_full(&mut r, &mut len_buf)? {
+            let len = prefix.decode(&len_buf);
+            self.check_block_size(len)?;
+            payload.clear();
+            let got = (&mut r).take(len).read_to_end(&mut payload)?;
+            if got as u64 != len {
+                return Err(Box::new(Error::new(ErrorKind::UnexpectedEof, ERROR_FRAMES_TRUNCATED)));
+            }
+            ids.push(self.put(&payload)?);
+        }
+        Ok(ids)
+    }
+
+    /// Write the payloads iter returns to w as frames, returning how many.
+    ///
+    /// A payload too large for the prefix fails with ErrorKind::InvalidData,
+    /// after the frames before it were written.
+    pub fn export_frames<W: Write>(&mut self, mut w: W, prefix: FramePrefix) -> Result<usize, Box<dyn std::error::Error>> {
+        let mut n = 0;
+        for block in self.iter() {
+            let (_, data) = block?;
+            let len = prefix
+                .encode(data.len() as u64)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, ERROR_FRAMES_TOO_LARGE))?;
+            w.write_all(&len)?;
+            w.write_all(&data)?;
+            n += 1;
+        }
+        w.flush()?;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+    use crate::store::StoreIO;
+
+    #[test]
+    fn frames_round_trip() {
+        std::fs::create_dir_all("testout").unwrap();
+        let mut s = Store::<B3BlockHasher>::create("testout/frames.st".to_string()).unwrap();
+        // a Kafka style dump: big endian u32 lengths
+        let mut dump = Vec::new();
+        for m in [&b"first"[..], b"", b"third message"] {
+            dump.extend_from_slice(&(m.len() as u32).to_be_bytes());
+            dump.extend_from_slice(m);
+        }
+        assert_eq!(s.import_frames(&dump[..], FramePrefix::U32Be).unwrap(), vec![0, 1, 2]);
+        assert_eq!(s.get(2).unwrap(), b"third message");
+        s.delete_block(0).unwrap();
+
+        let mut out = Vec::new();
+        assert_eq!(s.export_frames(&mut out, "u64le".parse().unwrap()).unwrap(), 2);
+        assert_eq!(&out[..8], &0u64.to_le_bytes());
+        assert_eq!(&out[8..16], &13u64.to_le_bytes());
+        assert_eq!(&out[16..], b"third message");
+        assert_eq!(s.import_frames(&out[..], FramePrefix::U64Le).unwrap(), vec![3, 4]);
+
+        // cut in the prefix, then in the payload
+        assert!(s.import_frames(&dump[..2], FramePrefix::U32Be).is_err());
+        let e = s.import_frames(&dump[..7], FramePrefix::U32Be).err().unwrap();
+        assert_eq!(e.downcast_ref::<Error>().unwrap().kind(), ErrorKind::UnexpectedEof);
+        s.set_max_block_size(Some(4));
+        assert!(s.import_frames(&dump[..], FramePrefix::U32Be).is_err());
+        assert_eq!(s.len(), 5);
+        assert!("u16".parse::<FramePrefix>().is_err());
+    }
+}
@@ -0,0 +1,1129 @@
+//Copyright 2021 Matthew Petricone
+//! A minimal ordered key value store on top of a Store.
+//!
+//! Values are blocks of their own, found through a B-tree whose pages are
+//! blocks too. Every block a KvStore writes starts with a kind byte:
+//! KV_BLOCK_VALUE before a value, KV_BLOCK_PAGE or KV_BLOCK_ROOT before a page.
+//!
+//! Pages are never changed in place: a put or delete writes new copies of
+//! the pages on the path from the root to its key, root last, then deletes
+//! the old ones. The last live root page is the tree, so a crash part way
+//! through leaves the tree as it was. When the store is next opened every
+//! block the last root doesn't reach is deleted: the pages and values
+//! written after it, and the old ones a crash kept it from deleting.
+//!
+//! A page is a u8 page type, then for a leaf a u32 count and per entry a
+//! u32 key length, the key and the u64 block index of the value; for an
+//! internal page a u32 key count, the u64 index of the first child, then
+//! per key a u32 key length, the key and the u64 index of the child holding
+//! keys from it on.
+//!
+//! Since pages hold block indexes, the store underneath must not be
+//! compacted as a plain Store, which gives blocks new indexes and leaves
+//! the pages pointing at the old ones. KvStore::compact rebuilds the tree
+//! instead.
+use crate::crypto::BlockHasher;
+use crate::platform;
+use crate::store::{BlockId, Store, StoreIO};
+use std::convert::TryFrom;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::ops::{Bound, RangeBounds};
+use std::time::SystemTime;
+
+/// Kind byte of a value block
+pub const KV_BLOCK_VALUE: u8 = 0;
+/// Kind byte of a B-tree page other than the root
+pub const KV_BLOCK_PAGE: u8 = 1;
+/// Kind byte of the B-tree root page
+pub const KV_BLOCK_ROOT: u8 = 2;
+
+const PAGE_LEAF: u8 = 0;
+const PAGE_INTERNAL: u8 = 1;
+/// default most entries in a page before it splits
+const KV_MAX_PAGE_ENTRIES: usize = 64;
+
+static ERROR_KV_NOT_KV: &str = "Store is not a key value store.";
+static ERROR_KV_PAGE: &str = "Invalid key value page.";
+static ERROR_KV_VALUE: &str = "Invalid key value block.";
+
+/// A key and its value
+pub type KvPair = (Vec<u8>, Vec<u8>);
+
+/// Picks the value to keep from the key, this store's value and the other's
+pub type MergeResolver<'a> = Box<dyn FnMut(&[u8], &[u8], &[u8]) -> Vec<u8> + 'a>;
+
+/// How KvStore::merge_from settles a key set to different values in both
+/// stores
+pub enum MergePolicy<'a> {
+    /// the value written last, by when its block was appended; this store's
+    /// if they were written in the same second or either time is unknown
+    KeepNewest,
+    /// the other store's value
+    KeepSource,
+    /// this store's value, and the other's under the key with the suffix
+    /// appended, replacing any value already there
+    KeepBoth(&'a [u8]),
+    /// whatever the resolver returns
+    Custom(MergeResolver<'a>),
+}
+
+/// What KvStore::merge_from did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MergeReport {
+    /// keys only the other store had
+    pub added: usize,
+    /// keys both had with different values
+    pub conflicts: usize,
+}
+
+/// One B-tree page
+#[derive(Debug, Clone, PartialEq)]
+enum Page {
+    /// keys in order, each with the block of its value
+    Leaf(Vec<(Vec<u8>, BlockId)>),
+    /// children[i + 1] holds the keys from keys[i] on
+    Internal { keys: Vec<Vec<u8>>, children: Vec<BlockId> },
+}
+
+impl Page {
+    fn encode(&self, kind: u8) -> Vec<u8> {
+        let mut out = vec![kind];
+        let push_key = |out: &mut Vec<u8>, key: &[u8]| {
+            out.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            out.extend_from_slice(key);
+        };
+        match self {
+            Page::Leaf(entries) => {
+                out.push(PAGE_LEAF);
+                out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+                for (k, v) in entries {
+                    push_key(&mut out, k);
+                    out.extend_from_slice(&(*v as u64).to_le_bytes());
+                }
+            }
+            Page::Internal { keys, children } => {
+                out.push(PAGE_INTERNAL);
+                out.extend_from_slice(&(keys.len() as u32).to_le_bytes());
+                out.extend_from_slice(&(children[0] as u64).to_le_bytes());
+                for (k, c) in keys.iter().zip(&children[1..]) {
+                    push_key(&mut out, k);
+                    out.extend_from_slice(&(*c as u64).to_le_bytes());
+                }
+            }
+        }
+        out
+    }
+
+    /// Inverse of encode, None if data isn't a page
+    fn decode(data: &[u8]) -> Option<Page> {
+        if data.len() < 6 || (data[0] != KV_BLOCK_PAGE && data[0] != KV_BLOCK_ROOT) {
+            return None;
+        }
+        let mut pos = 6;
+        let u64_at = |pos: &mut usize| -> Option<BlockId> {
+            let v = u64::from_le_bytes(data.get(*pos..*pos + 8)?.try_into().ok()?);
+            *pos += 8;
+            usize::try_from(v).ok()
+        };
+        let key_at = |pos: &mut usize| -> Option<Vec<u8>> {
+            let len = u32::from_le_bytes(data.get(*pos..*pos + 4)?.try_into().ok()?) as usize;
+            let key = data.get(*pos + 4..(*pos + 4).checked_add(len)?)?.to_vec();
+            *pos += 4 + len;
+            Some(key)
+        };
+        let count = u32::from_le_bytes(data[2..6].try_into().ok()?) as usize;
+        let page = match data[1] {
+            PAGE_LEAF => {
+                let mut entries = Vec::new();
+                for _ in 0..count {
+                    let k = key_at(&mut pos)?;
+                    entries.push((k, u64_at(&mut pos)?));
+                }
+                Page::Leaf(entries)
+            }
+            PAGE_INTERNAL => {
+                let mut keys = Vec::new();
+                let mut children = vec![u64_at(&mut pos)?];
+                for _ in 0..count {
+                    keys.push(key_at(&mut pos)?);
+                    children.push(u64_at(&mut pos)?);
+                }
+                Page::Internal { keys, children }
+            }
+            _ => return None,
+        };
+        if pos != data.len() {
+            return None;
+        }
+        Some(page)
+    }
+}
+
+/// A page with the smallest key it may hold
+type SplitPage = (Vec<u8>, Page);
+
+/// Child of an internal page whose keys include key
+fn child_for(keys: &[Vec<u8>], key: &[u8]) -> usize {
+    keys.partition_point(|k| k.as_slice() <= key)
+}
+
+/// Ordered key value store, see the module docs.
+///
+/// Compact it with KvStore::compact, never with Store::compact on its file.
+pub struct KvStore<T: BlockHasher> {
+    store: Store<T>,
+    /// block of the root page
+    root: BlockId,
+    /// most entries in a page before it splits
+    max_entries: usize,
+}
+
+impl<T: BlockHasher> KvStore<T> {
+    /// Create an empty key value store, overwriting any file at path
+    pub fn create(path: String) -> Result<KvStore<T>, Box<dyn std::error::Error>> {
+        let mut store = Store::<T>::create(path)?;
+        let root = store.put(&Page::Leaf(Vec::new()).encode(KV_BLOCK_ROOT))?;
+        Ok(KvStore {
+            store,
+            root,
+            max_entries: KV_MAX_PAGE_ENTRIES,
+        })
+    }
+
+    /// Open a key value store for reading and writing.
+    ///
+    /// Live blocks the last root page doesn't reach, left by a put or
+    /// delete that didn't finish, are deleted.
+    pub fn open(path: String) -> Result<KvStore<T>, Box<dyn std::error::Error>> {
+        let mut store = Store::<T>::open_for_write(path)?;
+        let mut root = None;
+        for i in (0..store.len()).rev() {
+            if !store.is_live(i)? {
+                continue;
+            }
+            let data = store.get(i)?;
+            if data.first() == Some(&KV_BLOCK_ROOT) && Page::decode(&data).is_some() {
+                root = Some(i);
+                break;
+            }
+        }
+        let root = root.ok_or(ERROR_KV_NOT_KV)?;
+        let mut kv = KvStore {
+            store,
+            root,
+            max_entries: KV_MAX_PAGE_ENTRIES,
+        };
+        let mut reached = HashSet::new();
+        kv.reach(root, &mut reached)?;
+        let mut unreached = Vec::new();
+        for i in 0..kv.store.len() {
+            if !reached.contains(&i) && kv.store.is_live(i)? {
+                unreached.push(i);
+            }
+        }
+        kv.store.delete_many(&unreached)?;
+        Ok(kv)
+    }
+
+    /// Add page id and every page and value below it to reached
+    fn reach(&mut self, id: BlockId, reached: &mut HashSet<BlockId>) -> Result<(), Box<dyn std::error::Error>> {
+        reached.insert(id);
+        match self.read_page(id)? {
+            Page::Leaf(entries) => reached.extend(entries.into_iter().map(|(_, v)| v)),
+            Page::Internal { children, .. } => {
+                for c in children {
+                    self.reach(c, reached)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Split pages with more than max entries from now on, at least 3
+    pub fn set_max_page_entries(&mut self, max: usize) {
+        self.max_entries = max.max(3);
+    }
+
+    /// The store underneath
+    pub fn store(&self) -> &Store<T> {
+        &self.store
+    }
+
+    /// Close the store underneath
+    pub fn close(self) -> Result<(), Box<dyn std::error::Error>> {
+        self.store.close()
+    }
+
+    /// Rewrite the store without its dead blocks.
+    ///
+    /// Every key and value is written to a new store next to this one (its
+    /// name with ".compact" appended), which is synced and renamed over it,
+    /// so a crash leaves either the old store or the compacted one. Unlike
+    /// Store::compact this rewrites the pages, so the block indexes in them
+    /// stay right.
+    pub fn compact(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let pairs = self.iter()?;
+        let path = self.store.path().to_string();
+        let tmp = format!("{}.compact", path);
+        let mut out = KvStore::<T>::create(tmp.clone())?;
+        out.max_entries = self.max_entries;
+        out.put_many(&pairs)?;
+        out.close()?;
+        platform::replace(&tmp, &path)?;
+        let mut fresh = KvStore::<T>::open(path)?;
+        fresh.max_entries = self.max_entries;
+        std::mem::replace(self, fresh).close()
+    }
+
+    fn read_page(&mut self, id: BlockId) -> Result<Page, Box<dyn std::error::Error>> {
+        Ok(Page::decode(&self.store.get(id)?).ok_or(ERROR_KV_PAGE)?)
+    }
+
+    fn write_page(&mut self, page: &Page) -> Result<BlockId, Box<dyn std::error::Error>> {
+        self.store.put(&page.encode(KV_BLOCK_PAGE))
+    }
+
+    fn read_value(&mut self, id: BlockId) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut data = self.store.get(id)?;
+        if data.first() != Some(&KV_BLOCK_VALUE) {
+            return Err(ERROR_KV_VALUE.into());
+        }
+        data.remove(0);
+        Ok(data)
+    }
+
+    /// Value of key, None if it isn't set
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        Ok(self.get_many(&[key])?.pop().unwrap())
+    }
+
+    /// Values of keys, in the order of keys, None for keys that aren't set.
+    ///
+    /// Each page on the way to any of the keys is read once.
+    pub fn get_many<K: AsRef<[u8]>>(&mut self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>, Box<dyn std::error::Error>> {
+        let mut sorted: Vec<&[u8]> = keys.iter().map(|k| k.as_ref()).collect();
+        sorted.sort_unstable();
+        sorted.dedup();
+        let mut found = Vec::new();
+        self.lookup(self.root, &sorted, &mut found)?;
+        let mut values = HashMap::new();
+        for (k, v) in found {
+            let value = self.read_value(v)?;
+            values.insert(k, value);
+        }
+        Ok(keys.iter().map(|k| values.get(k.as_ref()).cloned()).collect())
+    }
+
+    /// Value blocks of the sorted keys found below the page at id
+    fn lookup(&mut self, id: BlockId, keys: &[&[u8]], found: &mut Vec<(Vec<u8>, BlockId)>) -> Result<(), Box<dyn std::error::Error>> {
+        match self.read_page(id)? {
+            Page::Leaf(entries) => {
+                for key in keys {
+                    if let Ok(i) = entries.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+                        found.push(entries[i].clone());
+                    }
+                }
+            }
+            Page::Internal { keys: seps, children } => {
+                let mut rest = keys;
+                while let Some(first) = rest.first() {
+                    let c = child_for(&seps, first);
+                    let n = match seps.get(c) {
+                        Some(sep) => rest.partition_point(|k| *k < sep.as_slice()),
+                        None => rest.len(),
+                    };
+                    self.lookup(children[c], &rest[..n], found)?;
+                    rest = &rest[n..];
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Set key to value
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.put_many(&[(key, value)])
+    }
+
+    /// Set several keys at once, the last value wins for a key given twice.
+    ///
+    /// The values are written first, then each changed page once and a
+    /// single new root, so the whole batch is applied or none of it is.
+    pub fn put_many<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, pairs: &[(K, V)]) -> Result<(), Box<dyn std::error::Error>> {
+        if pairs.is_empty() {
+            return Ok(());
+        }
+        let mut entries = Vec::with_capacity(pairs.len());
+        for (k, v) in pairs {
+            let mut block = vec![KV_BLOCK_VALUE];
+            block.extend_from_slice(v.as_ref());
+            entries.push((k.as_ref().to_vec(), self.store.put(&block)?));
+        }
+        // stable, so of equal keys the last given ends up last
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut garbage = Vec::new();
+        let mut unique: Vec<(Vec<u8>, BlockId)> = Vec::with_capacity(entries.len());
+        for e in entries {
+            match unique.last_mut() {
+                Some(last) if last.0 == e.0 => garbage.push(std::mem::replace(last, e).1),
+                _ => unique.push(e),
+            }
+        }
+        garbage.push(self.root);
+        let mut pages = self.insert(self.root, &unique, &mut garbage)?;
+        // grow the tree until one page holds everything
+        while pages.len() > 1 {
+            let mut keys = Vec::new();
+            let mut children = Vec::new();
+            for (sep, page) in pages {
+                keys.push(sep);
+                children.push(self.write_page(&page)?);
+            }
+            keys.remove(0);
+            pages = self.split(Page::Internal { keys, children });
+        }
+        let root = pages.pop().unwrap().1;
+        self.commit(&root, &garbage)
+    }
+
+    /// Write root as the new root and delete what it replaces
+    fn commit(&mut self, root: &Page, garbage: &[BlockId]) -> Result<(), Box<dyn std::error::Error>> {
+        self.root = self.store.put(&root.encode(KV_BLOCK_ROOT))?;
+        self.store.delete_many(garbage)
+    }
+
+    /// Insert the sorted entries into the page at id, writing the pages below it.
+    ///
+    /// Returns the page, or the pages it split into, each with the smallest
+    /// key it may hold; the first key is meaningless.
+    fn insert(&mut self, id: BlockId, entries: &[(Vec<u8>, BlockId)], garbage: &mut Vec<BlockId>) -> Result<Vec<SplitPage>, Box<dyn std::error::Error>> {
+        match self.read_page(id)? {
+            Page::Leaf(mut old) => {
+                for (key, value) in entries {
+                    match old.binary_search_by(|(k, _)| k.cmp(key)) {
+                        Ok(i) => garbage.push(std::mem::replace(&mut old[i].1, *value)),
+                        Err(i) => old.insert(i, (key.clone(), *value)),
+                    }
+                }
+                Ok(self.split(Page::Leaf(old)))
+            }
+            Page::Internal { keys, children } => {
+                let mut new_keys = Vec::new();
+                let mut new_children = Vec::new();
+                let mut rest = entries;
+                for (c, child) in children.iter().enumerate() {
+                    if c > 0 {
+                        new_keys.push(keys[c - 1].clone());
+                    }
+                    let n = match keys.get(c) {
+                        Some(sep) => rest.partition_point(|(k, _)| k < sep),
+                        None => rest.len(),
+                    };
+                    if n == 0 {
+                        new_children.push(*child);
+                        continue;
+                    }
+                    garbage.push(*child);
+                    for (i, (sep, page)) in self.insert(*child, &rest[..n], garbage)?.into_iter().enumerate() {
+                        if i > 0 {
+                            new_keys.push(sep);
+                        }
+                        new_children.push(self.write_page(&page)?);
+                    }
+                    rest = &rest[n..];
+                }
+                Ok(self.split(Page::Internal {
+                    keys: new_keys,
+                    children: new_children,
+                }))
+            }
+        }
+    }
+
+    /// Split page into as few evenly sized pages as fit max entries, each
+    /// with the smallest key it may hold; the first key is meaningless.
+    fn split(&self, page: Page) -> Vec<SplitPage> {
+        let len = match &page {
+            Page::Leaf(entries) => entries.len(),
+            Page::Internal { children, .. } => children.len(),
+        };
+        let pieces = len.div_ceil(self.max_entries).max(1);
+        if pieces == 1 {
+            return vec![(Vec::new(), page)];
+        }
+        // where each piece starts
+        let starts: Vec<usize> = (0..pieces).map(|i| i * len / pieces).collect();
+        let mut out = Vec::with_capacity(pieces);
+        match page {
+            Page::Leaf(mut entries) => {
+                for start in starts.into_iter().rev() {
+                    let piece = entries.split_off(start);
+                    out.push((piece[0].0.clone(), Page::Leaf(piece)));
+                }
+            }
+            Page::Internal { mut keys, mut children } => {
+                for start in starts.into_iter().rev() {
+                    let piece = children.split_off(start);
+                    let piece_keys = keys.split_off(start.min(keys.len()));
+                    // keys[start - 1] separates this piece from the one before
+                    let sep = if start > 0 { keys.pop().unwrap() } else { Vec::new() };
+                    out.push((sep, Page::Internal { keys: piece_keys, children: piece }));
+                }
+            }
+        }
+        out.reverse();
+        out
+    }
+
+    /// Remove key, false if it wasn't set
+    ///
+    /// Pages left empty are dropped, but pages are not merged.
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut garbage = vec![self.root];
+        let root = match self.remove(self.root, key, &mut garbage)? {
+            None => return Ok(false),
+            Some(p) => p,
+        };
+        self.commit_removal(root, garbage)?;
+        Ok(true)
+    }
+
+    /// Remove every key starting with prefix, returning how many there were.
+    ///
+    /// Only the pages that can hold such keys are read, and the keys go in
+    /// one new root and one delete journal, so all are removed or none.
+    pub fn delete_prefix(&mut self, prefix: &[u8]) -> Result<usize, Box<dyn std::error::Error>> {
+        let end = prefix_end(prefix);
+        let mut garbage = vec![self.root];
+        let mut removed = 0;
+        let root = match self.remove_prefix(self.root, prefix, end.as_deref(), &mut garbage, &mut removed)? {
+            None => return Ok(0),
+            Some(p) => p,
+        };
+        self.commit_removal(root, garbage)?;
+        Ok(removed)
+    }
+
+    /// Copy every key of other into this store, settling keys both have
+    /// with different values by policy.
+    ///
+    /// The result depends only on the two stores and the policy, so
+    /// replicas merging the same stores agree. All the changes are put in
+    /// one batch, so the merge is applied or none of it is.
+    pub fn merge_from<U: BlockHasher>(
+        &mut self,
+        other: &mut KvStore<U>,
+        mut policy: MergePolicy,
+    ) -> Result<MergeReport, Box<dyn std::error::Error>> {
+        let theirs = other.iter()?;
+        let keys: Vec<&[u8]> = theirs.iter().map(|(k, _)| k.as_slice()).collect();
+        let mut their_blocks = Vec::new();
+        other.lookup(other.root, &keys, &mut their_blocks)?;
+        let mut our_blocks = Vec::new();
+        self.lookup(self.root, &keys, &mut our_blocks)?;
+        let mut ours: HashMap<Vec<u8>, BlockId> = our_blocks.into_iter().collect();
+        let mut report = MergeReport::default();
+        let mut puts: Vec<KvPair> = Vec::new();
+        for ((key, value), (_, their_block)) in theirs.into_iter().zip(their_blocks) {
+            let our_block = match ours.remove(&key) {
+                Some(b) => b,
+                None => {
+                    report.added += 1;
+                    puts.push((key, value));
+                    continue;
+                }
+            };
+            let our_value = self.read_value(our_block)?;
+            if our_value == value {
+                continue;
+            }
+            report.conflicts += 1;
+            match &mut policy {
+                MergePolicy::KeepNewest => {
+                    if source_is_newer(self.store.append_time(our_block), other.store.append_time(their_block)) {
+                        puts.push((key, value));
+                    }
+                }
+                MergePolicy::KeepSource => puts.push((key, value)),
+                MergePolicy::KeepBoth(suffix) => puts.push(([&key[..], suffix].concat(), value)),
+                MergePolicy::Custom(resolve) => {
+                    let kept = resolve(&key, &our_value, &value);
+                    if kept != our_value {
+                        puts.push((key, kept));
+                    }
+                }
+            }
+        }
+        self.put_many(&puts)?;
+        Ok(report)
+    }
+
+    /// Commit the root left by a removal, None if the tree is now empty
+    fn commit_removal(&mut self, root: Option<Page>, mut garbage: Vec<BlockId>) -> Result<(), Box<dyn std::error::Error>> {
+        let root = root.unwrap_or_else(|| Page::Leaf(Vec::new()));
+        // a root with one child is replaced by the child
+        let root = match root {
+            Page::Internal { children, .. } if children.len() == 1 => {
+                garbage.push(children[0]);
+                self.read_page(children[0])?
+            }
+            p => p,
+        };
+        self.commit(&root, &garbage)
+    }
+
+    /// Remove key from the page at id, writing the pages below it.
+    ///
+    /// None if key wasn't there, Some(None) if the page is left empty.
+    fn remove(
+        &mut self,
+        id: BlockId,
+        key: &[u8],
+        garbage: &mut Vec<BlockId>,
+    ) -> Result<Option<Option<Page>>, Box<dyn std::error::Error>> {
+        match self.read_page(id)? {
+            Page::Leaf(mut entries) => match entries.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+                Ok(i) => {
+                    garbage.push(entries.remove(i).1);
+                    Ok(Some(if entries.is_empty() { None } else { Some(Page::Leaf(entries)) }))
+                }
+                Err(_) => Ok(None),
+            },
+            Page::Internal { mut keys, mut children } => {
+                let c = child_for(&keys, key);
+                let below = match self.remove(children[c], key, garbage)? {
+                    None => return Ok(None),
+                    Some(p) => p,
+                };
+                garbage.push(children[c]);
+                match below {
+                    Some(p) => children[c] = self.write_page(&p)?,
+                    None => {
+                        children.remove(c);
+                        if !keys.is_empty() {
+                            keys.remove(c.saturating_sub(1));
+                        }
+                    }
+                }
+                if children.is_empty() {
+                    return Ok(Some(None));
+                }
+                Ok(Some(Some(Page::Internal { keys, children })))
+            }
+        }
+    }
+
+    /// Remove the keys from prefix up to end from the page at id, writing
+    /// the pages below it and counting the keys in removed.
+    ///
+    /// As remove, None if there were none, Some(None) if the page is left empty.
+    fn remove_prefix(
+        &mut self,
+        id: BlockId,
+        prefix: &[u8],
+        end: Option<&[u8]>,
+        garbage: &mut Vec<BlockId>,
+        removed: &mut usize,
+    ) -> Result<Option<Option<Page>>, Box<dyn std::error::Error>> {
+        match self.read_page(id)? {
+            Page::Leaf(entries) => {
+                let before = entries.len();
+                let mut kept = Vec::with_capacity(before);
+                for (k, v) in entries {
+                    if k.starts_with(prefix) {
+                        garbage.push(v);
+                    } else {
+                        kept.push((k, v));
+                    }
+                }
+                if kept.len() == before {
+                    return Ok(None);
+                }
+                *removed += before - kept.len();
+                Ok(Some(if kept.is_empty() { None } else { Some(Page::Leaf(kept)) }))
+            }
+            Page::Internal { keys, children } => {
+                // each child with the smallest key it may hold, the first meaningless
+                let mut kept: Vec<(Vec<u8>, BlockId)> = Vec::with_capacity(children.len());
+                let mut changed = false;
+                for (i, c) in children.iter().enumerate() {
+                    let lower = if i > 0 { keys[i - 1].clone() } else { Vec::new() };
+                    // child i holds keys from keys[i - 1] up to keys[i]
+                    let below = i < keys.len() && keys[i].as_slice() <= prefix;
+                    let past = i > 0 && end.is_some_and(|e| keys[i - 1].as_slice() >= e);
+                    if below || past {
+                        kept.push((lower, *c));
+                        continue;
+                    }
+                    match self.remove_prefix(*c, prefix, end, garbage, removed)? {
+                        None => kept.push((lower, *c)),
+                        Some(p) => {
+                            changed = true;
+                            garbage.push(*c);
+                            if let Some(p) = p {
+                                kept.push((lower, self.write_page(&p)?));
+                            }
+                        }
+                    }
+                }
+                if !changed {
+                    return Ok(None);
+                }
+                if kept.is_empty() {
+                    return Ok(Some(None));
+                }
+                let (mut keys, children): (Vec<_>, Vec<_>) = kept.into_iter().unzip();
+                keys.remove(0);
+                Ok(Some(Some(Page::Internal { keys, children })))
+            }
+        }
+    }
+
+    /// Keys and values in range, in key order
+    pub fn range<K, R>(&mut self, range: R) -> Result<Vec<KvPair>, Box<dyn std::error::Error>>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let start = map_bound(range.start_bound());
+        let end = map_bound(range.end_bound());
+        let mut out = Vec::new();
+        self.collect_range(self.root, start, end, &|_| true, &mut out)?;
+        Ok(out)
+    }
+
+    /// Keys starting with prefix and their values, in key order
+    pub fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Vec<KvPair>, Box<dyn std::error::Error>> {
+        let end = prefix_end(prefix);
+        let mut out = Vec::new();
+        self.collect_range(
+            self.root,
+            Bound::Included(prefix),
+            end.as_deref().map_or(Bound::Unbounded, Bound::Excluded),
+            &|_| true,
+            &mut out,
+        )?;
+        Ok(out)
+    }
+
+    /// Keys matching pattern and their values, in key order.
+    ///
+    /// In pattern `*` matches any run of bytes and `?` any one byte, and
+    /// `\` makes the byte after it literal. Only pages that can hold keys
+    /// starting with the literal bytes before the first wildcard are read.
+    pub fn scan_glob(&mut self, pattern: &[u8]) -> Result<Vec<KvPair>, Box<dyn std::error::Error>> {
+        let prefix = glob_prefix(pattern);
+        let end = prefix_end(&prefix);
+        let mut out = Vec::new();
+        self.collect_range(
+            self.root,
+            Bound::Included(&prefix),
+            end.as_deref().map_or(Bound::Unbounded, Bound::Excluded),
+            &|k| glob_match(pattern, k),
+            &mut out,
+        )?;
+        Ok(out)
+    }
+
+    /// Every key and value, in key order
+    pub fn iter(&mut self) -> Result<Vec<KvPair>, Box<dyn std::error::Error>> {
+        self.range::<&[u8], _>(..)
+    }
+
+    /// Number of keys set
+    pub fn len(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
+        self.count(self.root)
+    }
+
+    /// true if no keys are set
+    pub fn is_empty(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self.len()? == 0)
+    }
+
+    fn count(&mut self, id: BlockId) -> Result<usize, Box<dyn std::error::Error>> {
+        match self.read_page(id)? {
+            Page::Leaf(entries) => Ok(entries.len()),
+            Page::Internal { children, .. } => {
+                let mut n = 0;
+                for c in children {
+                    n += self.count(c)?;
+                }
+                Ok(n)
+            }
+        }
+    }
+
+    /// Collect the keys in bounds that keep accepts, and their values
+    fn collect_range(
+        &mut self,
+        id: BlockId,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        keep: &dyn Fn(&[u8]) -> bool,
+        out: &mut Vec<KvPair>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.read_page(id)? {
+            Page::Leaf(entries) => {
+                for (k, v) in entries {
+                    if in_bounds(&k, start, end) && keep(&k) {
+                        let value = self.read_value(v)?;
+                        out.push((k, value));
+                    }
+                }
+            }
+            Page::Internal { keys, children } => {
+                for (i, c) in children.iter().enumerate() {
+                    // child i holds keys from keys[i - 1] up to keys[i]
+                    let below_start = i < keys.len()
+                        && match start {
+                            Bound::Included(s) | Bound::Excluded(s) => keys[i].as_slice() <= s,
+                            Bound::Unbounded => false,
+                        };
+                    if below_start {
+                        continue;
+                    }
+                    let past_end = i > 0
+                        && match end {
+                            Bound::Included(e) => keys[i - 1].as_slice() > e,
+                            Bound::Excluded(e) => keys[i - 1].as_slice() >= e,
+                            Bound::Unbounded => false,
+                        };
+                    if past_end {
+                        break;
+                    }
+                    self.collect_range(*c, start, end, keep, out)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn map_bound<K: AsRef<[u8]>>(b: Bound<&K>) -> Bound<&[u8]> {
+    match b {
+        Bound::Included(k) => Bound::Included(k.as_ref()),
+        Bound::Excluded(k) => Bound::Excluded(k.as_ref()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// true if a value written at theirs should replace one written at ours,
+/// see MergePolicy::KeepNewest
+fn source_is_newer(ours: Option<SystemTime>, theirs: Option<SystemTime>) -> bool {
+    matches!((ours, theirs), (Some(o), Some(t)) if t > o)
+}
+
+/// Smallest key after every key starting with prefix, None if there is none
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Literal bytes of pattern before its first wildcard
+fn glob_prefix(pattern: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::new();
+    let mut i = 0;
+    while i < pattern.len() {
+        match pattern[i] {
+            b'*' | b'?' => break,
+            b'\\' if i + 1 < pattern.len() => {
+                prefix.push(pattern[i + 1]);
+                i += 2;
+            }
+            b => {
+                prefix.push(b);
+                i += 1;
+            }
+        }
+    }
+    prefix
+}
+
+/// true if key matches pattern, see KvStore::scan_glob
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // where to resume after the last `*`, in pattern and key
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, k));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'\\') if p + 1 < pattern.len() => Some(2).filter(|_| pattern[p + 1] == key[k]),
+            Some(b) => Some(1).filter(|_| *b == key[k]),
+            None => None,
+        };
+        match (step, star) {
+            (Some(n), _) => {
+                p += n;
+                k += 1;
+            }
+            (None, Some((sp, sk))) => {
+                // let the last `*` swallow one more byte
+                star = Some((sp, sk + 1));
+                p = sp;
+                k = sk + 1;
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|b| *b == b'*')
+}
+
+fn in_bounds(key: &[u8], start: Bound<&[u8]>, end: Bound<&[u8]>) -> bool {
+    let after_start = match start {
+        Bound::Included(s) => key >= s,
+        Bound::Excluded(s) => key > s,
+        Bound::Unbounded => true,
+    };
+    let before_end = match end {
+        Bound::Included(e) => key <= e,
+        Bound::Excluded(e) => key < e,
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key{:05}", i).into_bytes()
+    }
+
+    #[test]
+    fn ordered_puts_gets_and_ranges() {
+        std::fs::create_dir_all("testout").unwrap();
+        let path = "testout/kv.st".to_string();
+        let mut kv = KvStore::<B3BlockHasher>::create(path.clone()).unwrap();
+        kv.set_max_page_entries(4);
+        // out of order, so pages split all over
+        for i in (0..200u32).map(|i| (i * 37) % 200) {
+            kv.put(&key(i), &i.to_le_bytes()).unwrap();
+        }
+        kv.put(&key(5), b"five").unwrap();
+        assert_eq!(kv.get(&key(5)).unwrap(), Some(b"five".to_vec()));
+        assert_eq!(kv.get(&key(6)).unwrap(), Some(6u32.to_le_bytes().to_vec()));
+        assert_eq!(kv.get(b"nope").unwrap(), None);
+        assert_eq!(kv.len().unwrap(), 200);
+
+        let r = kv.range(key(10)..key(20)).unwrap();
+        assert_eq!(r.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>(), (10..20).map(key).collect::<Vec<_>>());
+        let r = kv.range(key(195)..).unwrap();
+        assert_eq!(r.len(), 5);
+        let r = kv.range(..=key(2)).unwrap();
+        assert_eq!(r.len(), 3);
+        let all = kv.iter().unwrap();
+        assert!(all.windows(2).all(|w| w[0].0 < w[1].0));
+
+        for i in 0..150u32 {
+            assert!(kv.delete(&key(i)).unwrap());
+        }
+        assert!(!kv.delete(&key(0)).unwrap());
+        assert_eq!(kv.len().unwrap(), 50);
+        kv.close().unwrap();
+
+        let mut kv = KvStore::<B3BlockHasher>::open(path).unwrap();
+        assert_eq!(kv.get(&key(150)).unwrap(), Some(150u32.to_le_bytes().to_vec()));
+        assert_eq!(kv.range(key(140)..key(160)).unwrap().len(), 10);
+        // only the live tree and values are left
+        let live = (0..kv.store().len()).filter(|i| kv.store.is_live(*i).unwrap()).count();
+        assert!(live < 50 + 30);
+    }
+
+    #[test]
+    fn prefix_and_glob_scans() {
+        std::fs::create_dir_all("testout").unwrap();
+        let mut kv = KvStore::<B3BlockHasher>::create("testout/kv_scan.st".to_string()).unwrap();
+        kv.set_max_page_entries(3);
+        for k in ["user:1", "user:2", "user:10", "users", "usez", "group:1", "user:1:name", "a*b", "axb"] {
+            kv.put(k.as_bytes(), b"v").unwrap();
+        }
+        kv.put(&[b'k', 0xff], b"v").unwrap();
+        kv.put(&[b'k', 0xff, 0xff, 1], b"v").unwrap();
+        kv.put(b"l", b"v").unwrap();
+        let keys = |r: Vec<KvPair>| r.into_iter().map(|(k, _)| String::from_utf8_lossy(&k).into_owned()).collect::<Vec<_>>();
+        assert_eq!(keys(kv.scan_prefix(b"user:").unwrap()), vec!["user:1", "user:10", "user:1:name", "user:2"]);
+        assert_eq!(kv.scan_prefix(&[b'k', 0xff]).unwrap().len(), 2);
+        assert_eq!(kv.scan_prefix(b"").unwrap().len(), 12);
+        assert_eq!(keys(kv.scan_glob(b"user:?").unwrap()), vec!["user:1", "user:2"]);
+        assert_eq!(keys(kv.scan_glob(b"user:*").unwrap()), vec!["user:1", "user:10", "user:1:name", "user:2"]);
+        assert_eq!(keys(kv.scan_glob(b"*:1").unwrap()), vec!["group:1", "user:1"]);
+        assert_eq!(keys(kv.scan_glob(b"us*s").unwrap()), vec!["users"]);
+        assert_eq!(keys(kv.scan_glob(b"a\\*b").unwrap()), vec!["a*b"]);
+        assert_eq!(keys(kv.scan_glob(b"a?b").unwrap()), vec!["a*b", "axb"]);
+    }
+
+    #[test]
+    fn batch_puts_and_gets() {
+        std::fs::create_dir_all("testout").unwrap();
+        let mut kv = KvStore::<B3BlockHasher>::create("testout/kv_batch.st".to_string()).unwrap();
+        kv.set_max_page_entries(4);
+        kv.put(&key(1000), b"single").unwrap();
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = (0..300u32).rev().map(|i| (key(i), i.to_le_bytes().to_vec())).collect();
+        let before = kv.store().len();
+        kv.put_many(&pairs).unwrap();
+        // one root and one delete journal however many pages changed
+        let written = kv.store().len() - before;
+        assert!(written < 300 + 300 / 2);
+        kv.put_many(&[(key(7), b"a".to_vec()), (key(7), b"b".to_vec()), (key(1000), b"c".to_vec())]).unwrap();
+        assert_eq!(kv.len().unwrap(), 301);
+
+        let got = kv.get_many(&[key(7), b"missing".to_vec(), key(299), key(7), key(1000)]).unwrap();
+        assert_eq!(got, vec![Some(b"b".to_vec()), None, Some(299u32.to_le_bytes().to_vec()), Some(b"b".to_vec()), Some(b"c".to_vec())]);
+        let all = kv.iter().unwrap();
+        assert_eq!(all.len(), 301);
+        assert!(all.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(kv.get_many::<&[u8]>(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unfinished_put_is_discarded() {
+        std::fs::create_dir_all("testout").unwrap();
+        let path = "testout/kv_orphans.st".to_string();
+        let mut kv = KvStore::<B3BlockHasher>::create(path.clone()).unwrap();
+        kv.put(b"a", b"1").unwrap();
+        // a value and a page written, but no new root
+        kv.store.put(&[KV_BLOCK_VALUE, 9]).unwrap();
+        kv.store.put(&Page::Leaf(Vec::new()).encode(KV_BLOCK_PAGE)).unwrap();
+        kv.close().unwrap();
+        let mut kv = KvStore::<B3BlockHasher>::open(path).unwrap();
+        assert_eq!(kv.iter().unwrap(), vec![(b"a".to_vec(), b"1".to_vec())]);
+        let n = kv.store().len();
+        assert!(!kv.store.is_live(n - 1).unwrap());
+        assert!(!kv.store.is_live(n - 2).unwrap());
+    }
+
+    #[test]
+    fn delete_prefix_removes_in_one_batch() {
+        std::fs::create_dir_all("testout").unwrap();
+        let path = "testout/kv_delete_prefix.st".to_string();
+        let mut kv = KvStore::<B3BlockHasher>::create(path.clone()).unwrap();
+        kv.set_max_page_entries(3);
+        for i in 0..100u32 {
+            kv.put(format!("a:{:03}", i).as_bytes(), b"v").unwrap();
+            kv.put(format!("b:{:03}", i).as_bytes(), b"v").unwrap();
+        }
+        kv.put(b"a", b"v").unwrap();
+        let before = kv.store().len();
+        assert_eq!(kv.delete_prefix(b"a:").unwrap(), 100);
+        // new pages, one root and one journal, no values
+        assert!(kv.store().len() - before < 40);
+        assert_eq!(kv.delete_prefix(b"a:").unwrap(), 0);
+        assert_eq!(kv.len().unwrap(), 101);
+        assert_eq!(kv.get(b"a").unwrap(), Some(b"v".to_vec()));
+        assert!(kv.scan_prefix(b"a:").unwrap().is_empty());
+        kv.close().unwrap();
+
+        let mut kv = KvStore::<B3BlockHasher>::open(path).unwrap();
+        assert_eq!(kv.scan_prefix(b"b:").unwrap().len(), 100);
+        assert_eq!(kv.delete_prefix(b"").unwrap(), 101);
+        assert!(kv.is_empty().unwrap());
+        kv.put(b"again", b"v").unwrap();
+        assert_eq!(kv.iter().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn merge_settles_conflicts_by_policy() {
+        std::fs::create_dir_all("testout").unwrap();
+        let open = |name: &str, pairs: &[(&str, &str)]| {
+            let mut kv = KvStore::<B3BlockHasher>::create(format!("testout/{}", name)).unwrap();
+            kv.put_many(pairs).unwrap();
+            kv
+        };
+        let mut source = open("kv_merge_src.st", &[("a", "1"), ("b", "theirs"), ("c", "3")]);
+        let base = [("a", "1"), ("b", "ours"), ("d", "4")];
+        let get = |kv: &mut KvStore<B3BlockHasher>, k: &str| kv.get(k.as_bytes()).unwrap().map(|v| String::from_utf8(v).unwrap());
+
+        let mut kv = open("kv_merge_newest.st", &base);
+        let report = kv.merge_from(&mut source, MergePolicy::KeepNewest).unwrap();
+        assert_eq!(report, MergeReport { added: 1, conflicts: 1 });
+        // written in the same second, so ours stays
+        assert_eq!(get(&mut kv, "b").as_deref(), Some("ours"));
+        assert_eq!(get(&mut kv, "c").as_deref(), Some("3"));
+        assert_eq!(get(&mut kv, "d").as_deref(), Some("4"));
+
+        let mut kv = open("kv_merge_source.st", &base);
+        kv.merge_from(&mut source, MergePolicy::KeepSource).unwrap();
+        assert_eq!(get(&mut kv, "b").as_deref(), Some("theirs"));
+
+        let mut kv = open("kv_merge_both.st", &base);
+        kv.merge_from(&mut source, MergePolicy::KeepBoth(b".remote")).unwrap();
+        assert_eq!(get(&mut kv, "b").as_deref(), Some("ours"));
+        assert_eq!(get(&mut kv, "b.remote").as_deref(), Some("theirs"));
+        assert_eq!(kv.len().unwrap(), 5);
+
+        let mut kv = open("kv_merge_custom.st", &base);
+        let mut seen = Vec::new();
+        let resolve = |k: &[u8], a: &[u8], b: &[u8]| {
+            seen.push(k.to_vec());
+            [a, b"+", b].concat()
+        };
+        kv.merge_from(&mut source, MergePolicy::Custom(Box::new(resolve))).unwrap();
+        assert_eq!(seen, vec![b"b".to_vec()]);
+        assert_eq!(get(&mut kv, "b").as_deref(), Some("ours+theirs"));
+
+        let t = SystemTime::now();
+        assert!(source_is_newer(Some(t), Some(t + std::time::Duration::from_secs(1))));
+        assert!(!source_is_newer(Some(t), Some(t)));
+        assert!(!source_is_newer(None, Some(t)));
+    }
+
+    #[test]
+    fn garbage_of_unfinished_commit_is_deleted() {
+        std::fs::create_dir_all("testout").unwrap();
+        let path = "testout/kv_garbage.st".to_string();
+        let mut kv = KvStore::<B3BlockHasher>::create(path.clone()).unwrap();
+        kv.put(b"a", b"1").unwrap();
+        let old_root = kv.root;
+        let old_value = kv.store.len() - 2;
+        // the new root is written, the old one and its value are not deleted
+        let value = kv.store.put(&[KV_BLOCK_VALUE, b'2']).unwrap();
+        let root = kv.store.put(&Page::Leaf(vec![(b"a".to_vec(), value)]).encode(KV_BLOCK_ROOT)).unwrap();
+        kv.close().unwrap();
+        let mut kv = KvStore::<B3BlockHasher>::open(path).unwrap();
+        assert_eq!(kv.root, root);
+        assert_eq!(kv.iter().unwrap(), vec![(b"a".to_vec(), b"2".to_vec())]);
+        assert!(!kv.store.is_live(old_root).unwrap());
+        assert!(!kv.store.is_live(old_value).unwrap());
+        assert!(kv.store.is_live(value).unwrap());
+    }
+
+    #[test]
+    fn compact_keeps_the_tree() {
+        std::fs::create_dir_all("testout").unwrap();
+        let path = "testout/kv_compact.st".to_string();
+        let mut kv = KvStore::<B3BlockHasher>::create(path.clone()).unwrap();
+        kv.set_max_page_entries(4);
+        for i in 0..50u32 {
+            kv.put(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        for i in 0..25u32 {
+            kv.delete(&i.to_be_bytes()).unwrap();
+        }
+        kv.put(b"z", b"last").unwrap();
+        let before = kv.iter().unwrap();
+        let blocks = kv.store().len();
+        kv.compact().unwrap();
+        assert!(kv.store().len() < blocks);
+        assert_eq!(kv.iter().unwrap(), before);
+        assert_eq!(kv.get(b"z").unwrap(), Some(b"last".to_vec()));
+        kv.put(b"a", b"1").unwrap();
+        kv.close().unwrap();
+        assert!(!std::path::Path::new("testout/kv_compact.st.compact").exists());
+        let mut kv = KvStore::<B3BlockHasher>::open(path).unwrap();
+        assert_eq!(kv.len().unwrap(), before.len() + 1);
+    }
+}
@@ -7,3 +7,42 @@
 pub mod data_header;
 pub mod store;
 pub mod crypto;
+pub mod bloom;
+pub mod writer;
+pub mod access_stats;
+pub mod tiered;
+pub mod mirror;
+pub mod multi;
+pub mod rotating;
+pub mod watch;
+pub mod format;
+pub mod manifest;
+pub mod diff;
+pub use crate::diff::diff;
+pub mod chunking;
+pub mod snapshot;
+pub mod counters;
+pub mod kv;
+pub mod column;
+pub mod audit;
+pub mod receipt;
+pub mod merkle;
+pub mod frames;
+pub mod content_type;
+pub mod search;
+pub mod zeroize;
+pub mod pool;
+mod mmap;
+mod platform;
+#[cfg(feature = "ecc")]
+pub mod ecc;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "fault-injection")]
+pub mod fault;
+#[cfg(feature = "arrow")]
+pub mod table;
+#[cfg(feature = "rkyv")]
+pub mod zerocopy;
+#[cfg(feature = "streaming")]
+pub mod streaming;
@@ -0,0 +1,247 @@
+//Copyright 2021 Matthew Petricone
+//! A listing of every block in a store, for auditing and comparing stores.
+//!
+//! A manifest can be signed into a sidecar next to the store (its name with
+//! ".sig" appended), so whoever receives the store file can authenticate all
+//! of its contents. Signing is left to a ManifestSigner, ed25519 is the
+//! usual choice, so fstore doesn't pick a signature library for its users.
+//!
+//! What is signed is MANIFEST_SIGNATURE_MAGIC then a blake3 digest of the
+//! manifest's JSON, with only required features, followed by the blake3 hash of every block payload, so
+//! stores whose own hasher is weak are covered as well as any other.
+//! The sidecar is MANIFEST_SIGNATURE_MAGIC, u16 signature length, then the
+//! signature.
+use crate::crypto::BlockHasher;
+use crate::platform;
+use crate::store::{BlockId, Store, StoreIO, FEATURES_REQUIRED_MASK};
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::fmt::Write;
+
+/// Starts a signed manifest message and its sidecar
+pub static MANIFEST_SIGNATURE_MAGIC: &[u8; 8] = b"FSTSIG01";
+static ERROR_MANIFEST_SIDECAR: &str = "Invalid manifest signature file.";
+
+/// Signs manifests, see Store::sign_manifest
+pub trait ManifestSigner {
+    /// Signature of message
+    fn sign(&self, message: &[u8]) -> Vec<u8>;
+}
+
+/// Checks manifest signatures, see Store::verify_manifest
+pub trait ManifestVerifier {
+    /// true if signature is a valid signature of message
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool;
+}
+
+/// One block of a Manifest
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub index: BlockId,
+    /// address of the block header in the file
+    pub offset: u64,
+    /// payload bytes
+    pub size: u64,
+    pub flags: u32,
+    /// checksum from the header, as lower case hex
+    pub hash: String,
+}
+
+/// Every block of a store, from Store::manifest
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub version: u32,
+    pub codec_id: u32,
+    pub features: u64,
+    pub block_count: usize,
+    pub blocks: Vec<ManifestEntry>,
+}
+
+impl<T: BlockHasher> Store<T> {
+    /// List every block, deleted and quarantined ones included
+    pub fn manifest(&mut self) -> Result<Manifest, Box<dyn std::error::Error>> {
+        let mut blocks = Vec::with_capacity(self.len());
+        for index in 0..self.len() {
+            let fields = self.block_header(index)?.fields();
+            blocks.push(ManifestEntry {
+                index,
+                offset: self.block_address(index).unwrap_or(0),
+                size: fields.size_data,
+                flags: fields.state_flag,
+                hash: to_hex(&fields.checksum),
+            });
+        }
+        Ok(Manifest {
+            version: self.version(),
+            codec_id: self.codec_id(),
+            features: self.features(),
+            block_count: blocks.len(),
+            blocks,
+        })
+    }
+
+    /// The message sign_manifest signs, see the module docs
+    pub fn manifest_message(&mut self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut manifest = self.manifest()?;
+        // optional features like the index footer come and go with opening and closing
+        manifest.features &= FEATURES_REQUIRED_MASK;
+        let mut digest = blake3::Hasher::new();
+        digest.update(manifest.to_json().as_bytes());
+        for index in 0..self.len() {
+            // deleted and quarantined payloads are part of the file too
+            let (_, data) = self.read_block(index)?;
+            digest.update(blake3::hash(&data).as_bytes());
+        }
+        let mut message = MANIFEST_SIGNATURE_MAGIC.to_vec();
+        message.extend_from_slice(digest.finalize().as_bytes());
+        Ok(message)
+    }
+
+    /// Sign the manifest with signer into the ".sig" sidecar, replacing any
+    /// signature already there.
+    ///
+    /// Any change to the store afterwards, compaction included, makes
+    /// verify_manifest fail until it is signed again.
+    pub fn sign_manifest(&mut self, signer: &dyn ManifestSigner) -> Result<(), Box<dyn std::error::Error>> {
+        let signature = signer.sign(&self.manifest_message()?);
+        let mut sidecar = MANIFEST_SIGNATURE_MAGIC.to_vec();
+        sidecar.extend_from_slice(&u16::try_from(signature.len())?.to_le_bytes());
+        sidecar.extend_from_slice(&signature);
+        platform::write_replace(&format!("{}.sig", self.path()), &sidecar)?;
+        Ok(())
+    }
+
+    /// true if the ".sig" sidecar holds a signature of the store as it is now
+    /// that verifier accepts. A missing sidecar is an error.
+    pub fn verify_manifest(&mut self, verifier: &dyn ManifestVerifier) -> Result<bool, Box<dyn std::error::Error>> {
+        let sidecar = std::fs::read(format!("{}.sig", self.path()))?;
+        if sidecar.len() < 10 || &sidecar[..8] != MANIFEST_SIGNATURE_MAGIC {
+            return Err(ERROR_MANIFEST_SIDECAR.into());
+        }
+        let len = usize::from(u16::from_le_bytes(sidecar[8..10].try_into()?));
+        if sidecar.len() != 10 + len {
+            return Err(ERROR_MANIFEST_SIDECAR.into());
+        }
+        Ok(verifier.verify(&self.manifest_message()?, &sidecar[10..]))
+    }
+}
+
+impl Manifest {
+    /// The manifest as a JSON object
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        write!(
+            out,
+            r#"{{"version":{},"codec_id":{},"features":{},"block_count":{},"blocks":["#,
+            self.version, self.codec_id, self.features, self.block_count
+        )
+        .unwrap();
+        for (i, b) in self.blocks.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write!(
+                out,
+                r#"{{"index":{},"offset":{},"size":{},"flags":{},"hash":"{}"}}"#,
+                b.index, b.offset, b.size, b.flags, b.hash
+            )
+            .unwrap();
+        }
+        out.push_str("]}");
+        out
+    }
+
+    /// One line per block: index, offset, size, flags and hash
+    pub fn to_text(&self) -> String {
+        let mut out = format!("version {} codec {} features {:#x} blocks {}\n", self.version, self.codec_id, self.features, self.block_count);
+        for b in &self.blocks {
+            writeln!(out, "{}\t{}\t{}\t{:#x}\t{}", b.index, b.offset, b.size, b.flags, b.hash).unwrap();
+        }
+        out
+    }
+}
+
+/// Lower case hex of bytes
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        write!(s, "{:02x}", b).unwrap();
+    }
+    s
+}
+
+/// Inverse of to_hex, None if s isn't hex
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|h| u8::from_str_radix(h, 16).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+
+    #[test]
+    fn manifest_lists_blocks() {
+        std::fs::create_dir_all("testout").unwrap();
+        let mut s = Store::<B3BlockHasher>::create("testout/manifest.st".to_string()).unwrap();
+        s.put(&[1, 2]).unwrap();
+        s.put(&[3]).unwrap();
+        s.delete_block(0).unwrap();
+        let m = s.manifest().unwrap();
+        assert_eq!(m.block_count, 2);
+        assert_eq!(m.blocks[0].flags, 1);
+        assert_eq!(m.blocks[1].size, 1);
+        assert_eq!(m.blocks[1].offset, s.block_address(1).unwrap());
+        assert_eq!(from_hex(&m.blocks[1].hash).unwrap(), blake3::hash(&[3]).as_bytes().to_vec());
+        assert!(m.to_json().contains(r#""index":1,"#));
+        assert_eq!(m.to_text().lines().count(), 3);
+        assert!(from_hex("0g").is_none());
+    }
+
+    /// Stands in for ed25519: a keyed hash, signed and checked with the same key
+    struct KeyedSigner([u8; 32]);
+
+    impl ManifestSigner for KeyedSigner {
+        fn sign(&self, message: &[u8]) -> Vec<u8> {
+            blake3::keyed_hash(&self.0, message).as_bytes().to_vec()
+        }
+    }
+
+    impl ManifestVerifier for KeyedSigner {
+        fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+            self.sign(message) == signature
+        }
+    }
+
+    #[test]
+    fn signed_manifest_covers_every_payload() {
+        std::fs::create_dir_all("testout").unwrap();
+        let path = "testout/signed.st".to_string();
+        let _ = std::fs::remove_file(format!("{}.sig", path));
+        let key = KeyedSigner([7; 32]);
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        s.put(&[1, 2]).unwrap();
+        s.put(&[3]).unwrap();
+        assert!(s.verify_manifest(&key).is_err());
+        s.sign_manifest(&key).unwrap();
+        assert!(s.verify_manifest(&key).unwrap());
+        assert!(!s.verify_manifest(&KeyedSigner([8; 32])).unwrap());
+        s.close().unwrap();
+        assert!(Store::<B3BlockHasher>::new(path.clone()).unwrap().verify_manifest(&key).unwrap());
+
+        let mut s = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        assert!(s.verify_manifest(&key).unwrap());
+        s.put(&[4]).unwrap();
+        assert!(!s.verify_manifest(&key).unwrap());
+        s.sign_manifest(&key).unwrap();
+        assert!(s.verify_manifest(&key).unwrap());
+        std::fs::write(format!("{}.sig", path), b"FSTSIG01").unwrap();
+        assert!(s.verify_manifest(&key).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+//Copyright 2021 Matthew Petricone
+//! A Merkle tree over a store's live blocks, so a single block can be shown
+//! to belong to a store to someone holding only its root hash.
+//!
+//! The leaves are the live blocks in index order, each the blake3 hash of
+//! MERKLE_LEAF, the u64 block index and the blake3 hash of the payload, so
+//! stores whose own hasher is weak are covered as well as any other. A
+//! node is the blake3 hash of MERKLE_NODE and its two children; the last
+//! node of a level with an odd count is carried up as it is. The root of a
+//! store with no live blocks is the hash of MERKLE_NODE alone.
+//!
+//! Any change to the live blocks changes the root: a put, a delete, and a
+//! compaction, which renumbers the blocks.
+use crate::crypto::BlockHasher;
+use crate::store::{BlockId, Store};
+use std::convert::TryFrom;
+use std::convert::TryInto;
+
+const MERKLE_LEAF: u8 = 0;
+const MERKLE_NODE: u8 = 1;
+
+static ERROR_MERKLE_NOT_LIVE: &str = "Only live blocks have Merkle proofs.";
+
+/// A root hash or node of the tree
+pub type MerkleHash = [u8; 32];
+
+/// One sibling on the way from a leaf to the root
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MerkleStep {
+    pub sibling: MerkleHash,
+    /// true if sibling is the left child
+    pub left: bool,
+}
+
+/// Proof that a block belongs to the tree of a root, see Store::prove
+///
+/// Serialized as u64 block_id, u32 step count, then each step as a u8 side,
+/// 1 for left, and the sibling.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub block_id: BlockId,
+    /// leaf to root
+    pub path: Vec<MerkleStep>,
+}
+
+impl MerkleProof {
+    /// Serialize for handing over with the block
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(12 + self.path.len() * 33);
+        out.extend_from_slice(&(self.block_id as u64).to_le_bytes());
+        out.extend_from_slice(&(self.path.len() as u32).to_le_bytes());
+        for step in &self.path {
+            out.push(step.left as u8);
+            out.extend_from_slice(&step.sibling);
+        }
+        out
+    }
+
+    /// Inverse of to_bytes, None if data isn't a proof
+    pub fn from_bytes(data: &[u8]) -> Option<MerkleProof> {
+        let block_id = usize::try_from(u64::from_le_bytes(data.get(..8)?.try_into().ok()?)).ok()?;
+        let n = u32::from_le_bytes(data.get(8..12)?.try_into().ok()?) as usize;
+        let steps = &data[12..];
+        if steps.len() != n.checked_mul(33)? {
+            return None;
+        }
+        let mut path = Vec::with_capacity(n);
+        for step in steps.chunks(33) {
+            let left = match step[0] {
+                0 => false,
+                1 => true,
+                _ => return None,
+            };
+            path.push(MerkleStep { sibling: step[1..].try_into().ok()?, left });
+        }
+        Some(MerkleProof { block_id, path })
+    }
+}
+
+/// The leaf of a block, see the module docs
+fn leaf(block_id: BlockId, payload_hash: &[u8]) -> MerkleHash {
+    let mut h = blake3::Hasher::new();
+    h.update(&[MERKLE_LEAF]);
+    h.update(&(block_id as u64).to_le_bytes());
+    h.update(payload_hash);
+    *h.finalize().as_bytes()
+}
+
+fn node(left: &MerkleHash, right: &MerkleHash) -> MerkleHash {
+    let mut h = blake3::Hasher::new();
+    h.update(&[MERKLE_NODE]);
+    h.update(left);
+    h.update(right);
+    *h.finalize().as_bytes()
+}
+
+/// The root over leaves, and the path of the leaf at position prove if given
+fn build(mut level: Vec<MerkleHash>, mut prove: Option<usize>) -> (MerkleHash, Vec<MerkleStep>) {
+    let mut path = Vec::new();
+    if level.is_empty() {
+        return (*blake3::hash(&[MERKLE_NODE]).as_bytes(), path);
+    }
+    while level.len() > 1 {
+        if let Some(pos) = prove {
+            let sibling = pos ^ 1;
+            if sibling < level.len() {
+                path.push(MerkleStep { sibling: level[sibling], left: sibling < pos });
+            }
+            prove = Some(pos / 2);
+        }
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [l, r] => node(l, r),
+                [odd] => *odd,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    (level[0], path)
+}
+
+/// true if proof shows a block whose payload has blake3 hash payload_hash
+/// belongs to the tree of root. Needs nothing from the store.
+pub fn verify_proof(root: &MerkleHash, proof: &MerkleProof, payload_hash: &[u8]) -> bool {
+    let mut h = leaf(proof.block_id, payload_hash);
+    for step in &proof.path {
+        h = if step.left { node(&step.sibling, &h) } else { node(&h, &step.sibling) };
+    }
+    &h == root
+}
+
+impl<T: BlockHasher> Store<T> {
+    /// The leaves of the live blocks, with their block indexes
+    fn merkle_leaves(&mut self) -> Result<Vec<(BlockId, MerkleHash)>, Box<dyn std::error::Error>> {
+        let mut leaves = Vec::new();
+        for block in self.iter() {
+            let (id, data) = block?;
+            leaves.push((id, leaf(id, blake3::hash(&data).as_bytes())));
+        }
+        Ok(leaves)
+    }
+
+    /// Root hash of the tree over the live blocks, see the module docs
+    pub fn merkle_root(&mut self) -> Result<MerkleHash, Box<dyn std::error::Error>> {
+        let leaves = self.merkle_leaves()?;
+        Ok(build(leaves.into_iter().map(|(_, h)| h).collect(), None).0)
+    }
+
+    /// Proof that the live block index belongs to the tree of merkle_root,
+    /// for verify_proof
+    pub fn prove(&mut self, index: BlockId) -> Result<MerkleProof, Box<dyn std::error::Error>> {
+        let leaves = self.merkle_leaves()?;
+        let pos = leaves.iter().position(|(id, _)| *id == index).ok_or(ERROR_MERKLE_NOT_LIVE)?;
+        let (_, path) = build(leaves.into_iter().map(|(_, h)| h).collect(), Some(pos));
+        Ok(MerkleProof { block_id: index, path })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+    use crate::store::StoreIO;
+
+    #[test]
+    fn proofs_verify_against_the_root() {
+        std::fs::create_dir_all("testout").unwrap();
+        let mut s = Store::<B3BlockHasher>::create("testout/merkle.st".to_string()).unwrap();
+        let empty = s.merkle_root().unwrap();
+        let payloads: Vec<Vec<u8>> = (0..7).map(|i| format!("block {}", i).into_bytes()).collect();
+        for p in &payloads {
+            s.put(p).unwrap();
+        }
+        s.delete_block(2).unwrap();
+        let root = s.merkle_root().unwrap();
+        assert_ne!(root, empty);
+
+        for i in [0, 1, 3, 4, 5, 6] {
+            let proof = s.prove(i).unwrap();
+            let hash = blake3::hash(&payloads[i]);
+            assert!(verify_proof(&root, &proof, hash.as_bytes()));
+            assert_eq!(MerkleProof::from_bytes(&proof.to_bytes()), Some(proof.clone()));
+            // another payload, or the same one claimed at another index
+            assert!(!verify_proof(&root, &proof, blake3::hash(b"forged").as_bytes()));
+            let moved = MerkleProof { block_id: i + 1, ..proof };
+            assert!(!verify_proof(&root, &moved, hash.as_bytes()));
+        }
+        assert!(s.prove(2).is_err());
+        assert!(MerkleProof::from_bytes(&s.prove(0).unwrap().to_bytes()[1..]).is_none());
+
+        let old = s.prove(0).unwrap();
+        s.put(b"more").unwrap();
+        assert_ne!(s.merkle_root().unwrap(), root);
+        assert!(!verify_proof(&s.merkle_root().unwrap(), &old, blake3::hash(&payloads[0]).as_bytes()));
+    }
+}
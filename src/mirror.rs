@@ -0,0 +1,436 @@
+//Copyright 2021 Matthew Petricone
+//! Two stores kept as copies of each other, e.g. a local disk and a network
+//! share.
+//!
+//! Every put and delete goes to both. Reads go to the primary, and to the
+//! mirror when the primary can't give a verified payload. A side a write
+//! fails on falls behind: it gets no more writes, and is read only when the
+//! other side fails, until resync brings it level. Both sides number blocks
+//! the same, so a block id means the same block in either.
+//!
+//! A payload that fails verification on one side is read from the other
+//! and written back over the bad copy (read repair), and that side's
+//! observers are told with StoreObserver::on_repair.
+use crate::crypto::BlockHasher;
+use crate::data_header::DataHeader;
+use crate::store::{BlockId, Store, StoreError, StoreErrorKind, StoreIO, StoreObserver};
+use std::io::Error;
+
+static ERROR_MIRROR_DIVERGED: &str = "Primary and mirror hold different blocks.";
+
+/// One of the two stores
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Primary,
+    Mirror,
+}
+
+impl Side {
+    /// The other store
+    pub fn other(&self) -> Side {
+        match self {
+            Side::Primary => Side::Mirror,
+            Side::Mirror => Side::Primary,
+        }
+    }
+}
+
+/// What MirroredStore::resync did
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ResyncReport {
+    /// blocks copied to the side that was missing them
+    pub copied: usize,
+    /// deletes applied to the side that missed them
+    pub deleted: usize,
+}
+
+/// A primary and a mirror Store behind one StoreIO
+pub struct MirroredStore<T: BlockHasher> {
+    primary: Store<T>,
+    mirror: Store<T>,
+    /// side that missed a write, if one did
+    behind: Option<Side>,
+    /// side of the last block seeked to, for read and read_data_header
+    current: Side,
+}
+
+impl<T: BlockHasher> MirroredStore<T> {
+    /// Create both stores, replacing anything already there
+    pub fn create(primary_path: String, mirror_path: String) -> Result<MirroredStore<T>, Box<dyn std::error::Error>> {
+        let primary = Store::<T>::create(primary_path)?;
+        let mirror = Store::<T>::create(mirror_path)?;
+        MirroredStore::from_stores(primary, mirror)
+    }
+
+    /// Open both stores for writing
+    pub fn open(primary_path: String, mirror_path: String) -> Result<MirroredStore<T>, Box<dyn std::error::Error>> {
+        let primary = Store::<T>::open_for_write(primary_path)?;
+        let mirror = Store::<T>::open_for_write(mirror_path)?;
+        MirroredStore::from_stores(primary, mirror)
+    }
+
+    /// Mirror two stores already open for writing.
+    ///
+    /// The headers of the blocks both sides have are compared, and a block
+    /// whose size or checksum differs fails with ERROR_MIRROR_DIVERGED, as
+    /// the stores aren't copies of each other. The one with fewer blocks is
+    /// taken to be behind; with as many, one that kept a block the other
+    /// deleted is.
+    pub fn from_stores(mut primary: Store<T>, mut mirror: Store<T>) -> Result<MirroredStore<T>, Box<dyn std::error::Error>> {
+        let mut missed_delete = None;
+        for index in 0..primary.len().min(mirror.len()) {
+            let (p, m) = (primary.block_header(index)?, mirror.block_header(index)?);
+            let (pf, mf) = (p.fields(), m.fields());
+            if pf.size_data != mf.size_data || pf.checksum != mf.checksum {
+                return Err(ERROR_MIRROR_DIVERGED.into());
+            }
+            if missed_delete.is_none() && p.is_deleted() != m.is_deleted() {
+                missed_delete = Some(if p.is_deleted() { Side::Mirror } else { Side::Primary });
+            }
+        }
+        let behind = match primary.len().cmp(&mirror.len()) {
+            std::cmp::Ordering::Less => Some(Side::Primary),
+            std::cmp::Ordering::Greater => Some(Side::Mirror),
+            std::cmp::Ordering::Equal => missed_delete,
+        };
+        Ok(MirroredStore { primary, mirror, behind, current: Side::Primary })
+    }
+
+    /// Append data to both stores, returning its id.
+    ///
+    /// Fails only if both fail; if one does, it falls behind.
+    pub fn put(&mut self, data: &[u8]) -> Result<BlockId, Box<dyn std::error::Error>> {
+        self.write(|s| s.put(data))
+    }
+
+    /// Payload of the block at index, from the mirror if the primary fails.
+    ///
+    /// Deleted blocks, and blocks the access policy denies, fail without
+    /// trying the mirror. A payload that fails verification is repaired
+    /// from the other side's copy; if the repair can't be written the read
+    /// still succeeds, and the next read or scrub finds the damage again.
+    pub fn get(&mut self, index: BlockId) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let first = self.read_side();
+        self.current = first;
+        let e = match self.store(first).get(index) {
+            Ok(data) => return Ok(data),
+            Err(e) => e,
+        };
+        let kind = error_kind(&*e);
+        if !fails_over(kind) {
+            return Err(e);
+        }
+        self.current = first.other();
+        let data = self.store(first.other()).get(index).map_err(|_| e)?;
+        if kind == Some(StoreErrorKind::Checksum) {
+            let _ = self.repair(first, index, &data);
+        }
+        Ok(data)
+    }
+
+    /// Have observer told about changes to the store on side, see
+    /// Store::add_observer
+    pub fn add_observer(&mut self, side: Side, observer: Box<dyn StoreObserver>) {
+        self.store(side).add_observer(observer);
+    }
+
+    /// Side that missed a write and needs resync, None if both are level
+    pub fn behind(&self) -> Option<Side> {
+        self.behind
+    }
+
+    /// The store on side
+    pub fn side(&self, side: Side) -> &Store<T> {
+        match side {
+            Side::Primary => &self.primary,
+            Side::Mirror => &self.mirror,
+        }
+    }
+
+    /// Bring both sides level: copy the blocks one is missing to it, then
+    /// delete what either side deleted from the other.
+    ///
+    /// Copies keep their content type, owner and permissions when the
+    /// store's codec records them.
+    pub fn resync(&mut self) -> Result<ResyncReport, Box<dyn std::error::Error>> {
+        let mut report = ResyncReport::default();
+        let (ahead, behind) = if self.primary.len() >= self.mirror.len() {
+            (Side::Primary, Side::Mirror)
+        } else {
+            (Side::Mirror, Side::Primary)
+        };
+        for index in self.store(behind).len()..self.store(ahead).len() {
+            let (dh, data) = self.store(ahead).read_block(index)?;
+            let id = self.store(behind).put_like(&data, &dh)?;
+            if dh.is_deleted() {
+                self.store(behind).delete_many(&[id])?;
+            }
+            report.copied += 1;
+        }
+        for index in 0..self.primary.len() {
+            let primary_deleted = self.primary.block_header(index)?.is_deleted();
+            if primary_deleted != self.mirror.block_header(index)?.is_deleted() {
+                let live = if primary_deleted { Side::Mirror } else { Side::Primary };
+                self.store(live).delete_many(&[index])?;
+                report.deleted += 1;
+            }
+        }
+        self.behind = None;
+        Ok(report)
+    }
+
+    /// Close both stores
+    pub fn close(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let primary = self.primary.close_in_place();
+        self.mirror.close_in_place()?;
+        primary
+    }
+
+    fn store(&mut self, side: Side) -> &mut Store<T> {
+        match side {
+            Side::Primary => &mut self.primary,
+            Side::Mirror => &mut self.mirror,
+        }
+    }
+
+    /// Side reads try first, the primary unless it is behind
+    fn read_side(&self) -> Side {
+        match self.behind {
+            Some(Side::Primary) => Side::Mirror,
+            _ => Side::Primary,
+        }
+    }
+
+    /// Run f on every side that isn't behind, marking behind a side it
+    /// fails on while the other succeeds
+    ///
+    /// Both sides number blocks the same, so if they return different ids
+    /// the one with the lower id is missing blocks and falls behind, and
+    /// the other's id is returned.
+    fn write<F>(&mut self, mut f: F) -> Result<BlockId, Box<dyn std::error::Error>>
+    where
+        F: FnMut(&mut Store<T>) -> Result<BlockId, Box<dyn std::error::Error>>,
+    {
+        if let Some(side) = self.behind {
+            return f(self.store(side.other()));
+        }
+        match (f(&mut self.primary), f(&mut self.mirror)) {
+            (Ok(id), Ok(mirror_id)) => {
+                if id != mirror_id {
+                    self.behind = Some(if id < mirror_id { Side::Primary } else { Side::Mirror });
+                }
+                Ok(id.max(mirror_id))
+            }
+            (Ok(id), Err(_)) => {
+                self.behind = Some(Side::Mirror);
+                Ok(id)
+            }
+            (Err(_), Ok(id)) => {
+                self.behind = Some(Side::Primary);
+                Ok(id)
+            }
+            (Err(e), Err(_)) => Err(e),
+        }
+    }
+
+    /// Write data, a verified copy from the other side, over the damaged
+    /// payload of the block at index on side
+    fn repair(&mut self, side: Side, index: BlockId, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let store = self.store(side);
+        let dh = store.block_header(index)?;
+        store.restore_payload(index, &dh, data)
+    }
+
+    /// Run f on the read side, then on the other if it fails for a reason
+    /// the other side might not share.
+    ///
+    /// The first side's error is returned if both fail.
+    fn with_failover<R, F>(&mut self, mut f: F) -> Result<R, Box<dyn std::error::Error>>
+    where
+        F: FnMut(&mut Store<T>) -> Result<R, Box<dyn std::error::Error>>,
+    {
+        let first = self.read_side();
+        self.current = first;
+        let e = match f(self.store(first)) {
+            Ok(r) => return Ok(r),
+            Err(e) => e,
+        };
+        if !fails_over(error_kind(&*e)) {
+            return Err(e);
+        }
+        self.current = first.other();
+        f(self.store(first.other())).map_err(|_| e)
+    }
+}
+
+/// Kind of e if it is a StoreError
+fn error_kind(e: &(dyn std::error::Error + 'static)) -> Option<StoreErrorKind> {
+    StoreError::find(e).map(|se| se.kind())
+}
+
+/// false for errors the other side would give too
+fn fails_over(kind: Option<StoreErrorKind>) -> bool {
+    !matches!(kind, Some(StoreErrorKind::NotLive) | Some(StoreErrorKind::AccessDenied))
+}
+
+impl<T: BlockHasher> StoreIO<T> for MirroredStore<T> {
+    /// Delete on both stores, failing only if both fail
+    fn delete_block(&mut self, index: usize) -> Result<(), Box<dyn std::error::Error>> {
+        self.write(|s| s.delete_block(index).map(|_| index)).map(|_| ())
+    }
+
+    /// Blocks on the side that isn't behind
+    fn len(&self) -> usize {
+        self.primary.len().max(self.mirror.len())
+    }
+
+    /// Address within the side reads try first
+    fn block_address(&self, index: usize) -> Option<u64> {
+        self.side(self.read_side()).block_address(index)
+    }
+
+    fn read_data_header(&mut self, data_header: &mut DataHeader<T>) -> Result<(), Box<dyn std::error::Error>> {
+        self.store(self.current).read_data_header(data_header)
+    }
+
+    fn read(&mut self, data: &mut Vec<u8>) -> Result<usize, Error> {
+        self.store(self.current).read(data)
+    }
+
+    /// Falls back to the other side if reading fails, the payload isn't
+    /// verified
+    fn read_at_index(&mut self, index: usize, data: &mut Vec<u8>) -> Result<usize, Box<dyn std::error::Error>> {
+        self.with_failover(|s| s.read_at_index(index, data))
+    }
+
+    fn seek(&mut self, index: usize) -> Result<u64, Box<dyn std::error::Error>> {
+        self.with_failover(|s| s.seek(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+    use crate::data_header::BlockSerializer;
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::sync::{Arc, Mutex};
+
+    /// Blocks repaired, shared with the test
+    struct RepairLog(Arc<Mutex<Vec<BlockId>>>);
+
+    impl StoreObserver for RepairLog {
+        fn on_relocate(&mut self, _old_addr: u64, _new_addr: u64, _block_id: BlockId) {}
+
+        fn on_repair(&mut self, block_id: BlockId) {
+            self.0.lock().unwrap().push(block_id);
+        }
+    }
+
+    #[test]
+    fn mirror_repairs_fails_over_and_resyncs() {
+        fs::create_dir_all("testout").unwrap();
+        let primary = "testout/mirror_primary.st".to_string();
+        let mirror = "testout/mirror_mirror.st".to_string();
+        let mut ms = MirroredStore::<B3BlockHasher>::create(primary.clone(), mirror.clone()).unwrap();
+        assert_eq!(ms.put(b"first").unwrap(), 0);
+        assert_eq!(ms.put(b"second").unwrap(), 1);
+        ms.delete_block(1).unwrap();
+        assert!(ms.get(1).is_err());
+        let payload = ms.side(Side::Primary).block_address(0).unwrap() + DataHeader::<B3BlockHasher>::size() as u64;
+        ms.close().unwrap();
+
+        // the primary's copy of block 0 goes bad, the mirror's is still good
+        let mut f = OpenOptions::new().write(true).open(&primary).unwrap();
+        f.seek(SeekFrom::Start(payload)).unwrap();
+        f.write_all(b"F").unwrap();
+        drop(f);
+        let mut ms = MirroredStore::<B3BlockHasher>::open(primary.clone(), mirror.clone()).unwrap();
+        let repairs = Arc::new(Mutex::new(Vec::new()));
+        ms.add_observer(Side::Primary, Box::new(RepairLog(Arc::clone(&repairs))));
+        assert_eq!(ms.get(0).unwrap(), b"first");
+        assert_eq!(*repairs.lock().unwrap(), vec![0]);
+        ms.close().unwrap();
+        let mut p = Store::<B3BlockHasher>::new(primary.clone()).unwrap();
+        assert_eq!(p.get(0).unwrap(), b"first");
+        drop(p);
+
+        // the mirror misses two puts and a delete
+        let mut p = Store::<B3BlockHasher>::open_for_write(primary).unwrap();
+        p.put(b"third").unwrap();
+        p.put(b"fourth").unwrap();
+        p.delete_many(&[0, 2]).unwrap();
+        let m = Store::<B3BlockHasher>::open_for_write(mirror.clone()).unwrap();
+        let mut ms = MirroredStore::from_stores(p, m).unwrap();
+        assert_eq!(ms.behind(), Some(Side::Mirror));
+        assert_eq!(ms.len(), 4);
+        assert_eq!(ms.put(b"fifth").unwrap(), 4);
+        assert_eq!(ms.side(Side::Mirror).len(), 2);
+
+        assert_eq!(ms.resync().unwrap(), ResyncReport { copied: 3, deleted: 1 });
+        assert_eq!(ms.behind(), None);
+        assert_eq!(ms.resync().unwrap(), ResyncReport::default());
+        ms.close().unwrap();
+        let mut m = Store::<B3BlockHasher>::new(mirror).unwrap();
+        assert_eq!(m.get(4).unwrap(), b"fifth");
+        assert!(!m.is_live(0).unwrap() && !m.is_live(2).unwrap());
+    }
+
+    #[test]
+    fn diverged_sides_are_noticed() {
+        fs::create_dir_all("testout").unwrap();
+        let primary = "testout/mirror_div_primary.st".to_string();
+        let mirror = "testout/mirror_div_mirror.st".to_string();
+        let mut ms = MirroredStore::<B3BlockHasher>::create(primary.clone(), mirror.clone()).unwrap();
+        ms.put(b"first").unwrap();
+        ms.put(b"second").unwrap();
+        ms.close().unwrap();
+
+        // the mirror missed a delete
+        let mut p = Store::<B3BlockHasher>::open_for_write(primary.clone()).unwrap();
+        p.delete_many(&[1]).unwrap();
+        let m = Store::<B3BlockHasher>::open_for_write(mirror.clone()).unwrap();
+        let mut ms = MirroredStore::from_stores(p, m).unwrap();
+        assert_eq!(ms.behind(), Some(Side::Mirror));
+        assert_eq!(ms.resync().unwrap(), ResyncReport { copied: 0, deleted: 1 });
+
+        // the mirror took a put the primary didn't, so their ids disagree
+        ms.mirror.put(b"stray").unwrap();
+        assert_eq!(ms.put(b"third").unwrap(), 3);
+        assert_eq!(ms.behind(), Some(Side::Primary));
+        ms.close().unwrap();
+
+        // the same index holds different payloads
+        let mut p = Store::<B3BlockHasher>::open_for_write(primary).unwrap();
+        p.put(b"fourth").unwrap();
+        let m = Store::<B3BlockHasher>::open_for_write(mirror).unwrap();
+        let e = MirroredStore::from_stores(p, m).err().unwrap();
+        assert_eq!(e.to_string(), ERROR_MIRROR_DIVERGED);
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn repair_is_synced_before_observers_are_told() {
+        use crate::fault::{run_with_faults, Fault, FaultPlan};
+        fs::create_dir_all("testout").unwrap();
+        let mut s = Store::<B3BlockHasher>::create("testout/mirror_repair_sync.st".to_string()).unwrap();
+        s.put(b"first").unwrap();
+        let dh = s.block_header(0).unwrap();
+        let ops = run_with_faults(&mut s, FaultPlan::new(), |s| s.restore_payload(0, &dh, b"first")).ops;
+        let repairs = Arc::new(Mutex::new(Vec::new()));
+        s.add_observer(Box::new(RepairLog(Arc::clone(&repairs))));
+        let payload = s.block_address(0).unwrap() + DataHeader::<B3BlockHasher>::size() as u64;
+        let mut f = OpenOptions::new().write(true).open("testout/mirror_repair_sync.st").unwrap();
+        f.seek(SeekFrom::Start(payload)).unwrap();
+        f.write_all(b"F").unwrap();
+        drop(f);
+        // the payload is written but the sync after it fails
+        let run = run_with_faults(&mut s, FaultPlan::new().at(ops - 1, Fault::IoError), |s| s.restore_payload(0, &dh, b"first"));
+        assert!(run.result.is_err());
+        assert_eq!(s.get(0).unwrap(), b"first");
+        assert!(repairs.lock().unwrap().is_empty());
+        s.restore_payload(0, &dh, b"first").unwrap();
+        assert_eq!(*repairs.lock().unwrap(), vec![0]);
+    }
+}
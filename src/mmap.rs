@@ -0,0 +1,84 @@
+//Copyright 2021 Matthew Petricone
+//! Read only memory maps of store files, for Store::read_aligned.
+//!
+//! Maps are made with the C library's mmap, on 64 bit unix; elsewhere
+//! mapping fails and read_aligned copies payloads into an aligned buffer
+//! instead.
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+
+#[cfg(not(all(unix, target_pointer_width = "64")))]
+static ERROR_MMAP_UNSUPPORTED: &str = "Memory maps aren't supported on this platform.";
+
+#[cfg(all(unix, target_pointer_width = "64"))]
+mod sys {
+    use std::os::raw::{c_int, c_void};
+
+    pub const PROT_READ: c_int = 1;
+    pub const MAP_SHARED: c_int = 1;
+
+    extern "C" {
+        pub fn mmap(addr: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, offset: i64) -> *mut c_void;
+        pub fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+}
+
+/// The first len bytes of a file, mapped read only
+#[derive(Debug)]
+pub(crate) struct Mmap {
+    ptr: *const u8,
+    len: usize,
+}
+
+// the map is read only, and unmapped only on drop
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Map the first len bytes of file, which must not be 0
+    ///
+    /// # Safety
+    ///
+    /// The map is shared with the file, so as_slice is only sound while the
+    /// file isn't shrunk below len, by any handle or process, and the bytes
+    /// read aren't changed, for as long as the map or a slice of it lives.
+    /// Reading a page past the end of the file raises SIGBUS.
+    #[cfg(all(unix, target_pointer_width = "64"))]
+    pub(crate) unsafe fn map(file: &File, len: u64) -> Result<Mmap, Error> {
+        use std::convert::TryFrom;
+        use std::os::unix::io::AsRawFd;
+        let len = usize::try_from(len).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        if len == 0 {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        let ptr = sys::mmap(std::ptr::null_mut(), len, sys::PROT_READ, sys::MAP_SHARED, file.as_raw_fd(), 0);
+        // MAP_FAILED
+        if ptr as isize == -1 {
+            return Err(Error::last_os_error());
+        }
+        Ok(Mmap { ptr: ptr as *const u8, len })
+    }
+
+    #[cfg(not(all(unix, target_pointer_width = "64")))]
+    pub(crate) unsafe fn map(_file: &File, _len: u64) -> Result<Mmap, Error> {
+        Err(Error::new(ErrorKind::Unsupported, ERROR_MMAP_UNSUPPORTED))
+    }
+
+    /// Bytes mapped
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        #[cfg(all(unix, target_pointer_width = "64"))]
+        unsafe {
+            sys::munmap(self.ptr as *mut _, self.len);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//Copyright 2021 Matthew Petricone
+//! Several store files read as one sequence of blocks.
+//!
+//! Blocks are numbered across the stores in the order given, so block 0 of
+//! the second store comes straight after the last block of the first.
+use crate::crypto::BlockHasher;
+use crate::data_header::DataHeader;
+use crate::store::{BlockId, Store, StoreIO};
+use std::io::{Error, ErrorKind};
+
+static ERROR_MULTI_READONLY: &str = "MultiStore is read only.";
+static ERROR_MULTI_OUTOFBOUNDS: &str = "Value out of bounds.";
+
+/// Read only view of several stores, e.g. daily log segments
+pub struct MultiStore<T: BlockHasher> {
+    stores: Vec<Store<T>>,
+    /// store of the last block seeked to, for read and read_data_header
+    current: usize,
+}
+
+impl<T: BlockHasher> MultiStore<T> {
+    /// Open every store in paths read only, in order
+    pub fn open(paths: &[String]) -> Result<MultiStore<T>, Box<dyn std::error::Error>> {
+        let stores = paths
+            .iter()
+            .map(|p| Store::<T>::new(p.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(MultiStore { stores, current: 0 })
+    }
+
+    /// The underlying stores
+    pub fn segments(&self) -> &[Store<T>] {
+        &self.stores
+    }
+
+    /// Segment holding global block index, and its index within that segment
+    pub fn locate(&self, index: BlockId) -> Option<(usize, BlockId)> {
+        let mut first = 0;
+        for (seg, s) in self.stores.iter().enumerate() {
+            if index < first + s.len() {
+                return Some((seg, index - first));
+            }
+            first += s.len();
+        }
+        None
+    }
+
+    /// Global index of the first block of segment
+    pub fn segment_start(&self, segment: usize) -> BlockId {
+        self.stores[..segment].iter().map(|s| s.len()).sum()
+    }
+
+    /// Payload of the block at global index, see Store::get
+    pub fn get(&mut self, index: BlockId) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let (seg, local) = self.locate_or_err(index)?;
+        self.stores[seg].get(local)
+    }
+
+    /// Iterate over live blocks of every segment, with global indexes
+    pub fn iter(&mut self) -> impl Iterator<Item = Result<(BlockId, Vec<u8>), Box<dyn std::error::Error>>> + '_ {
+        let mut first = 0;
+        self.stores.iter_mut().flat_map(move |s| {
+            let base = first;
+            first += s.len();
+            s.iter().map(move |r| r.map(|(i, data)| (base + i, data)))
+        })
+    }
+
+    fn locate_or_err(&self, index: BlockId) -> Result<(usize, BlockId), Error> {
+        self.locate(index)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, ERROR_MULTI_OUTOFBOUNDS))
+    }
+}
+
+impl<T: BlockHasher> StoreIO<T> for MultiStore<T> {
+    /// Always fails, segments are opened read only
+    fn delete_block(&mut self, _index: usize) -> Result<(), Box<dyn std::error::Error>> {
+        Err(Box::new(Error::new(ErrorKind::PermissionDenied, ERROR_MULTI_READONLY)))
+    }
+
+    fn len(&self) -> usize {
+        self.stores.iter().map(|s| s.len()).sum()
+    }
+
+    /// Address within the segment holding the block
+    fn block_address(&self, index: usize) -> Option<u64> {
+        let (seg, local) = self.locate(index)?;
+        self.stores[seg].block_address(local)
+    }
+
+    fn read_data_header(&mut self, data_header: &mut DataHeader<T>) -> Result<(), Box<dyn std::error::Error>> {
+        match self.stores.get_mut(self.current) {
+            Some(s) => s.read_data_header(data_header),
+            None => Err(Box::new(Error::new(ErrorKind::InvalidInput, ERROR_MULTI_OUTOFBOUNDS))),
+        }
+    }
+
+    fn read(&mut self, data: &mut Vec<u8>) -> Result<usize, Error> {
+        match self.stores.get_mut(self.current) {
+            Some(s) => s.read(data),
+            None => Ok(0),
+        }
+    }
+
+    fn read_at_index(&mut self, index: usize, data: &mut Vec<u8>) -> Result<usize, Box<dyn std::error::Error>> {
+        let (seg, local) = self.locate_or_err(index)?;
+        self.current = seg;
+        self.stores[seg].read_at_index(local, data)
+    }
+
+    fn seek(&mut self, index: usize) -> Result<u64, Box<dyn std::error::Error>> {
+        let (seg, local) = self.locate_or_err(index)?;
+        self.current = seg;
+        self.stores[seg].seek(local)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+
+    #[test]
+    fn segments_read_as_one() {
+        std::fs::create_dir_all("testout").unwrap();
+        let paths: Vec<String> = (0..3).map(|i| format!("testout/multi_{}.st", i)).collect();
+        for (seg, p) in paths.iter().enumerate() {
+            let mut s = Store::<B3BlockHasher>::create(p.clone()).unwrap();
+            for i in 0..seg + 1 {
+                s.put(&[seg as u8, i as u8]).unwrap();
+            }
+            if seg == 1 {
+                s.delete_block(0).unwrap();
+            }
+            s.close().unwrap();
+        }
+        let mut ms = MultiStore::<B3BlockHasher>::open(&paths).unwrap();
+        assert_eq!(ms.len(), 6);
+        assert_eq!(ms.locate(3), Some((2, 0)));
+        assert_eq!(ms.segment_start(2), 3);
+        assert_eq!(ms.get(4).unwrap(), vec![2, 1]);
+        let ids: Vec<BlockId> = ms.iter().map(|r| r.unwrap().0).collect();
+        assert_eq!(ids, vec![0, 2, 3, 4, 5]);
+        let mut dh = DataHeader::<B3BlockHasher>::new().unwrap();
+        ms.seek(2).unwrap();
+        ms.read_data_header(&mut dh).unwrap();
+        assert_eq!(dh.data_size().unwrap(), 2);
+        assert!(ms.get(6).is_err());
+        assert!(ms.delete_block(0).is_err());
+    }
+}
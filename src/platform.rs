@@ -0,0 +1,105 @@
+//Copyright 2021 Matthew Petricone
+//! File system calls whose meaning differs between platforms, so the rest
+//! of the crate can rely on one behaviour.
+//!
+//! Locks are advisory and held by the open file: flock on Linux and macOS,
+//! LockFileEx on Windows, through std. A second handle to the same file,
+//! even in the same process, can't take it.
+//!
+//! Renames are made durable by syncing the directory on unix. Windows can't
+//! open a directory as a file and NTFS journals its own metadata, so there
+//! syncing the file before the rename is all that's needed, and all that's
+//! done. std's sync calls use F_FULLFSYNC on macOS, where fsync alone
+//! leaves writes in the drive's cache.
+//!
+//! Store paths are UTF-8 and sidecars are named by appending ASCII to them,
+//! which gives a valid name on every platform. The store never punches
+//! holes, so sparse files need nothing here.
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Error, Write};
+use std::path::Path;
+
+/// Take an exclusive lock on file without waiting, false if another handle
+/// holds one
+pub(crate) fn try_lock(file: &File) -> Result<bool, Error> {
+    match file.try_lock() {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Make entries created, renamed or removed in the directory holding path
+/// survive a crash
+#[cfg(unix)]
+pub(crate) fn sync_dir_of(path: &str) -> Result<(), Error> {
+    let dir = match Path::new(path).parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn sync_dir_of(_path: &str) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Rename from over to, replacing any file there, and make the rename
+/// durable. from should already be synced.
+pub(crate) fn replace(from: &str, to: &str) -> Result<(), Error> {
+    // std replaces an existing file on every platform, MoveFileEx with
+    // MOVEFILE_REPLACE_EXISTING on Windows
+    std::fs::rename(from, to)?;
+    sync_dir_of(to)
+}
+
+/// Write data to path through a synced temporary file (path with ".tmp"
+/// appended), so a crash leaves either the old contents or the new
+pub(crate) fn write_replace(path: &str, data: &[u8]) -> Result<(), Error> {
+    let tmp = format!("{}.tmp", path);
+    let mut f = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp)?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    drop(f);
+    replace(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_file(name: &str) -> String {
+        std::fs::create_dir_all("testout").unwrap();
+        format!("testout/{}", name)
+    }
+
+    #[test]
+    fn locks_exclude_other_handles() {
+        let path = test_file("platform_lock");
+        let a = File::create(&path).unwrap();
+        let b = OpenOptions::new().write(true).open(&path).unwrap();
+        assert!(try_lock(&a).unwrap());
+        assert!(!try_lock(&b).unwrap());
+        a.unlock().unwrap();
+        assert!(try_lock(&b).unwrap());
+        drop(b);
+        // closing the handle releases it
+        assert!(try_lock(&a).unwrap());
+    }
+
+    #[test]
+    fn replace_is_atomic_over_existing_files() {
+        let path = test_file("platform_replace");
+        write_replace(&path, b"old").unwrap();
+        // the destination may be open, as the store file is during compaction
+        let held = File::open(&path).unwrap();
+        write_replace(&path, b"new").unwrap();
+        drop(held);
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        assert!(replace("testout/platform_missing", &path).is_err());
+        // a bare name is in the current directory
+        sync_dir_of("platform_bare").unwrap();
+    }
+}
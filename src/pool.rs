@@ -0,0 +1,125 @@
+//Copyright 2021 Matthew Petricone
+//! Buffers reused across reads, instead of allocated for each one.
+//!
+//! With StoreOptions::buffer_pool, the buffers a store reads headers and
+//! payloads into come from a pool of size classes, powers of two, and go
+//! back to it when done. Payloads handed to callers, by get for one, leave
+//! the pool; Store::recycle takes them back. The pool keeps what the
+//! workload uses, up to its byte limit, and lets the rest be freed.
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Smallest buffer pooled, smaller requests are rounded up to it
+const MIN_CLASS_SHIFT: u32 = 6;
+/// Largest buffer pooled, 16 MiB, bigger ones are allocated as usual
+const MAX_CLASS_SHIFT: u32 = 24;
+
+/// What a buffer pool has done, see Store::pool_stats
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PoolStats {
+    /// buffers handed out from the pool
+    pub hits: u64,
+    /// buffers that had to be allocated
+    pub misses: u64,
+    /// capacity of the buffers held for reuse
+    pub held_bytes: u64,
+}
+
+/// Free buffers by size class
+#[derive(Debug)]
+pub(crate) struct BufferPool {
+    max_bytes: u64,
+    /// classes[c] holds buffers with room for 1 << (c + MIN_CLASS_SHIFT) bytes
+    classes: Vec<Vec<Vec<u8>>>,
+    stats: PoolStats,
+}
+
+/// A pool shared by a store's handles
+pub(crate) type SharedPool = Arc<Mutex<BufferPool>>;
+
+impl BufferPool {
+    /// Pool holding at most max_bytes of buffers
+    pub(crate) fn shared(max_bytes: u64) -> SharedPool {
+        Arc::new(Mutex::new(BufferPool {
+            max_bytes,
+            classes: vec![Vec::new(); (MAX_CLASS_SHIFT - MIN_CLASS_SHIFT + 1) as usize],
+            stats: PoolStats::default(),
+        }))
+    }
+
+    /// The pool behind shared
+    pub(crate) fn lock(shared: &SharedPool) -> MutexGuard<'_, BufferPool> {
+        // a buffer list is never left half updated, so a poisoned lock is still usable
+        shared.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Class of the smallest buffers with room for len bytes, None if it is
+    /// too big to pool
+    fn class_for(len: usize) -> Option<usize> {
+        let shift = len.max(1).next_power_of_two().trailing_zeros().max(MIN_CLASS_SHIFT);
+        if shift > MAX_CLASS_SHIFT {
+            return None;
+        }
+        Some((shift - MIN_CLASS_SHIFT) as usize)
+    }
+
+    /// A zeroed buffer of len bytes
+    pub(crate) fn take(&mut self, len: usize) -> Vec<u8> {
+        let class = match BufferPool::class_for(len) {
+            Some(c) => c,
+            None => return vec![0u8; len],
+        };
+        let mut buf = match self.classes[class].pop() {
+            Some(b) => {
+                self.stats.hits += 1;
+                self.stats.held_bytes -= b.capacity() as u64;
+                b
+            }
+            None => {
+                self.stats.misses += 1;
+                Vec::with_capacity(1 << (class as u32 + MIN_CLASS_SHIFT))
+            }
+        };
+        buf.resize(len, 0);
+        buf
+    }
+
+    /// Keep buf for reuse, if it fits a class and the pool has room
+    pub(crate) fn give(&mut self, mut buf: Vec<u8>) {
+        let cap = buf.capacity();
+        if cap < 1 << MIN_CLASS_SHIFT || self.stats.held_bytes + cap as u64 > self.max_bytes {
+            return;
+        }
+        // the largest class buf has room for
+        let shift = (usize::BITS - 1 - cap.leading_zeros()).min(MAX_CLASS_SHIFT);
+        buf.clear();
+        self.stats.held_bytes += cap as u64;
+        self.classes[(shift - MIN_CLASS_SHIFT) as usize].push(buf);
+    }
+
+    pub(crate) fn stats(&self) -> PoolStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_come_back_by_class() {
+        let shared = BufferPool::shared(4096);
+        let mut pool = BufferPool::lock(&shared);
+        let a = pool.take(100);
+        assert_eq!((a.len(), a.capacity()), (100, 128));
+        pool.give(a);
+        let b = pool.take(65);
+        assert!(b.iter().all(|x| *x == 0));
+        assert_eq!(pool.stats(), PoolStats { hits: 1, misses: 1, held_bytes: 0 });
+        pool.give(b);
+        // a smaller class doesn't take from a bigger one, and over the limit isn't kept
+        pool.take(10);
+        pool.give(vec![0; 8192]);
+        assert_eq!(pool.stats(), PoolStats { hits: 1, misses: 2, held_bytes: 128 });
+        assert_eq!(pool.take(1 << 25).len(), 1 << 25);
+    }
+}
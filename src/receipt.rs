@@ -0,0 +1,115 @@
+//Copyright 2021 Matthew Petricone
+//! Receipts for blocks, to check later that a block is still as written.
+//!
+//! A receipt names a block by index and file offset and keeps the full hash
+//! of its payload, so it can be handed to an audit trail or external ledger
+//! and checked against the store with Store::verify_receipt. Anything that
+//! moves or rewrites the block, compaction or a swap, fails the check.
+use crate::crypto::BlockHasher;
+use crate::store::{BlockId, Store, StoreIO};
+use std::convert::TryFrom;
+use std::convert::TryInto;
+
+/// Proof of a put, see Store::put_with_receipt
+///
+/// Serialized as u64 block_id, u64 offset, u64 generation, u16 hash length
+/// then the hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Receipt {
+    pub block_id: BlockId,
+    /// address of the block's header in the file
+    pub offset: u64,
+    /// hash of the payload, see Store::hash
+    pub hash: Vec<u8>,
+    /// Store::generation just after the put, see Store::read_at_generation
+    pub generation: u64,
+}
+
+impl Receipt {
+    /// Serialize for keeping outside the store
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(26 + self.hash.len());
+        out.extend_from_slice(&(self.block_id as u64).to_le_bytes());
+        out.extend_from_slice(&self.offset.to_le_bytes());
+        out.extend_from_slice(&self.generation.to_le_bytes());
+        out.extend_from_slice(&(self.hash.len() as u16).to_le_bytes());
+        out.extend_from_slice(&self.hash);
+        out
+    }
+
+    /// Inverse of to_bytes, None if data isn't a receipt
+    pub fn from_bytes(data: &[u8]) -> Option<Receipt> {
+        if data.len() < 26 {
+            return None;
+        }
+        let u64_at = |i: usize| u64::from_le_bytes(data[i..i + 8].try_into().unwrap());
+        let len = usize::from(u16::from_le_bytes(data[24..26].try_into().ok()?));
+        if data.len() != 26 + len {
+            return None;
+        }
+        Some(Receipt {
+            block_id: usize::try_from(u64_at(0)).ok()?,
+            offset: u64_at(8),
+            generation: u64_at(16),
+            hash: data[26..].to_vec(),
+        })
+    }
+}
+
+impl<T: BlockHasher> Store<T> {
+    /// put, returning a Receipt for the new block
+    pub fn put_with_receipt(&mut self, data: &[u8]) -> Result<Receipt, Box<dyn std::error::Error>> {
+        let block_id = self.put(data)?;
+        Ok(Receipt {
+            block_id,
+            offset: self.block_address(block_id).unwrap(),
+            hash: self.hash(data),
+            generation: self.generation(),
+        })
+    }
+
+    /// true if the block of receipt is live, where it was, and unmodified
+    pub fn verify_receipt(&mut self, receipt: &Receipt) -> Result<bool, Box<dyn std::error::Error>> {
+        if self.block_address(receipt.block_id) != Some(receipt.offset) || !self.is_live(receipt.block_id)? {
+            return Ok(false);
+        }
+        // get fails if the payload no longer matches its own checksum
+        let data = match self.get(receipt.block_id) {
+            Ok(data) => data,
+            Err(_) => return Ok(false),
+        };
+        Ok(self.hash(&data) == receipt.hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+
+    #[test]
+    fn receipts_verify_until_the_block_changes() {
+        std::fs::create_dir_all("testout").unwrap();
+        let mut s = Store::<B3BlockHasher>::create("testout/receipt.st".to_string()).unwrap();
+        s.put(b"first").unwrap();
+        let r = s.put_with_receipt(b"ledger entry").unwrap();
+        let other = s.put_with_receipt(b"another").unwrap();
+        assert_eq!(r.block_id, 1);
+        assert!(other.generation > r.generation);
+        assert_eq!(Receipt::from_bytes(&r.to_bytes()), Some(r.clone()));
+        assert!(Receipt::from_bytes(&r.to_bytes()[1..]).is_none());
+        assert!(s.verify_receipt(&r).unwrap());
+
+        let mut forged = r.clone();
+        forged.hash[0] ^= 1;
+        assert!(!s.verify_receipt(&forged).unwrap());
+        let mut moved = r.clone();
+        moved.offset += 1;
+        assert!(!s.verify_receipt(&moved).unwrap());
+
+        s.update_if(1, &r.hash, b"ledger entrz").unwrap();
+        assert!(!s.verify_receipt(&r).unwrap());
+        s.delete_many(&[2]).unwrap();
+        assert!(!s.verify_receipt(&other).unwrap());
+    }
+}
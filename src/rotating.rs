@@ -0,0 +1,261 @@
+//Copyright 2021 Matthew Petricone
+//! A series of store files in one directory, with a new one started when
+//! the day changes or the current one gets too big.
+//!
+//! Daily segments are named YYYY-MM-DD.fst (UTC), size based ones are
+//! numbered 00000001.fst upwards, so sorting the names sorts the series.
+use crate::crypto::BlockHasher;
+use crate::multi::MultiStore;
+use crate::store::{BlockId, RetentionPolicy, Store, StoreIO};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Extension of segment files
+static SEGMENT_EXTENSION: &str = "fst";
+
+/// When to start a new segment
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    /// one segment per UTC day
+    Daily,
+    /// a new segment once the current one reaches this many bytes
+    BySize(u64),
+}
+
+/// Appends to the newest segment of a series, rotating as needed
+pub struct RotatingStore<T: BlockHasher> {
+    dir: PathBuf,
+    rotation: Rotation,
+    /// segment being written and its name without extension
+    current: Store<T>,
+    current_name: String,
+    /// blocks in the segments before current
+    base: BlockId,
+}
+
+impl<T: BlockHasher> RotatingStore<T> {
+    /// Open the series in dir, creating dir if needed.
+    ///
+    /// The newest segment is appended to if the policy still allows it.
+    pub fn with_policy<P: AsRef<Path>>(dir: P, rotation: Rotation) -> Result<RotatingStore<T>, Box<dyn std::error::Error>> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let names = RotatingStore::<T>::segment_names(&dir)?;
+        let mut base = 0;
+        for n in names.iter().take(names.len().saturating_sub(1)) {
+            base += Store::<T>::new(RotatingStore::<T>::path_of(&dir, n))?.len();
+        }
+        let (current, current_name) = match names.last() {
+            Some(n) => (Store::<T>::open_for_write(RotatingStore::<T>::path_of(&dir, n))?, n.clone()),
+            None => {
+                let n = RotatingStore::<T>::next_name(rotation, None, SystemTime::now());
+                (Store::<T>::create(RotatingStore::<T>::path_of(&dir, &n))?, n)
+            }
+        };
+        let mut rs = RotatingStore {
+            dir,
+            rotation,
+            current,
+            current_name,
+            base,
+        };
+        rs.rotate_if_needed(SystemTime::now())?;
+        Ok(rs)
+    }
+
+    /// Append data to the current segment, returning its index in the whole series
+    pub fn put(&mut self, data: &[u8]) -> Result<BlockId, Box<dyn std::error::Error>> {
+        self.rotate_if_needed(SystemTime::now())?;
+        Ok(self.base + self.current.put(data)?)
+    }
+
+    /// Paths of every segment, oldest first
+    pub fn segments(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        Ok(RotatingStore::<T>::segment_names(&self.dir)?
+            .iter()
+            .map(|n| RotatingStore::<T>::path_of(&self.dir, n))
+            .collect())
+    }
+
+    /// Read only view of the whole series, block indexes match put
+    pub fn series(&mut self) -> Result<MultiStore<T>, Box<dyn std::error::Error>> {
+        self.current.flush()?;
+        MultiStore::open(&self.segments()?)
+    }
+
+    /// Remove the oldest whole segments until the series is within policy.
+    ///
+    /// A segment is too old once its file hasn't been modified for max_age.
+    /// The current segment is never removed. Block indexes of the series
+    /// shift down by the blocks removed. Returns the removed paths.
+    pub fn enforce_retention(&mut self, policy: &RetentionPolicy) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut segs = Vec::new();
+        for p in self.segments()? {
+            let md = fs::metadata(&p)?;
+            let blocks = Store::<T>::new(p.clone())?.len();
+            segs.push((p, md.len(), md.modified()?, blocks));
+        }
+        let mut bytes: u64 = segs.iter().map(|s| s.1).sum();
+        let mut blocks: usize = segs.iter().map(|s| s.3).sum();
+        let now = SystemTime::now();
+        let mut removed = Vec::new();
+        let current = RotatingStore::<T>::path_of(&self.dir, &self.current_name);
+        for (p, len, modified, n) in segs {
+            if p == current {
+                break;
+            }
+            let too_old = policy
+                .max_age
+                .is_some_and(|age| now.duration_since(modified).map(|d| d > age).unwrap_or(false));
+            let too_big = policy.max_total_bytes.is_some_and(|m| bytes > m);
+            let too_many = policy.max_blocks.is_some_and(|m| blocks > m);
+            if !(too_old || too_big || too_many) {
+                break;
+            }
+            fs::remove_file(&p)?;
+            bytes -= len;
+            blocks -= n;
+            self.base -= n;
+            removed.push(p);
+        }
+        Ok(removed)
+    }
+
+    /// Close the current segment
+    pub fn close(self) -> Result<(), Box<dyn std::error::Error>> {
+        self.current.close()
+    }
+
+    /// Start a new segment if the policy says the current one is finished
+    fn rotate_if_needed(&mut self, now: SystemTime) -> Result<(), Box<dyn std::error::Error>> {
+        let next = RotatingStore::<T>::next_name(self.rotation, Some(&self.current_name), now);
+        let full = match self.rotation {
+            Rotation::Daily => next != self.current_name,
+            Rotation::BySize(bytes) => {
+                !self.current.is_empty()
+                    && fs::metadata(RotatingStore::<T>::path_of(&self.dir, &self.current_name))?.len() >= bytes
+            }
+        };
+        if full {
+            let st = Store::<T>::create(RotatingStore::<T>::path_of(&self.dir, &next))?;
+            self.base += self.current.len();
+            std::mem::replace(&mut self.current, st).close()?;
+            self.current_name = next;
+        }
+        Ok(())
+    }
+
+    /// Name of the segment to write to at now, given the current one
+    fn next_name(rotation: Rotation, current: Option<&str>, now: SystemTime) -> String {
+        match rotation {
+            Rotation::Daily => {
+                let days = now.duration_since(UNIX_EPOCH).map(|d| d.as_secs() / 86400).unwrap_or(0);
+                let (y, m, d) = civil_from_days(days);
+                format!("{:04}-{:02}-{:02}", y, m, d)
+            }
+            Rotation::BySize(_) => {
+                let n = current.and_then(|c| c.parse::<u64>().ok()).unwrap_or(0);
+                format!("{:08}", n + 1)
+            }
+        }
+    }
+
+    /// Segment names in dir without extension, sorted
+    fn segment_names(dir: &Path) -> Result<Vec<String>, std::io::Error> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let p = entry?.path();
+            if p.extension().and_then(|e| e.to_str()) == Some(SEGMENT_EXTENSION) {
+                if let Some(stem) = p.file_stem().and_then(|s| s.to_str()) {
+                    names.push(stem.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn path_of(dir: &Path, name: &str) -> String {
+        dir.join(format!("{}.{}", name, SEGMENT_EXTENSION)).to_string_lossy().into_owned()
+    }
+}
+
+/// Year, month and day of a count of days since 1970-01-01
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Howard Hinnant's algorithm, shifted so years start in March
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + u64::from(m <= 2);
+    (y, m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+    use std::time::Duration;
+
+    #[test]
+    fn days_to_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_723), (2024, 1, 1));
+    }
+
+    #[test]
+    fn rotates_by_size_and_day() {
+        let dir = "testout/rotating";
+        let _ = fs::remove_dir_all(dir);
+        let mut rs = RotatingStore::<B3BlockHasher>::with_policy(dir, Rotation::BySize(200)).unwrap();
+        for i in 0..10u8 {
+            assert_eq!(rs.put(&[i; 40]).unwrap(), usize::from(i));
+        }
+        let segs = rs.segments().unwrap();
+        assert!(segs.len() > 1);
+        assert!(segs[0].ends_with("00000001.fst"));
+        let mut ms = rs.series().unwrap();
+        assert_eq!(ms.len(), 10);
+        assert_eq!(ms.get(7).unwrap(), vec![7u8; 40]);
+        drop(ms);
+        rs.close().unwrap();
+
+        let mut rs = RotatingStore::<B3BlockHasher>::with_policy(dir, Rotation::BySize(200)).unwrap();
+        assert_eq!(rs.put(&[10; 40]).unwrap(), 10);
+        rs.close().unwrap();
+
+        let daily = "testout/rotating_daily";
+        let _ = fs::remove_dir_all(daily);
+        let mut rs = RotatingStore::<B3BlockHasher>::with_policy(daily, Rotation::Daily).unwrap();
+        rs.put(&[1]).unwrap();
+        rs.rotate_if_needed(SystemTime::now() + Duration::from_secs(86400)).unwrap();
+        assert_eq!(rs.put(&[2]).unwrap(), 1);
+        assert_eq!(rs.segments().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn retention_drops_old_segments() {
+        let dir = "testout/rotating_retention";
+        let _ = fs::remove_dir_all(dir);
+        let mut rs = RotatingStore::<B3BlockHasher>::with_policy(dir, Rotation::BySize(100)).unwrap();
+        for i in 0..6u8 {
+            rs.put(&[i; 80]).unwrap();
+        }
+        let before = rs.segments().unwrap().len();
+        let policy = RetentionPolicy {
+            max_blocks: Some(2),
+            ..Default::default()
+        };
+        let removed = rs.enforce_retention(&policy).unwrap();
+        assert_eq!(removed.len(), before - 2);
+        assert_eq!(rs.put(&[6; 80]).unwrap(), 2);
+        assert_eq!(rs.series().unwrap().get(1).unwrap(), vec![5u8; 80]);
+    }
+}
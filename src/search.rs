@@ -0,0 +1,135 @@
+//Copyright 2021 Matthew Petricone
+//! Searching block payloads for a byte pattern.
+//!
+//! Store::search reads each live payload in pieces and runs a Matcher over
+//! them, so blocks of any size are searched without loading them whole.
+//! Payloads aren't verified: this is for looking into a store, use get or
+//! verify to trust what is found.
+use crate::crypto::BlockHasher;
+use crate::store::{BlockId, Store, StoreIO};
+
+static ERROR_SEARCH_EMPTY: &str = "Search pattern is empty.";
+
+/// Bytes of a payload read at a time by Store::search
+const SEARCH_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Where a pattern was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchHit {
+    pub index: BlockId,
+    /// bytes from the start of the payload to the start of the match
+    pub offset: u64,
+}
+
+/// Finds a byte pattern in data fed to it in pieces (Knuth-Morris-Pratt).
+///
+/// Matches may overlap, and may span the pieces.
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    pattern: Vec<u8>,
+    /// length of the longest proper prefix of pattern[..=i] that is also a suffix of it
+    failure: Vec<usize>,
+    /// bytes of pattern matched so far
+    matched: usize,
+    /// bytes fed since the last reset
+    position: u64,
+}
+
+impl Matcher {
+    /// Matcher for pattern, which must not be empty
+    pub fn new(pattern: &[u8]) -> Result<Matcher, Box<dyn std::error::Error>> {
+        if pattern.is_empty() {
+            return Err(ERROR_SEARCH_EMPTY.into());
+        }
+        let mut failure = vec![0; pattern.len()];
+        let mut k = 0;
+        for i in 1..pattern.len() {
+            while k > 0 && pattern[i] != pattern[k] {
+                k = failure[k - 1];
+            }
+            if pattern[i] == pattern[k] {
+                k += 1;
+            }
+            failure[i] = k;
+        }
+        Ok(Matcher { pattern: pattern.to_vec(), failure, matched: 0, position: 0 })
+    }
+
+    /// Feed the next piece of data, pushing the offset of every match ending in it onto hits
+    pub fn feed(&mut self, data: &[u8], hits: &mut Vec<u64>) {
+        for &b in data {
+            while self.matched > 0 && self.pattern[self.matched] != b {
+                self.matched = self.failure[self.matched - 1];
+            }
+            if self.pattern[self.matched] == b {
+                self.matched += 1;
+            }
+            self.position += 1;
+            if self.matched == self.pattern.len() {
+                hits.push(self.position - self.pattern.len() as u64);
+                self.matched = self.failure[self.matched - 1];
+            }
+        }
+    }
+
+    /// Start over on new data
+    pub fn reset(&mut self) {
+        self.matched = 0;
+        self.position = 0;
+    }
+}
+
+impl<T: BlockHasher> Store<T> {
+    /// Every place pattern occurs in a live payload the access policy
+    /// allows, by block then offset
+    pub fn search(&mut self, pattern: &[u8]) -> Result<Vec<SearchHit>, Box<dyn std::error::Error>> {
+        let mut matcher = Matcher::new(pattern)?;
+        let mut hits = Vec::new();
+        let mut offsets = Vec::new();
+        for index in 0..self.len() {
+            let dh = self.block_header(index)?;
+            if !dh.is_live() || !self.allowed(index, &dh) {
+                continue;
+            }
+            matcher.reset();
+            self.stream_block(index, SEARCH_CHUNK_SIZE, |piece| matcher.feed(piece, &mut offsets))?;
+            hits.extend(offsets.drain(..).map(|offset| SearchHit { index, offset }));
+        }
+        Ok(hits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+
+    #[test]
+    fn matches_span_pieces_and_skip_dead_blocks() {
+        let mut m = Matcher::new(b"aab").unwrap();
+        let mut hits = Vec::new();
+        for piece in [&b"xaa"[..], b"ba", b"ab", b"aaab"] {
+            m.feed(piece, &mut hits);
+        }
+        assert_eq!(hits, vec![1, 4, 8]);
+        assert!(Matcher::new(b"").is_err());
+
+        std::fs::create_dir_all("testout").unwrap();
+        let mut s = Store::<B3BlockHasher>::create("testout/search.st".to_string()).unwrap();
+        s.put(b"needle in a haystack").unwrap();
+        s.put(b"nothing here").unwrap();
+        let dead = s.put(b"needle").unwrap();
+        let mut big = vec![0u8; SEARCH_CHUNK_SIZE * 2];
+        big[SEARCH_CHUNK_SIZE - 2..SEARCH_CHUNK_SIZE + 4].copy_from_slice(b"needle");
+        s.put(&big).unwrap();
+        s.put(b"anana").unwrap();
+        s.delete_many(&[dead]).unwrap();
+
+        let found = s.search(b"needle").unwrap();
+        assert_eq!(
+            found,
+            vec![SearchHit { index: 0, offset: 0 }, SearchHit { index: 3, offset: SEARCH_CHUNK_SIZE as u64 - 2 }]
+        );
+        assert_eq!(s.search(b"ana").unwrap().iter().map(|h| h.offset).collect::<Vec<_>>(), vec![0, 2]);
+    }
+}
@@ -0,0 +1,107 @@
+//Copyright 2021 Matthew Petricone
+//! Every live block of a store held in memory.
+//!
+//! For small stores whose callers want to let go of the file and serve
+//! lookups from RAM. Payloads are shared, so handing one out is cheap.
+use crate::crypto::BlockHasher;
+use crate::store::{BlockId, Store, StoreIO};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Live blocks of a store as they were when it was loaded, see Store::load_all
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct StoreSnapshot {
+    /// payloads in block order
+    blocks: Vec<Arc<[u8]>>,
+    /// position in blocks of each block index
+    by_id: HashMap<BlockId, usize>,
+    /// block index of each payload
+    ids: Vec<BlockId>,
+}
+
+impl StoreSnapshot {
+    /// Payload of the block at index, None if it wasn't live
+    pub fn get(&self, index: BlockId) -> Option<&[u8]> {
+        self.by_id.get(&index).map(|i| &self.blocks[*i][..])
+    }
+
+    /// Payload of the block at index, shared rather than borrowed
+    pub fn get_shared(&self, index: BlockId) -> Option<Arc<[u8]>> {
+        self.by_id.get(&index).map(|i| Arc::clone(&self.blocks[*i]))
+    }
+
+    /// true if the block at index was live
+    pub fn contains(&self, index: BlockId) -> bool {
+        self.by_id.contains_key(&index)
+    }
+
+    /// Number of blocks held
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// true if no blocks are held
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Block indexes held, in order
+    pub fn ids(&self) -> &[BlockId] {
+        &self.ids
+    }
+
+    /// Block index and payload of every block held, in order
+    pub fn iter(&self) -> impl Iterator<Item = (BlockId, &[u8])> {
+        self.ids.iter().copied().zip(self.blocks.iter().map(|b| &b[..]))
+    }
+
+    /// Total payload bytes held
+    pub fn size(&self) -> usize {
+        self.blocks.iter().map(|b| b.len()).sum()
+    }
+}
+
+impl<T: BlockHasher> Store<T> {
+    /// Read every live block into memory.
+    ///
+    /// Each payload is verified, and one that fails is an error.
+    /// Blocks keep their indexes, so lookups work as they did on the store.
+    pub fn load_all(&mut self) -> Result<StoreSnapshot, Box<dyn std::error::Error>> {
+        let mut snap = StoreSnapshot::default();
+        for i in 0..self.len() {
+            if !self.is_live(i)? {
+                continue;
+            }
+            let data = self.get(i)?;
+            snap.by_id.insert(i, snap.blocks.len());
+            snap.blocks.push(Arc::from(data));
+            snap.ids.push(i);
+        }
+        Ok(snap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+
+    #[test]
+    fn snapshot_outlives_store() {
+        std::fs::create_dir_all("testout").unwrap();
+        let mut s = Store::<B3BlockHasher>::create("testout/snapshot.st".to_string()).unwrap();
+        s.put(b"one").unwrap();
+        s.put(b"two").unwrap();
+        s.put(b"three").unwrap();
+        s.delete_many(&[1]).unwrap();
+        let snap = s.load_all().unwrap();
+        s.close().unwrap();
+        assert_eq!(snap.len(), 2);
+        assert_eq!(snap.get(0), Some(&b"one"[..]));
+        assert_eq!(snap.get(1), None);
+        assert_eq!(&snap.get_shared(2).unwrap()[..], b"three");
+        assert_eq!(snap.ids(), &[0, 2]);
+        assert_eq!(snap.size(), 8);
+        assert_eq!(snap.iter().map(|(i, _)| i).collect::<Vec<_>>(), vec![0, 2]);
+    }
+}
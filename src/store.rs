@@ -1,327 +1,6861 @@
-// Coyright 2021 Matthew Petricone
-use crate::data_header::DataHeader;
-use crate::data_header::{BlockFlags, BlockSerializer};
-use crate::crypto::BlockHasher;
-use std::convert::TryFrom;
-use std::fmt;
-use std::fs::{ File, OpenOptions };
-use std::io::{Error, ErrorKind};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::marker::PhantomData;
-
-// TODO: is there a better way in rust?
-static STORE_VERSIONTAG: &str = "FSTOREV.01BINARYR01";
-static STORE_VERSIONNUM: u32 = 1;
-
-// TODO: should these be static?
-static ERROR_FSTORE_VERSION: &str = "Unexpected version info.";
-static ERROR_FSTORE_INVALID: &str = "Invalid file descriptor.";
-static ERROR_FSTORE_INVSIZE: &str = "Unexpected data size encountered.";
-static ERROR_OUTOFBOUNDS: &str = "Value out of bounds.";
-
-
-/// Used by some fstore methods
-#[derive(Debug)]
-pub struct StoreError {
-    error: String,
-}
-
-impl StoreError {
-    /// Create new StoreError
-    fn new(error: String) -> StoreError {
-        StoreError { error }
-    }
-}
-
-impl fmt::Display for StoreError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.error)
-    }
-}
-
-impl std::error::Error for StoreError {}
-
-/// Store manages a file store.
-///
-/// Data is written in blocks of arbitrary size.
-///
-/// Consult DataHeader for block details.
-///
-/// There is a 32bit checksum availible for each block.
-///
-pub struct Store<T: BlockHasher> {
-    /// File data resides in
-    file: File,
-    /// the last stream position
-    data_start_address: u64,
-    /// Vector of written block addresses
-    block_addresses: Vec<u64>,
-    phantom: PhantomData<T>,
-    
-}
-
-/// Utilities for a Store
-pub trait StoreIO<T: BlockHasher> {
-    /// Delete block at index
-    fn delete_block(&mut self, index: usize) -> Result<(), Box<dyn std::error::Error>>;
-    /// Should return the number of blocks availible for access
-    fn len(&self) -> usize;
-    /// Get the address of the block at index
-    fn block_address(&self, index: usize) -> Option<&u64>;
-
-    fn read_data_header(
-        &mut self,
-        data_header: &mut DataHeader<T>,
-    ) -> Result<(), Box<dyn std::error::Error>>;
-    fn read(&mut self, data: &mut Vec<u8>) -> Result<usize, Error>;
-    fn read_at_index(&mut self, index: usize, data: &mut Vec<u8>) -> Result<usize,Box<dyn std::error::Error>>;
-
-    fn seek(&mut self, index: usize) -> Result<u64, Box<dyn std::error::Error>>;
-}
-
-impl<T: BlockHasher> Store<T> {
-    /// Open existing Store file
-    ///
-    /// Will return an error if the file is not a Store file
-    pub fn new(filename: String) -> Result<Store<T>, Box<dyn std::error::Error>> {
-        let v = File::open(filename)?;
-        let mut st = Store::<T> {
-            file: v,
-            data_start_address: 0,
-            block_addresses: Vec::new(),
-            phantom: PhantomData,
-        };
-        let fd = st.read_file_descriptor()?;
-        if !Store::<T>::validate_file_descriptor(fd) {
-            return Err(Box::new(Error::new(
-                ErrorKind::InvalidData,
-                ERROR_FSTORE_INVALID,
-            )));
-        }
-        st.index_blocks(0)?;
-        Ok(st)
-    }
-
-    ///Create new Store file
-    ///
-    ///Will overwrite an existing store.
-    pub fn create(filename: String) -> Result<Store<T>, Error> {
-        let mut f = OpenOptions::new().write(true).read(true).create(true).open(filename)?;
-        Store::<T>::write_file_descriptor(&mut f)?;
-        Ok(Store::<T> {
-            file: f,
-            data_start_address: 0,
-            block_addresses: Vec::new(),
-            phantom: PhantomData,
-        })
-    }
-
-    /// Writes the file descriptor (should be at the start of the file)
-    fn write_file_descriptor(file: &mut File) -> Result<(), Error> {
-        file.write(&STORE_VERSIONNUM.to_le_bytes())?;
-        // Panic here, there is no way this should fail unless we've typo'd
-        let sz = u64::try_from(STORE_VERSIONTAG.as_bytes().len()).unwrap();
-        file.write(&sz.to_le_bytes())?;
-        file.write(&STORE_VERSIONTAG.as_bytes())?;
-        Ok(())
-    }
-
-    /// reads the file descriptor
-    /// returns a tuple
-    fn read_file_descriptor(&mut self) -> Result<(u32, String), Error> {
-        // it's only at the start of the file
-        self.file.seek(SeekFrom::Start(0))?;
-        let mut buff = [0u8; 4];
-        let mut sz_buff = [0u8; 8];
-        self.file.read(&mut buff)?;
-        self.file.read(&mut sz_buff)?;
-        let mut str_buff = vec![0u8; usize::try_from(u64::from_le_bytes(sz_buff)).unwrap()];
-        self.file.read(&mut str_buff)?;
-        self.data_start_address = self.file.seek(SeekFrom::Current(0))?;
-        //Convert this error into a somewhat relevant io::Error
-        if let Ok(s) = String::from_utf8(str_buff) {
-            Ok((u32::from_le_bytes(buff), s))
-        } else {
-            return Err(Error::new(ErrorKind::InvalidData, ERROR_FSTORE_VERSION));
-        }
-    }
-
-    /// checks value to see if it's a valid file descriptor
-    pub fn validate_file_descriptor(value: (u32, String)) -> bool {
-        //NOTE: this should get more complicated when there are more versions;
-        if value == (STORE_VERSIONNUM, STORE_VERSIONTAG.to_string()) {
-            return true;
-        }
-        false
-    }
-
-    /// Read address of blocks for index
-    fn index_blocks(&mut self, startpos: u64) -> Result<(), Box<dyn std::error::Error>> {
-        // if startpos is 0, set it to the first block, otherwise it's a valid block start
-        // at this point, i'm failry sure an incorrect block location will still fill up a block
-        // albeit with incorect info if  there is enough data in the file
-        self.block_addresses.clear();
-        let mut curpos = if startpos == 0 {
-            self.data_start_address
-        } else {
-            startpos
-        };
-        // size of read ahead data
-        let buffsize = DataHeader::<T>::read_ahead_size();
-        // get metadata for file once
-        let md = self.file.metadata()?;
-        // Insert the first block address
-        self.block_addresses.push(curpos);
-        // We are assuming the file will not change size during this loop
-        while curpos < md.len() {
-            //TODO: is it faster to reuse a buffer?
-            let mut buffer = vec![0u8; buffsize];
-            // read the data, then pass it to dataBlock::read_ahead
-            self.file.read(&mut buffer)?;
-            // TODO: I think this logic is wrong, we want a more generic way to do this.
-            let tbs = DataHeader::<T>::read_ahead(&buffer)?;
-            // update curpos with next DataHeader addess, then push that onto the list
-            curpos = self.file.seek(SeekFrom::Current(tbs))?;
-            self.block_addresses.push(curpos);
-        }
-        self.file.seek(SeekFrom::Start(self.data_start_address))?;
-        Ok(())
-    }
-}
-
-impl<T: BlockHasher> Write for Store<T>  {
-    /// Writes data in buf to file, encapsulated in a DataHeader
-    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
-        if let Ok(mut bd) = DataHeader::<T>::new() {
-            if let Ok(sd) = bd.serialize(buf) {
-            self.file.write(sd)?;
-            } else {
-                return Err(Error::new(ErrorKind::InvalidInput, ERROR_FSTORE_INVSIZE));
-            }
-            let retval = self.file.write(&buf);
-            self.block_addresses.push(self.file.seek(SeekFrom::Current(0))?);
-            retval
-        } else {
-            return Err(Error::new(ErrorKind::InvalidInput, ERROR_FSTORE_INVSIZE));
-        }
-    }
-
-    /// Calls flush on self.file
-    fn flush(&mut self) -> Result<(), Error> {
-        self.file.flush()
-    }
-}
-
-impl<T: BlockHasher> StoreIO<T> for Store<T> {
-    fn delete_block(&mut self, index: usize) -> Result<(), Box<dyn std::error::Error>> {
-        if let Some(address) = self.block_addresses.get(index) {
-            self.file.seek(SeekFrom::Start(
-                *address + u64::try_from(DataHeader::<T>::delete_offset())?,
-            ))?;
-            self.file.write(&DataHeader::<T>::delete_flag().to_le_bytes())?;
-            self.file.seek(SeekFrom::Start(0))?;
-        } else {
-            return Err(Box::new(StoreError::new(ERROR_OUTOFBOUNDS.to_string())));
-        }
-        Ok(())
-    }
-
-    fn block_address(&self, index: usize) -> Option<&u64> {
-        self.block_addresses.get(index)
-    }
-
-    fn len(&self) -> usize {
-        self.block_addresses.len()
-    }
-    
-    fn seek(&mut self, index: usize) -> Result<u64, Box<dyn std::error::Error>> {
-        if let Some(a) = self.block_addresses.get(index) {
-            Ok(self.file.seek(SeekFrom::Start(*a))?)
-        } else {
-            return Err(Box::new(StoreError::new(ERROR_OUTOFBOUNDS.to_string())));
-        }
-    }
-
-    /// Reads data into buf according to surrounding DataHeader
-    fn read_data_header(
-        &mut self,
-        data_header: &mut DataHeader<T>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut db_buf = vec![0u8; DataHeader::<T>::size()];
-        self.file.read(&mut db_buf)?;
-        data_header.deserialize(&db_buf)?;
-        Ok(())
-    }
-
-    fn read(&mut self, data: &mut Vec<u8>) -> Result<usize, Error> {
-        self.file.read(data)
-    }
-
-    fn read_at_index(&mut self,index: usize, data: &mut Vec<u8>) -> Result<usize, Box<dyn std::error::Error>> {
-        if let Some(a) = self.block_addresses.get(index) {
-            self.file.seek(SeekFrom::Start(*a))?;
-            Ok(self.read(data)?)
-        } else {
-            return Err(Box::new(StoreError::new(ERROR_OUTOFBOUNDS.to_string())));
-        }
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::data_header::DataHeader;
-    use crate::store::Store;
-    use crate::crypto::B3BlockHasher;
-    use std::io::Write;
-
-    fn fill_test_vector(data: &mut Vec<u8>) {
-        data.append(&mut vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 255]);
-    }
-    #[test]
-    fn can_write_to_store() {
-        let mut s = Store::<B3BlockHasher>::create("testout/store.st".to_string()).unwrap();
-        let mut buf = vec![0, 1, 3, 4, 5, 11, 33, 0];
-        s.write(&mut buf).unwrap();
-        s.write(&mut buf).unwrap();
-    }
-
-    #[test]
-    fn can_read_from_store() {
-        let mut testval = Vec::new();
-        fill_test_vector(&mut testval);
-        {
-            let mut s = Store::<B3BlockHasher>::create("testout/store.test.st".to_string()).unwrap();
-            for _i in 1..10 {
-                s.write(&testval).unwrap();
-                s.write(&testval).unwrap();
-            }
-        }
-
-        let mut db = DataHeader::<B3BlockHasher>::new().unwrap();
-        let mut s = Store::<B3BlockHasher>::new("testout/store.test.st".to_string()).unwrap();
-        s.read_data_header(&mut db).unwrap();
-        println!("data header size: {:?}", db);
-        let mut data = vec![0u8; db.data_size().unwrap()];
-        s.read(&mut data).unwrap();
-        assert_eq!(testval, data);
-    }
-
-    #[test]
-    fn can_delete_block() {
-        let v = [
-            vec!(1, 244, 231,13,42,1,2,3,4,5,6,7),
-            vec!(1,2,3,4,5,6,7,8,9,0),
-            vec!(11,12,13,14,15,16,17,18,19,20),
-        ];
-        let mut s = Store::<B3BlockHasher>::create("testout/delete.tst".to_string()).unwrap();
-        for i in v {
-            s.write(&i).unwrap();
-        }
-        s.delete_block(2).unwrap();
-        let mut db = DataHeader::<B3BlockHasher>::new().unwrap();
-        s.seek(2).unwrap();
-        s.read_data_header(&mut db).unwrap();
-        assert_eq!(DataHeader::<B3BlockHasher>::delete_flag(),db.state_flag );
-    }
-}
+// Coyright 2021 Matthew Petricone
+use crate::data_header::DataHeader;
+use crate::data_header::{header_codec, BinaryHeaderCodec, BlockFlags, HeaderCodec};
+use crate::crypto::{B3BlockHasher, BlockHasher};
+use crate::bloom::BloomFilter;
+use crate::access_stats::AccessStats;
+use crate::audit::{AuditAction, AuditRecord};
+use crate::counters::{CountingFile, IoCounters};
+use crate::column::cf_prefix;
+use crate::content_type::ContentType;
+use crate::mmap::Mmap;
+use crate::platform;
+use crate::pool::{BufferPool, PoolStats, SharedPool};
+use crate::zeroize::{wipe, Scratch};
+#[cfg(feature = "fault-injection")]
+use crate::fault::{FaultPlan, FaultState, WriteLog};
+#[cfg(feature = "ecc")]
+use crate::ecc::{EccConfig, Parity, ReedSolomon, PARITY_CHECKSUM_SIZE};
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::BuildHasher;
+use std::fmt;
+use std::fs::{ File, OpenOptions };
+use std::io::{Error, ErrorKind, IoSlice};
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// TODO: is there a better way in rust?
+pub(crate) static STORE_VERSIONTAG: &str = "FSTOREV.01BINARYR01";
+pub(crate) static STORE_VERSIONNUM: u32 = 2;
+/// Version of stores whose descriptor ends at the tag, written before it
+/// had a codec, flags and features. They read as codec 0 with none set,
+/// and are written only after Store::upgrade.
+pub(crate) static STORE_VERSIONNUM_V1: u32 = 1;
+
+/// Descriptor flag set while a Store is open for writing, cleared by close
+pub(crate) const DESCRIPTOR_FLAG_DIRTY: u64 = 0b1;
+
+/// Feature bits in the low half are required: a reader that doesn't
+/// know one of them must refuse the store.
+/// Bits in the high half are optional and may be ignored.
+pub const FEATURES_REQUIRED_MASK: u64 = 0xFFFF_FFFF;
+/// Payloads are hashed with a personalized hasher, and the descriptor ends
+/// with u16 length and the personalization, see Store::create_personalized
+pub const FEATURE_PERSONALIZED: u64 = 1;
+/// The store ends with a valid index footer
+pub const FEATURE_INDEX_FOOTER: u64 = 1 << 32;
+/// Features this version of fstore understands
+pub const FEATURES_SUPPORTED: u64 = FEATURE_INDEX_FOOTER | FEATURE_PERSONALIZED;
+
+// TODO: should these be static?
+static ERROR_FSTORE_VERSION: &str = "Unexpected version info.";
+static ERROR_FSTORE_INVALID: &str = "Invalid file descriptor.";
+static ERROR_FSTORE_V1: &str = "Store has the version 1 layout, upgrade it to write.";
+static ERROR_FSTORE_INVSIZE: &str = "Unexpected data size encountered.";
+static ERROR_OUTOFBOUNDS: &str = "Value out of bounds.";
+static ERROR_FSTORE_LOCKED: &str = "Store is locked by another writer.";
+static ERROR_FSTORE_SEEK: &str = "Seek to before the start of the payload.";
+static ERROR_FSTORE_FEATURES: &str = "Store requires unsupported features.";
+static ERROR_FSTORE_CODEC: &str = "Unknown header codec.";
+static ERROR_FSTORE_CANCELLED: &str = "Open cancelled.";
+static ERROR_FSTORE_READONLY: &str = "Store is not open for writing.";
+static ERROR_FSTORE_TRUNCATED: &str = "Block payload is shorter than its header.";
+static ERROR_FSTORE_CHECKSUM: &str = "Block failed verification.";
+static ERROR_FSTORE_NOTLIVE: &str = "Block is deleted or quarantined.";
+static ERROR_FSTORE_TOOLARGE: &str = "Block is larger than the maximum block size.";
+static ERROR_FSTORE_CONFLICT: &str = "Block changed since it was read.";
+static ERROR_FSTORE_DUPLICATE: &str = "Block named more than once.";
+static ERROR_FSTORE_GENERATION: &str = "Block not retained at that generation.";
+static ERROR_FSTORE_PERSONALIZATION: &str = "Store personalization doesn't match.";
+static ERROR_FSTORE_UNPERSONALIZABLE: &str = "Hasher can't be personalized.";
+static ERROR_FSTORE_NODIGEST: &str = "Block has no strong digest.";
+static ERROR_FSTORE_UNTYPED: &str = "Header codec doesn't record content types.";
+static ERROR_FSTORE_CONTENTTYPE: &str = "Block has another content type.";
+static ERROR_FSTORE_DENIED: &str = "Reading the block is denied by the access policy.";
+static ERROR_FSTORE_NOACCESS: &str = "Header codec doesn't record owners and permissions.";
+static ERROR_FSTORE_NOSESSION: &str = "Header codec doesn't record write sessions.";
+static ERROR_FSTORE_SESSIONOPEN: &str = "A write session is already open.";
+static ERROR_FSTORE_ALIGNMENT: &str = "Alignment must be a power of two.";
+static ERROR_FSTORE_PINNED: &str = "Block is pinned by a BlockGuard.";
+static ERROR_FSTORE_MOVING: &str = "Blocks are being moved and can't be pinned.";
+static ERROR_FSTORE_EXISTS: &str = "File already exists.";
+#[cfg(feature = "ecc")]
+static ERROR_FSTORE_ECCHASH: &str = "Hasher output is empty, so ecc shards can't be checked.";
+
+/// Marks a relocation journal, see Store::compact_in_place
+static RELOCATION_MAGIC: &[u8; 8] = b"FSTREL01";
+
+/// Starts the sidecar naming the last index checkpoint, see Store::checkpoint
+static CHECKPOINT_MAGIC: &[u8; 8] = b"FSTCKP01";
+
+/// Marks the last bytes of a store closed with a valid index footer
+pub(crate) static INDEX_FOOTER_MAGIC: &[u8; 8] = b"FSTIDX01";
+/// Tags for sections of the index footer after the block addresses
+pub(crate) const FOOTER_SECTION_BLOOM: u32 = 1;
+pub(crate) const FOOTER_SECTION_QUARANTINE: u32 = 2;
+pub(crate) const FOOTER_SECTION_SCRUB: u32 = 3;
+pub(crate) const FOOTER_SECTION_TIMES: u32 = 4;
+pub(crate) const FOOTER_SECTION_DELETE_TIMES: u32 = 5;
+pub(crate) const FOOTER_SECTION_LIFETIME: u32 = 6;
+
+
+/// What went wrong, for errors callers may want to handle
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreErrorKind {
+    Other,
+    /// a block's payload is shorter than its header says
+    Truncated { expected: usize, found: usize },
+    /// a block's payload doesn't match its checksum
+    Checksum,
+    /// the block was deleted or quarantined
+    NotLive,
+    /// a payload, or a header's claimed payload size, is over the store's maximum
+    BlockTooLarge { size: u64, max: u64 },
+    /// the block's hash wasn't the one expected, see Store::update_if
+    Conflict,
+    /// the block's content type wasn't the one expected, see Store::get_typed
+    ContentType { expected: u16, found: u16 },
+    /// the store's access policy denied reading the block, see StoreOptions::access_policy
+    AccessDenied,
+    /// a block is pinned by a BlockGuard, or blocks are being moved so none can be, see Store::pin
+    Pinned,
+    /// the store wasn't opened for writing
+    ReadOnly,
+    /// there is no block at that index
+    OutOfBounds,
+    /// another writer has the store open
+    Locked,
+    /// the progress callback cancelled the operation
+    Cancelled,
+    /// the file isn't a store, or is of a version this fstore can't read
+    InvalidDescriptor,
+    /// a version 1 store was opened for writing, see Store::upgrade
+    NeedsUpgrade,
+    /// the store's header codec isn't one header_codec knows
+    UnknownCodec,
+    /// the store requires features this fstore doesn't have
+    UnsupportedFeatures,
+    /// the personalization asked for isn't the store's
+    Personalization,
+    /// the hasher can't be personalized
+    Unpersonalizable,
+    /// an alignment that isn't a power of two
+    Alignment,
+    /// the header codec doesn't record content types
+    NoContentTypes,
+    /// the header codec doesn't record owners and permissions
+    NoAccess,
+    /// the header codec doesn't record write sessions
+    NoSession,
+    /// a write session is already open, see Store::begin_session
+    SessionOpen,
+    /// the block isn't kept at that generation, see Store::read_at_generation
+    NotRetained,
+    /// the block has no strong digest
+    NoDigest,
+    /// a size that doesn't fit where it has to go
+    InvalidSize,
+    /// a block named more than once in one call
+    Duplicate,
+    /// the hasher's output is empty, so it can't check ecc shards
+    EmptyHash,
+    /// the file to write already exists
+    Exists,
+    /// a seek to before the start of a payload
+    SeekBeforeStart,
+}
+
+/// Where an error happened, see StoreError::context
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// the block it is about
+    pub index: Option<BlockId>,
+    /// address in the file of the block it is about
+    pub offset: Option<u64>,
+    /// a value read that wasn't accepted, with what it is: "codec",
+    /// "features" or "generation"
+    pub value: Option<(&'static str, u64)>,
+}
+
+/// Used by some fstore methods
+///
+/// Display is the message followed by the numbers in the kind and context,
+/// as "Block failed verification. (index 3, offset 4096)". Applications
+/// wanting their own wording can key it on kind, or on message, which is
+/// one of a fixed set, and fill in the numbers from context.
+#[derive(Debug)]
+pub struct StoreError {
+    message: &'static str,
+    kind: StoreErrorKind,
+    context: ErrorContext,
+}
+
+impl StoreError {
+    fn with_kind(message: &'static str, kind: StoreErrorKind) -> StoreError {
+        StoreError { message, kind, context: ErrorContext::default() }
+    }
+
+    /// The error about block index
+    fn at(mut self, index: BlockId) -> StoreError {
+        self.context.index = Some(index);
+        self
+    }
+
+    /// The error about the block at address offset
+    fn offset(mut self, offset: Option<u64>) -> StoreError {
+        self.context.offset = offset;
+        self
+    }
+
+    /// The error about value, named name
+    fn value(mut self, name: &'static str, value: u64) -> StoreError {
+        self.context.value = Some((name, value));
+        self
+    }
+
+    /// What went wrong
+    pub fn kind(&self) -> StoreErrorKind {
+        self.kind
+    }
+
+    /// The message without numbers
+    pub fn message(&self) -> &'static str {
+        self.message
+    }
+
+    /// Where it went wrong
+    pub fn context(&self) -> ErrorContext {
+        self.context
+    }
+
+    /// The StoreError e is, or an io::Error wraps, if there is one
+    pub fn find<'a>(e: &'a (dyn std::error::Error + 'static)) -> Option<&'a StoreError> {
+        e.downcast_ref::<StoreError>()
+            .or_else(|| e.downcast_ref::<Error>()?.get_ref()?.downcast_ref::<StoreError>())
+    }
+}
+
+/// For the std::io traits and functions returning io::Error, which wrap
+/// the StoreError with the nearest io kind
+impl From<StoreError> for Error {
+    fn from(e: StoreError) -> Error {
+        let kind = match e.kind {
+            StoreErrorKind::ReadOnly | StoreErrorKind::AccessDenied => ErrorKind::PermissionDenied,
+            StoreErrorKind::Locked => ErrorKind::WouldBlock,
+            StoreErrorKind::Cancelled => ErrorKind::Interrupted,
+            StoreErrorKind::Exists => ErrorKind::AlreadyExists,
+            StoreErrorKind::OutOfBounds | StoreErrorKind::NotLive => ErrorKind::NotFound,
+            StoreErrorKind::Truncated { .. }
+            | StoreErrorKind::Checksum
+            | StoreErrorKind::InvalidDescriptor
+            | StoreErrorKind::NeedsUpgrade
+            | StoreErrorKind::UnknownCodec
+            | StoreErrorKind::UnsupportedFeatures
+            | StoreErrorKind::Personalization => ErrorKind::InvalidData,
+            StoreErrorKind::Unpersonalizable
+            | StoreErrorKind::Alignment
+            | StoreErrorKind::InvalidSize
+            | StoreErrorKind::Duplicate
+            | StoreErrorKind::SeekBeforeStart => ErrorKind::InvalidInput,
+            _ => ErrorKind::Other,
+        };
+        Error::new(kind, e)
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(index) = self.context.index {
+            parts.push(format!("index {}", index));
+        }
+        if let Some(offset) = self.context.offset {
+            parts.push(format!("offset {}", offset));
+        }
+        match self.context.value {
+            Some(("features", v)) => parts.push(format!("features {:#x}", v)),
+            Some((name, v)) => parts.push(format!("{} {}", name, v)),
+            None => {}
+        }
+        match self.kind {
+            StoreErrorKind::Truncated { expected, found } => parts.push(format!("{} of {} bytes", found, expected)),
+            StoreErrorKind::BlockTooLarge { size, max } => parts.push(format!("{} > {}", size, max)),
+            StoreErrorKind::ContentType { expected, found } => {
+                parts.push(format!("{} not {}", ContentType(found), ContentType(expected)))
+            }
+            _ => {}
+        }
+        if parts.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{} ({})", self.message, parts.join(", "))
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+/// Store manages a file store.
+///
+/// Data is written in blocks of arbitrary size.
+///
+/// Consult DataHeader for block details.
+///
+/// There is a 32bit checksum availible for each block.
+///
+/// Stores are closed by `close`, or on drop as a best effort.
+/// A writable Store holds an exclusive lock on its file until then,
+/// and the descriptor is marked dirty so a crash can be detected.
+pub struct Store<T: BlockHasher> {
+    /// File data resides in
+    file: CountingFile,
+    /// version number from the file descriptor
+    version: u32,
+    /// flags stored in the file descriptor
+    descriptor_flags: u64,
+    /// feature bitmap stored in the file descriptor
+    features: u64,
+    /// id of the header codec from the file descriptor
+    codec_id: u32,
+    /// layout of block headers, chosen at creation
+    codec: Box<dyn HeaderCodec>,
+    /// size of every block header in this store
+    header_size: usize,
+    /// true if the descriptor was dirty when we opened it
+    opened_dirty: bool,
+    /// the last stream position
+    data_start_address: u64,
+    /// shared with handles from try_clone
+    index: Arc<RwLock<BlockIndex>>,
+    /// file name, so try_clone can open its own handle
+    path: String,
+    /// true if the file was opened for writing
+    writable: bool,
+    /// set once close has run, so drop doesn't repeat it
+    closed: bool,
+    /// parity is written for new blocks when set
+    #[cfg(feature = "ecc")]
+    ecc: Option<ReedSolomon>,
+    /// largest payload accepted by put or believed in a header
+    max_block_size: Option<u64>,
+    /// reads per block, when enabled
+    access_stats: Option<AccessStats>,
+    /// told when blocks move
+    observers: Vec<Box<dyn StoreObserver>>,
+    /// context payloads are hashed with, from the file descriptor
+    personalization: Option<String>,
+    /// a strong digest is written for new blocks when set
+    strong_digests: bool,
+    /// when to write index checkpoints
+    checkpoint_policy: CheckpointPolicy,
+    /// blocks put and bytes appended since the last checkpoint
+    since_checkpoint: (usize, u64),
+    /// set when the store was opened unclean
+    recovery: Option<RecoveryReport>,
+    /// asked before payloads are read
+    access_policy: Option<AccessPolicy>,
+    /// what bytes written through this handle were for
+    space: SpaceCounters,
+    /// recent payloads put, see set_dedup_window
+    dedup: Option<DedupWindow>,
+    /// buffers reused for reads, shared with handles from try_clone
+    pool: Option<SharedPool>,
+    /// payloads of new blocks start at a multiple of this
+    payload_alignment: Option<u64>,
+    /// read_aligned maps the file when set
+    use_mmap: bool,
+    /// the map read_aligned last used, and the index epoch it was made at
+    map: Option<(Mmap, u64)>,
+    /// where read_aligned copies payloads it can't map
+    aligned_buf: Vec<u8>,
+    /// picks the reads get verifies, every one when None
+    sampler: Option<ReadSampler>,
+    /// what get verified through this handle
+    verify_stats: VerifyStats,
+    /// changes are recorded in the audit log under this, when set
+    audit_label: Option<String>,
+    /// operations taking longer are reported to observers, when set
+    slow_threshold: Option<Duration>,
+    /// seed of deterministic output, see set_deterministic
+    deterministic: Option<u64>,
+    /// recorded in the blocks this handle puts, see begin_session
+    session: Option<u64>,
+    phantom: PhantomData<T>,
+}
+
+/// Index of a block in a Store
+pub type BlockId = usize;
+
+/// Result of Store::scrub
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ScrubReport {
+    /// number of blocks verified
+    pub blocks_checked: usize,
+    /// payload bytes verified
+    pub bytes_checked: u64,
+    /// blocks that failed verification and were rebuilt from parity
+    pub repaired: Vec<BlockId>,
+    /// blocks that failed verification and could not be repaired
+    pub failed: Vec<BlockId>,
+    /// true if the last block of the store was reached
+    pub pass_complete: bool,
+}
+
+/// Where the blocks of a store are, shared by every handle on it
+#[derive(Debug)]
+struct BlockIndex {
+    /// bumped whenever the index or a block changes
+    epoch: u64,
+    /// address the next block will be written at
+    data_end_address: u64,
+    /// Vector of written block addresses, always ascending: blocks are
+    /// indexed as they are appended and compaction keeps their order
+    block_addresses: Vec<u64>,
+    /// checksums of every block written
+    bloom: BloomFilter,
+    /// blocks marked corrupt, in the order they were quarantined
+    quarantined: Vec<BlockId>,
+    /// where scrub_incremental carries on from
+    scrub_position: BlockId,
+    /// when each block was written, unix seconds, 0 if unknown
+    append_times: Vec<u64>,
+    /// when deleted blocks were deleted, unix seconds, for those known
+    delete_times: BTreeMap<BlockId, u64>,
+    /// counters kept for the life of the store
+    lifetime: LifetimeCounters,
+    /// live BlockGuards per block
+    pins: HashMap<BlockId, usize>,
+    /// set while blocks are moved or reclaimed, when nothing may be pinned
+    moving: bool,
+}
+
+/// Counters kept for the life of a store, times in unix seconds, 0 if
+/// unknown. See Store::lifetime_stats
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct LifetimeCounters {
+    blocks_written: u64,
+    bytes_written: u64,
+    compactions: u64,
+    created: u64,
+    last_verified: u64,
+    /// the last id begin_session handed out
+    last_session: u64,
+}
+
+impl LifetimeCounters {
+    /// Layout of the lifetime footer section, each field a u64
+    fn to_bytes(self) -> Vec<u8> {
+        [self.blocks_written, self.bytes_written, self.compactions, self.created, self.last_verified, self.last_session]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect()
+    }
+
+    /// Stores written before sessions were counted have no last_session
+    fn from_bytes(b: &[u8]) -> Option<LifetimeCounters> {
+        if b.len() != 40 && b.len() != 48 {
+            return None;
+        }
+        let mut v: Vec<u64> = b.chunks_exact(8).map(|c| u64::from_le_bytes(c.try_into().unwrap())).collect();
+        v.resize(6, 0);
+        Some(LifetimeCounters {
+            blocks_written: v[0],
+            bytes_written: v[1],
+            compactions: v[2],
+            created: v[3],
+            last_verified: v[4],
+            last_session: v[5],
+        })
+    }
+}
+
+/// Blocks found by a walk over the headers
+#[derive(Default)]
+struct BlockScan {
+    addresses: Vec<u64>,
+    checksums: Vec<Vec<u8>>,
+    quarantined: Vec<BlockId>,
+    journaled: Vec<BlockId>,
+    /// where the next block would be written
+    end: u64,
+    /// the highest session any block records
+    last_session: u64,
+}
+
+/// What opening a store that wasn't closed cleanly found, see Store::recovery
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RecoveryReport {
+    /// address of the checkpoint the scan resumed from, None if the whole file was scanned
+    pub resumed_from: Option<u64>,
+    /// blocks found by the scan, less those torn
+    pub blocks_recovered: usize,
+    /// bytes after the last whole block, and those of torn blocks, cut off
+    /// by a writer and ignored by a reader
+    pub bytes_discarded: u64,
+    /// blocks at the end of the scan whose payload failed verification,
+    /// written only in part before the crash, and cut off by a writer
+    pub blocks_torn: usize,
+    /// blocks of the scan that failed verification with whole blocks after
+    /// them, quarantined by a writer
+    pub quarantined: Vec<BlockId>,
+}
+
+/// Result of Store::reindex
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ReindexReport {
+    /// blocks indexed that weren't before
+    pub new_blocks: usize,
+    /// the file had shrunk and was indexed from scratch
+    pub truncated: bool,
+}
+
+/// How payloads are erased, see Store::purge_tombstones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Erase {
+    /// overwrite with zeros
+    Zero,
+    /// overwrite with random bytes and sync, then with zeros, for devices
+    /// that might otherwise keep the old bytes where zeros are written
+    Secure,
+}
+
+/// Result of Store::purge_tombstones
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PurgeReport {
+    /// blocks whose payloads were erased
+    pub purged: Vec<BlockId>,
+    /// payload bytes overwritten, parity and digests included
+    pub bytes_erased: u64,
+}
+
+/// Result of Store::compact
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CompactReport {
+    /// new index of each old block, by old index, None if it was dropped
+    pub remap: Vec<Option<BlockId>>,
+    /// how much smaller the file got
+    pub bytes_reclaimed: u64,
+}
+
+impl CompactReport {
+    /// New index of the block that was at old, None if it was dropped
+    pub fn new_id(&self, old: BlockId) -> Option<BlockId> {
+        self.remap.get(old).copied().flatten()
+    }
+}
+
+/// Share of the data that can be dead before Store::health calls the store degraded
+pub const HEALTH_FRAGMENTATION_THRESHOLD: f64 = 0.5;
+
+/// Overall state from Store::health
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HealthStatus {
+    /// nothing wrong
+    Ok,
+    /// usable, but needs recovery, a finished compaction or compacting
+    Degraded,
+    /// some blocks are known to be bad
+    Corrupt,
+}
+
+/// Result of Store::health
+#[derive(Debug, Clone, PartialEq)]
+pub struct Health {
+    pub status: HealthStatus,
+    /// the store wasn't closed cleanly and this handle couldn't recover it
+    pub dirty: bool,
+    /// blocks that failed verification
+    pub quarantined: Vec<BlockId>,
+    /// share of the data that isn't live blocks or their parity, 0 to 1
+    pub fragmentation: f64,
+    /// an in place compaction was interrupted and not yet finished
+    pub journal_pending: bool,
+    /// one line per problem found, empty when status is Ok
+    pub problems: Vec<String>,
+}
+
+/// Result of Store::fsck
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FsckReport {
+    /// the store wasn't closed cleanly and this handle couldn't recover it
+    pub dirty: bool,
+    /// the store wasn't closed cleanly and was recovered when opened for writing
+    pub recovered: bool,
+    /// headers walked from the start of the data, system blocks included
+    pub headers_checked: usize,
+    /// delete journal blocks among them
+    pub journal_blocks: usize,
+    /// an in place compaction was interrupted and not yet finished
+    pub journal_pending: bool,
+    /// one line per problem with the chain of headers
+    pub chain_errors: Vec<String>,
+    /// payload verification, skipped if the chain is broken
+    pub scrub: ScrubReport,
+    /// blocks that failed verification and were quarantined by this check
+    pub quarantined: Vec<BlockId>,
+    /// blocks that fail verification and aren't quarantined
+    pub unresolved: Vec<BlockId>,
+}
+
+impl FsckReport {
+    /// true if nothing was wrong, fixed or not
+    pub fn is_clean(&self) -> bool {
+        self.is_consistent() && !self.recovered && self.scrub.failed.is_empty() && self.scrub.repaired.is_empty()
+    }
+
+    /// true if nothing is left wrong but known bad blocks in quarantine
+    pub fn is_consistent(&self) -> bool {
+        !self.dirty && !self.journal_pending && self.chain_errors.is_empty() && self.unresolved.is_empty()
+    }
+}
+
+/// What get has verified through a handle, see Store::verify_stats
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct VerifyStats {
+    /// payloads read by get
+    pub reads: u64,
+    /// of those, the ones checked against their checksums
+    pub verified: u64,
+    /// of those, the ones that failed
+    pub failures: u64,
+}
+
+/// What a slow operation was, see SlowOp
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowOpKind {
+    /// get reading and verifying a payload
+    Read,
+    /// appending a block, companions included
+    Write,
+    /// reindex scanning for blocks
+    Index,
+}
+
+/// An operation that took longer than the slow threshold, see
+/// Store::set_slow_threshold
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowOp {
+    pub kind: SlowOpKind,
+    /// the block read or written
+    pub block_id: Option<BlockId>,
+    /// its address in the file
+    pub offset: Option<u64>,
+    /// payload bytes read or written, or file bytes scanned by Index
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+/// Picks a random fraction of reads, see Store::set_verify_sample
+#[derive(Debug, Clone, Copy)]
+struct ReadSampler {
+    /// a read is picked when the next number is below this
+    threshold: u64,
+    /// xorshift state, never 0
+    state: u64,
+}
+
+impl ReadSampler {
+    fn new(fraction: f64) -> ReadSampler {
+        let threshold = if fraction >= 1.0 { u64::MAX } else { (fraction.max(0.0) * u64::MAX as f64) as u64 };
+        ReadSampler { threshold, state: RandomState::new().hash_one(0u64) | 1 }
+    }
+
+    /// true for the reads to verify
+    fn pick(&mut self) -> bool {
+        if self.threshold == u64::MAX {
+            return true;
+        }
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state < self.threshold
+    }
+}
+
+/// The last blocks put, by a hash of their payloads, see
+/// Store::set_dedup_window
+#[derive(Debug)]
+struct DedupWindow {
+    capacity: usize,
+    hasher: RandomState,
+    /// hash and block of each payload, oldest first
+    order: VecDeque<(u64, BlockId)>,
+    /// latest block put with each hash
+    blocks: HashMap<u64, BlockId>,
+}
+
+impl DedupWindow {
+    fn new(capacity: usize) -> DedupWindow {
+        DedupWindow {
+            capacity,
+            hasher: RandomState::new(),
+            order: VecDeque::with_capacity(capacity),
+            blocks: HashMap::with_capacity(capacity),
+        }
+    }
+
+    fn key(&self, data: &[u8]) -> u64 {
+        self.hasher.hash_one(data)
+    }
+
+    /// Remember id was put with a payload hashing to key, forgetting the
+    /// oldest block if the window is full
+    fn insert(&mut self, key: u64, id: BlockId) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.order.len() == self.capacity {
+            if let Some((old_key, old_id)) = self.order.pop_front() {
+                if self.blocks.get(&old_key) == Some(&old_id) {
+                    self.blocks.remove(&old_key);
+                }
+            }
+        }
+        self.order.push_back((key, id));
+        self.blocks.insert(key, id);
+    }
+}
+
+/// Bytes written through a Store handle, by what they were for
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct SpaceCounters {
+    logical: u64,
+    headers: u64,
+    companions: u64,
+    journal: u64,
+    index: u64,
+    filler: u64,
+    /// bytes written to files compact has since replaced
+    replaced: u64,
+}
+
+/// Result of Store::space_report.
+///
+/// The bytes written are counted since the handle was opened, through
+/// compactions, and aren't reset by reset_counters. The rest describes the
+/// file as it is now.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SpaceReport {
+    /// payload bytes given to put, write, swap and the like
+    pub logical_bytes: u64,
+    /// bytes written to the file in all, everything below included
+    pub physical_bytes: u64,
+    /// headers of those payloads' blocks
+    pub header_bytes: u64,
+    /// parity and digest blocks
+    pub companion_bytes: u64,
+    /// delete journal blocks
+    pub journal_bytes: u64,
+    /// index footers and checkpoints
+    pub index_bytes: u64,
+    /// filler blocks padding out space left by payloads rewritten in place
+    pub filler_bytes: u64,
+    /// the rest: blocks copied or moved by compaction, flag updates and the
+    /// file descriptor
+    pub rewrite_bytes: u64,
+    /// size of the file
+    pub file_bytes: u64,
+    /// payloads of live blocks
+    pub live_bytes: u64,
+    /// payloads of deleted and quarantined blocks, which compaction reclaims
+    pub dead_bytes: u64,
+}
+
+impl SpaceReport {
+    /// physical_bytes for every logical byte, 0 if nothing was put
+    pub fn write_amplification(&self) -> f64 {
+        if self.logical_bytes == 0 {
+            0.0
+        } else {
+            self.physical_bytes as f64 / self.logical_bytes as f64
+        }
+    }
+
+    /// share of the file that isn't live payload, 0 to 1
+    pub fn overhead(&self) -> f64 {
+        if self.file_bytes == 0 {
+            0.0
+        } else {
+            self.file_bytes.saturating_sub(self.live_bytes) as f64 / self.file_bytes as f64
+        }
+    }
+}
+
+/// Result of Store::lifetime_stats.
+///
+/// Kept in the index footer and checkpoints, so they survive close and
+/// reopen, and compaction. Counts since the last footer or checkpoint are
+/// lost if the store isn't closed cleanly.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LifetimeStats {
+    /// blocks ever put, including payloads swapped in place
+    pub blocks_written: u64,
+    /// payload bytes of those blocks
+    pub bytes_written: u64,
+    /// times the store was compacted, either way
+    pub compactions: u64,
+    /// when the store was created, None if not known
+    pub created: Option<SystemTime>,
+    /// when scrub last finished checking every block, None if it never has
+    pub last_verified: Option<SystemTime>,
+}
+
+/// Options of a single write, see Store::write_with
+///
+/// Fields left at their defaults make it a plain put:
+/// `WriteOpts { content_type: Some(ContentType(2)), ..Default::default() }`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct WriteOpts<'a> {
+    /// recorded in the header, as by put_typed
+    pub content_type: Option<ContentType>,
+    /// owner and permissions recorded in the header, as by put_owned
+    pub access: Option<(u32, u16)>,
+    /// column family the block is put in, as by Store::cf
+    pub namespace: Option<&'a str>,
+}
+
+/// Options of a single read, see Store::read_with
+#[derive(Debug)]
+pub struct ReadOpts<'a> {
+    /// check the payload against its checksum, true by default
+    pub verify: bool,
+    /// read deleted and quarantined blocks too, for forensics
+    pub include_dead: bool,
+    /// read the payload into this buffer, reusing its allocation, instead
+    /// of returning it
+    pub into: Option<&'a mut Vec<u8>>,
+}
+
+impl Default for ReadOpts<'_> {
+    fn default() -> Self {
+        ReadOpts { verify: true, include_dead: false, into: None }
+    }
+}
+
+/// Content type, owner, permissions and write session of a block, which
+/// swap and compaction keep
+#[derive(Debug, Default, Clone, Copy)]
+struct BlockAttrs {
+    content_type: u16,
+    owner: u32,
+    permissions: u16,
+    session: u64,
+}
+
+impl BlockAttrs {
+    fn of<T: BlockHasher>(dh: &DataHeader<T>) -> BlockAttrs {
+        BlockAttrs { content_type: dh.content_type(), owner: dh.owner(), permissions: dh.permissions(), session: dh.session() }
+    }
+
+    /// Set them in dh, for its next serialize
+    fn apply<T: BlockHasher>(&self, dh: &mut DataHeader<T>) {
+        dh.set_content_type(self.content_type);
+        dh.set_access(self.owner, self.permissions);
+        dh.set_session(self.session);
+    }
+}
+
+/// Bytes to write at dst, in place of those that ended at src + bytes.len().
+///
+/// Space between is covered with a filler block, see Store::apply_relocation.
+struct Relocation {
+    src: u64,
+    dst: u64,
+    bytes: Vec<u8>,
+}
+
+impl Drop for Relocation {
+    /// bytes may hold a payload
+    fn drop(&mut self) {
+        wipe(&mut self.bytes);
+    }
+}
+
+/// Store::swap, ready to commit
+#[derive(Default)]
+struct StagedSwap {
+    /// writes that commit it
+    moves: Vec<Relocation>,
+    /// index of each replacement once committed
+    ids: Vec<BlockId>,
+    /// blocks rewritten where they were
+    in_place: Vec<BlockId>,
+    /// payload bytes rewritten where they were
+    in_place_bytes: u64,
+    /// checksums of payloads rewritten in place
+    checksums: Vec<Vec<u8>>,
+}
+
+/// Remap, block addresses, append times and data end from Store::slide_blocks
+type SlidBlocks = (Vec<Option<BlockId>>, Vec<u64>, Vec<u64>, u64);
+
+/// Addresses and headers of parity and digest blocks, see Store::companions
+type Companions<T> = Vec<(u64, DataHeader<T>)>;
+
+/// A block and its header, see next_readable
+type Readable<T> = (BlockId, DataHeader<T>);
+
+/// A copy of a store's live blocks and the new index of each, see Store::copy_live
+type LiveCopy<T> = (Store<T>, Vec<Option<BlockId>>);
+
+/// Limits on what a Store keeps, see Store::enforce_retention
+///
+/// None means no limit.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct RetentionPolicy {
+    /// blocks written longer ago than this are deleted
+    pub max_age: Option<Duration>,
+    /// live payload bytes to keep, oldest blocks go first
+    pub max_total_bytes: Option<u64>,
+    /// live blocks to keep, oldest blocks go first
+    pub max_blocks: Option<usize>,
+}
+
+/// When a Store writes index checkpoints, see Store::checkpoint
+///
+/// A checkpoint is due when either limit is reached since the last one;
+/// None means no limit, so the default never writes one.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct CheckpointPolicy {
+    /// blocks put since the last checkpoint
+    pub every_blocks: Option<usize>,
+    /// bytes appended since the last checkpoint, headers included
+    pub every_bytes: Option<u64>,
+}
+
+/// Options for opening a Store
+#[derive(Default, Debug, Clone)]
+pub struct StoreOptions {
+    write: bool,
+    access_stats: bool,
+    max_block_size: Option<u64>,
+    personalization: Option<String>,
+    strong_digests: bool,
+    checkpoints: CheckpointPolicy,
+    access_policy: Option<AccessPolicy>,
+    dedup_window: Option<usize>,
+    buffer_pool: Option<u64>,
+    payload_alignment: Option<u64>,
+    mmap: bool,
+    verify_sample: Option<f64>,
+    audit_label: Option<String>,
+    slow_threshold: Option<Duration>,
+    deterministic: Option<u64>,
+}
+
+impl StoreOptions {
+    /// Default options, read only
+    pub fn new() -> StoreOptions {
+        StoreOptions::default()
+    }
+
+    /// Open for appending, see Store::open_for_write
+    pub fn write(mut self, write: bool) -> StoreOptions {
+        self.write = write;
+        self
+    }
+
+    /// Track reads per block, see Store::enable_access_stats
+    pub fn access_stats(mut self, access_stats: bool) -> StoreOptions {
+        self.access_stats = access_stats;
+        self
+    }
+
+    /// Largest block payload, see Store::set_max_block_size
+    pub fn max_block_size(mut self, bytes: u64) -> StoreOptions {
+        self.max_block_size = Some(bytes);
+        self
+    }
+
+    /// Keep strong digests of new blocks, see Store::enable_strong_digests
+    pub fn strong_digests(mut self, strong_digests: bool) -> StoreOptions {
+        self.strong_digests = strong_digests;
+        self
+    }
+
+    /// Write index checkpoints, see Store::set_checkpoint_policy
+    pub fn checkpoints(mut self, policy: CheckpointPolicy) -> StoreOptions {
+        self.checkpoints = policy;
+        self
+    }
+
+    /// Personalization the store must have, see Store::create_personalized
+    pub fn personalization(mut self, context: &str) -> StoreOptions {
+        self.personalization = Some(context.to_string());
+        self
+    }
+
+    /// Ask policy before reading any block's payload, see Store::set_access_policy
+    pub fn access_policy(mut self, policy: AccessPolicy) -> StoreOptions {
+        self.access_policy = Some(policy);
+        self
+    }
+
+    /// Return the earlier block for payloads put again soon, see
+    /// Store::set_dedup_window
+    pub fn dedup_window(mut self, blocks: usize) -> StoreOptions {
+        self.dedup_window = Some(blocks);
+        self
+    }
+
+    /// Reuse read buffers, see Store::set_buffer_pool
+    pub fn buffer_pool(mut self, max_bytes: u64) -> StoreOptions {
+        self.buffer_pool = Some(max_bytes);
+        self
+    }
+
+    /// Start payloads of new blocks at a multiple of bytes, see
+    /// Store::set_payload_alignment
+    pub fn payload_alignment(mut self, bytes: u64) -> StoreOptions {
+        self.payload_alignment = Some(bytes);
+        self
+    }
+
+    /// Map the file for read_aligned, see Store::set_mmap
+    pub fn mmap(mut self, mmap: bool) -> StoreOptions {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Verify only this fraction of reads by get, see Store::set_verify_sample
+    pub fn verify_sample(mut self, fraction: f64) -> StoreOptions {
+        self.verify_sample = Some(fraction);
+        self
+    }
+
+    /// Keep an audit trail under label, see Store::set_audit_label
+    pub fn audit_label(mut self, label: &str) -> StoreOptions {
+        self.audit_label = Some(label.to_string());
+        self
+    }
+
+    /// Report operations slower than threshold, see Store::set_slow_threshold
+    pub fn slow_threshold(mut self, threshold: Duration) -> StoreOptions {
+        self.slow_threshold = Some(threshold);
+        self
+    }
+
+    /// Write reproducible output from seed, see Store::set_deterministic
+    pub fn deterministic(mut self, seed: u64) -> StoreOptions {
+        self.deterministic = Some(seed);
+        self
+    }
+}
+
+/// What an AccessPolicy decides
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Allow,
+    Deny,
+}
+
+/// A block as an AccessPolicy sees it
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataHeaderInfo {
+    pub index: BlockId,
+    /// payload bytes
+    pub size: u64,
+    pub state_flag: u32,
+    pub content_type: ContentType,
+    /// see Store::put_owned
+    pub owner: u32,
+    pub permissions: u16,
+    /// see Store::begin_session
+    pub session: u64,
+}
+
+/// Decides whether a block's payload may be read, see Store::set_access_policy
+pub type AccessPolicy = fn(&DataHeaderInfo) -> Access;
+
+/// Which check Store::verify makes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strength {
+    /// the header checksum, with the store's hasher
+    Fast,
+    /// the strong digest, see Store::enable_strong_digests
+    Strong,
+}
+
+/// Told about changes to a Store, see Store::add_observer
+///
+/// For structures layered on a store that remember where its blocks are.
+pub trait StoreObserver: Send {
+    /// A block moved from old_addr to new_addr and is now block_id.
+    ///
+    /// Called once compaction is finished, for every block kept whose
+    /// address or index changed.
+    fn on_relocate(&mut self, old_addr: u64, new_addr: u64, block_id: BlockId);
+
+    /// Compaction finished, with report mapping old indexes to new.
+    ///
+    /// Called after on_relocate for every block it moved.
+    fn on_compact(&mut self, _report: &CompactReport) {}
+
+    /// The payload of block_id failed verification and was rewritten from
+    /// a good copy, by scrub from parity or by a mirror's read repair.
+    ///
+    /// The new payload is on disk by the time this is called.
+    fn on_repair(&mut self, _block_id: BlockId) {}
+
+    /// get found the payload of block_id doesn't match its checksum
+    fn on_verify_failure(&mut self, _block_id: BlockId) {}
+
+    /// An operation took longer than the slow threshold, see
+    /// Store::set_slow_threshold
+    fn on_slow_op(&mut self, _op: &SlowOp) {}
+}
+
+/// Utilities for a Store
+pub trait StoreIO<T: BlockHasher> {
+    /// Delete block at index
+    fn delete_block(&mut self, index: usize) -> Result<(), Box<dyn std::error::Error>>;
+    /// Should return the number of blocks availible for access
+    fn len(&self) -> usize;
+    /// true if there are no blocks
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Get the address of the block at index
+    fn block_address(&self, index: usize) -> Option<u64>;
+
+    fn read_data_header(
+        &mut self,
+        data_header: &mut DataHeader<T>,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+    fn read(&mut self, data: &mut Vec<u8>) -> Result<usize, Error>;
+    /// Read the payload of the block at index into data, resizing it to fit.
+    ///
+    /// Returns the payload length. A payload cut short fails with a StoreError
+    /// of kind StoreErrorKind::Truncated.
+    fn read_at_index(&mut self, index: usize, data: &mut Vec<u8>) -> Result<usize,Box<dyn std::error::Error>>;
+
+    fn seek(&mut self, index: usize) -> Result<u64, Box<dyn std::error::Error>>;
+}
+
+impl<T: BlockHasher> Store<T> {
+    /// Open existing Store file
+    ///
+    /// Will return an error if the file is not a Store file
+    pub fn new(filename: String) -> Result<Store<T>, Box<dyn std::error::Error>> {
+        Store::<T>::open_with_progress(filename, &StoreOptions::new(), |_, _| true)
+    }
+
+    /// Open existing Store file for appending
+    ///
+    /// If the store was not closed cleanly, the blocks written since the last
+    /// checkpoint, or all of them, are verified before writes are allowed.
+    /// Torn blocks at the end are discarded and others quarantined, see
+    /// RecoveryReport.
+    pub fn open_for_write(filename: String) -> Result<Store<T>, Box<dyn std::error::Error>> {
+        Store::<T>::open_with_progress(filename, &StoreOptions::new().write(true), |_, _| true)
+    }
+
+    /// Open existing Store file, reporting progress while blocks are indexed.
+    ///
+    /// progress is called with bytes indexed so far and the file size.
+    /// Return false from it to cancel the open, which fails with StoreErrorKind::Cancelled.
+    pub fn open_with_progress<F>(
+        filename: String,
+        opts: &StoreOptions,
+        mut progress: F,
+    ) -> Result<Store<T>, Box<dyn std::error::Error>>
+    where
+        F: FnMut(u64, u64) -> bool,
+    {
+        let f = OpenOptions::new().write(opts.write).read(true).open(&filename)?;
+        if opts.write {
+            Store::<T>::lock_file(&f)?;
+        }
+        let mut st = Store::<T>::from_file(f, filename);
+        st.max_block_size = opts.max_block_size;
+        st.strong_digests = opts.strong_digests;
+        st.checkpoint_policy = opts.checkpoints;
+        st.access_policy = opts.access_policy;
+        st.set_dedup_window(opts.dedup_window);
+        st.set_buffer_pool(opts.buffer_pool);
+        st.set_payload_alignment(opts.payload_alignment)?;
+        st.set_mmap(opts.mmap);
+        st.set_verify_sample(opts.verify_sample);
+        st.audit_label = opts.audit_label.clone();
+        st.slow_threshold = opts.slow_threshold;
+        st.deterministic = opts.deterministic;
+        st.open_file_descriptor()?;
+        if opts.write && st.version == STORE_VERSIONNUM_V1 {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_V1, StoreErrorKind::NeedsUpgrade)));
+        }
+        if st.personalization != opts.personalization {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_PERSONALIZATION, StoreErrorKind::Personalization)));
+        }
+        if opts.write && st.opened_dirty {
+            st.recover(&mut progress)?;
+        } else if st.opened_dirty {
+            // a dirty store has no trustworthy footer
+            st.index_unclean(&mut progress)?;
+        } else if st.features & FEATURE_INDEX_FOOTER == 0 || !st.read_index_footer()? {
+            st.index_blocks(0, &mut progress)?;
+        } else {
+            let len = st.file.metadata()?.len();
+            if !progress(len, len) {
+                return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_CANCELLED, StoreErrorKind::Cancelled)));
+            }
+        }
+        if opts.access_stats {
+            st.enable_access_stats()?;
+        }
+        if opts.write {
+            st.unseal()?;
+            st.writable = true;
+        }
+        st.file.seek(SeekFrom::Start(st.data_start_address))?;
+        Ok(st)
+    }
+
+    ///Create new Store file
+    ///
+    ///Will overwrite an existing store.
+    pub fn create(filename: String) -> Result<Store<T>, Error> {
+        Store::<T>::create_with_codec(filename, Box::new(BinaryHeaderCodec))
+    }
+
+    ///Create new Store file with block headers laid out by codec
+    ///
+    ///The codec must be one header_codec can find, or the store can't be reopened.
+    pub fn create_with_codec(filename: String, codec: Box<dyn HeaderCodec>) -> Result<Store<T>, Error> {
+        Store::<T>::create_with(filename, codec, None)
+    }
+
+    ///Create new Store file whose payloads are hashed with T personalized by
+    ///context, blake3's derive_key mode for B3BlockHasher.
+    ///
+    ///Hashes then differ from those of stores with another context, or none,
+    ///so content addressed pipelines of different deployments can't confuse
+    ///them. The context is kept in the file descriptor, and opening the store
+    ///needs the same one, see StoreOptions::personalization.
+    pub fn create_personalized(filename: String, context: &str) -> Result<Store<T>, Error> {
+        if T::create_personalized(context).is_none() || context.len() > usize::from(u16::MAX) {
+            return Err(Error::from(StoreError::with_kind(ERROR_FSTORE_UNPERSONALIZABLE, StoreErrorKind::Unpersonalizable)));
+        }
+        Store::<T>::create_with(filename, Box::new(BinaryHeaderCodec), Some(context))
+    }
+
+    /// Create a store with codec and optional personalization
+    fn create_with(filename: String, codec: Box<dyn HeaderCodec>, personalization: Option<&str>) -> Result<Store<T>, Error> {
+        let mut f = OpenOptions::new().write(true).read(true).create(true).truncate(false).open(&filename)?;
+        Store::<T>::lock_file(&f)?;
+        f.set_len(0)?;
+        let features = if personalization.is_some() { FEATURE_PERSONALIZED } else { 0 };
+        Store::<T>::write_file_descriptor(&mut f, codec.id(), DESCRIPTOR_FLAG_DIRTY, features, personalization)?;
+        let start = f.stream_position()?;
+        let mut st = Store::<T>::from_file(f, filename);
+        st.features = features;
+        st.personalization = personalization.map(|p| p.to_string());
+        st.codec_id = codec.id();
+        st.header_size = codec.size(T::size());
+        st.codec = codec;
+        st.descriptor_flags = DESCRIPTOR_FLAG_DIRTY;
+        st.data_start_address = start;
+        {
+            let mut index = st.index_mut();
+            index.data_end_address = start;
+            index.lifetime.created = unix_now();
+        }
+        st.writable = true;
+        Ok(st)
+    }
+
+    /// Store around file, nothing read yet
+    fn from_file(file: File, path: String) -> Store<T> {
+        Store::<T> {
+            file: CountingFile::new(file),
+            path,
+            version: STORE_VERSIONNUM,
+            descriptor_flags: 0,
+            features: 0,
+            codec_id: 0,
+            codec: Box::new(BinaryHeaderCodec),
+            header_size: BinaryHeaderCodec.size(T::size()),
+            opened_dirty: false,
+            data_start_address: 0,
+            index: Arc::new(RwLock::new(BlockIndex {
+                epoch: 0,
+                data_end_address: 0,
+                block_addresses: Vec::new(),
+                bloom: BloomFilter::with_capacity(0),
+                quarantined: Vec::new(),
+                scrub_position: 0,
+                append_times: Vec::new(),
+                delete_times: BTreeMap::new(),
+                lifetime: LifetimeCounters::default(),
+                pins: HashMap::new(),
+                moving: false,
+            })),
+            writable: false,
+            closed: false,
+            #[cfg(feature = "ecc")]
+            ecc: None,
+            max_block_size: None,
+            access_stats: None,
+            observers: Vec::new(),
+            personalization: None,
+            strong_digests: false,
+            checkpoint_policy: CheckpointPolicy::default(),
+            since_checkpoint: (0, 0),
+            recovery: None,
+            access_policy: None,
+            space: SpaceCounters::default(),
+            dedup: None,
+            pool: None,
+            payload_alignment: None,
+            use_mmap: false,
+            map: None,
+            aligned_buf: Vec::new(),
+            sampler: None,
+            verify_stats: VerifyStats::default(),
+            audit_label: None,
+            slow_threshold: None,
+            deterministic: None,
+            session: None,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Append data as a new block, returning its index
+    ///
+    /// Empty data makes a valid block with an empty payload. With a dedup
+    /// window, data the same as a live block in the window returns that
+    /// block instead.
+    pub fn put(&mut self, data: &[u8]) -> Result<BlockId, Box<dyn std::error::Error>> {
+        self.check_block_size(data.len() as u64)?;
+        let key = self.dedup.as_ref().map(|w| w.key(data));
+        if let Some(key) = key {
+            if let Some(id) = self.recent_duplicate(key, data) {
+                return Ok(id);
+            }
+        }
+        let id = self.append_block(data)?;
+        if let (Some(w), Some(key)) = (self.dedup.as_mut(), key) {
+            w.insert(key, id);
+        }
+        Ok(id)
+    }
+
+    /// Keep the last blocks put, up to blocks of them, so put can return one
+    /// of them for the same payload instead of writing it again; None to
+    /// stop.
+    ///
+    /// Cheaper than deduplicating the whole store, for bursts of repeated
+    /// payloads. A match is by hash, confirmed by reading the earlier block
+    /// back, so it costs one read. Only put takes part, and the window is
+    /// emptied by compaction, since block ids change.
+    pub fn set_dedup_window(&mut self, blocks: Option<usize>) {
+        self.dedup = blocks.map(DedupWindow::new);
+    }
+
+    /// Verify only a random fraction of the payloads get reads, 0.01 for
+    /// one in a hundred, instead of all of them; None to verify all again.
+    ///
+    /// For very hot read paths where hashing every payload costs too much.
+    /// A payload not verified is returned even if it is corrupt, so pair
+    /// this with scrub. Failures found are counted in verify_stats and
+    /// reported to observers either way.
+    pub fn set_verify_sample(&mut self, fraction: Option<f64>) {
+        self.sampler = fraction.map(ReadSampler::new);
+    }
+
+    /// What get has read and verified through this handle
+    pub fn verify_stats(&self) -> VerifyStats {
+        self.verify_stats
+    }
+
+    /// Record what is done through this handle in the audit log, under
+    /// label, or with None stop.
+    ///
+    /// Each live block appended, batch of deletes, swap, truncation and
+    /// compaction is followed by a record in the store's ".audit" sidecar,
+    /// see the audit module. Records take no block indexes.
+    pub fn set_audit_label(&mut self, label: Option<&str>) {
+        self.audit_label = label.map(|l| l.to_string());
+    }
+
+    /// Tell observers about reads, writes and reindexing taking longer than
+    /// threshold, with the block and its offset, or with None stop.
+    ///
+    /// For spotting pathological blocks or a failing disk. Indexing while
+    /// opening is before any observer can be added, so isn't reported.
+    pub fn set_slow_threshold(&mut self, threshold: Option<Duration>) {
+        self.slow_threshold = threshold;
+    }
+
+    /// Make everything this handle writes depend only on what is written
+    /// and seed, so the same writes give a byte identical file; None to
+    /// use the clock and random bytes again.
+    ///
+    /// For artifact caches and reproducible builds. Every time the store
+    /// records, of appends, deletes, audit records and its lifetime
+    /// counters, is seed seconds after the unix epoch, and the bytes
+    /// Erase::Secure writes come from seed and their address. Retention
+    /// and purge_tombstones judge ages by the same time. Padding is zeros
+    /// either way.
+    pub fn set_deterministic(&mut self, seed: Option<u64>) {
+        self.deterministic = seed;
+        if let Some(seed) = seed {
+            self.index_mut().lifetime.created = seed;
+        }
+    }
+
+    /// Seconds since the unix epoch, or the seed when deterministic
+    fn now(&self) -> u64 {
+        self.deterministic.unwrap_or_else(unix_now)
+    }
+
+    /// Key of the bytes Erase::Secure writes at start
+    fn erase_key(&self, start: u64) -> [u8; 32] {
+        match self.deterministic {
+            Some(seed) => {
+                let mut h = blake3::Hasher::new_derive_key("fstore 2021 erase");
+                h.update(&seed.to_le_bytes());
+                h.update(&start.to_le_bytes());
+                *h.finalize().as_bytes()
+            }
+            None => random_key(),
+        }
+    }
+
+    /// Report an operation begun at started, if it was slow
+    fn note_slow(&mut self, kind: SlowOpKind, block_id: Option<BlockId>, bytes: u64, started: Instant) {
+        let elapsed = started.elapsed();
+        if self.slow_threshold.is_none_or(|t| elapsed <= t) || self.observers.is_empty() {
+            return;
+        }
+        let op = SlowOp { kind, block_id, offset: block_id.and_then(|b| self.block_address(b)), bytes, elapsed };
+        for o in self.observers.iter_mut() {
+            o.on_slow_op(&op);
+        }
+    }
+
+    /// Append an audit record of action to the sidecar, if auditing
+    fn audit(&mut self, action: AuditAction) -> Result<(), Error> {
+        let label = match &self.audit_label {
+            Some(l) => l.clone(),
+            None => return Ok(()),
+        };
+        AuditRecord { time: UNIX_EPOCH + Duration::from_secs(self.now()), label, action }.append(&self.path)
+    }
+
+    /// Read headers and payloads into buffers from a pool holding up to
+    /// max_bytes of them, instead of allocating for every read; None to stop.
+    ///
+    /// Handles from try_clone and compaction share the pool. See the pool
+    /// module.
+    pub fn set_buffer_pool(&mut self, max_bytes: Option<u64>) {
+        self.pool = max_bytes.map(BufferPool::shared);
+    }
+
+    /// Give the pool back a buffer, such as a payload from get that is no
+    /// longer needed, to be read into again. Does nothing without a pool.
+    pub fn recycle(&mut self, buf: Vec<u8>) {
+        drop(Scratch::from_pool(self.pool.as_ref(), buf));
+    }
+
+    /// What the buffer pool has done, None without one
+    pub fn pool_stats(&self) -> Option<PoolStats> {
+        self.pool.as_ref().map(|p| BufferPool::lock(p).stats())
+    }
+
+    /// Start the payload of every block written from now on at a multiple
+    /// of bytes in the file, None to stop.
+    ///
+    /// Blocks are padded out with filler, so alignments near the header size
+    /// or bigger cost up to that much per block. compact keeps the alignment,
+    /// compact_in_place loses it. bytes must be a power of two.
+    pub fn set_payload_alignment(&mut self, bytes: Option<u64>) -> Result<(), Box<dyn std::error::Error>> {
+        if bytes.is_some_and(|b| !b.is_power_of_two()) {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_ALIGNMENT, StoreErrorKind::Alignment)));
+        }
+        self.payload_alignment = bytes;
+        Ok(())
+    }
+
+    /// Have read_aligned map the file instead of copying payloads, where
+    /// memory maps are supported. See read_aligned for what the file must
+    /// be spared while it is mapped.
+    pub fn set_mmap(&mut self, mmap: bool) {
+        self.use_mmap = mmap;
+        if !mmap {
+            self.map = None;
+        }
+    }
+
+    /// Payload of the block at index, checked as get does, starting at a
+    /// multiple of align in memory, for deserializing in place.
+    ///
+    /// With set_mmap and a payload aligned in the file to align, up to the
+    /// page size (see set_payload_alignment), the slice is the mapped file
+    /// itself and nothing is copied; the map follows the file as it grows
+    /// and is remade after compaction. Otherwise the payload is copied into
+    /// an aligned buffer the store keeps for it. align must be a power of two.
+    ///
+    /// # Safety
+    ///
+    /// Through a map the slice is the file as it is now, checked once, not a
+    /// copy. While it is held the caller must make sure that no other handle
+    /// to the file, from try_clone or another process, truncates it (as
+    /// truncate_to, compact_in_place and recovery do) or changes the block's
+    /// payload in place (as swap and repairs do). Shrinking the file under
+    /// a map is undefined behaviour, typically SIGBUS; a payload changed
+    /// under it breaks the checksum check and, for zero copy values, what
+    /// they may be relied on to hold. Other processes must not shrink the
+    /// file at all while set_mmap is on, since the map outlives the slice.
+    /// Without set_mmap, or where maps aren't supported, nothing is mapped
+    /// and there is no requirement.
+    pub unsafe fn read_aligned(&mut self, index: BlockId, align: usize) -> Result<&[u8], Box<dyn std::error::Error>> {
+        if !align.is_power_of_two() {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_ALIGNMENT, StoreErrorKind::Alignment)));
+        }
+        let dh = self.block_header(index)?;
+        if !dh.is_live() {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_NOTLIVE, StoreErrorKind::NotLive).at(index)));
+        }
+        self.check_access(index, &dh)?;
+        let start = usize::try_from(self.seek_block(index)?)? + self.header_size;
+        let end = start + dh.data_size()?;
+        let mapped = self.use_mmap
+            && self.map_covering(end as u64)
+            && self.map.as_ref().is_some_and(|(m, _)| m.as_slice().as_ptr().wrapping_add(start).align_offset(align) == 0);
+        let range = if mapped {
+            start..end
+        } else {
+            self.aligned_buf.resize(end - start + align, 0);
+            let offset = self.aligned_buf.as_ptr().align_offset(align);
+            self.file.seek(SeekFrom::Start(start as u64))?;
+            self.file.read_exact(&mut self.aligned_buf[offset..offset + end - start])?;
+            offset..offset + end - start
+        };
+        if !dh.verify_personalized(self.aligned_slice(mapped, range.clone()), self.personalization.as_deref()) {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_CHECKSUM, StoreErrorKind::Checksum).at(index).offset(self.block_address(index))));
+        }
+        self.record_access(index);
+        Ok(self.aligned_slice(mapped, range))
+    }
+
+    /// range of the map, or of the aligned buffer
+    fn aligned_slice(&self, mapped: bool, range: std::ops::Range<usize>) -> &[u8] {
+        match &self.map {
+            Some((m, _)) if mapped => &m.as_slice()[range],
+            _ => &self.aligned_buf[range],
+        }
+    }
+
+    /// Make sure the map covers the first len bytes of the file, remapping
+    /// if it is too short or the blocks have changed since it was made.
+    ///
+    /// false if the file can't be mapped.
+    fn map_covering(&mut self, len: u64) -> bool {
+        let epoch = self.index().epoch;
+        if let Some((m, at)) = &self.map {
+            if *at == epoch && m.len() as u64 >= len {
+                return true;
+            }
+        }
+        self.map = None;
+        let file_len = match self.file.metadata() {
+            Ok(md) => md.len(),
+            Err(_) => return false,
+        };
+        // only read_aligned maps, and its callers keep the file from
+        // shrinking or changing under the map, see its safety section
+        match unsafe { Mmap::map(&self.file, file_len) } {
+            Ok(m) if m.len() as u64 >= len => {
+                self.map = Some((m, epoch));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// len zeroed bytes, from the pool if there is one
+    fn scratch(&self, len: usize) -> Scratch {
+        Scratch::zeroed(self.pool.as_ref(), len)
+    }
+
+    /// Block in the dedup window with payload data, if it is still live
+    fn recent_duplicate(&mut self, key: u64, data: &[u8]) -> Option<BlockId> {
+        let id = *self.dedup.as_ref()?.blocks.get(&key)?;
+        match self.read_block(id) {
+            Ok((dh, payload)) if dh.is_live() && payload[..] == *data => Some(id),
+            _ => None,
+        }
+    }
+
+    /// put the buffers one after another as one block, without joining them
+    /// first
+    pub fn put_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<BlockId, Box<dyn std::error::Error>> {
+        let parts: Vec<&[u8]> = bufs.iter().map(|b| &**b).collect();
+        self.check_block_size(parts.iter().map(|p| p.len() as u64).sum())?;
+        let id = self.append_parts(&parts, 0, BlockAttrs::default())?;
+        self.checkpoint_if_due()?;
+        Ok(id)
+    }
+
+    /// put, recording content_type in the block's header.
+    ///
+    /// Only codecs that record content types can, FieldsHeaderCodec with
+    /// HEADER_FIELD_CONTENT_TYPE among the built in ones; others fail without writing.
+    pub fn put_typed(&mut self, data: &[u8], content_type: ContentType) -> Result<BlockId, Box<dyn std::error::Error>> {
+        self.write_with(data, &WriteOpts { content_type: Some(content_type), ..WriteOpts::default() })
+    }
+
+    /// put, recording owner and permissions in the block's header for the
+    /// access policy to judge.
+    ///
+    /// Only codecs that record them can, FieldsHeaderCodec with
+    /// HEADER_FIELD_ACCESS among the built in ones; others fail without
+    /// writing.
+    pub fn put_owned(&mut self, data: &[u8], owner: u32, permissions: u16) -> Result<BlockId, Box<dyn std::error::Error>> {
+        self.write_with(data, &WriteOpts { access: Some((owner, permissions)), ..WriteOpts::default() })
+    }
+
+    /// put, with any of the per block behaviour of put_typed, put_owned and
+    /// column families at once, see WriteOpts.
+    ///
+    /// Fails without writing if the codec can't record what opts asks for.
+    /// Only a write with no header attributes takes part in the dedup window.
+    pub fn write_with(&mut self, data: &[u8], opts: &WriteOpts) -> Result<BlockId, Box<dyn std::error::Error>> {
+        if opts.content_type.is_some() && !self.codec.records_content_type() {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_UNTYPED, StoreErrorKind::NoContentTypes)));
+        }
+        if opts.access.is_some() && !self.codec.records_access() {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_NOACCESS, StoreErrorKind::NoAccess)));
+        }
+        let prefixed;
+        let data = match opts.namespace {
+            Some(ns) => {
+                prefixed = [&cf_prefix(ns)?[..], data].concat();
+                &prefixed[..]
+            }
+            None => data,
+        };
+        if opts.content_type.is_none() && opts.access.is_none() {
+            return self.put(data);
+        }
+        self.check_block_size(data.len() as u64)?;
+        let (owner, permissions) = opts.access.unwrap_or((0, 0));
+        let attrs = BlockAttrs { content_type: opts.content_type.unwrap_or_default().0, owner, permissions, ..BlockAttrs::default() };
+        let id = self.append_block_with_flags(data, 0, attrs)?;
+        self.checkpoint_if_due()?;
+        Ok(id)
+    }
+
+    /// put, with the content type, owner and permissions of like, for copies
+    /// of a block from another store
+    pub(crate) fn put_like(&mut self, data: &[u8], like: &DataHeader<T>) -> Result<BlockId, Box<dyn std::error::Error>> {
+        self.check_block_size(data.len() as u64)?;
+        let id = self.append_block_with_flags(data, 0, BlockAttrs::of(like))?;
+        self.checkpoint_if_due()?;
+        Ok(id)
+    }
+
+    /// Record a new session id in the header of every block this handle
+    /// puts until end_session, and return it.
+    ///
+    /// For rolling back a failed ingest run with delete_session. Ids come
+    /// from a counter kept with the lifetime stats, so none is handed out
+    /// twice, even once compaction has dropped the blocks of a session, and
+    /// handles from try_clone share it; a store that wasn't closed cleanly
+    /// goes on from the highest id its blocks record. Only codecs that
+    /// record sessions can, FieldsHeaderCodec with HEADER_FIELD_SESSION
+    /// among the built in ones; others fail, as does beginning while a
+    /// session is open. Compaction and swap keep the session of the blocks
+    /// they move; other handles, from try_clone or not, don't share the
+    /// open session.
+    pub fn begin_session(&mut self) -> Result<u64, Box<dyn std::error::Error>> {
+        if !self.codec.records_session() {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_NOSESSION, StoreErrorKind::NoSession)));
+        }
+        if self.session.is_some() {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_SESSIONOPEN, StoreErrorKind::SessionOpen)));
+        }
+        let id = {
+            let mut index = self.index_mut();
+            index.lifetime.last_session += 1;
+            index.lifetime.last_session
+        };
+        self.session = Some(id);
+        Ok(id)
+    }
+
+    /// Stop recording the open session, returning its id, None if none was open
+    pub fn end_session(&mut self) -> Option<u64> {
+        self.session.take()
+    }
+
+    /// iter, over the blocks put in session id only
+    pub fn iter_session(&mut self, id: u64) -> StoreIter<'_, T> {
+        StoreIter { store: self, next: 0, session: Some(id) }
+    }
+
+    /// Delete every live block put in session id, as one delete_many, and
+    /// return their indexes. Session 0 is no session and matches no block.
+    pub fn delete_session(&mut self, id: u64) -> Result<Vec<BlockId>, Box<dyn std::error::Error>> {
+        self.delete_where(|h| id != 0 && h.session == id)
+    }
+
+    /// Ask policy before reading the payload of any block from now on, None
+    /// to stop asking.
+    ///
+    /// get, get_typed, read_at_generation, read_at_index and block_reader
+    /// fail with StoreErrorKind::AccessDenied for blocks it denies; iter and
+    /// search skip them. Headers, and the payloads the store reads for its
+    /// own checks and compaction, are not covered. Handles from try_clone
+    /// and compaction keep the policy.
+    pub fn set_access_policy(&mut self, policy: Option<AccessPolicy>) {
+        self.access_policy = policy;
+    }
+
+    /// true unless the access policy denies reading the block at index with header dh
+    pub(crate) fn allowed(&self, index: BlockId, dh: &DataHeader<T>) -> bool {
+        let policy = match self.access_policy {
+            Some(p) => p,
+            None => return true,
+        };
+        policy(&Store::header_info(index, dh)) == Access::Allow
+    }
+
+    /// The block at index with header dh, as an AccessPolicy or delete_where sees it
+    fn header_info(index: BlockId, dh: &DataHeader<T>) -> DataHeaderInfo {
+        DataHeaderInfo {
+            index,
+            size: dh.fields().size_data,
+            state_flag: dh.state_flag,
+            content_type: ContentType(dh.content_type()),
+            owner: dh.owner(),
+            permissions: dh.permissions(),
+            session: dh.session(),
+        }
+    }
+
+    /// allowed, as an AccessDenied error
+    fn check_access(&self, index: BlockId, dh: &DataHeader<T>) -> Result<(), Box<dyn std::error::Error>> {
+        if self.allowed(index, dh) {
+            return Ok(());
+        }
+        Err(Box::new(StoreError::with_kind(ERROR_FSTORE_DENIED, StoreErrorKind::AccessDenied).at(index)))
+    }
+
+    /// Content type of the block at index, ContentType::UNKNOWN if it has none
+    pub fn content_type(&mut self, index: BlockId) -> Result<ContentType, Box<dyn std::error::Error>> {
+        Ok(ContentType(self.block_header(index)?.content_type()))
+    }
+
+    /// get, failing with StoreErrorKind::ContentType unless the block's content
+    /// type is expected
+    pub fn get_typed(&mut self, index: BlockId, expected: ContentType) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let found = self.content_type(index)?;
+        if found != expected {
+            let kind = StoreErrorKind::ContentType { expected: expected.0, found: found.0 };
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_CONTENTTYPE, kind).at(index)));
+        }
+        self.get(index)
+    }
+
+    /// Refuse payloads over size and headers claiming them, None for no limit.
+    ///
+    /// Index footers and other blocks the store writes for itself are exempt.
+    pub fn set_max_block_size(&mut self, bytes: Option<u64>) {
+        self.max_block_size = bytes;
+    }
+
+    /// Error if size is over the maximum block size
+    pub(crate) fn check_block_size(&self, size: u64) -> Result<(), StoreError> {
+        match self.max_block_size {
+            Some(max) if size > max => Err(StoreError::with_kind(ERROR_FSTORE_TOOLARGE, StoreErrorKind::BlockTooLarge { size, max })),
+            _ => Ok(()),
+        }
+    }
+
+    /// Payload of the block at index, checked against its checksum.
+    ///
+    /// Deleted and quarantined blocks fail with StoreErrorKind::NotLive,
+    /// and a payload that doesn't verify with StoreErrorKind::Checksum.
+    pub fn get(&mut self, index: BlockId) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let started = Instant::now();
+        let (dh, data) = self.read_block(index)?;
+        if !dh.is_live() {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_NOTLIVE, StoreErrorKind::NotLive).at(index)));
+        }
+        self.check_access(index, &dh)?;
+        self.verify_stats.reads += 1;
+        if self.sampler.as_mut().is_none_or(|s| s.pick()) {
+            self.verify_stats.verified += 1;
+            if !dh.verify_personalized(&data, self.personalization.as_deref()) {
+                self.verify_stats.failures += 1;
+                for o in self.observers.iter_mut() {
+                    o.on_verify_failure(index);
+                }
+                return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_CHECKSUM, StoreErrorKind::Checksum).at(index).offset(self.block_address(index))));
+            }
+        }
+        self.record_access(index);
+        self.note_slow(SlowOpKind::Read, Some(index), data.len() as u64, started);
+        Ok(data.into_inner())
+    }
+
+    /// get, with the checks and buffer chosen by opts, see ReadOpts.
+    ///
+    /// Returns the payload, or an empty Vec when opts.into took it. Reads
+    /// aren't sampled, counted in verify_stats or reported as slow; the
+    /// access policy applies as for get.
+    pub fn read_with(&mut self, index: BlockId, opts: ReadOpts<'_>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let dh = self.block_header(index)?;
+        if !opts.include_dead && !dh.is_live() {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_NOTLIVE, StoreErrorKind::NotLive).at(index)));
+        }
+        let mut own = Vec::new();
+        let buf = match opts.into {
+            Some(b) => b,
+            None => &mut own,
+        };
+        self.read_at_index(index, buf)?;
+        if opts.verify && !dh.verify_personalized(buf, self.personalization.as_deref()) {
+            let e = StoreError::with_kind(ERROR_FSTORE_CHECKSUM, StoreErrorKind::Checksum).at(index).offset(self.block_address(index));
+            return Err(Box::new(e));
+        }
+        self.record_access(index);
+        Ok(own)
+    }
+
+    /// Number of blocks the store has held, which grows by one with every
+    /// block appended, see read_at_generation
+    pub fn generation(&self) -> u64 {
+        self.len() as u64
+    }
+
+    /// Payload of the block at index as it was at generation gen, when the
+    /// store held gen blocks.
+    ///
+    /// Blocks are never overwritten by put or delete, and swap appends a
+    /// replacement that doesn't fit, so old payloads stay in the file, deleted,
+    /// until compaction. What is retained is limited:
+    ///  - compaction drops dead blocks and renumbers the rest, so generations
+    ///    from before it mean nothing afterwards
+    ///  - a block swap rewrote in place has lost its old payload, and is only
+    ///    served at the current generation
+    ///  - deletes aren't dated, so a block is served whether or not it was
+    ///    live at gen
+    ///
+    /// Anything not retained fails, as does an index not yet written at gen.
+    pub fn read_at_generation(&mut self, index: BlockId, gen: u64) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let (dh, data) = self.read_block(index)?;
+        if index as u64 >= gen || dh.is_corrupt() || (dh.is_rewritten() && gen < self.generation()) {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_GENERATION, StoreErrorKind::NotRetained).at(index).value("generation", gen)));
+        }
+        self.check_access(index, &dh)?;
+        if !dh.verify_personalized(&data, self.personalization.as_deref()) {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_CHECKSUM, StoreErrorKind::Checksum).at(index).offset(self.block_address(index))));
+        }
+        Ok(data.into_inner())
+    }
+
+    /// true if index is a block of the store, live or not
+    pub fn contains(&self, index: BlockId) -> bool {
+        index < self.len()
+    }
+
+    /// true if the block at index is neither deleted nor quarantined
+    pub fn is_live(&mut self, index: BlockId) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self.block_header(index)?.is_live())
+    }
+
+    /// Header of the block at index
+    pub fn block_header(&mut self, index: BlockId) -> Result<DataHeader<T>, Box<dyn std::error::Error>> {
+        self.seek_block(index)?;
+        let mut dh = DataHeader::<T>::new()?;
+        self.read_data_header(&mut dh)?;
+        Ok(dh)
+    }
+
+    /// Sync the blocks written so far to disk
+    pub(crate) fn sync_blocks(&mut self) -> Result<(), Error> {
+        self.file.flush()?;
+        self.file.sync_data()
+    }
+
+    /// Index of the last block, None if the store is empty
+    pub fn last_index(&self) -> Option<BlockId> {
+        self.len().checked_sub(1)
+    }
+
+    /// Index of the block whose header is at address, None if no block's is.
+    ///
+    /// For addresses found in journals, logs or manifests. Deleted blocks
+    /// are found too, but not parity, digest or other blocks the store
+    /// keeps for itself.
+    pub fn index_of_address(&self, address: u64) -> Option<BlockId> {
+        self.index().block_addresses.binary_search(&address).ok()
+    }
+
+    /// Write data in a DataHeader at the end of the store
+    ///
+    /// The block is only added to the index once it is written, so other
+    /// handles never see a block they can't read.
+    fn append_block(&mut self, buf: &[u8]) -> Result<BlockId, Error> {
+        let id = self.append_block_with_flags(buf, 0, BlockAttrs::default())?;
+        self.checkpoint_if_due().map_err(|e| Error::other(e.to_string()))?;
+        Ok(id)
+    }
+
+    /// append_block with the block's state flags set to flags and its
+    /// content type to content_type, if the codec records it
+    fn append_block_with_flags(&mut self, buf: &[u8], flags: u32, attrs: BlockAttrs) -> Result<BlockId, Error> {
+        self.append_parts(&[buf], flags, attrs)
+    }
+
+    /// append_block_with_flags for a payload of parts one after another,
+    /// written and hashed without joining them
+    fn append_parts(&mut self, parts: &[&[u8]], flags: u32, attrs: BlockAttrs) -> Result<BlockId, Error> {
+        self.check_writable()?;
+        let started = Instant::now();
+        if let Some(align) = self.payload_alignment {
+            self.pad_for_alignment(align)?;
+        }
+        if let Ok(mut bd) = DataHeader::<T>::new() {
+            bd.state_flag = flags;
+            attrs.apply(&mut bd);
+            // payloads put in an open session, not the store's own blocks
+            if flags == 0 && attrs.session == 0 {
+                bd.set_session(self.session.unwrap_or(0));
+            }
+            let address = self.index().data_end_address;
+            self.file.seek(SeekFrom::Start(address))?;
+            let header_len = if let Ok(sd) = bd.serialize_parts(&*self.codec, parts, self.personalization.as_deref()) {
+                self.file.write_all(sd)?;
+                sd.len() as u64
+            } else {
+                return Err(Error::from(StoreError::with_kind(ERROR_FSTORE_INVSIZE, StoreErrorKind::InvalidSize)));
+            };
+            for part in parts {
+                self.file.write_all(part)?;
+            }
+            let payload_end = self.file.stream_position()?;
+            // parity is computed over shards of the whole payload
+            #[cfg(feature = "ecc")]
+            match parts {
+                [part] => self.write_parity(part)?,
+                _ if self.ecc.is_some() => self.write_parity(&Scratch::new(parts.concat()))?,
+                _ => (),
+            }
+            if self.strong_digests {
+                self.write_digest(parts)?;
+            }
+            let end = self.file.stream_position()?;
+            self.since_checkpoint.0 += 1;
+            self.since_checkpoint.1 += end - address;
+            self.space.logical += payload_end - address - header_len;
+            self.space.headers += header_len;
+            self.space.companions += end - payload_end;
+            let (id, full) = {
+                let mut index = self.index_mut();
+                index.bloom.insert(&bd.fields().checksum);
+                index.block_addresses.push(address);
+                index.append_times.push(self.now());
+                index.lifetime.blocks_written += 1;
+                index.lifetime.bytes_written += payload_end - address - header_len;
+                index.data_end_address = end;
+                index.epoch += 1;
+                (index.block_addresses.len() - 1, index.bloom.is_full())
+            };
+            if full {
+                self.rebuild_bloom().map_err(|e| Error::other(e.to_string()))?;
+            }
+            self.note_slow(SlowOpKind::Write, Some(id), payload_end - address - header_len, started);
+            if flags == 0 {
+                self.audit(AuditAction::Write(id))?;
+            }
+            Ok(id)
+        } else {
+            Err(Error::from(StoreError::with_kind(ERROR_FSTORE_INVSIZE, StoreErrorKind::InvalidSize)))
+        }
+    }
+
+    /// Open another read only handle on this store, sharing its index.
+    ///
+    /// Blocks written through any handle sharing the index are visible to all
+    /// of them as soon as the write returns: len() counts them and
+    /// read_at_index can read them, without reopening.
+    /// epoch() changes whenever that happens, so a handle can cheaply tell
+    /// whether anything changed since it last looked.
+    pub fn try_clone(&self) -> Result<Store<T>, Box<dyn std::error::Error>> {
+        let mut st = Store::<T>::from_file(File::open(&self.path)?, self.path.clone());
+        st.descriptor_flags = self.descriptor_flags;
+        st.features = self.features;
+        st.codec_id = self.codec_id;
+        st.personalization = self.personalization.clone();
+        st.codec = header_codec(self.codec_id)
+            .ok_or_else(|| StoreError::with_kind(ERROR_FSTORE_CODEC, StoreErrorKind::UnknownCodec).value("codec", u64::from(self.codec_id)))?;
+        st.header_size = self.header_size;
+        st.opened_dirty = self.opened_dirty;
+        st.access_policy = self.access_policy;
+        st.pool = self.pool.clone();
+        st.sampler = self.sampler;
+        st.slow_threshold = self.slow_threshold;
+        st.deterministic = self.deterministic;
+        st.data_start_address = self.data_start_address;
+        st.index = Arc::clone(&self.index);
+        st.file.seek(SeekFrom::Start(st.data_start_address))?;
+        Ok(st)
+    }
+
+    /// Counter that changes whenever blocks are added or changed through any
+    /// handle sharing this store's index
+    pub fn epoch(&self) -> u64 {
+        self.index().epoch
+    }
+
+    /// Lock the shared index for reading
+    fn index(&self) -> RwLockReadGuard<'_, BlockIndex> {
+        // the index is never left half updated, so a poisoned lock is still usable
+        self.index.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Lock the shared index for writing
+    fn index_mut(&self) -> RwLockWriteGuard<'_, BlockIndex> {
+        self.index.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Rewrite the state flags of the block at index
+    fn update_block_flags<F>(&mut self, index: usize, update: F) -> Result<(), Box<dyn std::error::Error>>
+    where
+        F: FnOnce(u32) -> u32,
+    {
+        if let Some(address) = self.block_address(index) {
+            self.update_flags_at(address, update)
+        } else {
+            Err(Box::new(StoreError::with_kind(ERROR_OUTOFBOUNDS, StoreErrorKind::OutOfBounds).at(index)))
+        }
+    }
+
+    /// Rewrite the state flags of the block, indexed or not, at address
+    fn update_flags_at<F>(&mut self, address: u64, update: F) -> Result<(), Box<dyn std::error::Error>>
+    where
+        F: FnOnce(u32) -> u32,
+    {
+        // rewrite the whole header, the codec knows where the flags live
+        let mut dh = DataHeader::<T>::new()?;
+        self.file.seek(SeekFrom::Start(address))?;
+        self.read_data_header(&mut dh)?;
+        dh.state_flag = update(dh.state_flag);
+        self.file.seek(SeekFrom::Start(address))?;
+        self.file.write_all(dh.encode_with(&*self.codec)?)?;
+        self.index_mut().epoch += 1;
+        Ok(())
+    }
+
+    /// Delete several blocks at once.
+    ///
+    /// The indexes are first written to a journal block and synced, so if
+    /// the process dies part way through, the deletes are finished when the
+    /// store is next opened for writing. Deleting a block twice is harmless.
+    pub fn delete_many(&mut self, indexes: &[BlockId]) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        if let Some(i) = indexes.iter().find(|i| **i >= self.len()) {
+            return Err(Box::new(StoreError::with_kind(ERROR_OUTOFBOUNDS, StoreErrorKind::OutOfBounds).at(*i)));
+        }
+        if indexes.is_empty() {
+            return Ok(());
+        }
+        let mut journal = u64::try_from(indexes.len())?.to_le_bytes().to_vec();
+        for i in indexes {
+            journal.extend_from_slice(&u64::try_from(*i)?.to_le_bytes());
+        }
+        // put the read position back afterwards, so a read in progress carries on
+        let cursor = self.file.stream_position()?;
+        self.append_system_block(DataHeader::<T>::journal_flag(), &journal)?;
+        self.file.sync_data()?;
+        self.apply_deletes(indexes)?;
+        self.audit(AuditAction::Delete(indexes.to_vec()))?;
+        self.file.seek(SeekFrom::Start(cursor))?;
+        Ok(())
+    }
+
+    /// Delete every live block whose header matches, returning their indexes.
+    ///
+    /// Only headers are read, and the matches are deleted as one journaled
+    /// batch, see delete_many.
+    pub fn delete_where<F>(&mut self, mut matches: F) -> Result<Vec<BlockId>, Box<dyn std::error::Error>>
+    where
+        F: FnMut(&DataHeaderInfo) -> bool,
+    {
+        self.check_writable()?;
+        let cursor = self.file.stream_position()?;
+        let mut found = Vec::new();
+        for index in 0..self.len() {
+            let dh = self.block_header(index)?;
+            if dh.is_live() && matches(&Store::header_info(index, &dh)) {
+                found.push(index);
+            }
+        }
+        self.file.seek(SeekFrom::Start(cursor))?;
+        self.delete_many(&found)?;
+        Ok(found)
+    }
+
+    /// Discard every block after index, cutting the file back to where the
+    /// next one started.
+    ///
+    /// For log structured users dropping writes a failed transaction left
+    /// behind. The blocks are gone, not deleted: their indexes are handed
+    /// out again by the next puts. Parity and digests of the blocks kept are
+    /// kept with them. The index checkpoint, if any, is dropped, since it
+    /// may list blocks that no longer exist.
+    pub fn truncate_to(&mut self, index: BlockId) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        if index >= self.len() {
+            return Err(Box::new(StoreError::with_kind(ERROR_OUTOFBOUNDS, StoreErrorKind::OutOfBounds).at(index)));
+        }
+        let end = match self.block_address(index + 1) {
+            Some(a) => a,
+            None => return Ok(()),
+        };
+        let dropped: Vec<BlockId> = (index + 1..self.len()).collect();
+        self.unpinned(Some(&dropped), |st| st.truncate_unpinned(index, end))
+    }
+
+    /// The work of truncate_to, keeping blocks up to index and cutting the
+    /// file at end, once none of the blocks dropped is pinned
+    fn truncate_unpinned(&mut self, index: BlockId, end: u64) -> Result<(), Box<dyn std::error::Error>> {
+        self.drop_checkpoint()?;
+        // a map past the new end would fault when read
+        self.map = None;
+        self.file.set_len(end)?;
+        self.file.sync_data()?;
+        self.set_dedup_window(self.dedup.as_ref().map(|w| w.capacity));
+        let kept = index + 1;
+        if let Some(st) = self.access_stats.take() {
+            let remap: Vec<Option<BlockId>> = (0..self.len()).map(|i| Some(i).filter(|i| *i < kept)).collect();
+            self.access_stats = Some(st.remap(&remap));
+        }
+        let dropped: Vec<BlockId> = (kept..self.len()).collect();
+        {
+            let mut index = self.index_mut();
+            index.block_addresses.truncate(kept);
+            index.append_times.truncate(kept);
+            index.delete_times.split_off(&kept);
+            index.quarantined.retain(|i| *i < kept);
+            if index.scrub_position >= kept {
+                index.scrub_position = 0;
+            }
+            index.data_end_address = end;
+            index.epoch += 1;
+        }
+        self.audit(AuditAction::Delete(dropped))?;
+        Ok(())
+    }
+
+    /// Delete the block at index after erasing its payload, and those of
+    /// its parity and digest blocks, for stores holding secrets.
+    ///
+    /// Erase::Zero overwrites with zeros, Erase::Secure with random bytes
+    /// then zeros. The erase is synced before the delete is journaled, so a
+    /// crash in between leaves a live block that fails verification, for
+    /// scrub to quarantine, but never the old payload.
+    pub fn delete_block_secure(&mut self, index: BlockId, erase: Erase) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        self.unpinned(Some(&[index]), |st| {
+            let cursor = st.file.stream_position()?;
+            st.erase_payloads(index, erase)?;
+            st.file.sync_data()?;
+            st.file.seek(SeekFrom::Start(cursor))?;
+            st.delete_many(&[index])
+        })
+    }
+
+    /// Set the delete flag of every block in indexes and sync
+    fn apply_deletes(&mut self, indexes: &[BlockId]) -> Result<(), Box<dyn std::error::Error>> {
+        for i in indexes {
+            self.update_block_flags(*i, |f| DataHeader::<T>::set_delete_flag(true, f))?;
+        }
+        self.file.sync_data()?;
+        let now = self.now();
+        let mut index = self.index_mut();
+        for i in indexes {
+            index.delete_times.entry(*i).or_insert(now);
+        }
+        Ok(())
+    }
+
+    /// Block indexes in a journal payload, u64 count then u64 indexes
+    fn decode_journal(payload: &[u8]) -> Vec<BlockId> {
+        if payload.len() < 8 {
+            return Vec::new();
+        }
+        payload[8..]
+            .chunks_exact(8)
+            .map(|c| u64::from_le_bytes(c.try_into().unwrap()) as BlockId)
+            .collect()
+    }
+
+    /// Append a block the store keeps for itself, which is not indexed
+    fn append_system_block(&mut self, flags: u32, payload: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut dh = DataHeader::<T>::new()?;
+        dh.state_flag = flags;
+        let address = self.index().data_end_address;
+        self.file.seek(SeekFrom::Start(address))?;
+        self.file.write_all(dh.serialize_personalized(&*self.codec, payload, self.personalization.as_deref())?)?;
+        self.file.write_all(payload)?;
+        let end = self.file.stream_position()?;
+        if flags == DataHeader::<T>::journal_flag() {
+            self.space.journal += end - address;
+        } else {
+            self.space.index += end - address;
+        }
+        let mut index = self.index_mut();
+        index.data_end_address = end;
+        index.epoch += 1;
+        Ok(())
+    }
+
+    /// Write a filler block at the end of the data, if needed, so the
+    /// payload of the next block starts at a multiple of align
+    fn pad_for_alignment(&mut self, align: u64) -> Result<(), Error> {
+        let hsize = self.header_size as u64;
+        let address = self.index().data_end_address;
+        let mut gap = match (address + hsize) % align {
+            0 => return Ok(()),
+            r => align - r,
+        };
+        while gap < hsize {
+            gap += align;
+        }
+        let filler_error = |e: Box<dyn std::error::Error>| Error::other(e.to_string());
+        let mut filler = DataHeader::<T>::new().map_err(filler_error)?;
+        filler.state_flag = DataHeader::<T>::filler_flag();
+        filler.serialize_personalized(&*self.codec, &[], self.personalization.as_deref()).map_err(filler_error)?;
+        filler.set_data_size(gap - hsize);
+        self.file.seek(SeekFrom::Start(address))?;
+        self.file.write_all(filler.encode_with(&*self.codec).map_err(filler_error)?)?;
+        self.file.write_all(&vec![0u8; (gap - hsize) as usize])?;
+        self.space.filler += gap;
+        let mut index = self.index_mut();
+        index.data_end_address = address + gap;
+        index.epoch += 1;
+        Ok(())
+    }
+
+    /// Fail with StoreErrorKind::ReadOnly unless the store is open for writing
+    fn check_writable(&self) -> Result<(), StoreError> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(StoreError::with_kind(ERROR_FSTORE_READONLY, StoreErrorKind::ReadOnly))
+        }
+    }
+
+    /// Mark the block at index as corrupt.
+    ///
+    /// It stays in the store, but iter skips it and it is listed by quarantined.
+    pub fn quarantine(&mut self, index: BlockId) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        self.mark_corrupt(index)
+    }
+
+    /// The work of quarantine, also done by recovery before the handle is writable
+    fn mark_corrupt(&mut self, index: BlockId) -> Result<(), Box<dyn std::error::Error>> {
+        self.update_block_flags(index, |f| f | DataHeader::<T>::corrupt_flag())?;
+        self.drop_checkpoint()?;
+        let mut idx = self.index_mut();
+        if !idx.quarantined.contains(&index) {
+            idx.quarantined.push(index);
+        }
+        Ok(())
+    }
+
+    /// Blocks that have been quarantined
+    pub fn quarantined(&self) -> Vec<BlockId> {
+        self.index().quarantined.clone()
+    }
+
+    /// When the block at index was written, None if it is from before the
+    /// store kept times or the index was rebuilt by a scan
+    pub fn append_time(&self, index: BlockId) -> Option<SystemTime> {
+        match self.index().append_times.get(index) {
+            Some(0) | None => None,
+            Some(t) => Some(UNIX_EPOCH + Duration::from_secs(*t)),
+        }
+    }
+
+    /// When the block at index was deleted, None if it is live or was
+    /// deleted before the index was last rebuilt by a scan or compaction
+    pub fn delete_time(&self, index: BlockId) -> Option<SystemTime> {
+        self.index().delete_times.get(&index).map(|t| UNIX_EPOCH + Duration::from_secs(*t))
+    }
+
+    /// Erase the payloads of blocks deleted more than older_than ago, and of
+    /// their parity and digest blocks, without compacting.
+    ///
+    /// The blocks keep their indexes and headers, so nothing else moves, but
+    /// what they held is gone: read_at_generation and verification fail on
+    /// them. Deleted blocks with no known delete time count as old enough.
+    /// The space isn't given back to the file system, compact does that.
+    /// Blocks already erased, or pinned, are left as they are.
+    pub fn purge_tombstones(&mut self, older_than: Duration, erase: Erase) -> Result<PurgeReport, Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let cursor = self.file.stream_position()?;
+        let now = self.now();
+        let mut report = PurgeReport::default();
+        for index in 0..self.len() {
+            if !self.block_header(index)?.is_deleted() {
+                continue;
+            }
+            let (deleted, pinned) = {
+                let idx = self.index();
+                (idx.delete_times.get(&index).copied().unwrap_or(0), idx.pins.contains_key(&index))
+            };
+            if pinned || now.saturating_sub(deleted) < older_than.as_secs() {
+                continue;
+            }
+            let erased = self.erase_payloads(index, erase)?;
+            if erased > 0 {
+                report.purged.push(index);
+                report.bytes_erased += erased;
+            }
+        }
+        self.file.sync_data()?;
+        self.file.seek(SeekFrom::Start(cursor))?;
+        Ok(report)
+    }
+
+    /// Overwrite the payload of the block at index, and those of its parity
+    /// and digest blocks, as erase says. Returns the bytes overwritten, 0 if
+    /// they were all zeros already.
+    fn erase_payloads(&mut self, index: BlockId, erase: Erase) -> Result<u64, Box<dyn std::error::Error>> {
+        let hsize = u64::try_from(self.header_size)?;
+        let address = self.block_address(index).ok_or_else(|| StoreError::with_kind(ERROR_OUTOFBOUNDS, StoreErrorKind::OutOfBounds).at(index))?;
+        let size = self.block_header(index)?.fields().size_data;
+        let mut extents = vec![(address + hsize, size)];
+        for (pos, dh) in self.companions(address, hsize + size)? {
+            extents.push((pos + hsize, dh.fields().size_data));
+        }
+        let mut zeroed = true;
+        for (start, len) in &extents {
+            zeroed &= self.is_zeroed(*start, *len)?;
+        }
+        if zeroed {
+            return Ok(0);
+        }
+        if erase == Erase::Secure {
+            for (start, len) in &extents {
+                let mut bytes = blake3::Hasher::new_keyed(&self.erase_key(*start)).finalize_xof();
+                self.overwrite(*start, *len, |b| bytes.fill(b))?;
+            }
+            self.file.sync_data()?;
+        }
+        for (start, len) in &extents {
+            self.overwrite(*start, *len, |b| b.fill(0))?;
+        }
+        Ok(extents.iter().map(|e| e.1).sum())
+    }
+
+    /// true if the len bytes from start are all zero
+    fn is_zeroed(&mut self, start: u64, len: u64) -> Result<bool, Box<dyn std::error::Error>> {
+        self.file.seek(SeekFrom::Start(start))?;
+        let mut buf = self.scratch(len.min(ERASE_CHUNK_SIZE) as usize);
+        let mut left = len;
+        while left > 0 {
+            let n = left.min(ERASE_CHUNK_SIZE) as usize;
+            self.file.read_exact(&mut buf[..n])?;
+            if buf[..n].iter().any(|b| *b != 0) {
+                return Ok(false);
+            }
+            left -= n as u64;
+        }
+        Ok(true)
+    }
+
+    /// Write len bytes from start, a chunk at a time, each made by fill
+    fn overwrite<F: FnMut(&mut [u8])>(&mut self, start: u64, len: u64, mut fill: F) -> Result<(), Error> {
+        self.file.seek(SeekFrom::Start(start))?;
+        let mut buf = self.scratch(len.min(ERASE_CHUNK_SIZE) as usize);
+        let mut left = len;
+        while left > 0 {
+            let n = left.min(ERASE_CHUNK_SIZE) as usize;
+            fill(&mut buf[..n]);
+            self.file.write_all(&buf[..n])?;
+            left -= n as u64;
+        }
+        Ok(())
+    }
+
+    /// Delete the oldest live blocks until the store is within policy.
+    ///
+    /// Blocks with no known append time never count as too old, but are
+    /// still deleted to meet the byte and block limits.
+    /// Returns the deleted blocks.
+    pub fn enforce_retention(&mut self, policy: &RetentionPolicy) -> Result<Vec<BlockId>, Box<dyn std::error::Error>> {
+        let now = self.now();
+        let max_age = policy.max_age.map(|a| a.as_secs());
+        let mut live = Vec::new();
+        let mut dh = DataHeader::<T>::new()?;
+        for i in 0..self.len() {
+            self.seek_block(i)?;
+            self.read_data_header(&mut dh)?;
+            if dh.is_live() {
+                live.push((i, u64::try_from(dh.data_size()?)?));
+            }
+        }
+        let mut bytes: u64 = live.iter().map(|(_, sz)| sz).sum();
+        let mut blocks = live.len();
+        let mut deleted = Vec::new();
+        // blocks are in append order, so the oldest come first
+        for (i, sz) in live {
+            let written = self.index().append_times.get(i).copied().unwrap_or(0);
+            let too_old = match max_age {
+                Some(age) => written != 0 && now.saturating_sub(written) > age,
+                None => false,
+            };
+            let too_big = policy.max_total_bytes.is_some_and(|m| bytes > m);
+            let too_many = policy.max_blocks.is_some_and(|m| blocks > m);
+            if !(too_old || too_big || too_many) {
+                continue;
+            }
+            deleted.push(i);
+            bytes -= sz;
+            blocks -= 1;
+        }
+        self.delete_many(&deleted)?;
+        Ok(deleted)
+    }
+
+    /// Iterate over the payloads of blocks that are neither deleted nor
+    /// quarantined, and that the access policy allows
+    pub fn iter(&mut self) -> StoreIter<'_, T> {
+        StoreIter { store: self, next: 0, session: None }
+    }
+
+    /// Pin the live block at index where it is until the guard is dropped.
+    ///
+    /// While any handle sharing the index holds a guard, compaction fails
+    /// with StoreErrorKind::Pinned, as do swap, truncate_to and
+    /// delete_block_secure of the block; purge_tombstones leaves it be.
+    /// So a reader holding the guard can keep reading the block by address,
+    /// through a map or a stream, without it moving under them. Pinning
+    /// while blocks are being moved fails the same way.
+    pub fn pin(&mut self, index: BlockId) -> Result<BlockGuard, Box<dyn std::error::Error>> {
+        if !self.is_live(index)? {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_NOTLIVE, StoreErrorKind::NotLive).at(index)));
+        }
+        let mut shared = self.index_mut();
+        if shared.moving {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_MOVING, StoreErrorKind::Pinned)));
+        }
+        *shared.pins.entry(index).or_insert(0) += 1;
+        drop(shared);
+        Ok(BlockGuard { index: Arc::clone(&self.index), block: index })
+    }
+
+    /// true while a BlockGuard for the block at index is alive
+    pub fn is_pinned(&self, index: BlockId) -> bool {
+        self.index().pins.contains_key(&index)
+    }
+
+    /// Run f, which moves or reclaims blocks, unless one of blocks, or with
+    /// None any block, is pinned. No block can be pinned meanwhile.
+    fn unpinned<R, F>(&mut self, blocks: Option<&[BlockId]>, f: F) -> Result<R, Box<dyn std::error::Error>>
+    where
+        F: FnOnce(&mut Self) -> Result<R, Box<dyn std::error::Error>>,
+    {
+        // compact swaps in a new index, this one still has to be let go
+        let shared = Arc::clone(&self.index);
+        {
+            let mut index = self.index_mut();
+            let pinned = match blocks {
+                None => index.pins.keys().min().copied(),
+                Some(b) => b.iter().find(|i| index.pins.contains_key(i)).copied(),
+            };
+            if let Some(i) = pinned {
+                return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_PINNED, StoreErrorKind::Pinned).at(i)));
+            }
+            index.moving = true;
+        }
+        let result = f(self);
+        shared.write().unwrap_or_else(|e| e.into_inner()).moving = false;
+        result
+    }
+
+    /// The payloads iter would return, joined into one stream read from
+    /// the file a buffer at a time.
+    ///
+    /// Blocks that aren't returned are skipped by their headers alone,
+    /// without reading their payloads. As with iter, payloads aren't
+    /// checked against their checksums; see scrub.
+    pub fn as_reader(&mut self) -> StoreReader<'_, T> {
+        let mut buf = self.scratch(BLOCK_READER_BUFFER);
+        buf.clear();
+        StoreReader { store: self, next: 0, pos: 0, left: 0, buf, buf_pos: 0 }
+    }
+
+    /// The payload of the live block at index as a seekable stream, read
+    /// from the file a buffer at a time.
+    ///
+    /// The payload is checked against its checksum when the reader first
+    /// reaches the end, which fails with ErrorKind::InvalidData wrapping a
+    /// StoreErrorKind::Checksum StoreError. Bytes read before then aren't
+    /// verified, so parsers should read to the end before trusting them.
+    pub fn block_reader(&mut self, index: BlockId) -> Result<BlockReader<'_, T>, Box<dyn std::error::Error>> {
+        let start = self.seek_block(index)? + u64::try_from(self.header_size)?;
+        let mut header = DataHeader::<T>::new()?;
+        self.read_data_header(&mut header)?;
+        if !header.is_live() {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_NOTLIVE, StoreErrorKind::NotLive).at(index)));
+        }
+        self.check_access(index, &header)?;
+        self.record_access(index);
+        let len = header.fields().size_data;
+        // filled on first read
+        let mut buf = self.scratch(len.min(BLOCK_READER_BUFFER as u64) as usize);
+        buf.clear();
+        Ok(BlockReader { store: self, index, start, len, pos: 0, buf, buf_pos: 0, verified: false })
+    }
+
+    /// Count reads of each block from now on.
+    ///
+    /// Counts are loaded from, and saved on close to, a sidecar file next to
+    /// the store (see AccessStats), so read only handles keep them too.
+    /// Reads are seek, read_at_index and iter; internal reads like scrub don't count.
+    pub fn enable_access_stats(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.access_stats.is_none() {
+            self.access_stats = Some(AccessStats::load(&self.path)?);
+        }
+        Ok(())
+    }
+
+    /// Read counts per block, None unless enabled
+    pub fn access_stats(&self) -> Option<&AccessStats> {
+        self.access_stats.as_ref()
+    }
+
+    /// Count a read of index, if stats are on
+    fn record_access(&mut self, index: BlockId) {
+        if let Some(st) = self.access_stats.as_mut() {
+            st.record(index);
+        }
+    }
+
+    /// Move to the block at index
+    fn seek_block(&mut self, index: BlockId) -> Result<u64, Box<dyn std::error::Error>> {
+        if let Some(a) = self.block_address(index) {
+            Ok(self.file.seek(SeekFrom::Start(a))?)
+        } else {
+            Err(Box::new(StoreError::with_kind(ERROR_OUTOFBOUNDS, StoreErrorKind::OutOfBounds).at(index)))
+        }
+    }
+
+    /// Read the header and payload of the block at index
+    pub(crate) fn read_block(&mut self, index: BlockId) -> Result<(DataHeader<T>, Scratch), Box<dyn std::error::Error>> {
+        self.seek_block(index)?;
+        let mut dh = DataHeader::<T>::new()?;
+        self.read_data_header(&mut dh)?;
+        let data = self.read_payload(&dh)?;
+        Ok((dh, data))
+    }
+
+    /// Read the payload of the block whose header dh was just read
+    fn read_payload(&mut self, dh: &DataHeader<T>) -> Result<Scratch, Box<dyn std::error::Error>> {
+        let mut data = self.scratch(dh.data_size()?);
+        self.file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Header of the first block from index on that iter would return, and
+    /// its index, leaving the file at its payload. None past the last block.
+    ///
+    /// Only headers are read: the payloads of blocks skipped are never
+    /// touched, each header is found from the index.
+    fn next_readable(&mut self, mut index: BlockId) -> Result<Option<Readable<T>>, Box<dyn std::error::Error>> {
+        while index < self.len() {
+            let dh = self.block_header(index)?;
+            if dh.is_live() && self.allowed(index, &dh) {
+                return Ok(Some((index, dh)));
+            }
+            index += 1;
+        }
+        Ok(None)
+    }
+
+    /// Read the header of the block at index, then hand its payload to f in
+    /// pieces of at most chunk bytes, without holding or verifying all of it
+    pub(crate) fn stream_block<F>(&mut self, index: BlockId, chunk: usize, mut f: F) -> Result<DataHeader<T>, Box<dyn std::error::Error>>
+    where
+        F: FnMut(&[u8]),
+    {
+        self.seek_block(index)?;
+        let mut dh = DataHeader::<T>::new()?;
+        self.read_data_header(&mut dh)?;
+        let mut left = dh.data_size()?;
+        let mut buf = self.scratch(chunk.min(left));
+        while left > 0 {
+            let n = chunk.min(left);
+            self.file.read_exact(&mut buf[..n])?;
+            f(&buf[..n]);
+            left -= n;
+        }
+        Ok(dh)
+    }
+
+    /// Write parity for every block written from now on.
+    ///
+    /// Parity is stored in a block of its own straight after the data, and
+    /// lets scrub repair up to config.parity_shards damaged shards of the data.
+    /// Each shard is checked against a checksum made with T, so hashers with
+    /// empty output, as NullBlockHasher, can't be used.
+    #[cfg(feature = "ecc")]
+    pub fn enable_ecc(&mut self, config: EccConfig) -> Result<(), Box<dyn std::error::Error>> {
+        if T::size() == 0 {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_ECCHASH, StoreErrorKind::EmptyHash)));
+        }
+        self.ecc = Some(ReedSolomon::new(config)?);
+        Ok(())
+    }
+
+    /// Stop writing parity for new blocks
+    #[cfg(feature = "ecc")]
+    pub fn disable_ecc(&mut self) {
+        self.ecc = None;
+    }
+
+    /// Keep a strong digest of every block written from now on, see verify.
+    ///
+    /// The digest is blake3, personalized like the store, whatever the
+    /// store's own hasher, so a store can check headers with a fast hasher
+    /// routinely and digests for audits. It is stored in a block of its own
+    /// after the data and any parity.
+    pub fn enable_strong_digests(&mut self) {
+        self.strong_digests = true;
+    }
+
+    /// Stop keeping strong digests for new blocks
+    pub fn disable_strong_digests(&mut self) {
+        self.strong_digests = false;
+    }
+
+    /// blake3 of the payload in parts, personalized like the store
+    fn strong_digest(&self, parts: &[&[u8]]) -> Vec<u8> {
+        let mut hasher = match &self.personalization {
+            Some(p) => B3BlockHasher::create_personalized(p).unwrap(),
+            None => B3BlockHasher::create(),
+        };
+        hasher.hash_parts(parts).to_vec()
+    }
+
+    /// Write the digest block for the payload in parts at the current position.
+    ///
+    /// Its payload is the id of the hasher, then the digest.
+    fn write_digest(&mut self, parts: &[&[u8]]) -> Result<(), Error> {
+        let mut payload = vec![B3BlockHasher::id()];
+        payload.extend_from_slice(&self.strong_digest(parts));
+        let mut dh = DataHeader::<T>::new().map_err(|e| Error::other(e.to_string()))?;
+        dh.state_flag = DataHeader::<T>::digest_flag();
+        let sd = dh
+            .serialize_personalized(&*self.codec, &payload, self.personalization.as_deref())
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+        self.file.write_all(sd)?;
+        self.file.write_all(&payload)
+    }
+
+    /// Check the block at index against its header checksum, Strength::Fast,
+    /// or its strong digest, Strength::Strong.
+    ///
+    /// A block written without strong digests on fails Strong with an error.
+    pub fn verify(&mut self, index: BlockId, strength: Strength) -> Result<bool, Box<dyn std::error::Error>> {
+        if strength == Strength::Fast {
+            return self.verify_block(index);
+        }
+        let (_, data) = self.read_block(index)?;
+        let address = self.block_address(index).unwrap();
+        let unit = u64::try_from(self.header_size + data.len())?;
+        let (digest_address, dh) = self
+            .companions(address, unit)?
+            .into_iter()
+            .find(|(_, h)| h.is_digest())
+            .ok_or_else(|| StoreError::with_kind(ERROR_FSTORE_NODIGEST, StoreErrorKind::NoDigest).at(index))?;
+        let mut payload = vec![0u8; dh.data_size()?];
+        self.file.seek(SeekFrom::Start(digest_address + u64::try_from(self.header_size)?))?;
+        self.file.read_exact(&mut payload)?;
+        if !dh.verify_personalized(&payload, self.personalization.as_deref()) || payload.first() != Some(&B3BlockHasher::id()) {
+            return Ok(false);
+        }
+        Ok(payload[1..] == self.strong_digest(&[&data])[..])
+    }
+
+    /// Checksum of one ecc shard
+    #[cfg(feature = "ecc")]
+    fn shard_checksum(shard: &[u8]) -> [u8; PARITY_CHECKSUM_SIZE] {
+        let mut out = [0u8; PARITY_CHECKSUM_SIZE];
+        let mut hasher = T::create();
+        let h = hasher.hash(shard);
+        let n = h.len().min(PARITY_CHECKSUM_SIZE);
+        out[..n].copy_from_slice(&h[..n]);
+        out
+    }
+
+    /// Write the parity block for data at the current position, if ecc is on
+    #[cfg(feature = "ecc")]
+    fn write_parity(&mut self, data: &[u8]) -> Result<(), Error> {
+        let rs = match &self.ecc {
+            Some(rs) if !data.is_empty() => rs,
+            _ => return Ok(()),
+        };
+        let shards = rs.encode(data);
+        let checksums = rs
+            .split(data)
+            .iter()
+            .chain(shards.iter())
+            .map(|s| Store::<T>::shard_checksum(s))
+            .collect();
+        let config = rs.config();
+        let payload = Parity { config, checksums, shards }.to_bytes();
+        let mut dh = DataHeader::<T>::new().map_err(|e| Error::other(e.to_string()))?;
+        dh.state_flag = DataHeader::<T>::parity_flag();
+        let sd = dh
+            .serialize_personalized(&*self.codec, &payload, self.personalization.as_deref())
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+        self.file.write_all(sd)?;
+        self.file.write_all(&payload)
+    }
+
+    /// Try to rebuild the payload of a block from its parity block
+    #[cfg(feature = "ecc")]
+    fn repair_from_parity(
+        &mut self,
+        index: BlockId,
+        dh: &DataHeader<T>,
+        data: &[u8],
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let address = self.block_address(index).ok_or_else(|| StoreError::with_kind(ERROR_OUTOFBOUNDS, StoreErrorKind::OutOfBounds).at(index))?;
+        let unit = u64::try_from(self.header_size + data.len())?;
+        let (parity_address, ph) = match self.companions(address, unit)?.into_iter().find(|(_, h)| h.is_parity()) {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        self.file.seek(SeekFrom::Start(parity_address + u64::try_from(self.header_size)?))?;
+        let mut payload = vec![0u8; ph.data_size()?];
+        self.file.read_exact(&mut payload)?;
+        let parity = match Parity::from_bytes(&payload) {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        let rs = ReedSolomon::new(parity.config)?;
+        let checksums = parity.checksums;
+        let mut shards: Vec<Option<Vec<u8>>> = rs
+            .split(data)
+            .into_iter()
+            .chain(parity.shards)
+            .enumerate()
+            .map(|(i, s)| match checksums.get(i) {
+                Some(c) if *c == Store::<T>::shard_checksum(&s) => Some(s),
+                _ => None,
+            })
+            .collect();
+        shards.resize(shards.len().max(checksums.len()), None);
+        match rs.reconstruct(&shards, data.len()) {
+            Ok(fixed) if dh.verify_personalized(&fixed, self.personalization.as_deref()) => Ok(Some(fixed)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Verify every block, repairing what parity allows.
+    ///
+    /// Repairs need the ecc feature and a writable store; a repaired block is
+    /// rewritten in place and taken out of quarantine. Deleted blocks are skipped.
+    pub fn scrub(&mut self) -> Result<ScrubReport, Box<dyn std::error::Error>> {
+        self.scrub_with_progress(|_, _| true)
+    }
+
+    /// scrub, reporting progress after each block.
+    ///
+    /// progress is called with bytes of data passed so far and in all, as
+    /// for open_with_progress. Return false from it to stop, which fails
+    /// with StoreErrorKind::Cancelled; repairs already made are kept.
+    pub fn scrub_with_progress<F>(&mut self, mut progress: F) -> Result<ScrubReport, Box<dyn std::error::Error>>
+    where
+        F: FnMut(u64, u64) -> bool,
+    {
+        let mut report = ScrubReport::default();
+        let end = self.index().data_end_address;
+        let total = end - self.data_start_address;
+        for index in 0..self.len() {
+            self.scrub_block(index, &mut report)?;
+            let done = self.block_address(index + 1).unwrap_or(end) - self.data_start_address;
+            if !progress(done, total) {
+                return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_CANCELLED, StoreErrorKind::Cancelled)));
+            }
+        }
+        report.pass_complete = true;
+        self.index_mut().lifetime.last_verified = self.now();
+        Ok(report)
+    }
+
+    /// Scrub blocks until about budget_bytes of payload have been verified.
+    ///
+    /// Carries on from where the last call stopped, wrapping back to the first
+    /// block at the end of the store. The position is saved in the index footer,
+    /// so it survives close and reopen.
+    /// At least one block is checked per call, so a block bigger than the budget
+    /// doesn't stall it.
+    pub fn scrub_incremental(&mut self, budget_bytes: u64) -> Result<ScrubReport, Box<dyn std::error::Error>> {
+        let mut report = ScrubReport::default();
+        let len = self.len();
+        let mut index = self.index().scrub_position;
+        if index >= len {
+            index = 0;
+        }
+        // never go round more than once
+        for _ in 0..len {
+            self.scrub_block(index, &mut report)?;
+            index += 1;
+            if index == len {
+                index = 0;
+                report.pass_complete = true;
+            }
+            if report.bytes_checked >= budget_bytes || report.pass_complete {
+                break;
+            }
+        }
+        let mut idx = self.index_mut();
+        idx.scrub_position = index;
+        if report.pass_complete {
+            idx.lifetime.last_verified = self.now();
+        }
+        drop(idx);
+        Ok(report)
+    }
+
+    /// Verify one block for scrub
+    fn scrub_block(&mut self, index: BlockId, report: &mut ScrubReport) -> Result<(), Box<dyn std::error::Error>> {
+        let (dh, data) = self.read_block(index)?;
+        if dh.state_flag & DataHeader::<T>::delete_flag() != 0 {
+            return Ok(());
+        }
+        report.blocks_checked += 1;
+        report.bytes_checked += u64::try_from(data.len())?;
+        if dh.verify_personalized(&data, self.personalization.as_deref()) {
+            return Ok(());
+        }
+        #[cfg(feature = "ecc")]
+        {
+            if self.writable {
+                if let Some(fixed) = self.repair_from_parity(index, &dh, &data)? {
+                    self.restore_payload(index, &dh, &fixed)?;
+                    report.repaired.push(index);
+                    return Ok(());
+                }
+            }
+        }
+        report.failed.push(index);
+        Ok(())
+    }
+
+    /// Write payload over the damaged payload of the block at index, whose
+    /// header is dh, taking it out of quarantine and telling observers.
+    ///
+    /// payload must verify against dh, so only a good copy of what was there
+    /// can be written. The repair is synced before observers are told.
+    pub(crate) fn restore_payload(&mut self, index: BlockId, dh: &DataHeader<T>, payload: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        if !dh.verify_personalized(payload, self.personalization.as_deref()) {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_CHECKSUM, StoreErrorKind::Checksum).at(index).offset(self.block_address(index))));
+        }
+        let address = self.block_address(index).ok_or_else(|| StoreError::with_kind(ERROR_OUTOFBOUNDS, StoreErrorKind::OutOfBounds).at(index))?;
+        self.file.seek(SeekFrom::Start(address + u64::try_from(self.header_size)?))?;
+        self.file.write_all(payload)?;
+        if dh.is_corrupt() {
+            self.drop_checkpoint()?;
+            self.update_block_flags(index, |f| f & !DataHeader::<T>::corrupt_flag())?;
+            self.index_mut().quarantined.retain(|q| *q != index);
+        }
+        self.sync_blocks()?;
+        for o in self.observers.iter_mut() {
+            o.on_repair(index);
+        }
+        Ok(())
+    }
+
+    /// Compact the store without a second copy of it, by sliding live blocks
+    /// down over the dead ones.
+    ///
+    /// For when there isn't room for compact's new file. Blocks are moved
+    /// one at a time, each with its parity block if it has one. Before a
+    /// block is moved it is copied to a relocation journal next to the store
+    /// (its name with ".reloc" appended) and synced, and the space it leaves
+    /// behind is covered by a filler block, so the store can be scanned after
+    /// every step. If the process dies part way through, the interrupted move
+    /// is finished when the store is next opened for writing; blocks moved
+    /// before then keep their new indexes and the rest their old ones, less
+    /// any dead blocks already passed, so run it again to finish.
+    ///
+    /// The extra space needed is one block in the journal. Blocks get new
+    /// indexes as with compact, and delete journals already applied are
+    /// turned into filler, since their indexes would no longer mean anything.
+    /// Payloads are moved as they are, without being verified. Fails with
+    /// StoreErrorKind::Pinned while any block is pinned, see pin.
+    pub fn compact_in_place(&mut self) -> Result<CompactReport, Box<dyn std::error::Error>> {
+        self.unpinned(None, |st| st.slide_compact())
+    }
+
+    /// The work of compact_in_place, once no block is pinned
+    fn slide_compact(&mut self) -> Result<CompactReport, Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let (before, old_addresses) = {
+            let index = self.index();
+            (index.data_end_address, index.block_addresses.clone())
+        };
+        self.drop_checkpoint()?;
+        self.retire_journals()?;
+        self.set_dedup_window(self.dedup.as_ref().map(|w| w.capacity));
+        let journal_path = format!("{}.reloc", self.path);
+        let mut journal = OpenOptions::new().write(true).create(true).truncate(true).open(&journal_path)?;
+        platform::sync_dir_of(&journal_path)?;
+        let slid = self.slide_blocks(&mut journal);
+        let (remap, addresses, times, end) = match slid {
+            Ok(s) => s,
+            Err(e) => {
+                // blocks moved so far are only known to the file now
+                self.index_blocks(0, &mut |_, _| true)?;
+                return Err(e);
+            }
+        };
+        self.file.set_len(end)?;
+        self.file.sync_data()?;
+        drop(journal);
+        std::fs::remove_file(&journal_path)?;
+        {
+            let mut index = self.index_mut();
+            index.scrub_position = remap.iter().skip(index.scrub_position).flatten().next().copied().unwrap_or(0);
+            index.block_addresses = addresses;
+            index.append_times = times;
+            index.delete_times.clear();
+            index.lifetime.compactions += 1;
+            index.quarantined.clear();
+            index.data_end_address = end;
+            index.epoch += 1;
+        }
+        self.rebuild_bloom()?;
+        self.access_stats = self.access_stats.take().map(|st| st.remap(&remap));
+        self.file.seek(SeekFrom::Start(self.data_start_address))?;
+        let report = CompactReport {
+            remap,
+            bytes_reclaimed: before.saturating_sub(end),
+        };
+        self.notify_compacted(&old_addresses, &report);
+        self.audit(AuditAction::Compact { bytes_reclaimed: report.bytes_reclaimed })?;
+        Ok(report)
+    }
+
+    /// Turn every delete journal block into filler
+    fn retire_journals(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let end = self.index().data_end_address;
+        let hsize = u64::try_from(self.header_size)?;
+        let mut pos = self.data_start_address;
+        let mut dh = DataHeader::<T>::new()?;
+        while pos + hsize <= end {
+            self.file.seek(SeekFrom::Start(pos))?;
+            self.read_data_header(&mut dh)?;
+            if dh.is_journal() {
+                self.update_flags_at(pos, |_| DataHeader::<T>::filler_flag())?;
+            }
+            pos += hsize + u64::try_from(dh.data_size()?)?;
+        }
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// The moving part of compact_in_place.
+    ///
+    /// Returns the remap, the new block addresses and append times, and
+    /// where the last block moved ends.
+    fn slide_blocks(&mut self, journal: &mut File) -> Result<SlidBlocks, Box<dyn std::error::Error>> {
+        let (old_addresses, old_times) = {
+            let index = self.index();
+            (index.block_addresses.clone(), index.append_times.clone())
+        };
+        let hsize = u64::try_from(self.header_size)?;
+        let mut dst = self.data_start_address;
+        let mut remap = Vec::with_capacity(old_addresses.len());
+        let mut addresses = Vec::new();
+        let mut times = Vec::new();
+        let mut dh = DataHeader::<T>::new()?;
+        for (i, src) in old_addresses.iter().enumerate() {
+            self.file.seek(SeekFrom::Start(*src))?;
+            self.read_data_header(&mut dh)?;
+            let live = dh.is_live();
+            let mut unit = hsize + u64::try_from(dh.data_size()?)?;
+            // parity and digests have to stay straight after their block
+            unit = self.companions_end(*src, unit)? - src;
+            if !live {
+                remap.push(None);
+                continue;
+            }
+            if *src != dst {
+                let mut bytes = vec![0u8; usize::try_from(unit)?];
+                self.file.seek(SeekFrom::Start(*src))?;
+                self.file.read_exact(&mut bytes)?;
+                let m = Relocation { src: *src, dst, bytes };
+                self.write_relocation(journal, std::slice::from_ref(&m))?;
+                self.apply_relocation(&m)?;
+                self.file.sync_data()?;
+                journal.set_len(0)?;
+                journal.sync_data()?;
+            }
+            remap.push(Some(addresses.len()));
+            addresses.push(dst);
+            times.push(old_times.get(i).copied().unwrap_or(0));
+            dst += unit;
+        }
+        Ok((remap, addresses, times, dst))
+    }
+
+    /// Record moves in the relocation journal and sync it.
+    ///
+    /// The journal is RELOCATION_MAGIC, a u64 count, then u64 src, u64 dst,
+    /// u64 length and the bytes of each move, then the hash with T of
+    /// everything before it.
+    fn write_relocation(&self, journal: &mut File, moves: &[Relocation]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut out = RELOCATION_MAGIC.to_vec();
+        out.extend_from_slice(&u64::try_from(moves.len())?.to_le_bytes());
+        for m in moves {
+            out.extend_from_slice(&m.src.to_le_bytes());
+            out.extend_from_slice(&m.dst.to_le_bytes());
+            out.extend_from_slice(&u64::try_from(m.bytes.len())?.to_le_bytes());
+            out.extend_from_slice(&m.bytes);
+        }
+        let hash = T::create().hash(&out).to_vec();
+        out.extend_from_slice(&hash);
+        journal.set_len(0)?;
+        journal.seek(SeekFrom::Start(0))?;
+        journal.write_all(&out)?;
+        journal.sync_data()?;
+        Ok(())
+    }
+
+    /// Inverse of write_relocation, None if the journal is empty or was torn
+    fn decode_relocation(data: &[u8]) -> Option<Vec<Relocation>> {
+        let body_len = data.len().checked_sub(T::size())?;
+        if body_len < 16 || &data[0..8] != RELOCATION_MAGIC {
+            return None;
+        }
+        let (body, hash) = data.split_at(body_len);
+        if T::create().hash(body) != hash {
+            return None;
+        }
+        let count = u64::from_le_bytes(body[8..16].try_into().ok()?);
+        let mut moves = Vec::new();
+        let mut pos = 16;
+        for _ in 0..count {
+            let field = |at: usize| Some(u64::from_le_bytes(body.get(at..at + 8)?.try_into().ok()?));
+            let len = usize::try_from(field(pos + 16)?).ok()?;
+            let bytes = body.get(pos + 24..(pos + 24).checked_add(len)?)?.to_vec();
+            moves.push(Relocation {
+                src: field(pos)?,
+                dst: field(pos + 8)?,
+                bytes,
+            });
+            pos += 24 + len;
+        }
+        Some(moves)
+    }
+
+    /// Write the bytes of a move at dst, and cover the rest of the space
+    /// up to where they ended at src with a filler block
+    fn apply_relocation(&mut self, m: &Relocation) -> Result<(), Box<dyn std::error::Error>> {
+        let hsize = u64::try_from(self.header_size)?;
+        let len = u64::try_from(m.bytes.len())?;
+        let gap = m
+            .src
+            .checked_sub(m.dst)
+            .filter(|g| *g == 0 || *g >= hsize)
+            .ok_or_else(|| StoreError::with_kind(ERROR_FSTORE_INVSIZE, StoreErrorKind::InvalidSize))?;
+        self.file.seek(SeekFrom::Start(m.dst))?;
+        self.file.write_all(&m.bytes)?;
+        if gap == 0 {
+            return Ok(());
+        }
+        let mut filler = DataHeader::<T>::new()?;
+        filler.state_flag = DataHeader::<T>::filler_flag();
+        filler.serialize_personalized(&*self.codec, &[], self.personalization.as_deref())?;
+        filler.set_data_size(gap - hsize);
+        self.file.seek(SeekFrom::Start(m.dst + len))?;
+        self.file.write_all(filler.encode_with(&*self.codec)?)?;
+        self.space.filler += hsize;
+        Ok(())
+    }
+
+    /// Apply moves all or nothing, through a journal of their own
+    fn relocate_journaled(&mut self, moves: &[Relocation]) -> Result<(), Box<dyn std::error::Error>> {
+        let journal_path = format!("{}.reloc", self.path);
+        let mut journal = OpenOptions::new().write(true).create(true).truncate(true).open(&journal_path)?;
+        platform::sync_dir_of(&journal_path)?;
+        self.write_relocation(&mut journal, moves)?;
+        for m in moves {
+            self.apply_relocation(m)?;
+        }
+        self.file.sync_data()?;
+        drop(journal);
+        std::fs::remove_file(&journal_path)?;
+        Ok(())
+    }
+
+    /// Bytes the block at index takes up, with its parity and digest blocks
+    fn block_extent(&mut self, index: BlockId) -> Result<u64, Box<dyn std::error::Error>> {
+        let dh = self.block_header(index)?;
+        let hsize = u64::try_from(self.header_size)?;
+        let address = self.block_address(index).unwrap();
+        let extent = hsize + u64::try_from(dh.data_size()?)?;
+        Ok(self.companions_end(address, extent)? - address)
+    }
+
+    /// Parity and digest blocks straight after the block at address, whose
+    /// header and payload take unit bytes, with their addresses
+    fn companions(&mut self, address: u64, unit: u64) -> Result<Companions<T>, Box<dyn std::error::Error>> {
+        let hsize = u64::try_from(self.header_size)?;
+        let end = self.index().data_end_address;
+        let mut pos = address + unit;
+        let mut out = Vec::new();
+        while pos + hsize <= end {
+            let mut dh = DataHeader::<T>::new()?;
+            self.file.seek(SeekFrom::Start(pos))?;
+            self.read_data_header(&mut dh)?;
+            if !dh.is_parity() && !dh.is_digest() {
+                break;
+            }
+            let size = hsize + u64::try_from(dh.data_size()?)?;
+            out.push((pos, dh));
+            pos += size;
+        }
+        Ok(out)
+    }
+
+    /// Where the companions of the block at address end, see companions
+    fn companions_end(&mut self, address: u64, unit: u64) -> Result<u64, Box<dyn std::error::Error>> {
+        let hsize = u64::try_from(self.header_size)?;
+        Ok(match self.companions(address, unit)?.last() {
+            Some((pos, dh)) => pos + hsize + u64::try_from(dh.data_size()?)?,
+            None => address + unit,
+        })
+    }
+
+    /// A move rewriting just the header at address, with its flags changed by update
+    fn flag_rewrite<F>(&mut self, address: u64, update: F) -> Result<Relocation, Box<dyn std::error::Error>>
+    where
+        F: FnOnce(u32) -> u32,
+    {
+        let mut dh = DataHeader::<T>::new()?;
+        self.file.seek(SeekFrom::Start(address))?;
+        self.read_data_header(&mut dh)?;
+        dh.state_flag = update(dh.state_flag);
+        Ok(Relocation {
+            src: address,
+            dst: address,
+            bytes: dh.encode_with(&*self.codec)?.clone(),
+        })
+    }
+
+    /// Replace the payloads of several blocks at once.
+    ///
+    /// Either every replacement happens or, after a crash, none do: the
+    /// changes are staged, then committed through the relocation journal
+    /// (see compact_in_place), which is finished when the store is next
+    /// opened for writing.
+    ///
+    /// A payload that fits where the old one was is written there and keeps
+    /// its index. One that doesn't is appended as a new block and the old
+    /// one deleted. Returns the index each replacement ended up at, in order.
+    /// A block rewritten in place loses its parity. Fails with
+    /// StoreErrorKind::Pinned if a block replaced is pinned, see pin.
+    pub fn swap(&mut self, replacements: &[(BlockId, &[u8])]) -> Result<Vec<BlockId>, Box<dyn std::error::Error>> {
+        let blocks: Vec<BlockId> = replacements.iter().map(|r| r.0).collect();
+        self.unpinned(Some(&blocks), |st| st.swap_unpinned(replacements))
+    }
+
+    /// The work of swap, once none of the blocks replaced is pinned
+    fn swap_unpinned(&mut self, replacements: &[(BlockId, &[u8])]) -> Result<Vec<BlockId>, Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let cursor = self.file.stream_position()?;
+        self.drop_checkpoint()?;
+        let staged = self.stage_swap(replacements)?;
+        self.relocate_journaled(&staged.moves)?;
+        self.space.logical += staged.in_place_bytes;
+        self.space.headers += u64::try_from(self.header_size * staged.in_place.len())?;
+        let full = {
+            let mut idx = self.index_mut();
+            for c in &staged.checksums {
+                idx.bloom.insert(c);
+            }
+            let now = self.now();
+            for i in &staged.in_place {
+                if let Some(t) = idx.append_times.get_mut(*i) {
+                    *t = now;
+                }
+            }
+            idx.lifetime.blocks_written += staged.in_place.len() as u64;
+            idx.lifetime.bytes_written += staged.in_place_bytes;
+            for ((old, _), id) in replacements.iter().zip(&staged.ids) {
+                if old != id {
+                    idx.delete_times.insert(*old, now);
+                }
+            }
+            idx.epoch += 1;
+            idx.bloom.is_full()
+        };
+        if full {
+            self.rebuild_bloom()?;
+        }
+        for id in &staged.ids {
+            self.audit(AuditAction::Write(*id))?;
+        }
+        self.file.seek(SeekFrom::Start(cursor))?;
+        Ok(staged.ids)
+    }
+
+    /// Check the replacements for swap and write what can be written
+    /// before the commit: blocks that move are appended already deleted.
+    fn stage_swap(&mut self, replacements: &[(BlockId, &[u8])]) -> Result<StagedSwap, Box<dyn std::error::Error>> {
+        let mut seen = std::collections::HashSet::new();
+        for (index, data) in replacements {
+            self.check_block_size(data.len() as u64)?;
+            if !seen.insert(*index) {
+                return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_DUPLICATE, StoreErrorKind::Duplicate).at(*index)));
+            }
+            if !self.block_header(*index)?.is_live() {
+                return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_NOTLIVE, StoreErrorKind::NotLive).at(*index)));
+            }
+        }
+        let hsize = u64::try_from(self.header_size)?;
+        let mut staged = StagedSwap::default();
+        for (index, data) in replacements {
+            let address = self.block_address(*index).unwrap();
+            let old_extent = self.block_extent(*index)?;
+            let attrs = BlockAttrs::of(&self.block_header(*index)?);
+            let mut nh = DataHeader::<T>::new()?;
+            nh.state_flag = DataHeader::<T>::rewritten_flag();
+            attrs.apply(&mut nh);
+            let mut bytes = nh.serialize_personalized(&*self.codec, data, self.personalization.as_deref())?.clone();
+            bytes.extend_from_slice(data);
+            let new_extent = u64::try_from(bytes.len())?;
+            if new_extent == old_extent || (new_extent < old_extent && old_extent - new_extent >= hsize) {
+                staged.moves.push(Relocation {
+                    src: address + old_extent - new_extent,
+                    dst: address,
+                    bytes,
+                });
+                staged.checksums.push(nh.fields().checksum);
+                staged.in_place.push(*index);
+                staged.in_place_bytes += u64::try_from(data.len())?;
+                staged.ids.push(*index);
+            } else {
+                let id = self.append_block_with_flags(data, DataHeader::<T>::delete_flag(), attrs)?;
+                let new_address = self.block_address(id).unwrap();
+                staged.moves.push(self.flag_rewrite(new_address, |f| DataHeader::<T>::set_delete_flag(false, f))?);
+                staged.moves.push(self.flag_rewrite(address, |f| DataHeader::<T>::set_delete_flag(true, f))?);
+                staged.ids.push(id);
+            }
+        }
+        // the new blocks must be on disk before the journal says to use them
+        self.file.sync_data()?;
+        Ok(staged)
+    }
+
+    /// Replace the payload of the block at index, but only if its checksum
+    /// is still expected_hash, for optimistic concurrency.
+    ///
+    /// expected_hash is compared as far as the store keeps checksums, as in
+    /// maybe_contains. A block that changed fails with StoreErrorKind::Conflict.
+    /// Otherwise this is swap of the one block, and returns the index the
+    /// new payload is at.
+    pub fn update_if(
+        &mut self,
+        index: BlockId,
+        expected_hash: &[u8],
+        new_data: &[u8],
+    ) -> Result<BlockId, Box<dyn std::error::Error>> {
+        let dh = self.block_header(index)?;
+        let checksum = dh.fields().checksum;
+        if dh.is_live() && (expected_hash.len() < checksum.len() || expected_hash[..checksum.len()] != checksum[..]) {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_CONFLICT, StoreErrorKind::Conflict).at(index)));
+        }
+        Ok(self.swap(&[(index, new_data)])?[0])
+    }
+
+    /// Finish moves interrupted by a crash, then drop the relocation journal
+    fn replay_relocation(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let path = format!("{}.reloc", self.path);
+        let data = match std::fs::read(&path) {
+            Ok(d) => d,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(Box::new(e)),
+        };
+        // a torn journal means the moves never started
+        if let Some(moves) = Store::<T>::decode_relocation(&data) {
+            for m in &moves {
+                self.apply_relocation(m)?;
+            }
+            self.file.sync_data()?;
+        }
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    /// Check the store is fit to serve, for readiness and liveness probes.
+    ///
+    /// Uses HEALTH_FRAGMENTATION_THRESHOLD, see health_with.
+    pub fn health(&mut self) -> Result<Health, Box<dyn std::error::Error>> {
+        self.health_with(HEALTH_FRAGMENTATION_THRESHOLD)
+    }
+
+    /// Check the store is fit to serve.
+    ///
+    /// Quarantined blocks make it Corrupt. A store left dirty that this
+    /// handle didn't recover (writers recover on open), an interrupted in
+    /// place compaction, or more than fragmentation_threshold of the data
+    /// being dead make it Degraded.
+    /// Only block headers are read, payloads are not verified; see scrub.
+    pub fn health_with(&mut self, fragmentation_threshold: f64) -> Result<Health, Box<dyn std::error::Error>> {
+        let cursor = self.file.stream_position()?;
+        let end = self.index().data_end_address;
+        let hsize = u64::try_from(self.header_size)?;
+        let mut used = 0;
+        let mut pos = self.data_start_address;
+        let mut dh = DataHeader::<T>::new()?;
+        while pos + hsize <= end {
+            self.file.seek(SeekFrom::Start(pos))?;
+            self.read_data_header(&mut dh)?;
+            let size = hsize + u64::try_from(dh.data_size()?)?;
+            let kept = if dh.is_system() { dh.is_parity() || dh.is_digest() } else { dh.is_live() };
+            if kept {
+                used += size;
+            }
+            pos += size;
+        }
+        self.file.seek(SeekFrom::Start(cursor))?;
+        let total = end - self.data_start_address;
+        let fragmentation = if total == 0 { 0.0 } else { (total - used.min(total)) as f64 / total as f64 };
+        let dirty = self.opened_dirty && !self.writable;
+        let quarantined = self.quarantined();
+        let journal_pending = Path::new(&format!("{}.reloc", self.path)).exists();
+        let mut problems = Vec::new();
+        if !quarantined.is_empty() {
+            problems.push(format!("{} quarantined blocks", quarantined.len()));
+        }
+        if dirty {
+            problems.push("store was not closed cleanly".to_string());
+        }
+        if journal_pending {
+            problems.push("in place compaction was interrupted".to_string());
+        }
+        if fragmentation > fragmentation_threshold {
+            problems.push(format!("{:.0}% of the data is dead", fragmentation * 100.0));
+        }
+        let status = if !quarantined.is_empty() {
+            HealthStatus::Corrupt
+        } else if problems.is_empty() {
+            HealthStatus::Ok
+        } else {
+            HealthStatus::Degraded
+        };
+        Ok(Health {
+            status,
+            dirty,
+            quarantined,
+            fragmentation,
+            journal_pending,
+            problems,
+        })
+    }
+
+    /// Check the whole store: the chain of headers against the index, delete
+    /// and relocation journals, and every payload, as scrub does.
+    ///
+    /// A writable handle also repairs: a dirty store was already recovered
+    /// when opened, scrub repairs what parity allows, and blocks that still
+    /// fail are quarantined.
+    pub fn fsck(&mut self) -> Result<FsckReport, Box<dyn std::error::Error>> {
+        let mut report = FsckReport {
+            dirty: self.opened_dirty && !self.writable,
+            recovered: self.opened_dirty && self.writable,
+            journal_pending: Path::new(&format!("{}.reloc", self.path)).exists(),
+            ..FsckReport::default()
+        };
+        self.check_chain(&mut report)?;
+        if !report.chain_errors.is_empty() {
+            return Ok(report);
+        }
+        report.scrub = self.scrub()?;
+        let quarantined = self.quarantined();
+        for &index in &report.scrub.failed {
+            if quarantined.contains(&index) {
+                continue;
+            }
+            if self.writable {
+                self.quarantine(index)?;
+                report.quarantined.push(index);
+            } else {
+                report.unresolved.push(index);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Walk the headers from the start of the data, checking each block
+    /// fits and every indexed block is where the index says
+    fn check_chain(&mut self, report: &mut FsckReport) -> Result<(), Box<dyn std::error::Error>> {
+        let cursor = self.file.stream_position()?;
+        let (end, addresses) = {
+            let index = self.index();
+            (index.data_end_address, index.block_addresses.clone())
+        };
+        let hsize = u64::try_from(self.header_size)?;
+        let mut pos = self.data_start_address;
+        let mut found = 0;
+        let mut dh = DataHeader::<T>::new()?;
+        while pos < end {
+            if pos + hsize > end {
+                report.chain_errors.push(format!("partial header at {}", pos));
+                break;
+            }
+            self.file.seek(SeekFrom::Start(pos))?;
+            if let Err(e) = self.read_data_header(&mut dh) {
+                report.chain_errors.push(format!("bad header at {}: {}", pos, e));
+                break;
+            }
+            let next = (pos + hsize).saturating_add(u64::try_from(dh.data_size()?)?);
+            if next > end {
+                report.chain_errors.push(format!("block at {} runs past the end of the data at {}", pos, end));
+                break;
+            }
+            report.headers_checked += 1;
+            if dh.is_journal() {
+                report.journal_blocks += 1;
+            }
+            if !dh.is_system() {
+                match addresses.get(found) {
+                    Some(a) if *a == pos => {}
+                    Some(a) => {
+                        report.chain_errors.push(format!("block {} is indexed at {} but found at {}", found, a, pos));
+                        break;
+                    }
+                    None => {
+                        report.chain_errors.push(format!("block at {} is not indexed", pos));
+                        break;
+                    }
+                }
+                found += 1;
+            }
+            pos = next;
+        }
+        if report.chain_errors.is_empty() && found < addresses.len() {
+            report.chain_errors.push(format!("{} indexed blocks not found", addresses.len() - found));
+        }
+        self.file.seek(SeekFrom::Start(cursor))?;
+        Ok(())
+    }
+
+    /// true if the store was not closed cleanly before we opened it
+    pub fn is_dirty(&self) -> bool {
+        self.opened_dirty
+    }
+
+    /// Check the payload of the block at index against its hash
+    pub fn verify_block(&mut self, index: usize) -> Result<bool, Box<dyn std::error::Error>> {
+        let (dh, data) = self.read_block(index)?;
+        Ok(dh.verify_personalized(&data, self.personalization.as_deref()))
+    }
+
+    /// Rebuild the index of a dirty store and cut off anything after the last whole block.
+    ///
+    /// Blocks scanned whose payload doesn't verify are torn writes: those at
+    /// the end are cut off, others are quarantined. Blocks before the
+    /// checkpoint were synced before it was written, so they aren't read;
+    /// damage to them is for scrub to find.
+    fn recover(&mut self, progress: &mut dyn FnMut(u64, u64) -> bool) -> Result<(), Box<dyn std::error::Error>> {
+        // a block half way through a move can't be scanned past until it's finished
+        self.replay_relocation()?;
+        let (journaled, first) = self.index_unclean(progress)?;
+        let mut torn = Vec::new();
+        for i in first..self.len() {
+            let (dh, data) = self.read_block(i)?;
+            // already known to be bad
+            if !dh.is_corrupt() && !dh.verify_personalized(&data, self.personalization.as_deref()) {
+                torn.push(i);
+            }
+        }
+        let mut kept = self.len();
+        while kept > first && torn.last() == Some(&(kept - 1)) {
+            torn.pop();
+            kept -= 1;
+        }
+        if kept < self.len() {
+            let cut = self.block_address(kept).ok_or_else(|| StoreError::with_kind(ERROR_OUTOFBOUNDS, StoreErrorKind::OutOfBounds).at(kept))?;
+            let mut index = self.index_mut();
+            let dropped = index.data_end_address - cut;
+            let count = index.block_addresses.len() - kept;
+            index.block_addresses.truncate(kept);
+            index.append_times.truncate(kept);
+            index.quarantined.retain(|q| *q < kept);
+            index.data_end_address = cut;
+            index.epoch += 1;
+            drop(index);
+            if let Some(report) = self.recovery.as_mut() {
+                report.blocks_recovered -= count;
+                report.blocks_torn = count;
+                report.bytes_discarded += dropped;
+            }
+        }
+        for i in &torn {
+            self.mark_corrupt(*i)?;
+        }
+        if let Some(report) = self.recovery.as_mut() {
+            report.quarantined = torn;
+        }
+        let end = self.index().data_end_address;
+        self.file.set_len(end)?;
+        // finish deletes interrupted by the crash, repeating finished ones is harmless
+        let len = self.len();
+        let journaled: Vec<BlockId> = journaled.into_iter().filter(|i| *i < len).collect();
+        self.apply_deletes(&journaled)
+    }
+
+    /// Flush, write the index footer, sync to disk and release the lock.
+    ///
+    /// Dropping a Store does the same, but any error is lost.
+    pub fn close(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.close_in_place()
+    }
+
+    /// close for owners that can't give up the Store
+    ///
+    /// Nothing may be done with the store afterwards but dropping it.
+    pub(crate) fn close_in_place(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.closed = true;
+        self.finalize()
+    }
+
+    /// Does the work of close
+    fn finalize(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(st) = &self.access_stats {
+            st.save(&self.path)?;
+        }
+        if self.writable {
+            self.seal()?;
+            self.file.unlock()?;
+        }
+        Ok(())
+    }
+
+    /// Write the index footer, sync, then mark the store clean
+    fn seal(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.file.flush()?;
+        self.write_index_footer()?;
+        self.file.sync_all()?;
+        // only clean once the footer is on disk
+        self.write_descriptor_state(
+            self.descriptor_flags & !DESCRIPTOR_FLAG_DIRTY,
+            self.features | FEATURE_INDEX_FOOTER,
+        )?;
+        Ok(())
+    }
+
+    /// Undo seal before writing, marking the store dirty
+    fn unseal(&mut self) -> Result<(), Error> {
+        // drop the footer, so a crash before close can't leave pieces
+        // of it after new blocks
+        let end = self.index().data_end_address;
+        self.file.set_len(end)?;
+        self.write_descriptor_state(
+            self.descriptor_flags | DESCRIPTOR_FLAG_DIRTY,
+            self.features & !FEATURE_INDEX_FOOTER,
+        )
+    }
+
+    /// Rewrite the store without its deleted and quarantined blocks.
+    ///
+    /// The live blocks are copied in order to a new file next to the store
+    /// (its name with ".compact" appended), which is synced and then renamed
+    /// over the store, so a crash at any point leaves either the old store
+    /// or the compacted one. A leftover ".compact" file is only a partial
+    /// copy and can be removed.
+    ///
+    /// Blocks get new indexes; the report maps old ones to new. Indexes
+    /// kept inside payloads aren't changed, so a KvStore's file, whose pages
+    /// hold block indexes, must be compacted with KvStore::compact instead.
+    /// Every block copied is verified first, and one that fails stops the
+    /// compaction with StoreErrorKind::Checksum, so scrub or quarantine it first.
+    /// Handles from try_clone keep reading the old file. Fails with
+    /// StoreErrorKind::Pinned while any block is pinned, see pin.
+    pub fn compact(&mut self) -> Result<CompactReport, Box<dyn std::error::Error>> {
+        self.compact_with_progress(|_, _| true)
+    }
+
+    /// compact, reporting progress after each block copied.
+    ///
+    /// progress is called with bytes of the old data passed so far and in
+    /// all, as for open_with_progress. Return false from it to cancel, which
+    /// removes the partial copy and fails with StoreErrorKind::Cancelled.
+    pub fn compact_with_progress<F>(&mut self, progress: F) -> Result<CompactReport, Box<dyn std::error::Error>>
+    where
+        F: FnMut(u64, u64) -> bool,
+    {
+        let codec = self.own_codec()?;
+        self.unpinned(None, |st| st.copy_compact(codec, progress))
+    }
+
+    /// compact, writing the headers of the compacted store with codec, so
+    /// compaction upgrades a store to another header layout.
+    ///
+    /// Payloads are copied as they are, with checksums, parity and digests
+    /// made afresh as for any block put now. A block with a content type,
+    /// owner or permissions codec can't record stops the compaction, before
+    /// the store is replaced, rather than lose them.
+    pub fn compact_with_codec(&mut self, codec: Box<dyn HeaderCodec>) -> Result<CompactReport, Box<dyn std::error::Error>> {
+        self.unpinned(None, |st| st.copy_compact(codec, |_, _| true))
+    }
+
+    /// A new instance of this store's header codec
+    fn own_codec(&self) -> Result<Box<dyn HeaderCodec>, StoreError> {
+        header_codec(self.codec_id).ok_or_else(|| StoreError::with_kind(ERROR_FSTORE_CODEC, StoreErrorKind::UnknownCodec).value("codec", u64::from(self.codec_id)))
+    }
+
+    /// The work of compact_with_progress, once no block is pinned
+    fn copy_compact<F>(&mut self, codec: Box<dyn HeaderCodec>, mut progress: F) -> Result<CompactReport, Box<dyn std::error::Error>>
+    where
+        F: FnMut(u64, u64) -> bool,
+    {
+        self.check_writable()?;
+        let (before, old_addresses) = {
+            let index = self.index();
+            (index.data_end_address, index.block_addresses.clone())
+        };
+        let tmp = format!("{}.compact", self.path);
+        let (mut out, remap) = self.copy_live::<T>(&tmp, codec, &mut progress)?;
+        let (scrub_position, lifetime) = {
+            let index = self.index();
+            (index.scrub_position, index.lifetime)
+        };
+        {
+            let mut index = out.index_mut();
+            index.scrub_position = remap.iter().skip(scrub_position).flatten().next().copied().unwrap_or(0);
+            index.lifetime = LifetimeCounters { compactions: lifetime.compactions + 1, ..lifetime };
+        }
+        out.seal()?;
+        self.drop_checkpoint()?;
+        platform::replace(&tmp, &self.path)?;
+        out.path = self.path.clone();
+        out.unseal()?;
+        out.access_stats = self.access_stats.take().map(|st| st.remap(&remap));
+        out.observers = std::mem::take(&mut self.observers);
+        out.session = self.session;
+        // the copy counts as rewriting, not as new payloads
+        out.space = SpaceCounters { replaced: self.space.replaced + self.file.total_written(), ..self.space };
+        let after = out.index().data_end_address;
+        let mut old = std::mem::replace(self, out);
+        // the old file is gone, there is nothing to close
+        old.closed = true;
+        self.audit_label = old.audit_label.take();
+        let report = CompactReport {
+            remap,
+            bytes_reclaimed: before.saturating_sub(after),
+        };
+        self.notify_compacted(&old_addresses, &report);
+        self.audit(AuditAction::Compact { bytes_reclaimed: report.bytes_reclaimed })?;
+        Ok(report)
+    }
+
+    /// Write a compacted copy of the store to a new file at path, as
+    /// SQLite's VACUUM INTO does, returning how the blocks were renumbered.
+    ///
+    /// The live blocks are verified and copied in order with fresh headers
+    /// and checksums, the copy is synced and closed, and this store is left
+    /// as it was, so a read only handle can do it. Fails if path exists. A
+    /// block failing verification stops the copy with StoreErrorKind::Checksum
+    /// and the partial copy is removed. bytes_reclaimed is how much smaller
+    /// the copy is than this store's data.
+    pub fn vacuum_into(&mut self, path: &str) -> Result<CompactReport, Box<dyn std::error::Error>> {
+        let codec = self.own_codec()?;
+        self.migrate_into::<T>(path, codec)
+    }
+
+    /// vacuum_into, writing the copy with hasher U and header codec codec,
+    /// to move a store's data to another hasher or header layout.
+    ///
+    /// Every payload is verified with this store's hasher and gets a
+    /// checksum from U. As with compact_with_codec, a block with attributes
+    /// codec can't record stops the copy. A personalized store can only be
+    /// migrated to a hasher that can be personalized.
+    pub fn migrate_into<U: BlockHasher>(
+        &mut self,
+        path: &str,
+        codec: Box<dyn HeaderCodec>,
+    ) -> Result<CompactReport, Box<dyn std::error::Error>> {
+        if Path::new(path).exists() {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_EXISTS, StoreErrorKind::Exists)));
+        }
+        let cursor = self.file.stream_position()?;
+        let copied = self.copy_live::<U>(path, codec, &mut |_, _| true);
+        self.file.seek(SeekFrom::Start(cursor))?;
+        let (out, remap) = match copied {
+            Ok(c) => c,
+            Err(e) => {
+                let _ = std::fs::remove_file(path);
+                return Err(e);
+            }
+        };
+        let after = out.index().data_end_address;
+        out.close()?;
+        platform::sync_dir_of(path)?;
+        Ok(CompactReport {
+            remap,
+            bytes_reclaimed: self.index().data_end_address.saturating_sub(after),
+        })
+    }
+
+    /// Copy the live blocks, verified, to a new store at to with this one's
+    /// settings, hasher U and header codec codec, for compact and
+    /// vacuum_into. Returns the copy, open for writing, and the new index
+    /// of each block.
+    ///
+    /// progress is as for compact_with_progress; when it cancels the copy is
+    /// removed. A block failing verification, or with attributes codec
+    /// can't record, leaves the copy as it is.
+    fn copy_live<U: BlockHasher>(
+        &mut self,
+        to: &str,
+        codec: Box<dyn HeaderCodec>,
+        progress: &mut dyn FnMut(u64, u64) -> bool,
+    ) -> Result<LiveCopy<U>, Box<dyn std::error::Error>> {
+        if let Some(p) = &self.personalization {
+            if U::create_personalized(p).is_none() {
+                return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_UNPERSONALIZABLE, StoreErrorKind::Unpersonalizable)));
+            }
+        }
+        let mut out = Store::<U>::create_with(to.to_string(), codec, self.personalization.as_deref())?;
+        out.max_block_size = self.max_block_size;
+        out.strong_digests = self.strong_digests;
+        out.checkpoint_policy = self.checkpoint_policy;
+        out.access_policy = self.access_policy;
+        out.set_dedup_window(self.dedup.as_ref().map(|w| w.capacity));
+        out.pool = self.pool.clone();
+        out.payload_alignment = self.payload_alignment;
+        out.sampler = self.sampler;
+        out.slow_threshold = self.slow_threshold;
+        out.deterministic = self.deterministic;
+        // the copied blocks keep their sessions, so their ids stay taken
+        let last_session = self.index().lifetime.last_session;
+        out.index_mut().lifetime.last_session = last_session;
+        #[cfg(feature = "ecc")]
+        {
+            if let Some(rs) = &self.ecc {
+                out.enable_ecc(rs.config())?;
+            }
+        }
+        let (before, old_addresses) = {
+            let index = self.index();
+            (index.data_end_address, index.block_addresses.clone())
+        };
+        let mut remap = Vec::with_capacity(self.len());
+        for i in 0..self.len() {
+            let (dh, data) = self.read_block(i)?;
+            if !dh.is_live() {
+                remap.push(None);
+                continue;
+            }
+            if !dh.verify_personalized(&data, self.personalization.as_deref()) {
+                let e = StoreError::with_kind(ERROR_FSTORE_CHECKSUM, StoreErrorKind::Checksum).at(i).offset(self.block_address(i));
+                return Err(Box::new(e));
+            }
+            let attrs = BlockAttrs::of(&dh);
+            if attrs.content_type != 0 && !out.codec.records_content_type() {
+                return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_UNTYPED, StoreErrorKind::NoContentTypes).at(i)));
+            }
+            if (attrs.owner, attrs.permissions) != (0, 0) && !out.codec.records_access() {
+                return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_NOACCESS, StoreErrorKind::NoAccess).at(i)));
+            }
+            if attrs.session != 0 && !out.codec.records_session() {
+                return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_NOSESSION, StoreErrorKind::NoSession).at(i)));
+            }
+            let id = out.append_block_with_flags(&data, 0, attrs)?;
+            let written = self.index().append_times.get(i).copied().unwrap_or(0);
+            out.index_mut().append_times[id] = written;
+            remap.push(Some(id));
+            let done = old_addresses.get(i + 1).copied().unwrap_or(before) - self.data_start_address;
+            if !progress(done, before - self.data_start_address) {
+                out.closed = true;
+                drop(out);
+                std::fs::remove_file(to)?;
+                return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_CANCELLED, StoreErrorKind::Cancelled)));
+            }
+        }
+        Ok((out, remap))
+    }
+
+    /// What compact would do, without doing it.
+    ///
+    /// The remap is exact. bytes_reclaimed assumes every live block keeps
+    /// the parity and digest blocks it has, whatever the store writes now,
+    /// and leaves out the index footer written on close.
+    pub fn compact_dry_run(&mut self) -> Result<CompactReport, Box<dyn std::error::Error>> {
+        let before = self.index().data_end_address;
+        let mut after = self.data_start_address;
+        let mut remap = Vec::with_capacity(self.len());
+        let mut next = 0;
+        for i in 0..self.len() {
+            if self.is_live(i)? {
+                after += self.block_extent(i)?;
+                remap.push(Some(next));
+                next += 1;
+            } else {
+                remap.push(None);
+            }
+        }
+        Ok(CompactReport {
+            remap,
+            bytes_reclaimed: before.saturating_sub(after),
+        })
+    }
+
+    /// Have observer told when blocks move or are repaired
+    pub fn add_observer(&mut self, observer: Box<dyn StoreObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Tell observers about a finished compaction, old_addresses being
+    /// where the blocks were before it
+    fn notify_compacted(&mut self, old_addresses: &[u64], report: &CompactReport) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let new_addresses = self.index().block_addresses.clone();
+        for (old, new) in report.remap.iter().enumerate() {
+            if let Some(new) = new {
+                if old != *new || old_addresses[old] != new_addresses[*new] {
+                    for o in self.observers.iter_mut() {
+                        o.on_relocate(old_addresses[old], new_addresses[*new], *new);
+                    }
+                }
+            }
+        }
+        for o in self.observers.iter_mut() {
+            o.on_compact(report);
+        }
+    }
+
+    /// Take an exclusive lock on a file we intend to write to
+    fn lock_file(file: &File) -> Result<(), Error> {
+        if platform::try_lock(file)? {
+            Ok(())
+        } else {
+            Err(Error::from(StoreError::with_kind(ERROR_FSTORE_LOCKED, StoreErrorKind::Locked)))
+        }
+    }
+
+    /// Writes the file descriptor (should be at the start of the file)
+    fn write_file_descriptor(
+        file: &mut File,
+        codec: u32,
+        flags: u64,
+        features: u64,
+        personalization: Option<&str>,
+    ) -> Result<(), Error> {
+        file.write_all(&STORE_VERSIONNUM.to_le_bytes())?;
+        // Panic here, there is no way this should fail unless we've typo'd
+        let sz = u64::try_from(STORE_VERSIONTAG.len()).unwrap();
+        file.write_all(&sz.to_le_bytes())?;
+        file.write_all(STORE_VERSIONTAG.as_bytes())?;
+        file.write_all(&codec.to_le_bytes())?;
+        file.write_all(&flags.to_le_bytes())?;
+        file.write_all(&features.to_le_bytes())?;
+        if let Some(p) = personalization {
+            file.write_all(&(p.len() as u16).to_le_bytes())?;
+            file.write_all(p.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Overwrite the descriptor flags and features, the last fields before the data
+    fn write_descriptor_state(&mut self, flags: u64, features: u64) -> Result<(), Error> {
+        let mut buff = flags.to_le_bytes().to_vec();
+        buff.extend_from_slice(&features.to_le_bytes());
+        let personalization = self.personalization.as_ref().map_or(0, |p| 2 + p.len() as u64);
+        self.file.seek(SeekFrom::Start(self.data_start_address - personalization - 16))?;
+        self.file.write_all(&buff)?;
+        self.file.sync_data()?;
+        self.descriptor_flags = flags;
+        self.features = features;
+        Ok(())
+    }
+
+    /// File name the store was opened or created with
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Personalization payloads are hashed with, see Store::create_personalized
+    pub fn personalization(&self) -> Option<&str> {
+        self.personalization.as_deref()
+    }
+
+    /// Hash of data as this store hashes payloads, personalization included.
+    ///
+    /// Checksums in block headers, and so maybe_contains and update_if, use
+    /// the start of this.
+    pub fn hash(&self, data: &[u8]) -> Vec<u8> {
+        match &self.personalization {
+            Some(p) => T::create_personalized(p).unwrap().hash(data).to_vec(),
+            None => T::create().hash(data).to_vec(),
+        }
+    }
+
+    /// Feature bitmap from the file descriptor
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    /// Version number from the file descriptor, 1 for stores Store::upgrade
+    /// hasn't rewritten yet
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Rewrite the version 1 store at filename with the current descriptor,
+    /// so it can be opened for writing. Stores already current are left be.
+    ///
+    /// The blocks are copied as they are, deleted ones and all, so block
+    /// indexes don't change. The copy is made next to the store (its name
+    /// with ".upgrade" appended), synced and renamed over it.
+    pub fn upgrade(filename: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let old = Store::<T>::new(filename.to_string())?;
+        if old.version != STORE_VERSIONNUM_V1 {
+            return Ok(());
+        }
+        let tmp = format!("{}.upgrade", filename);
+        let mut out = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp)?;
+        Store::<T>::write_file_descriptor(&mut out, BinaryHeaderCodec.id(), 0, 0, None)?;
+        let mut src = File::open(filename)?;
+        src.seek(SeekFrom::Start(old.data_start_address))?;
+        std::io::copy(&mut src, &mut out)?;
+        out.sync_all()?;
+        drop(out);
+        platform::replace(&tmp, filename)?;
+        Ok(())
+    }
+
+    /// Header codec id from the file descriptor, see data_header::header_codec
+    pub fn codec_id(&self) -> u32 {
+        self.codec_id
+    }
+
+    /// Read and validate the file descriptor
+    fn open_file_descriptor(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let fd = self.read_file_descriptor()?;
+        if !Store::<T>::validate_file_descriptor(fd) {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_INVALID, StoreErrorKind::InvalidDescriptor)));
+        }
+        let unknown = self.features & FEATURES_REQUIRED_MASK & !FEATURES_SUPPORTED;
+        if unknown != 0 {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_FEATURES, StoreErrorKind::UnsupportedFeatures).value("features", unknown)));
+        }
+        if let Some(codec) = header_codec(self.codec_id) {
+            self.header_size = codec.size(T::size());
+            self.codec = codec;
+        } else {
+            return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_CODEC, StoreErrorKind::UnknownCodec).value("codec", u64::from(self.codec_id))));
+        }
+        self.opened_dirty = self.descriptor_flags & DESCRIPTOR_FLAG_DIRTY != 0;
+        Ok(())
+    }
+
+    /// reads the file descriptor
+    /// returns a tuple
+    fn read_file_descriptor(&mut self) -> Result<(u32, String), Error> {
+        // it's only at the start of the file
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut buff = [0u8; 4];
+        let mut sz_buff = [0u8; 8];
+        self.file.read_exact(&mut buff)?;
+        self.file.read_exact(&mut sz_buff)?;
+        // Anything longer than our tag can't be a store, don't allocate for it
+        let sz = u64::from_le_bytes(sz_buff);
+        if sz > STORE_VERSIONTAG.len() as u64 {
+            return Err(Error::from(StoreError::with_kind(ERROR_FSTORE_VERSION, StoreErrorKind::InvalidDescriptor)));
+        }
+        let mut str_buff = vec![0u8; sz as usize];
+        self.file.read_exact(&mut str_buff)?;
+        self.version = u32::from_le_bytes(buff);
+        self.personalization = None;
+        if self.version == STORE_VERSIONNUM_V1 {
+            self.codec_id = BinaryHeaderCodec.id();
+            self.descriptor_flags = 0;
+            self.features = 0;
+            self.data_start_address = self.file.stream_position()?;
+            return String::from_utf8(str_buff).map(|s| (self.version, s)).map_err(|_| Error::from(StoreError::with_kind(ERROR_FSTORE_VERSION, StoreErrorKind::InvalidDescriptor)));
+        }
+        let mut codec_buff = [0u8; 4];
+        self.file.read_exact(&mut codec_buff)?;
+        self.codec_id = u32::from_le_bytes(codec_buff);
+        let mut flags_buff = [0u8; 8];
+        self.file.read_exact(&mut flags_buff)?;
+        self.descriptor_flags = u64::from_le_bytes(flags_buff);
+        self.file.read_exact(&mut flags_buff)?;
+        self.features = u64::from_le_bytes(flags_buff);
+        if self.features & FEATURE_PERSONALIZED != 0 {
+            let mut len_buff = [0u8; 2];
+            self.file.read_exact(&mut len_buff)?;
+            let mut p = vec![0u8; usize::from(u16::from_le_bytes(len_buff))];
+            self.file.read_exact(&mut p)?;
+            let p = String::from_utf8(p).map_err(|_| Error::from(StoreError::with_kind(ERROR_FSTORE_INVALID, StoreErrorKind::InvalidDescriptor)))?;
+            self.personalization = Some(p);
+        }
+        self.data_start_address = self.file.stream_position()?;
+        //Convert this error into a somewhat relevant io::Error
+        if let Ok(s) = String::from_utf8(str_buff) {
+            Ok((u32::from_le_bytes(buff), s))
+        } else {
+            Err(Error::from(StoreError::with_kind(ERROR_FSTORE_VERSION, StoreErrorKind::InvalidDescriptor)))
+        }
+    }
+
+    /// checks value to see if it's a valid file descriptor
+    pub fn validate_file_descriptor(value: (u32, String)) -> bool {
+        (value.0 == STORE_VERSIONNUM || value.0 == STORE_VERSIONNUM_V1) && value.1 == STORE_VERSIONTAG
+    }
+
+    /// Read address of blocks for index
+    ///
+    /// progress is called after each block, and returns false to cancel.
+    /// Returns the blocks named by journaled deletes.
+    fn index_blocks(
+        &mut self,
+        startpos: u64,
+        progress: &mut dyn FnMut(u64, u64) -> bool,
+    ) -> Result<Vec<BlockId>, Box<dyn std::error::Error>> {
+        let scan = self.scan_blocks(startpos, 0, progress)?;
+        let mut bloom = BloomFilter::with_capacity(u64::try_from(scan.checksums.len())? * 2);
+        for c in &scan.checksums {
+            bloom.insert(c);
+        }
+        {
+            let mut index = self.index_mut();
+            // a scan can't tell when blocks were written
+            index.append_times = vec![0; scan.addresses.len()];
+            index.delete_times.clear();
+            index.block_addresses = scan.addresses;
+            index.data_end_address = scan.end;
+            index.bloom = bloom;
+            index.quarantined = scan.quarantined;
+            index.lifetime.last_session = index.lifetime.last_session.max(scan.last_session);
+            index.epoch += 1;
+        }
+        self.file.seek(SeekFrom::Start(self.data_start_address))?;
+        Ok(scan.journaled)
+    }
+
+    /// Walk the block headers from startpos to the end of the file.
+    ///
+    /// first is the index the first block found will get.
+    /// The end returned is after the last block that isn't an index footer,
+    /// since the next write goes over the footer.
+    fn scan_blocks(
+        &mut self,
+        startpos: u64,
+        first: BlockId,
+        progress: &mut dyn FnMut(u64, u64) -> bool,
+    ) -> Result<BlockScan, Box<dyn std::error::Error>> {
+        // if startpos is 0, set it to the first block, otherwise it's a valid block start
+        // at this point, i'm failry sure an incorrect block location will still fill up a block
+        // albeit with incorect info if  there is enough data in the file
+        let mut scan = BlockScan::default();
+        let mut curpos = if startpos == 0 {
+            self.data_start_address
+        } else {
+            startpos
+        };
+        scan.end = curpos;
+        let hsize = u64::try_from(self.header_size)?;
+        // get metadata for file once
+        let md = self.file.metadata()?;
+        let mut dh = DataHeader::<T>::new()?;
+        // We are assuming the file will not change size during this loop
+        while curpos + hsize <= md.len() {
+            self.file.seek(SeekFrom::Start(curpos))?;
+            self.read_data_header(&mut dh)?;
+            let next = (curpos + hsize).saturating_add(u64::try_from(dh.data_size()?)?);
+            // a partially written block is not a block
+            if next > md.len() {
+                break;
+            }
+            if dh.is_journal() {
+                let mut payload = vec![0u8; dh.data_size()?];
+                self.file.read_exact(&mut payload)?;
+                if dh.verify_personalized(&payload, self.personalization.as_deref()) {
+                    scan.journaled.extend(Store::<T>::decode_journal(&payload));
+                }
+            } else if !dh.is_system() {
+                if dh.is_corrupt() {
+                    scan.quarantined.push(first + scan.addresses.len());
+                }
+                scan.addresses.push(curpos);
+                scan.checksums.push(dh.fields().checksum);
+                scan.last_session = scan.last_session.max(dh.session());
+            }
+            curpos = next;
+            if !dh.is_index() {
+                scan.end = curpos;
+            }
+            if !progress(curpos, md.len()) {
+                return Err(Box::new(StoreError::with_kind(ERROR_FSTORE_CANCELLED, StoreErrorKind::Cancelled)));
+            }
+        }
+        Ok(scan)
+    }
+
+    /// Pick up blocks appended to the file since this handle last looked.
+    ///
+    /// Meant for read only handles on a store another process writes to.
+    /// If the file is now shorter than the blocks already indexed, it was
+    /// truncated or rewritten, so the whole index is rebuilt and the report
+    /// says so; block indexes from before may no longer be valid.
+    pub fn reindex(&mut self) -> Result<ReindexReport, Box<dyn std::error::Error>> {
+        let started = Instant::now();
+        let cursor = self.file.stream_position()?;
+        let (old_len, end) = {
+            let index = self.index();
+            (index.block_addresses.len(), index.data_end_address)
+        };
+        let report = if self.file.metadata()?.len() < end {
+            self.index_blocks(0, &mut |_, _| true)?;
+            ReindexReport {
+                new_blocks: self.len(),
+                truncated: true,
+            }
+        } else {
+            let scan = self.scan_blocks(end, old_len, &mut |_, _| true)?;
+            ReindexReport {
+                new_blocks: self.extend_index(scan)?,
+                truncated: false,
+            }
+        };
+        let new_end = self.index().data_end_address;
+        self.file.seek(SeekFrom::Start(cursor.min(new_end)))?;
+        let from = if report.truncated { self.data_start_address } else { end };
+        self.note_slow(SlowOpKind::Index, None, new_end.saturating_sub(from), started);
+        Ok(report)
+    }
+
+    /// Add the blocks of a scan that started at the end of the index,
+    /// returning how many there were
+    fn extend_index(&mut self, scan: BlockScan) -> Result<usize, Box<dyn std::error::Error>> {
+        let new_blocks = scan.addresses.len();
+        let full = {
+            let mut index = self.index_mut();
+            for c in &scan.checksums {
+                index.bloom.insert(c);
+            }
+            index.append_times.extend(std::iter::repeat_n(0, new_blocks));
+            index.block_addresses.extend(scan.addresses);
+            index.quarantined.extend(scan.quarantined);
+            index.data_end_address = scan.end;
+            index.lifetime.last_session = index.lifetime.last_session.max(scan.last_session);
+            if new_blocks > 0 {
+                index.epoch += 1;
+            }
+            index.bloom.is_full()
+        };
+        if full {
+            self.rebuild_bloom()?;
+        }
+        Ok(new_blocks)
+    }
+
+    /// Index a store that wasn't closed cleanly, from the last checkpoint if
+    /// it is usable or else from the start, and keep a RecoveryReport.
+    ///
+    /// Returns the blocks named by journaled deletes found, and the index of
+    /// the first block scanned.
+    fn index_unclean(&mut self, progress: &mut dyn FnMut(u64, u64) -> bool) -> Result<(Vec<BlockId>, BlockId), Box<dyn std::error::Error>> {
+        let len = self.file.metadata()?.len();
+        let mut report = RecoveryReport::default();
+        let checkpoint = match self.checkpoint_address()? {
+            Some(a) => self.load_index_block(a, false)?.map(|end| (a, end)),
+            None => None,
+        };
+        let (journaled, first) = match checkpoint {
+            Some((address, end)) => {
+                report.resumed_from = Some(address);
+                let first = self.len();
+                let scan = self.scan_blocks(end, first, progress)?;
+                let journaled = scan.journaled.clone();
+                self.extend_index(scan)?;
+                self.file.seek(SeekFrom::Start(self.data_start_address))?;
+                (journaled, first)
+            }
+            None => (self.index_blocks(0, progress)?, 0),
+        };
+        report.blocks_recovered = self.len() - first;
+        report.bytes_discarded = len.saturating_sub(self.index().data_end_address);
+        self.recovery = Some(report);
+        Ok((journaled, first))
+    }
+
+    /// What was found opening a store that wasn't closed cleanly, None if it was
+    pub fn recovery(&self) -> Option<&RecoveryReport> {
+        self.recovery.as_ref()
+    }
+
+    /// Rebuild the bloom filter from the block headers, with room to grow
+    fn rebuild_bloom(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut bloom = BloomFilter::with_capacity(u64::try_from(self.len())? * 2);
+        let mut dh = DataHeader::<T>::new()?;
+        for i in 0..self.len() {
+            self.seek_block(i)?;
+            self.read_data_header(&mut dh)?;
+            bloom.insert(&dh.fields().checksum);
+        }
+        self.index_mut().bloom = bloom;
+        Ok(())
+    }
+
+    /// false if no block with this payload hash was ever written.
+    ///
+    /// hash is cut to the length of the checksums kept by the store's codec.
+    /// Deleted blocks are still reported as maybe present.
+    pub fn maybe_contains(&self, hash: &[u8]) -> bool {
+        let n = self.codec.checksum_size(T::size()).min(hash.len());
+        let maybe = self.index().bloom.maybe_contains(&hash[..n]);
+        if !maybe {
+            self.file.count(|c| c.cache_hits += 1);
+        }
+        maybe
+    }
+
+    /// I/O done through this handle since it was opened or reset_counters
+    pub fn io_counters(&self) -> IoCounters {
+        self.file.counters()
+    }
+
+    /// Start counting I/O from zero
+    pub fn reset_counters(&mut self) {
+        self.file.set_counters(IoCounters::default());
+    }
+
+    /// Bytes written through this handle, by what they were for, against
+    /// how the file is used now, see SpaceReport.
+    ///
+    /// Reads every block header, payloads are not read.
+    pub fn space_report(&mut self) -> Result<SpaceReport, Box<dyn std::error::Error>> {
+        let cursor = self.file.stream_position()?;
+        let (mut live_bytes, mut dead_bytes) = (0, 0);
+        for index in 0..self.len() {
+            let dh = self.block_header(index)?;
+            if dh.is_live() {
+                live_bytes += dh.fields().size_data;
+            } else {
+                dead_bytes += dh.fields().size_data;
+            }
+        }
+        self.file.seek(SeekFrom::Start(cursor))?;
+        let sp = self.space;
+        let physical_bytes = sp.replaced + self.file.total_written();
+        let accounted = sp.logical + sp.headers + sp.companions + sp.journal + sp.index + sp.filler;
+        Ok(SpaceReport {
+            logical_bytes: sp.logical,
+            physical_bytes,
+            header_bytes: sp.headers,
+            companion_bytes: sp.companions,
+            journal_bytes: sp.journal,
+            index_bytes: sp.index,
+            filler_bytes: sp.filler,
+            rewrite_bytes: physical_bytes.saturating_sub(accounted),
+            file_bytes: self.file.metadata()?.len(),
+            live_bytes,
+            dead_bytes,
+        })
+    }
+
+    /// Counters kept for the life of the store, see LifetimeStats
+    pub fn lifetime_stats(&self) -> LifetimeStats {
+        let l = self.index().lifetime;
+        let time = |t: u64| if t == 0 { None } else { Some(UNIX_EPOCH + Duration::from_secs(t)) };
+        LifetimeStats {
+            blocks_written: l.blocks_written,
+            bytes_written: l.bytes_written,
+            compactions: l.compactions,
+            created: time(l.created),
+            last_verified: time(l.last_verified),
+        }
+    }
+
+    /// Inject the faults of plan into I/O from now on, see the fault module
+    #[cfg(feature = "fault-injection")]
+    pub fn inject_faults(&mut self, plan: FaultPlan) {
+        self.file.set_faults(Some(FaultState::new(plan)));
+    }
+
+    /// Stop injecting faults, including after a crash
+    #[cfg(feature = "fault-injection")]
+    pub fn clear_faults(&mut self) {
+        self.file.set_faults(None);
+    }
+
+    /// Operations counted since inject_faults
+    #[cfg(feature = "fault-injection")]
+    pub fn fault_ops(&self) -> u64 {
+        self.file.faults().map(|f| f.ops()).unwrap_or(0)
+    }
+
+    /// Record every write to the file in log from now on, see the fault module
+    #[cfg(feature = "fault-injection")]
+    pub fn record_writes(&mut self, log: WriteLog) {
+        self.file.set_log(Some(log));
+    }
+
+    /// true if an injected crash has happened
+    #[cfg(feature = "fault-injection")]
+    pub fn crashed(&self) -> bool {
+        self.file.faults().is_some_and(|f| f.crashed())
+    }
+
+    /// Write the block addresses as an index block at the end of the data.
+    ///
+    /// The footer payload is the address count, the addresses, tagged sections
+    /// (u32 tag, u64 length, data), the footer's own address and INDEX_FOOTER_MAGIC,
+    /// so the last 16 bytes of the file locate it.
+    /// The next write overwrites it; a reader that can't find it falls back to index_blocks.
+    fn write_index_footer(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let data_end_address = self.index().data_end_address;
+        let payload = self.index_payload(data_end_address)?;
+        let mut dh = DataHeader::<T>::new()?;
+        dh.state_flag = DataHeader::<T>::index_flag();
+        self.file.seek(SeekFrom::Start(data_end_address))?;
+        self.file.write_all(dh.serialize_personalized(&*self.codec, &payload, self.personalization.as_deref())?)?;
+        self.file.write_all(&payload)?;
+        let end = self.file.stream_position()?;
+        self.space.index += end - data_end_address;
+        self.file.set_len(end)?;
+        Ok(())
+    }
+
+    /// Payload of an index block at address, for the blocks indexed now
+    fn index_payload(&self, address: u64) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let index = self.index();
+        let mut payload = Vec::with_capacity((index.block_addresses.len() + 2) * 8 + 8);
+        payload.extend_from_slice(&u64::try_from(index.block_addresses.len())?.to_le_bytes());
+        for a in &index.block_addresses {
+            payload.extend_from_slice(&a.to_le_bytes());
+        }
+        Store::<T>::push_footer_section(&mut payload, FOOTER_SECTION_BLOOM, &index.bloom.to_bytes())?;
+        let quarantined: Vec<u8> = index.quarantined.iter().flat_map(|i| (*i as u64).to_le_bytes()).collect();
+        Store::<T>::push_footer_section(&mut payload, FOOTER_SECTION_QUARANTINE, &quarantined)?;
+        Store::<T>::push_footer_section(&mut payload, FOOTER_SECTION_SCRUB, &(index.scrub_position as u64).to_le_bytes())?;
+        let times: Vec<u8> = index.append_times.iter().flat_map(|t| t.to_le_bytes()).collect();
+        Store::<T>::push_footer_section(&mut payload, FOOTER_SECTION_TIMES, &times)?;
+        let deleted: Vec<u8> = index
+            .delete_times
+            .iter()
+            .flat_map(|(i, t)| [(*i as u64).to_le_bytes(), t.to_le_bytes()].concat())
+            .collect();
+        Store::<T>::push_footer_section(&mut payload, FOOTER_SECTION_DELETE_TIMES, &deleted)?;
+        Store::<T>::push_footer_section(&mut payload, FOOTER_SECTION_LIFETIME, &index.lifetime.to_bytes())?;
+        payload.extend_from_slice(&address.to_le_bytes());
+        payload.extend_from_slice(INDEX_FOOTER_MAGIC);
+        Ok(payload)
+    }
+
+    /// Write index checkpoints as policy says during long ingests
+    pub fn set_checkpoint_policy(&mut self, policy: CheckpointPolicy) {
+        self.checkpoint_policy = policy;
+    }
+
+    /// Write an index checkpoint now.
+    ///
+    /// A checkpoint is an index block laid out as the index footer, but
+    /// written among the blocks where it stays, and named by a sidecar next
+    /// to the store (its name with ".ckpt" appended) once it is synced.
+    /// Like any index block it is skipped by scans and dropped by compaction.
+    /// Swaps, compaction and quarantining change what it describes, so they
+    /// remove the sidecar until the next checkpoint.
+    pub fn checkpoint(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let address = self.index().data_end_address;
+        let payload = self.index_payload(address)?;
+        self.append_system_block(DataHeader::<T>::index_flag(), &payload)?;
+        self.file.sync_data()?;
+        let mut sidecar = CHECKPOINT_MAGIC.to_vec();
+        sidecar.extend_from_slice(&address.to_le_bytes());
+        platform::write_replace(&format!("{}.ckpt", self.path), &sidecar)?;
+        self.since_checkpoint = (0, 0);
+        Ok(())
+    }
+
+    /// checkpoint, if the policy says one is due
+    fn checkpoint_if_due(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let (blocks, bytes) = self.since_checkpoint;
+        let policy = self.checkpoint_policy;
+        if policy.every_blocks.is_some_and(|n| blocks >= n) || policy.every_bytes.is_some_and(|n| bytes >= n) {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Address of the last checkpoint, from its sidecar, None if there is none
+    pub fn checkpoint_address(&self) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+        let sidecar = match std::fs::read(format!("{}.ckpt", self.path)) {
+            Ok(d) => d,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Box::new(e)),
+        };
+        if sidecar.len() != 16 || &sidecar[..8] != CHECKPOINT_MAGIC {
+            return Ok(None);
+        }
+        Ok(Some(u64::from_le_bytes(sidecar[8..].try_into()?)))
+    }
+
+    /// Remove the checkpoint sidecar, once the checkpoint no longer describes the store
+    fn drop_checkpoint(&mut self) -> Result<(), Error> {
+        self.since_checkpoint = (0, 0);
+        match std::fs::remove_file(format!("{}.ckpt", self.path)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Append a tagged section to a footer payload
+    fn push_footer_section(payload: &mut Vec<u8>, tag: u32, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        payload.extend_from_slice(&tag.to_le_bytes());
+        payload.extend_from_slice(&u64::try_from(data.len())?.to_le_bytes());
+        payload.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Load block addresses from the index footer.
+    ///
+    /// Returns false if there is no usable footer.
+    fn read_index_footer(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        let len = self.file.metadata()?.len();
+        let hsize = u64::try_from(self.header_size)?;
+        if len < self.data_start_address + hsize + 24 {
+            return Ok(false);
+        }
+        let mut trailer = [0u8; 16];
+        self.file.seek(SeekFrom::Start(len - 16))?;
+        self.file.read_exact(&mut trailer)?;
+        if &trailer[8..] != INDEX_FOOTER_MAGIC {
+            return Ok(false);
+        }
+        let address = u64::from_le_bytes(trailer[0..8].try_into()?);
+        Ok(self.load_index_block(address, true)?.is_some())
+    }
+
+    /// Load block addresses from the index block at address, a footer or a
+    /// checkpoint, returning where it ends.
+    ///
+    /// at_end says it must end the file. Returns None, changing nothing, if
+    /// there is no usable index block there.
+    fn load_index_block(&mut self, address: u64, at_end: bool) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+        let len = self.file.metadata()?.len();
+        let hsize = u64::try_from(self.header_size)?;
+        if address < self.data_start_address || address + hsize + 24 > len {
+            return Ok(None);
+        }
+        let mut dh = DataHeader::<T>::new()?;
+        self.file.seek(SeekFrom::Start(address))?;
+        self.read_data_header(&mut dh)?;
+        let end = address + hsize + u64::try_from(dh.data_size()?)?;
+        if !dh.is_index() || end > len || (at_end && end != len) || dh.data_size()? < 24 {
+            return Ok(None);
+        }
+        let mut payload = vec![0u8; dh.data_size()?];
+        self.file.read_exact(&mut payload)?;
+        let trailer = &payload[payload.len() - 16..];
+        if !dh.verify_personalized(&payload, self.personalization.as_deref())
+            || &trailer[8..] != INDEX_FOOTER_MAGIC
+            || trailer[..8] != address.to_le_bytes()
+        {
+            return Ok(None);
+        }
+        let count = u64::from_le_bytes(payload[0..8].try_into()?);
+        let sections_start = match count.checked_mul(8).and_then(|c| c.checked_add(8)) {
+            Some(s) if s + 16 <= payload.len() as u64 => s as usize,
+            _ => return Ok(None),
+        };
+        let mut bloom = None;
+        let mut quarantined = Vec::new();
+        let mut scrub_position = 0;
+        let mut append_times = vec![0; count as usize];
+        let mut delete_times = BTreeMap::new();
+        let mut lifetime = None;
+        let mut pos = sections_start;
+        let sections_end = payload.len() - 16;
+        while pos < sections_end {
+            if pos + 12 > sections_end {
+                return Ok(None);
+            }
+            let tag = u32::from_le_bytes(payload[pos..pos + 4].try_into()?);
+            let slen = u64::from_le_bytes(payload[pos + 4..pos + 12].try_into()?);
+            pos += 12;
+            if slen > (sections_end - pos) as u64 {
+                return Ok(None);
+            }
+            let section = &payload[pos..pos + slen as usize];
+            // unknown sections are skipped
+            if tag == FOOTER_SECTION_BLOOM {
+                bloom = BloomFilter::from_bytes(section);
+            } else if tag == FOOTER_SECTION_QUARANTINE {
+                quarantined = section
+                    .chunks_exact(8)
+                    .map(|c| u64::from_le_bytes(c.try_into().unwrap()) as BlockId)
+                    .collect();
+            } else if tag == FOOTER_SECTION_SCRUB && section.len() == 8 {
+                scrub_position = u64::from_le_bytes(section.try_into()?) as BlockId;
+            } else if tag == FOOTER_SECTION_TIMES && section.len() as u64 == count * 8 {
+                append_times = section
+                    .chunks_exact(8)
+                    .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+                    .collect();
+            } else if tag == FOOTER_SECTION_DELETE_TIMES {
+                delete_times = section
+                    .chunks_exact(16)
+                    .map(|c| {
+                        let i = u64::from_le_bytes(c[..8].try_into().unwrap()) as BlockId;
+                        (i, u64::from_le_bytes(c[8..].try_into().unwrap()))
+                    })
+                    .collect();
+            } else if tag == FOOTER_SECTION_LIFETIME {
+                lifetime = LifetimeCounters::from_bytes(section);
+            }
+            pos += slen as usize;
+        }
+        {
+            let mut index = self.index_mut();
+            index.block_addresses = payload[8..sections_start]
+                .chunks_exact(8)
+                .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+                .collect();
+            index.data_end_address = address;
+            index.quarantined = quarantined;
+            index.scrub_position = scrub_position;
+            index.append_times = append_times;
+            index.delete_times = delete_times;
+            if let Some(l) = lifetime {
+                index.lifetime = l;
+            }
+            index.epoch += 1;
+        }
+        match bloom {
+            Some(b) => self.index_mut().bloom = b,
+            None => self.rebuild_bloom()?,
+        }
+        Ok(Some(end))
+    }
+}
+
+/// Bytes overwritten at a time when erasing
+const ERASE_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Key for the random bytes of Erase::Secure, good enough to hide what was
+/// there but not for keys
+fn random_key() -> [u8; 32] {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    let mut key = [0u8; 32];
+    for k in key.chunks_exact_mut(8) {
+        // every RandomState is keyed differently, from the OS at first
+        k.copy_from_slice(&RandomState::new().build_hasher().finish().to_le_bytes());
+    }
+    key
+}
+
+/// Seconds since the unix epoch
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Iterator over live blocks of a Store, from Store::iter
+pub struct StoreIter<'a, T: BlockHasher> {
+    store: &'a mut Store<T>,
+    next: BlockId,
+    /// only blocks of this session, from Store::iter_session
+    session: Option<u64>,
+}
+
+impl<T: BlockHasher> Iterator for StoreIter<'_, T> {
+    type Item = Result<(BlockId, Vec<u8>), Box<dyn std::error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (index, dh) = loop {
+            let (index, dh) = match self.store.next_readable(self.next) {
+                Ok(found) => found?,
+                Err(e) => return Some(Err(e)),
+            };
+            self.next = index + 1;
+            if self.session.is_none_or(|id| id == dh.session()) {
+                break (index, dh);
+            }
+        };
+        self.store.record_access(index);
+        Some(self.store.read_payload(&dh).map(|data| (index, data.into_inner())))
+    }
+}
+
+/// Payloads of the blocks iter would return, one after another, as a
+/// BufRead stream, from Store::as_reader
+pub struct StoreReader<'a, T: BlockHasher> {
+    store: &'a mut Store<T>,
+    /// block to look for the next payload from
+    next: BlockId,
+    /// file address of the rest of the current payload
+    pos: u64,
+    /// bytes of the current payload not yet read into buf
+    left: u64,
+    buf: Scratch,
+    /// offset in buf of the next byte to hand out
+    buf_pos: usize,
+}
+
+impl<T: BlockHasher> BufRead for StoreReader<'_, T> {
+    fn fill_buf(&mut self) -> Result<&[u8], Error> {
+        while self.buf_pos >= self.buf.len() {
+            if self.left == 0 {
+                let found = self.store.next_readable(self.next).map_err(|e| Error::other(e.to_string()))?;
+                let (index, dh) = match found {
+                    Some(f) => f,
+                    None => return Ok(&[]),
+                };
+                self.next = index + 1;
+                self.store.record_access(index);
+                self.pos = self.store.file.stream_position()?;
+                self.left = dh.fields().size_data;
+                continue;
+            }
+            // the file is shared with the store, so always seek first
+            let n = self.left.min(BLOCK_READER_BUFFER as u64) as usize;
+            self.buf.resize(n, 0);
+            self.store.file.seek(SeekFrom::Start(self.pos))?;
+            self.store.file.read_exact(&mut self.buf)?;
+            self.pos += n as u64;
+            self.left -= n as u64;
+            self.buf_pos = 0;
+        }
+        Ok(&self.buf[self.buf_pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.buf_pos += amt;
+    }
+}
+
+impl<T: BlockHasher> Read for StoreReader<'_, T> {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+        let n = {
+            let available = self.fill_buf()?;
+            let n = available.len().min(out.len());
+            out[..n].copy_from_slice(&available[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+/// Keeps a block from being moved or reclaimed while alive, from Store::pin
+#[derive(Debug)]
+pub struct BlockGuard {
+    index: Arc<RwLock<BlockIndex>>,
+    block: BlockId,
+}
+
+impl BlockGuard {
+    /// Index of the block pinned
+    pub fn block(&self) -> BlockId {
+        self.block
+    }
+}
+
+impl Drop for BlockGuard {
+    fn drop(&mut self) {
+        let mut index = self.index.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(n) = index.pins.get_mut(&self.block) {
+            *n -= 1;
+            if *n == 0 {
+                index.pins.remove(&self.block);
+            }
+        }
+    }
+}
+
+/// Bytes a BlockReader reads from the file at a time
+const BLOCK_READER_BUFFER: usize = 64 * 1024;
+
+/// Payload of one block as a BufRead and Seek stream, from Store::block_reader
+pub struct BlockReader<'a, T: BlockHasher> {
+    store: &'a mut Store<T>,
+    index: BlockId,
+    /// address of the first payload byte
+    start: u64,
+    len: u64,
+    /// offset in the payload
+    pos: u64,
+    buf: Scratch,
+    /// offset in the payload of buf[0]
+    buf_pos: u64,
+    verified: bool,
+}
+
+impl<T: BlockHasher> BlockReader<'_, T> {
+    /// Payload bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// true once the whole payload has been checked against its checksum
+    pub fn is_verified(&self) -> bool {
+        self.verified
+    }
+
+    /// Check the payload against its checksum now rather than at the end
+    pub fn verify(&mut self) -> Result<(), Error> {
+        if self.verified {
+            return Ok(());
+        }
+        let good = self.store.verify_block(self.index).map_err(|e| Error::other(e.to_string()))?;
+        if !good {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                StoreError::with_kind(ERROR_FSTORE_CHECKSUM, StoreErrorKind::Checksum)
+                    .at(self.index)
+                    .offset(self.store.block_address(self.index)),
+            ));
+        }
+        self.verified = true;
+        Ok(())
+    }
+}
+
+impl<T: BlockHasher> BufRead for BlockReader<'_, T> {
+    fn fill_buf(&mut self) -> Result<&[u8], Error> {
+        if self.pos >= self.len {
+            self.verify()?;
+            return Ok(&[]);
+        }
+        if self.pos < self.buf_pos || self.pos >= self.buf_pos + self.buf.len() as u64 {
+            // the file is shared with the store, so always seek first
+            let n = (self.len - self.pos).min(BLOCK_READER_BUFFER as u64) as usize;
+            self.buf.resize(n, 0);
+            self.store.file.seek(SeekFrom::Start(self.start + self.pos))?;
+            self.store.file.read_exact(&mut self.buf)?;
+            self.buf_pos = self.pos;
+        }
+        Ok(&self.buf[(self.pos - self.buf_pos) as usize..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt as u64;
+    }
+}
+
+impl<T: BlockHasher> Read for BlockReader<'_, T> {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+        let n = {
+            let available = self.fill_buf()?;
+            let n = available.len().min(out.len());
+            out[..n].copy_from_slice(&available[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<T: BlockHasher> Seek for BlockReader<'_, T> {
+    /// Seeking past the end is allowed, reads there return nothing
+    fn seek(&mut self, to: SeekFrom) -> Result<u64, Error> {
+        let (base, offset) = match to {
+            SeekFrom::Start(p) => (p, 0),
+            SeekFrom::End(o) => (self.len, o),
+            SeekFrom::Current(o) => (self.pos, o),
+        };
+        self.pos = base
+            .checked_add_signed(offset)
+            .ok_or_else(|| Error::from(StoreError::with_kind(ERROR_FSTORE_SEEK, StoreErrorKind::SeekBeforeStart)))?;
+        Ok(self.pos)
+    }
+}
+
+impl<T: BlockHasher> Drop for Store<T> {
+    /// Best effort close, errors are ignored
+    fn drop(&mut self) {
+        if !self.closed {
+            self.closed = true;
+            let _ = self.finalize();
+        }
+    }
+}
+
+impl<T: BlockHasher> Write for Store<T>  {
+    /// Writes data in buf to file, encapsulated in a DataHeader
+    ///
+    /// Blocks are always appended, regardless of the current read position.
+    /// An empty buf writes nothing, as write_all would; use put for an empty block.
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.check_block_size(buf.len() as u64)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        self.append_block(buf)?;
+        Ok(buf.len())
+    }
+
+    /// Writes the buffers as one block, see Store::put_vectored.
+    ///
+    /// Like write, all empty buffers write nothing.
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize, Error> {
+        let len: usize = bufs.iter().map(|b| b.len()).sum();
+        if len == 0 {
+            return Ok(0);
+        }
+        self.check_block_size(len as u64)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let parts: Vec<&[u8]> = bufs.iter().map(|b| &**b).collect();
+        self.append_parts(&parts, 0, BlockAttrs::default())?;
+        self.checkpoint_if_due().map_err(|e| Error::other(e.to_string()))?;
+        Ok(len)
+    }
+
+    /// Calls flush on self.file
+    fn flush(&mut self) -> Result<(), Error> {
+        self.file.flush()
+    }
+}
+
+impl<T: BlockHasher> StoreIO<T> for Store<T> {
+    /// Journaled, see Store::delete_many
+    fn delete_block(&mut self, index: usize) -> Result<(), Box<dyn std::error::Error>> {
+        self.delete_many(&[index])
+    }
+
+    fn block_address(&self, index: usize) -> Option<u64> {
+        self.index().block_addresses.get(index).copied()
+    }
+
+    fn len(&self) -> usize {
+        self.index().block_addresses.len()
+    }
+    
+    fn seek(&mut self, index: usize) -> Result<u64, Box<dyn std::error::Error>> {
+        if let Some(a) = self.block_address(index) {
+            self.record_access(index);
+            Ok(self.file.seek(SeekFrom::Start(a))?)
+        } else {
+            Err(Box::new(StoreError::with_kind(ERROR_OUTOFBOUNDS, StoreErrorKind::OutOfBounds).at(index)))
+        }
+    }
+
+    /// Reads data into buf according to surrounding DataHeader
+    fn read_data_header(
+        &mut self,
+        data_header: &mut DataHeader<T>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut db_buf = self.scratch(self.header_size);
+        self.file.read_exact(&mut db_buf)?;
+        data_header.deserialize_with(&*self.codec, &db_buf)?;
+        if !data_header.is_system() {
+            self.check_block_size(data_header.fields().size_data)?;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, data: &mut Vec<u8>) -> Result<usize, Error> {
+        self.file.read(data)
+    }
+
+    fn read_at_index(&mut self,index: usize, data: &mut Vec<u8>) -> Result<usize, Box<dyn std::error::Error>> {
+        self.seek(index)?;
+        let mut dh = DataHeader::<T>::new()?;
+        self.read_data_header(&mut dh)?;
+        self.check_access(index, &dh)?;
+        let expected = dh.data_size()?;
+        data.resize(expected, 0);
+        let mut found = 0;
+        while found < expected {
+            match self.file.read(&mut data[found..])? {
+                0 => {
+                    data.truncate(found);
+                    let kind = StoreErrorKind::Truncated { expected, found };
+                    let e = StoreError::with_kind(ERROR_FSTORE_TRUNCATED, kind).at(index).offset(self.block_address(index));
+                    return Err(Box::new(e));
+                }
+                n => found += n,
+            }
+        }
+        Ok(expected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_header::{
+        BlockSerializer, DataHeader, FieldsHeaderCodec, HEADER_FIELD_ACCESS, HEADER_FIELD_CONTENT_TYPE, HEADER_FIELD_SESSION,
+    };
+    use crate::store::Store;
+    use crate::crypto::Crc32BlockHasher;
+    use std::io::Write;
+
+    fn fill_test_vector(data: &mut Vec<u8>) {
+        data.append(&mut vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 255]);
+    }
+
+    /// path for a test file, each test needs its own
+    fn test_file(name: &str) -> String {
+        std::fs::create_dir_all("testout").unwrap();
+        format!("testout/{}", name)
+    }
+
+    /// leave a store as a crash would, without running close
+    fn crash<T: BlockHasher>(s: Store<T>) {
+        s.file.unlock().unwrap();
+        std::mem::forget(s);
+    }
+    #[test]
+    #[allow(clippy::unused_io_amount, clippy::unnecessary_mut_passed)]
+    fn can_write_to_store() {
+        let mut s = Store::<B3BlockHasher>::create("testout/store.st".to_string()).unwrap();
+        let mut buf = vec![0, 1, 3, 4, 5, 11, 33, 0];
+        s.write(&mut buf).unwrap();
+        s.write(&mut buf).unwrap();
+    }
+
+    #[test]
+    #[allow(clippy::unused_io_amount)]
+    fn can_read_from_store() {
+        let mut testval = Vec::new();
+        fill_test_vector(&mut testval);
+        {
+            let mut s = Store::<B3BlockHasher>::create("testout/store.test.st".to_string()).unwrap();
+            for _i in 1..10 {
+                s.write(&testval).unwrap();
+                s.write(&testval).unwrap();
+            }
+        }
+
+        let mut db = DataHeader::<B3BlockHasher>::new().unwrap();
+        let mut s = Store::<B3BlockHasher>::new("testout/store.test.st".to_string()).unwrap();
+        s.read_data_header(&mut db).unwrap();
+        println!("data header size: {:?}", db);
+        let mut data = vec![0u8; db.data_size().unwrap()];
+        s.read(&mut data).unwrap();
+        assert_eq!(testval, data);
+    }
+
+    #[test]
+    #[allow(clippy::unused_io_amount)]
+    fn can_delete_block() {
+        let v = [
+            vec!(1, 244, 231,13,42,1,2,3,4,5,6,7),
+            vec!(1,2,3,4,5,6,7,8,9,0),
+            vec!(11,12,13,14,15,16,17,18,19,20),
+        ];
+        let mut s = Store::<B3BlockHasher>::create("testout/delete.tst".to_string()).unwrap();
+        for i in v {
+            s.write(&i).unwrap();
+        }
+        s.delete_block(2).unwrap();
+        let mut db = DataHeader::<B3BlockHasher>::new().unwrap();
+        s.seek(2).unwrap();
+        s.read_data_header(&mut db).unwrap();
+        assert_eq!(DataHeader::<B3BlockHasher>::delete_flag(),db.state_flag );
+    }
+
+    #[test]
+    fn close_writes_index_footer() {
+        let path = test_file("close.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        for i in 0..5u8 {
+            s.write_all(&vec![i; usize::from(i) * 3 + 1]).unwrap();
+        }
+        let addresses: Vec<u64> = (0..5).map(|i| s.block_address(i).unwrap()).collect();
+        s.close().unwrap();
+
+        let mut s = Store::<B3BlockHasher>::new(path.clone()).unwrap();
+        assert!(s.read_index_footer().unwrap());
+        assert_eq!(s.len(), 5);
+        for (i, a) in addresses.iter().enumerate() {
+            assert_eq!(s.block_address(i), Some(*a));
+        }
+    }
+
+    #[test]
+    fn drop_closes_store() {
+        let path = test_file("drop.st");
+        {
+            let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+            s.write_all(&[1, 2, 3]).unwrap();
+            s.write_all(&[4, 5]).unwrap();
+        }
+        let mut s = Store::<B3BlockHasher>::new(path.clone()).unwrap();
+        assert!(s.read_index_footer().unwrap());
+        assert_eq!(s.len(), 2);
+        // the lock was released, so we can create over it
+        let _s = Store::<B3BlockHasher>::create(path).unwrap();
+    }
+
+    #[test]
+    fn missing_footer_falls_back_to_scan() {
+        let path = test_file("nofooter.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        s.write_all(&[1, 2, 3]).unwrap();
+        s.write_all(&[4, 5, 6, 7]).unwrap();
+        s.close().unwrap();
+        // chop the magic off the footer
+        let f = OpenOptions::new().write(true).open(&path).unwrap();
+        let len = f.metadata().unwrap().len();
+        f.set_len(len - 1).unwrap();
+
+        let mut s = Store::<B3BlockHasher>::new(path).unwrap();
+        assert!(!s.read_index_footer().unwrap());
+        assert_eq!(s.len(), 2);
+        let mut db = DataHeader::<B3BlockHasher>::new().unwrap();
+        s.seek(1).unwrap();
+        s.read_data_header(&mut db).unwrap();
+        let mut data = vec![0u8; db.data_size().unwrap()];
+        s.read(&mut data).unwrap();
+        assert_eq!(data, vec![4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn second_writer_is_locked_out() {
+        let path = test_file("locked.st");
+        let _s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        assert!(Store::<B3BlockHasher>::create(path).is_err());
+    }
+
+    #[test]
+    fn open_for_write_appends() {
+        let path = test_file("append.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        s.write_all(&[1, 2, 3]).unwrap();
+        s.close().unwrap();
+        let mut s = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        assert!(!s.is_dirty());
+        assert_eq!(s.len(), 1);
+        s.write_all(&[4, 5]).unwrap();
+        s.close().unwrap();
+
+        let mut s = Store::<B3BlockHasher>::new(path).unwrap();
+        assert!(!s.is_dirty());
+        assert_eq!(s.len(), 2);
+        assert!(s.verify_block(0).unwrap());
+        assert!(s.verify_block(1).unwrap());
+    }
+
+    #[test]
+    fn crashed_store_is_dirty_and_recovers() {
+        let path = test_file("dirty.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        s.write_all(&[1, 2, 3]).unwrap();
+        s.write_all(&[4, 5, 6, 7]).unwrap();
+        // simulate a crash: no close, and half of the last block made it to disk
+        let end = s.block_address(1).unwrap() + 10;
+        crash(s);
+        let f = OpenOptions::new().write(true).open(&path).unwrap();
+        f.set_len(end).unwrap();
+        drop(f);
+
+        let s = Store::<B3BlockHasher>::new(path.clone()).unwrap();
+        assert!(s.is_dirty());
+        assert_eq!(s.len(), 1);
+        drop(s);
+
+        let mut s = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        assert!(s.is_dirty());
+        s.write_all(&[8, 9]).unwrap();
+        s.close().unwrap();
+
+        let mut s = Store::<B3BlockHasher>::new(path).unwrap();
+        assert!(!s.is_dirty());
+        assert_eq!(s.len(), 2);
+        assert!(s.verify_block(1).unwrap());
+    }
+
+    #[test]
+    fn recovery_cuts_off_torn_blocks() {
+        let path = test_file("dirtycorrupt.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        for i in 0..4u8 {
+            s.put(&[i; 8]).unwrap();
+        }
+        let payload = |s: &Store<B3BlockHasher>, i| s.block_address(i).unwrap() + DataHeader::<B3BlockHasher>::size() as u64;
+        let (second, last) = (payload(&s, 1), payload(&s, 3));
+        let end = s.block_address(3).unwrap();
+        crash(s);
+        // the header of the last block landed, its payload didn't; the
+        // second block's pages were lost with whole blocks after them
+        let mut f = OpenOptions::new().write(true).open(&path).unwrap();
+        for at in [second, last] {
+            f.seek(SeekFrom::Start(at)).unwrap();
+            f.write_all(&[0; 8]).unwrap();
+        }
+        drop(f);
+
+        let mut s = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        let report = s.recovery().unwrap().clone();
+        assert_eq!((report.blocks_recovered, report.blocks_torn, report.quarantined), (3, 1, vec![1]));
+        assert_eq!(s.len(), 3);
+        assert_eq!(s.quarantined(), vec![1]);
+        assert_eq!(s.get(2).unwrap(), vec![2; 8]);
+        assert_eq!(s.put(&[4; 8]).unwrap(), 3);
+        assert_eq!(s.block_address(3), Some(end));
+        s.close().unwrap();
+        let mut s = Store::<B3BlockHasher>::new(path).unwrap();
+        assert!(!s.is_live(1).unwrap());
+        assert_eq!(s.get(3).unwrap(), vec![4; 8]);
+    }
+
+    #[test]
+    fn footer_feature_tracks_footer() {
+        let path = test_file("features.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        assert_eq!(s.features() & FEATURE_INDEX_FOOTER, 0);
+        s.write_all(&[1, 2, 3]).unwrap();
+        s.close().unwrap();
+        let s = Store::<B3BlockHasher>::new(path.clone()).unwrap();
+        assert_ne!(s.features() & FEATURE_INDEX_FOOTER, 0);
+        drop(s);
+        let s = Store::<B3BlockHasher>::open_for_write(path).unwrap();
+        assert_eq!(s.features() & FEATURE_INDEX_FOOTER, 0);
+    }
+
+    #[test]
+    fn unknown_required_feature_is_refused() {
+        let path = test_file("unknownfeature.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        s.write_descriptor_state(0, 1 << 31).unwrap();
+        crash(s);
+        assert!(Store::<B3BlockHasher>::new(path.clone()).is_err());
+
+        // unknown optional features are fine
+        let f = OpenOptions::new().write(true).read(true).open(&path).unwrap();
+        let mut s = Store::<B3BlockHasher>::from_file(f, path.clone());
+        s.read_file_descriptor().unwrap();
+        s.write_descriptor_state(0, 1 << 63).unwrap();
+        drop(s);
+        assert!(Store::<B3BlockHasher>::new(path).is_ok());
+    }
+
+    #[test]
+    fn personalization_is_kept_and_checked() {
+        let path = test_file("personalized.st");
+        let plain = test_file("unpersonalized.st");
+        let mut s = Store::<B3BlockHasher>::create_personalized(path.clone(), "fstore test deployment").unwrap();
+        let mut p = Store::<B3BlockHasher>::create(plain.clone()).unwrap();
+        s.put(&[1; 100]).unwrap();
+        p.put(&[1; 100]).unwrap();
+        assert_ne!(s.block_header(0).unwrap().fields().checksum, p.block_header(0).unwrap().fields().checksum);
+        assert!(s.maybe_contains(&s.hash(&[1; 100])));
+        s.close().unwrap();
+        p.close().unwrap();
+        assert!(Store::<Crc32BlockHasher>::create_personalized(test_file("crcpersonalized.st"), "x").is_err());
+
+        assert!(Store::<B3BlockHasher>::new(path.clone()).is_err());
+        assert!(Store::<B3BlockHasher>::open_with_progress(plain, &StoreOptions::new().personalization("fstore test deployment"), |_, _| true).is_err());
+        let opts = StoreOptions::new().write(true).personalization("other deployment");
+        assert!(Store::<B3BlockHasher>::open_with_progress(path.clone(), &opts, |_, _| true).is_err());
+        let opts = StoreOptions::new().write(true).personalization("fstore test deployment");
+        let mut s = Store::<B3BlockHasher>::open_with_progress(path.clone(), &opts, |_, _| true).unwrap();
+        assert_eq!(s.personalization(), Some("fstore test deployment"));
+        assert_eq!(s.get(0).unwrap(), vec![1; 100]);
+        s.put(&[2; 10]).unwrap();
+        s.compact().unwrap();
+        crash(s);
+        // recovery verifies every block with the personalized hasher
+        let mut s = Store::<B3BlockHasher>::open_with_progress(path, &opts, |_, _| true).unwrap();
+        assert_eq!(s.get(1).unwrap(), vec![2; 10]);
+        assert!(s.verify_block(0).unwrap());
+    }
+
+    #[test]
+    fn strong_digests_are_kept_and_checked() {
+        let path = test_file("digests.st");
+        let mut s = Store::<Crc32BlockHasher>::create(path.clone()).unwrap();
+        s.put(&[1; 100]).unwrap();
+        s.enable_strong_digests();
+        s.put(&[2; 100]).unwrap();
+        s.put(&[3; 100]).unwrap();
+        assert!(s.verify(0, Strength::Fast).unwrap());
+        assert!(s.verify(0, Strength::Strong).is_err());
+        assert!(s.verify(1, Strength::Strong).unwrap());
+        // digest blocks are not indexed
+        assert_eq!(s.len(), 3);
+
+        // both checks catch a damaged payload
+        let payload = s.block_address(1).unwrap() + u64::try_from(s.header_size).unwrap();
+        s.file.seek(SeekFrom::Start(payload)).unwrap();
+        s.file.write_all(&[9]).unwrap();
+        assert!(!s.verify(1, Strength::Fast).unwrap());
+        assert!(!s.verify(1, Strength::Strong).unwrap());
+        s.file.seek(SeekFrom::Start(payload)).unwrap();
+        s.file.write_all(&[2]).unwrap();
+
+        // digests move with their blocks
+        s.delete_many(&[0]).unwrap();
+        s.compact_in_place().unwrap();
+        assert!(s.verify(0, Strength::Strong).unwrap());
+        assert!(s.verify(1, Strength::Strong).unwrap());
+        s.close().unwrap();
+        let mut s = Store::<Crc32BlockHasher>::open_with_progress(path, &StoreOptions::new().write(true).strong_digests(true), |_, _| true).unwrap();
+        let id = s.put(&[4; 10]).unwrap();
+        s.compact().unwrap();
+        assert!(s.verify(id, Strength::Strong).unwrap());
+        assert_eq!(s.get(0).unwrap(), vec![2; 100]);
+    }
+
+    #[test]
+    fn unknown_codec_is_refused() {
+        let path = test_file("unknowncodec.st");
+        let s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        let codec_address = s.data_start_address - 20;
+        crash(s);
+        let mut f = OpenOptions::new().write(true).open(&path).unwrap();
+        f.seek(SeekFrom::Start(codec_address)).unwrap();
+        f.write_all(&77u32.to_le_bytes()).unwrap();
+        drop(f);
+        let e = Store::<B3BlockHasher>::new(path).err().unwrap();
+        assert!(e.to_string().starts_with(ERROR_FSTORE_CODEC));
+    }
+
+    #[test]
+    fn compact_store_round_trip() {
+        use crate::data_header::CompactHeaderCodec;
+        let path = test_file("compact.st");
+        let codec = Box::new(CompactHeaderCodec { truncate_hash: true });
+        let mut s = Store::<B3BlockHasher>::create_with_codec(path.clone(), codec).unwrap();
+        for i in 0..10u8 {
+            s.write_all(&[i, i + 1]).unwrap();
+        }
+        let first = s.block_address(0).unwrap();
+        assert_eq!(s.block_address(1).unwrap() - first, 13 + 2);
+        s.delete_block(3).unwrap();
+        s.close().unwrap();
+
+        let mut s = Store::<B3BlockHasher>::new(path).unwrap();
+        assert_eq!(s.len(), 10);
+        for i in 0..10 {
+            assert!(s.verify_block(i).unwrap());
+        }
+        let mut db = DataHeader::<B3BlockHasher>::new().unwrap();
+        s.seek(3).unwrap();
+        s.read_data_header(&mut db).unwrap();
+        assert_eq!(db.state_flag, DataHeader::<B3BlockHasher>::delete_flag());
+    }
+
+    #[test]
+    fn hashers_mix_with_tagged_codec() {
+        use crate::crypto::Crc32BlockHasher;
+        use crate::data_header::TaggedHeaderCodec;
+        let path = test_file("tagged.st");
+        let mut s = Store::<Crc32BlockHasher>::create_with_codec(path.clone(), Box::new(TaggedHeaderCodec)).unwrap();
+        s.put(&[1, 2]).unwrap();
+        s.close().unwrap();
+
+        let mut s = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        s.put(&[3, 4]).unwrap();
+        s.close().unwrap();
+        let mut s = Store::<B3BlockHasher>::new(path).unwrap();
+        assert_eq!(s.get(0).unwrap(), vec![1, 2]);
+        assert_eq!(s.get(1).unwrap(), vec![3, 4]);
+        assert!(s.scrub().unwrap().failed.is_empty());
+    }
+
+    #[test]
+    fn bloom_survives_reopen() {
+        let path = test_file("bloom.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        // enough blocks to force the filter to grow
+        for i in 0..300u32 {
+            s.write_all(&i.to_le_bytes()).unwrap();
+        }
+        s.close().unwrap();
+
+        let s = Store::<B3BlockHasher>::new(path.clone()).unwrap();
+        let mut h = B3BlockHasher::create();
+        for i in 0..300u32 {
+            assert!(s.maybe_contains(h.hash(&i.to_le_bytes())));
+        }
+        let misses = (1000..1100u32).filter(|i| !s.maybe_contains(h.hash(&i.to_le_bytes()))).count();
+        assert!(misses > 90);
+        drop(s);
+
+        // rebuilt from headers when there's no footer
+        let f = OpenOptions::new().write(true).open(&path).unwrap();
+        let len = f.metadata().unwrap().len();
+        f.set_len(len - 1).unwrap();
+        drop(f);
+        let s = Store::<B3BlockHasher>::new(path).unwrap();
+        assert!(s.maybe_contains(h.hash(&7u32.to_le_bytes())));
+    }
+
+    #[test]
+    fn open_reports_progress_and_cancels() {
+        let path = test_file("progress.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        for i in 0..20u8 {
+            s.write_all(&[i; 100]).unwrap();
+        }
+        // no footer, so the blocks get scanned
+        crash(s);
+
+        let mut calls = Vec::new();
+        let s = Store::<B3BlockHasher>::open_with_progress(path.clone(), &StoreOptions::new(), |done, total| {
+            calls.push((done, total));
+            true
+        })
+        .unwrap();
+        assert_eq!(s.len(), 20);
+        assert_eq!(calls.len(), 20);
+        let (done, total) = *calls.last().unwrap();
+        assert_eq!(done, total);
+        assert!(calls.windows(2).all(|w| w[0].0 < w[1].0));
+
+        let e = Store::<B3BlockHasher>::open_with_progress(path, &StoreOptions::new().write(true), |done, _| done < 500)
+            .err()
+            .unwrap();
+        assert_eq!(e.downcast_ref::<StoreError>().unwrap().kind(), StoreErrorKind::Cancelled);
+    }
+
+    #[test]
+    fn cloned_handles_see_new_blocks() {
+        let path = test_file("clone.st");
+        let mut w = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        w.put(&[1, 2, 3]).unwrap();
+        let mut r = w.try_clone().unwrap();
+        assert_eq!(r.len(), 1);
+        let epoch = r.epoch();
+
+        let id = w.put(&[4, 5, 6, 7]).unwrap();
+        // visible without reopening
+        assert_ne!(r.epoch(), epoch);
+        assert_eq!(r.len(), 2);
+        let mut db = DataHeader::<B3BlockHasher>::new().unwrap();
+        r.seek(id).unwrap();
+        r.read_data_header(&mut db).unwrap();
+        let mut data = vec![0u8; db.data_size().unwrap()];
+        r.read(&mut data).unwrap();
+        assert_eq!(data, vec![4, 5, 6, 7]);
+        assert!(r.verify_block(id).unwrap());
+
+        // clones are read only
+        assert!(r.put(&[1]).is_err());
+        let epoch = r.epoch();
+        w.delete_block(0).unwrap();
+        assert_ne!(r.epoch(), epoch);
+    }
+
+    #[test]
+    fn quarantined_blocks_are_skipped_and_remembered() {
+        let path = test_file("quarantine.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        for i in 0..4u8 {
+            s.put(&[i; 3]).unwrap();
+        }
+        s.quarantine(1).unwrap();
+        s.delete_block(3).unwrap();
+        let live: Vec<BlockId> = s.iter().map(|r| r.unwrap().0).collect();
+        assert_eq!(live, vec![0, 2]);
+        assert_eq!(s.quarantined(), vec![1]);
+        assert!(s.quarantine(9).is_err());
+        s.close().unwrap();
+
+        let mut s = Store::<B3BlockHasher>::new(path.clone()).unwrap();
+        assert_eq!(s.quarantined(), vec![1]);
+        let live: Vec<Vec<u8>> = s.iter().map(|r| r.unwrap().1).collect();
+        assert_eq!(live, vec![vec![0; 3], vec![2; 3]]);
+        let e = s.quarantine(2).unwrap_err();
+        assert_eq!(e.downcast_ref::<StoreError>().unwrap().kind(), StoreErrorKind::ReadOnly);
+        // through the io path the StoreError is still there to find
+        let e = s.put_vectored(&[IoSlice::new(b"x")]).unwrap_err();
+        assert_eq!(e.downcast_ref::<Error>().unwrap().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(StoreError::find(e.as_ref()).unwrap().kind(), StoreErrorKind::ReadOnly);
+        drop(s);
+
+        // found again by scanning headers
+        let f = OpenOptions::new().write(true).open(&path).unwrap();
+        let len = f.metadata().unwrap().len();
+        f.set_len(len - 1).unwrap();
+        drop(f);
+        let s = Store::<B3BlockHasher>::new(path).unwrap();
+        assert_eq!(s.quarantined(), vec![1]);
+    }
+
+    #[test]
+    fn scrub_reports_bad_blocks() {
+        let path = test_file("scrub.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        s.put(&[1; 40]).unwrap();
+        s.put(&[2; 40]).unwrap();
+        let payload = s.block_address(1).unwrap() + DataHeader::<B3BlockHasher>::size() as u64;
+        s.file.seek(SeekFrom::Start(payload + 3)).unwrap();
+        s.file.write_all(&[7]).unwrap();
+
+        let report = s.scrub().unwrap();
+        assert_eq!(report.blocks_checked, 2);
+        assert_eq!(report.bytes_checked, 80);
+        assert_eq!(report.failed, vec![1]);
+        assert!(report.repaired.is_empty());
+
+        let mut last = (0, 1);
+        s.scrub_with_progress(|done, total| {
+            last = (done, total);
+            true
+        })
+        .unwrap();
+        assert_eq!(last.0, last.1);
+        assert!(s.scrub_with_progress(|_, _| false).is_err());
+    }
+
+    #[cfg(feature = "ecc")]
+    #[test]
+    fn scrub_repairs_from_parity() {
+        use crate::crypto::NullBlockHasher;
+        use crate::ecc::EccConfig;
+        let path = test_file("scrubecc.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        let mut null = Store::<NullBlockHasher>::create(test_file("scrubecc_null.st")).unwrap();
+        assert!(null.enable_ecc(EccConfig { data_shards: 4, parity_shards: 2 }).is_err());
+        s.enable_ecc(EccConfig { data_shards: 4, parity_shards: 2 }).unwrap();
+        assert!(s.migrate_into::<NullBlockHasher>(&test_file("scrubecc_nullcopy.st"), Box::new(BinaryHeaderCodec)).is_err());
+        let data: Vec<u8> = (0..100u8).collect();
+        s.put(&data).unwrap();
+        s.put(&data).unwrap();
+        assert_eq!(s.len(), 2);
+        // damage two shards of block 1
+        let payload = s.block_address(1).unwrap() + DataHeader::<B3BlockHasher>::size() as u64;
+        s.file.seek(SeekFrom::Start(payload + 3)).unwrap();
+        s.file.write_all(&[255]).unwrap();
+        s.file.seek(SeekFrom::Start(payload + 80)).unwrap();
+        s.file.write_all(&[255]).unwrap();
+        s.quarantine(1).unwrap();
+
+        let report = s.scrub().unwrap();
+        assert_eq!(report.repaired, vec![1]);
+        assert!(report.failed.is_empty());
+        assert!(s.quarantined().is_empty());
+        assert!(s.verify_block(1).unwrap());
+        s.close().unwrap();
+
+        // parity blocks are not indexed
+        let mut s = Store::<B3BlockHasher>::new(path).unwrap();
+        assert_eq!(s.len(), 2);
+        assert_eq!(s.iter().count(), 2);
+    }
+
+    #[test]
+    fn incremental_scrub_resumes() {
+        let path = test_file("scrubinc.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        for i in 0..10u8 {
+            s.put(&[i; 10]).unwrap();
+        }
+        let r = s.scrub_incremental(25).unwrap();
+        assert_eq!(r.blocks_checked, 3);
+        assert!(!r.pass_complete);
+        s.close().unwrap();
+
+        let mut s = Store::<B3BlockHasher>::open_for_write(path).unwrap();
+        let r = s.scrub_incremental(1000).unwrap();
+        // picks up at block 3 and stops at the end of the pass
+        assert_eq!(r.blocks_checked, 7);
+        assert!(r.pass_complete);
+        let r = s.scrub_incremental(1).unwrap();
+        assert_eq!(r.blocks_checked, 1);
+        assert!(r.failed.is_empty());
+    }
+
+    #[test]
+    fn access_stats_persist() {
+        let path = test_file("access.st");
+        let _ = std::fs::remove_file(AccessStats::sidecar_path(&path));
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        for i in 0..3u8 {
+            s.put(&[i]).unwrap();
+        }
+        assert!(s.access_stats().is_none());
+        s.close().unwrap();
+
+        let opts = StoreOptions::new().access_stats(true);
+        let mut s = Store::<B3BlockHasher>::open_with_progress(path.clone(), &opts, |_, _| true).unwrap();
+        let mut buf = vec![0u8; 1];
+        s.read_at_index(2, &mut buf).unwrap();
+        s.seek(2).unwrap();
+        assert_eq!(s.iter().count(), 3);
+        // scrub is not an access
+        s.scrub().unwrap();
+        let st = s.access_stats().unwrap();
+        assert_eq!(st.get(2).reads, 3);
+        assert_eq!(st.get(0).reads, 1);
+        drop(s);
+
+        let s = Store::<B3BlockHasher>::open_with_progress(path, &opts, |_, _| true).unwrap();
+        assert_eq!(s.access_stats().unwrap().get(2).reads, 3);
+        assert_eq!(s.access_stats().unwrap().least_frequently_used(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn retention_deletes_oldest() {
+        let path = test_file("retention.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        for i in 0..6u8 {
+            s.put(&[i; 10]).unwrap();
+        }
+        assert!(s.append_time(0).is_some());
+        s.delete_block(1).unwrap();
+        let policy = RetentionPolicy {
+            max_blocks: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(s.enforce_retention(&policy).unwrap(), vec![0, 2]);
+        let policy = RetentionPolicy {
+            max_total_bytes: Some(15),
+            ..Default::default()
+        };
+        assert_eq!(s.enforce_retention(&policy).unwrap(), vec![3, 4]);
+        // nothing is older than an hour
+        let policy = RetentionPolicy {
+            max_age: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        assert!(s.enforce_retention(&policy).unwrap().is_empty());
+        s.close().unwrap();
+
+        let s = Store::<B3BlockHasher>::new(path).unwrap();
+        assert!(s.append_time(5).is_some());
+        let ids: Vec<BlockId> = (0..s.len()).filter(|i| s.append_time(*i).is_some()).collect();
+        assert_eq!(ids.len(), 6);
+    }
+
+    #[test]
+    fn journaled_deletes_are_replayed() {
+        let path = test_file("journal.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        for i in 0..4u8 {
+            s.put(&[i]).unwrap();
+        }
+        s.delete_many(&[0, 2]).unwrap();
+        // the cursor is left alone
+        s.seek(3).unwrap();
+        s.delete_block(1).unwrap();
+        let mut dh = DataHeader::<B3BlockHasher>::new().unwrap();
+        s.read_data_header(&mut dh).unwrap();
+        assert_eq!(dh.data_size().unwrap(), 1);
+        assert!(s.delete_many(&[9]).is_err());
+        // journal written, flags not
+        s.append_system_block(DataHeader::<B3BlockHasher>::journal_flag(), &[1, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+        crash(s);
+
+        let mut s = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        assert_eq!(s.len(), 4);
+        assert_eq!(s.iter().count(), 0);
+        s.put(&[4]).unwrap();
+        s.close().unwrap();
+        let mut s = Store::<B3BlockHasher>::new(path).unwrap();
+        let live: Vec<BlockId> = s.iter().map(|r| r.unwrap().0).collect();
+        assert_eq!(live, vec![4]);
+    }
+
+    #[test]
+    fn read_at_index_returns_payload() {
+        let path = test_file("readindex.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        s.put(&[1, 2, 3]).unwrap();
+        s.put(&[4, 5, 6, 7]).unwrap();
+        let mut buf = vec![0u8; 100];
+        assert_eq!(s.read_at_index(0, &mut buf).unwrap(), 3);
+        assert_eq!(buf, vec![1, 2, 3]);
+        assert_eq!(s.read_at_index(1, &mut buf).unwrap(), 4);
+        assert_eq!(buf, vec![4, 5, 6, 7]);
+        let end = s.block_address(1).unwrap() + DataHeader::<B3BlockHasher>::size() as u64 + 2;
+        s.file.set_len(end).unwrap();
+        let e = s.read_at_index(1, &mut buf).err().unwrap();
+        let kind = e.downcast_ref::<StoreError>().unwrap().kind();
+        assert_eq!(kind, StoreErrorKind::Truncated { expected: 4, found: 2 });
+        assert!(s.read_at_index(2, &mut buf).is_err());
+    }
+
+    #[test]
+    fn checkpoints_are_written_as_policy_says() {
+        let path = test_file("checkpoint.st");
+        let _ = std::fs::remove_file(format!("{}.ckpt", path));
+        let policy = CheckpointPolicy { every_blocks: Some(3), every_bytes: None };
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        s.set_checkpoint_policy(policy);
+        for i in 0..7u8 {
+            s.put(&[i; 10]).unwrap();
+        }
+        let at = s.checkpoint_address().unwrap().unwrap();
+        assert!(at > s.block_address(5).unwrap() && at < s.block_address(6).unwrap());
+        assert!(s.block_header(6).is_ok());
+        s.close().unwrap();
+
+        let opts = StoreOptions::new().write(true).checkpoints(CheckpointPolicy { every_blocks: None, every_bytes: Some(1) });
+        let mut s = Store::<B3BlockHasher>::open_with_progress(path.clone(), &opts, |_, _| true).unwrap();
+        assert_eq!(s.len(), 7);
+        s.put(&[7]).unwrap();
+        let at = s.checkpoint_address().unwrap().unwrap();
+        assert!(at > s.block_address(7).unwrap());
+        s.swap(&[(0, &[1, 2])]).unwrap();
+        assert_eq!(s.checkpoint_address().unwrap(), None);
+        assert!(s.fsck().unwrap().is_clean());
+        s.close().unwrap();
+        let mut s = Store::<B3BlockHasher>::new(path).unwrap();
+        let all: Vec<Vec<u8>> = s.iter().map(|r| r.unwrap().1).collect();
+        assert_eq!(all.len(), 8);
+        assert_eq!(all[6..], [vec![7], vec![1, 2]]);
+    }
+
+    #[test]
+    fn recovery_resumes_from_checkpoint() {
+        let path = test_file("checkpoint_recover.st");
+        let _ = std::fs::remove_file(format!("{}.ckpt", path));
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        for i in 0..5u8 {
+            s.put(&[i; 10]).unwrap();
+        }
+        crash(s);
+        let s = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        assert_eq!(s.recovery(), Some(&RecoveryReport { resumed_from: None, blocks_recovered: 5, ..RecoveryReport::default() }));
+
+        let mut s = s;
+        s.checkpoint().unwrap();
+        let at = s.checkpoint_address().unwrap().unwrap();
+        s.put(&[5; 10]).unwrap();
+        s.put(&[6; 10]).unwrap();
+        let end = s.index().data_end_address;
+        crash(s);
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(&[1, 2, 3]).unwrap();
+        drop(f);
+
+        let s = Store::<B3BlockHasher>::new(path.clone()).unwrap();
+        assert_eq!(s.recovery(), Some(&RecoveryReport { resumed_from: Some(at), blocks_recovered: 2, bytes_discarded: 3, ..RecoveryReport::default() }));
+        drop(s);
+        let mut s = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        assert_eq!(s.recovery().unwrap().resumed_from, Some(at));
+        assert_eq!(s.len(), 7);
+        assert_eq!(s.get(6).unwrap(), vec![6; 10]);
+        assert_eq!(s.index().data_end_address, end);
+        s.close().unwrap();
+        assert!(Store::<B3BlockHasher>::new(path).unwrap().recovery().is_none());
+    }
+
+    #[test]
+    fn fsck_finds_and_quarantines_bad_blocks() {
+        let path = test_file("fsck.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        s.put(&[1, 2, 3]).unwrap();
+        let b = s.put(&[4, 5]).unwrap();
+        s.put(&[6]).unwrap();
+        s.delete_many(&[0]).unwrap();
+        let report = s.fsck().unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.headers_checked, 4);
+        assert_eq!(report.journal_blocks, 1);
+        assert_eq!(report.scrub.blocks_checked, 2);
+        let payload = s.block_address(b).unwrap() + DataHeader::<B3BlockHasher>::size() as u64;
+        s.file.seek(SeekFrom::Start(payload)).unwrap();
+        s.file.write_all(&[7]).unwrap();
+        s.close().unwrap();
+
+        let mut r = Store::<B3BlockHasher>::new(path.clone()).unwrap();
+        let report = r.fsck().unwrap();
+        assert_eq!(report.unresolved, vec![b]);
+        assert!(!report.is_consistent());
+        drop(r);
+        let mut w = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        let report = w.fsck().unwrap();
+        assert_eq!(report.quarantined, vec![b]);
+        assert!(report.is_consistent() && !report.is_clean());
+        w.close().unwrap();
+        let mut r = Store::<B3BlockHasher>::new(path).unwrap();
+        assert!(r.fsck().unwrap().is_consistent());
+        r.index_mut().block_addresses[1] += 1;
+        assert_eq!(r.fsck().unwrap().chain_errors.len(), 1);
+    }
+
+    #[test]
+    fn addresses_map_back_to_indexes() {
+        let mut s = Store::<B3BlockHasher>::create(test_file("address_of.st")).unwrap();
+        for i in 0..4u8 {
+            s.put(&[i; 5]).unwrap();
+        }
+        s.delete_many(&[1]).unwrap();
+        let moved = s.swap(&[(2, &[9; 50])]).unwrap()[0];
+        for i in 0..s.len() {
+            assert_eq!(s.index_of_address(s.block_address(i).unwrap()), Some(i));
+        }
+        assert_eq!(moved, 4);
+        assert_eq!(s.index_of_address(s.block_address(0).unwrap() + 1), None);
+        assert_eq!(s.index_of_address(0), None);
+    }
+
+    #[test]
+    fn get_verifies_payload() {
+        let path = test_file("get.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        let a = s.put(&[1, 2, 3]).unwrap();
+        let b = s.put(&[4, 5]).unwrap();
+        let c = s.put(&[6]).unwrap();
+        assert_eq!(s.get(a).unwrap(), vec![1, 2, 3]);
+        s.delete_block(b).unwrap();
+        let kind = |e: Box<dyn std::error::Error>| e.downcast_ref::<StoreError>().unwrap().kind();
+        assert_eq!(kind(s.get(b).err().unwrap()), StoreErrorKind::NotLive);
+        let payload = s.block_address(c).unwrap() + DataHeader::<B3BlockHasher>::size() as u64;
+        s.file.seek(SeekFrom::Start(payload)).unwrap();
+        s.file.write_all(&[7]).unwrap();
+        assert_eq!(kind(s.get(c).err().unwrap()), StoreErrorKind::Checksum);
+        assert!(s.get(3).is_err());
+    }
+
+    #[test]
+    fn bounds_helpers() {
+        let path = test_file("bounds.st");
+        let mut s = Store::<B3BlockHasher>::create(path).unwrap();
+        assert_eq!(s.last_index(), None);
+        assert!(!s.contains(0));
+        s.put(&[1]).unwrap();
+        s.put(&[2]).unwrap();
+        s.delete_block(0).unwrap();
+        assert_eq!(s.last_index(), Some(1));
+        assert!(s.contains(1));
+        assert!(!s.contains(2));
+        assert!(!s.is_live(0).unwrap());
+        assert!(s.is_live(1).unwrap());
+        assert!(s.is_live(2).is_err());
+    }
+
+    #[test]
+    fn reindex_sees_other_writers() {
+        let path = test_file("reindex.st");
+        let mut w = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        w.put(&[1]).unwrap();
+        w.close().unwrap();
+        let mut r = Store::<B3BlockHasher>::new(path.clone()).unwrap();
+        assert_eq!(r.len(), 1);
+        assert_eq!(r.reindex().unwrap(), ReindexReport::default());
+
+        let mut w = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        w.put(&[2]).unwrap();
+        w.put(&[3]).unwrap();
+        w.flush().unwrap();
+        let rep = r.reindex().unwrap();
+        assert_eq!(rep.new_blocks, 2);
+        assert_eq!(r.get(2).unwrap(), vec![3]);
+        // the footer written on close is not a block
+        w.close().unwrap();
+        assert_eq!(r.reindex().unwrap().new_blocks, 0);
+        let mut w = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        w.put(&[4]).unwrap();
+        w.close().unwrap();
+        assert_eq!(r.reindex().unwrap().new_blocks, 1);
+        assert_eq!(r.get(3).unwrap(), vec![4]);
+
+        let a = r.block_address(1).unwrap();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(a).unwrap();
+        let rep = r.reindex().unwrap();
+        assert!(rep.truncated);
+        assert_eq!(r.len(), 1);
+    }
+
+    #[test]
+    fn empty_blocks() {
+        let path = test_file("empty.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        assert_eq!(s.write(&[]).unwrap(), 0);
+        assert!(s.is_empty());
+        s.put(&[]).unwrap();
+        s.put(&[1]).unwrap();
+        s.put(&[]).unwrap();
+        assert_eq!(s.get(0).unwrap(), Vec::<u8>::new());
+        let mut buf = vec![9u8; 4];
+        assert_eq!(s.read_at_index(2, &mut buf).unwrap(), 0);
+        assert!(buf.is_empty());
+        assert!(s.verify_block(2).unwrap());
+        s.close().unwrap();
+
+        let mut s = Store::<B3BlockHasher>::new(path.clone()).unwrap();
+        let blocks: Vec<(BlockId, Vec<u8>)> = s.iter().map(|r| r.unwrap()).collect();
+        assert_eq!(blocks, vec![(0, vec![]), (1, vec![1]), (2, vec![])]);
+        drop(s);
+        // and without the footer
+        let mut s = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        s.put(&[]).unwrap();
+        crash(s);
+        let s = Store::<B3BlockHasher>::open_for_write(path).unwrap();
+        assert_eq!(s.len(), 4);
+    }
+
+    #[test]
+    fn max_block_size_is_enforced() {
+        let path = test_file("maxsize.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        s.put(&[0; 100]).unwrap();
+        s.set_max_block_size(Some(10));
+        let e = s.put(&[0; 11]).err().unwrap();
+        let kind = e.downcast_ref::<StoreError>().unwrap().kind();
+        assert_eq!(kind, StoreErrorKind::BlockTooLarge { size: 11, max: 10 });
+        assert!(s.write(&[0; 11]).is_err());
+        s.put(&[0; 10]).unwrap();
+        // the old block is now too big to believe
+        assert!(s.get(0).is_err());
+        assert_eq!(s.get(1).unwrap().len(), 10);
+        s.close().unwrap();
+
+        let opts = StoreOptions::new().max_block_size(50);
+        assert!(Store::<B3BlockHasher>::open_with_progress(path.clone(), &opts, |_, _| true).is_ok());
+        let opts = StoreOptions::new().max_block_size(5);
+        let mut s = Store::<B3BlockHasher>::open_with_progress(path, &opts, |_, _| true).unwrap();
+        assert!(s.get(1).is_err());
+    }
+
+    #[test]
+    fn compact_drops_dead_blocks() {
+        let path = test_file("compact_out.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        for i in 0..5u8 {
+            s.put(&[i; 64]).unwrap();
+        }
+        s.delete_many(&[1, 3]).unwrap();
+        s.quarantine(4).unwrap();
+        let dry = s.compact_dry_run().unwrap();
+        let e = s.compact_with_progress(|_, _| false).err().unwrap();
+        assert_eq!(e.downcast_ref::<StoreError>().unwrap().kind(), StoreErrorKind::Cancelled);
+        assert!(!Path::new(&format!("{}.compact", path)).exists());
+        let mut calls = Vec::new();
+        let report = s
+            .compact_with_progress(|done, total| {
+                calls.push((done, total));
+                true
+            })
+            .unwrap();
+        assert_eq!(calls.len(), 2);
+        assert!(calls[0].0 < calls[1].0 && calls[1].0 < calls[1].1);
+        assert_eq!(dry, report);
+        assert_eq!(report.remap, vec![Some(0), None, Some(1), None, None]);
+        assert_eq!(report.new_id(2), Some(1));
+        assert!(report.bytes_reclaimed > 0);
+        assert_eq!(s.len(), 2);
+        assert!(s.quarantined().is_empty());
+        assert_eq!(s.get(1).unwrap(), vec![2; 64]);
+        assert_eq!(s.put(&[9]).unwrap(), 2);
+        s.close().unwrap();
+        assert!(!Path::new(&format!("{}.compact", path)).exists());
+
+        let mut s = Store::<B3BlockHasher>::new(path).unwrap();
+        assert!(!s.is_dirty());
+        let all: Vec<Vec<u8>> = s.iter().map(|r| r.unwrap().1).collect();
+        assert_eq!(all, vec![vec![0; 64], vec![2; 64], vec![9]]);
+    }
+
+    /// Moves seen by an observer, shared with the test
+    struct MoveLog(Arc<RwLock<Vec<(u64, u64, BlockId)>>>);
+
+    impl StoreObserver for MoveLog {
+        fn on_relocate(&mut self, old_addr: u64, new_addr: u64, block_id: BlockId) {
+            self.0.write().unwrap().push((old_addr, new_addr, block_id));
+        }
+    }
+
+    #[test]
+    fn observers_see_relocations() {
+        let path = test_file("observe.st");
+        let mut s = Store::<B3BlockHasher>::create(path).unwrap();
+        for i in 0..4u8 {
+            s.put(&[i; 32]).unwrap();
+        }
+        s.delete_many(&[1]).unwrap();
+        let moves = Arc::new(RwLock::new(Vec::new()));
+        s.add_observer(Box::new(MoveLog(Arc::clone(&moves))));
+        let old: Vec<u64> = (0..4).map(|i| s.block_address(i).unwrap()).collect();
+        s.compact_in_place().unwrap();
+        let new: Vec<u64> = (0..3).map(|i| s.block_address(i).unwrap()).collect();
+        assert_eq!(*moves.read().unwrap(), vec![(old[2], new[1], 1), (old[3], new[2], 2)]);
+
+        // observers stay with the store through compact
+        moves.write().unwrap().clear();
+        s.delete_many(&[0]).unwrap();
+        s.compact().unwrap();
+        assert_eq!(moves.read().unwrap().iter().map(|m| m.2).collect::<Vec<_>>(), vec![0, 1]);
+    }
+
+    #[test]
+    fn compact_in_place_slides_blocks_down() {
+        let path = test_file("compact_in.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        for (i, n) in [10usize, 300, 40, 5, 200].iter().enumerate() {
+            s.put(&vec![i as u8; *n]).unwrap();
+        }
+        s.delete_many(&[0, 2]).unwrap();
+        let before = s.index().data_end_address;
+        let report = s.compact_in_place().unwrap();
+        assert_eq!(report.remap, vec![None, Some(0), None, Some(1), Some(2)]);
+        assert_eq!(report.bytes_reclaimed, before - s.index().data_end_address);
+        assert!(!Path::new(&format!("{}.reloc", path)).exists());
+        assert_eq!(s.get(0).unwrap(), vec![1; 300]);
+        assert_eq!(s.get(2).unwrap(), vec![4; 200]);
+        s.close().unwrap();
+        let mut s = Store::<B3BlockHasher>::new(path.clone()).unwrap();
+        assert_eq!(s.len(), 3);
+        assert_eq!(s.get(1).unwrap(), vec![3; 5]);
+        drop(s);
+
+        // an interrupted move is finished on the next open for writing
+        let mut s = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        s.delete_many(&[0]).unwrap();
+        s.retire_journals().unwrap();
+        let (src, dst) = (s.block_address(1).unwrap(), s.block_address(0).unwrap());
+        let unit = s.block_address(2).unwrap() - src;
+        let mut bytes = vec![0u8; unit as usize];
+        s.file.seek(SeekFrom::Start(src)).unwrap();
+        s.file.read_exact(&mut bytes).unwrap();
+        let mut journal = File::create(format!("{}.reloc", path)).unwrap();
+        s.write_relocation(&mut journal, &[Relocation { src, dst, bytes: bytes.clone() }]).unwrap();
+        // half the move made it to disk
+        s.file.seek(SeekFrom::Start(dst)).unwrap();
+        s.file.write_all(&bytes[..bytes.len() / 2]).unwrap();
+        crash(s);
+        let mut s = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        let all: Vec<Vec<u8>> = s.iter().map(|r| r.unwrap().1).collect();
+        assert_eq!(all, vec![vec![3; 5], vec![4; 200]]);
+        assert_eq!(s.len(), 2);
+        assert!(!Path::new(&format!("{}.reloc", path)).exists());
+    }
+
+    #[test]
+    fn health_reports_problems() {
+        let path = test_file("health.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        assert_eq!(s.health().unwrap().status, HealthStatus::Ok);
+        for i in 0..4u8 {
+            s.put(&[i; 100]).unwrap();
+        }
+        let h = s.health().unwrap();
+        assert_eq!(h.status, HealthStatus::Ok);
+        assert!(h.problems.is_empty());
+        s.delete_many(&[0, 1, 2]).unwrap();
+        let h = s.health().unwrap();
+        assert_eq!(h.status, HealthStatus::Degraded);
+        assert!(h.fragmentation > 0.5);
+        assert_eq!(s.health_with(1.0).unwrap().status, HealthStatus::Ok);
+        s.quarantine(3).unwrap();
+        let h = s.health_with(1.0).unwrap();
+        assert_eq!(h.status, HealthStatus::Corrupt);
+        assert_eq!(h.quarantined, vec![3]);
+        crash(s);
+
+        let mut s = Store::<B3BlockHasher>::new(path).unwrap();
+        let h = s.health_with(1.0).unwrap();
+        assert!(h.dirty);
+        assert!(!h.journal_pending);
+        assert_eq!(h.problems.len(), 2);
+    }
+
+    #[test]
+    fn io_counters_count_and_reset() {
+        let path = test_file("counters.st");
+        let mut s = Store::<B3BlockHasher>::create(path).unwrap();
+        s.reset_counters();
+        s.put(&[7; 100]).unwrap();
+        let c = s.io_counters();
+        assert!(c.bytes_written >= 100);
+        assert!(c.syscalls >= 2);
+        assert!(c.seeks >= 1);
+        s.reset_counters();
+        assert_eq!(s.get(0).unwrap(), vec![7; 100]);
+        let c = s.io_counters();
+        assert!(c.bytes_read >= 100);
+        assert_eq!(c.bytes_written, 0);
+        assert!(!s.maybe_contains(&[1; 32]));
+        assert_eq!(s.io_counters().cache_hits, 1);
+        s.reset_counters();
+        assert_eq!(s.io_counters(), IoCounters::default());
+    }
+
+    #[test]
+    fn update_if_checks_the_hash() {
+        let path = test_file("update_if.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        s.put(&[1; 100]).unwrap();
+        s.put(&[9; 10]).unwrap();
+        let mut h = B3BlockHasher::create();
+        let e = s.update_if(0, h.hash(&[2; 100]), &[2; 100]).err().unwrap();
+        assert_eq!(e.downcast_ref::<StoreError>().unwrap().kind(), StoreErrorKind::Conflict);
+        assert_eq!(s.update_if(0, h.hash(&[1; 100]), &[2; 100]).unwrap(), 0);
+        assert_eq!(s.get(0).unwrap(), vec![2; 100]);
+        // smaller, with a filler after it
+        assert_eq!(s.update_if(0, h.hash(&[2; 100]), &[3; 20]).unwrap(), 0);
+        assert_eq!(s.get(0).unwrap(), vec![3; 20]);
+        assert_eq!(s.get(1).unwrap(), vec![9; 10]);
+        // too big to fit, so it moves
+        assert_eq!(s.update_if(0, h.hash(&[3; 20]), &[4; 200]).unwrap(), 2);
+        assert!(!s.is_live(0).unwrap());
+        crash(s);
+
+        let mut s = Store::<B3BlockHasher>::open_for_write(path).unwrap();
+        assert_eq!(s.len(), 3);
+        assert_eq!(s.get(1).unwrap(), vec![9; 10]);
+        assert_eq!(s.get(2).unwrap(), vec![4; 200]);
+    }
+
+    #[test]
+    fn swap_is_all_or_nothing() {
+        let path = test_file("swap.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        for i in 0..3u8 {
+            s.put(&[i; 50]).unwrap();
+        }
+        assert!(s.swap(&[(0, &[9; 50]), (0, &[8; 50])]).is_err());
+        let ids = s.swap(&[(0, &[10; 50]), (1, &[11; 500])]).unwrap();
+        assert_eq!(ids, vec![0, 3]);
+        assert_eq!(s.get(0).unwrap(), vec![10; 50]);
+        assert_eq!(s.get(3).unwrap(), vec![11; 500]);
+        assert!(!s.is_live(1).unwrap());
+
+        // staged but not committed, then committed by recovery
+        let staged = s.stage_swap(&[(2, &[12; 50]), (3, &[13; 900])]).unwrap();
+        assert_eq!(staged.ids, vec![2, 4]);
+        assert!(!s.is_live(4).unwrap());
+        s.file.sync_all().unwrap();
+        let mut journal = File::create(format!("{}.reloc", path)).unwrap();
+        s.write_relocation(&mut journal, &staged.moves).unwrap();
+        crash(s);
+        let mut s = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        assert_eq!(s.get(2).unwrap(), vec![12; 50]);
+        assert_eq!(s.get(4).unwrap(), vec![13; 900]);
+        assert!(!s.is_live(3).unwrap());
+
+        // staged without the journal, so nothing changes
+        s.stage_swap(&[(0, &[14; 5000])]).unwrap();
+        crash(s);
+        let mut s = Store::<B3BlockHasher>::open_for_write(path).unwrap();
+        assert_eq!(s.get(0).unwrap(), vec![10; 50]);
+        assert!(!s.is_live(5).unwrap());
+    }
+
+    #[test]
+    fn read_at_generation_serves_old_payloads() {
+        let path = test_file("generation.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        s.put(&[1; 50]).unwrap();
+        s.put(&[2; 50]).unwrap();
+        let gen = s.generation();
+        assert_eq!(gen, 2);
+        // moved, so the old payload is kept
+        assert_eq!(s.swap(&[(1, &[3; 500])]).unwrap(), vec![2]);
+        // rewritten in place, so it isn't
+        s.swap(&[(0, &[4; 50])]).unwrap();
+        s.delete_many(&[2]).unwrap();
+        s.close().unwrap();
+
+        let mut s = Store::<B3BlockHasher>::open_for_write(path).unwrap();
+        assert_eq!(s.read_at_generation(1, gen).unwrap(), vec![2; 50]);
+        assert_eq!(s.read_at_generation(2, s.generation()).unwrap(), vec![3; 500]);
+        assert!(s.read_at_generation(2, gen).is_err());
+        assert!(s.read_at_generation(0, gen).is_err());
+        assert_eq!(s.read_at_generation(0, s.generation()).unwrap(), vec![4; 50]);
+        assert!(s.block_header(0).unwrap().is_rewritten());
+    }
+
+    #[test]
+    fn vectored_writes_store_one_block() {
+        let mut s = Store::<Crc32BlockHasher>::create(test_file("vectored.st")).unwrap();
+        s.enable_strong_digests();
+        let whole = s.put(b"header,body,trailer").unwrap();
+        let parts = [IoSlice::new(b"header,"), IoSlice::new(b""), IoSlice::new(b"body,trailer")];
+        let split = s.put_vectored(&parts).unwrap();
+        assert_eq!(s.get(split).unwrap(), b"header,body,trailer");
+        assert_eq!(s.block_header(split).unwrap().fields().checksum, s.block_header(whole).unwrap().fields().checksum);
+        assert!(s.verify(split, Strength::Strong).unwrap());
+
+        assert_eq!(s.write_vectored(&parts[..2]).unwrap(), 7);
+        assert_eq!(s.write_vectored(&[IoSlice::new(b"")]).unwrap(), 0);
+        assert_eq!(s.len(), 3);
+        assert_eq!(s.get(2).unwrap(), b"header,");
+        s.set_max_block_size(Some(10));
+        assert!(s.put_vectored(&parts).is_err());
+        assert_eq!(s.len(), 3);
+    }
+
+    #[test]
+    fn block_reader_streams_and_verifies_at_the_end() {
+        let mut s = Store::<B3BlockHasher>::create(test_file("block_reader.st")).unwrap();
+        let text: Vec<u8> = (0..20000).flat_map(|i| format!("line {}\n", i).into_bytes()).collect();
+        assert!(text.len() > BLOCK_READER_BUFFER);
+        let big = s.put(&text).unwrap();
+        let small = s.put(b"abcdef").unwrap();
+
+        let mut r = s.block_reader(big).unwrap();
+        assert_eq!(r.len(), text.len() as u64);
+        let mut lines = 0;
+        let mut line = String::new();
+        while r.read_line(&mut line).unwrap() > 0 {
+            lines += 1;
+            line.clear();
+        }
+        assert_eq!(lines, 20000);
+        assert!(r.is_verified());
+        r.seek(SeekFrom::End(-8)).unwrap();
+        let mut tail = String::new();
+        r.read_to_string(&mut tail).unwrap();
+        assert_eq!(tail, "e 19999\n");
+        assert!(r.seek(SeekFrom::Current(-(text.len() as i64) - 1)).is_err());
+
+        let payload = s.block_address(small).unwrap() + DataHeader::<B3BlockHasher>::size() as u64;
+        s.file.seek(SeekFrom::Start(payload + 4)).unwrap();
+        s.file.write_all(b"x").unwrap();
+        let mut r = s.block_reader(small).unwrap();
+        let mut head = [0u8; 4];
+        r.read_exact(&mut head).unwrap();
+        assert_eq!(&head, b"abcd");
+        let e = r.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        let inner = e.into_inner().unwrap();
+        assert_eq!(inner.downcast_ref::<StoreError>().unwrap().kind(), StoreErrorKind::Checksum);
+
+        s.delete_many(&[big]).unwrap();
+        assert!(s.block_reader(big).is_err());
+    }
+
+    #[test]
+    fn space_report_accounts_for_every_byte() {
+        let mut s = Store::<B3BlockHasher>::create(test_file("space.st")).unwrap();
+        let hsize = DataHeader::<B3BlockHasher>::size() as u64;
+        let start = s.space_report().unwrap();
+        assert_eq!(start.logical_bytes, 0);
+        assert_eq!(start.write_amplification(), 0.0);
+
+        s.put(&[1; 100]).unwrap();
+        s.put(&[2; 100]).unwrap();
+        s.enable_strong_digests();
+        s.put(&[3; 100]).unwrap();
+        s.reset_counters();
+        s.delete_many(&[0]).unwrap();
+        s.swap(&[(1, &[4; 40])]).unwrap();
+        let r = s.space_report().unwrap();
+        assert_eq!(r.logical_bytes, 340);
+        assert_eq!(r.header_bytes, 4 * hsize);
+        assert_eq!(r.companion_bytes, hsize + 33);
+        assert!(r.journal_bytes > hsize);
+        assert_eq!(r.filler_bytes, hsize);
+        assert_eq!(r.index_bytes, 0);
+        assert_eq!((r.live_bytes, r.dead_bytes), (140, 100));
+        assert!(r.write_amplification() > 1.0);
+        assert!(r.overhead() > 0.0 && r.overhead() < 1.0);
+        assert_eq!(
+            r.physical_bytes,
+            r.logical_bytes + r.header_bytes + r.companion_bytes + r.journal_bytes + r.filler_bytes + r.rewrite_bytes
+        );
+
+        s.compact().unwrap();
+        let c = s.space_report().unwrap();
+        assert_eq!(c.logical_bytes, r.logical_bytes);
+        assert!(c.rewrite_bytes >= r.rewrite_bytes + 140);
+        assert_eq!((c.live_bytes, c.dead_bytes), (140, 0));
+        assert!(c.overhead() < r.overhead());
+    }
+
+    #[test]
+    fn purge_erases_deleted_payloads() {
+        let path = test_file("purge.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        s.enable_strong_digests();
+        s.put(&[1; 100]).unwrap();
+        s.put(&[2; 100]).unwrap();
+        s.put(&[3; 100]).unwrap();
+        s.delete_many(&[0, 1]).unwrap();
+        assert!(s.delete_time(0).is_some() && s.delete_time(2).is_none());
+        assert!(s.purge_tombstones(Duration::from_secs(3600), Erase::Zero).unwrap().purged.is_empty());
+        s.close().unwrap();
+
+        let mut s = Store::<B3BlockHasher>::open_for_write(path).unwrap();
+        assert!(s.delete_time(1).is_some());
+        let report = s.purge_tombstones(Duration::ZERO, Erase::Secure).unwrap();
+        assert_eq!(report.purged, vec![0, 1]);
+        assert_eq!(report.bytes_erased, 2 * (100 + 33));
+        assert_eq!(*s.read_block(1).unwrap().1, vec![0; 100]);
+        assert!(s.read_at_generation(0, 1).is_err());
+        assert_eq!(s.get(2).unwrap(), vec![3; 100]);
+        assert!(s.verify(2, Strength::Strong).unwrap());
+        assert!(s.purge_tombstones(Duration::ZERO, Erase::Zero).unwrap().purged.is_empty());
+    }
+
+    #[test]
+    fn secure_delete_erases_before_deleting() {
+        let mut s = Store::<Crc32BlockHasher>::create(test_file("secure_delete.st")).unwrap();
+        s.enable_strong_digests();
+        s.put(b"secret").unwrap();
+        s.put(b"public").unwrap();
+        s.delete_block_secure(0, Erase::Secure).unwrap();
+        assert!(!s.is_live(0).unwrap());
+        assert!(s.delete_time(0).is_some());
+        assert_eq!(*s.read_block(0).unwrap().1, vec![0; 6]);
+        let address = s.block_address(0).unwrap();
+        let hsize = s.header_size as u64;
+        let (_, digest) = s.companions(address, hsize + 6).unwrap().pop().unwrap();
+        assert!(digest.is_digest());
+        assert!(s.is_zeroed(address + 2 * hsize + 6, digest.fields().size_data).unwrap());
+        assert_eq!(s.get(1).unwrap(), b"public");
+        assert!(s.delete_block_secure(2, Erase::Zero).is_err());
+    }
+
+    /// Blocks without an owner, or readable by anyone
+    fn world_readable(b: &DataHeaderInfo) -> Access {
+        if b.owner == 0 || b.permissions & 0o004 != 0 {
+            Access::Allow
+        } else {
+            Access::Deny
+        }
+    }
+
+    #[test]
+    fn access_policy_guards_payloads() {
+        let mut plain = Store::<B3BlockHasher>::create(test_file("access_plain.st")).unwrap();
+        assert!(plain.put_owned(b"x", 1, 0).is_err());
+
+        let path = test_file("access.st");
+        let mut s = Store::<B3BlockHasher>::create_with_codec(path.clone(), Box::new(FieldsHeaderCodec { fields: HEADER_FIELD_ACCESS })).unwrap();
+        s.put(b"public").unwrap();
+        let private = s.put_owned(b"private", 7, 0o600).unwrap();
+        s.put_owned(b"shared", 7, 0o644).unwrap();
+        s.set_access_policy(Some(world_readable));
+        let denied = |e: Box<dyn std::error::Error>| e.downcast_ref::<StoreError>().unwrap().kind() == StoreErrorKind::AccessDenied;
+        assert!(denied(s.get(private).err().unwrap()));
+        assert!(denied(s.read_at_index(private, &mut Vec::new()).err().unwrap()));
+        assert!(s.block_reader(private).is_err());
+        assert_eq!(s.iter().map(|r| r.unwrap().0).collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(s.search(b"private").unwrap(), vec![]);
+        assert_eq!(s.get(2).unwrap(), b"shared");
+        assert!(s.try_clone().unwrap().get(private).is_err());
+
+        s.set_access_policy(None);
+        let moved = s.swap(&[(private, b"still private")]).unwrap()[0];
+        let private = s.compact().unwrap().remap[moved].unwrap();
+        let dh = s.block_header(private).unwrap();
+        assert_eq!((dh.owner(), dh.permissions()), (7, 0o600));
+        s.close().unwrap();
+
+        let mut s = Store::<B3BlockHasher>::open_with_progress(path, &StoreOptions::new().access_policy(world_readable), |_, _| true).unwrap();
+        assert!(s.get(private).is_err());
+        assert_eq!(s.get(0).unwrap(), b"public");
+    }
+
+    #[test]
+    fn lifetime_stats_survive_reopen_and_compaction() {
+        let path = test_file("lifetime.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        let before = SystemTime::now() - Duration::from_secs(1);
+        let a = s.put(&[1; 10]).unwrap();
+        s.put(&[2; 20]).unwrap();
+        s.swap(&[(a, &[3; 5])]).unwrap();
+        let stats = s.lifetime_stats();
+        assert_eq!((stats.blocks_written, stats.bytes_written, stats.compactions), (3, 35, 0));
+        assert!(stats.created.unwrap() >= before);
+        assert_eq!(stats.last_verified, None);
+        s.close().unwrap();
+
+        let mut s = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        assert_eq!(s.lifetime_stats(), stats);
+        s.scrub().unwrap();
+        s.delete_many(&[a]).unwrap();
+        s.compact().unwrap();
+        s.compact_in_place().unwrap();
+        let after = s.lifetime_stats();
+        assert_eq!((after.blocks_written, after.bytes_written, after.compactions), (3, 35, 2));
+        assert_eq!(after.created, stats.created);
+        assert!(after.last_verified.is_some());
+        s.close().unwrap();
+        assert_eq!(Store::<B3BlockHasher>::new(path).unwrap().lifetime_stats(), after);
+    }
+
+    #[test]
+    fn dedup_window_returns_recent_duplicates() {
+        let path = test_file("dedup.st");
+        Store::<B3BlockHasher>::create(path.clone()).unwrap().close().unwrap();
+        let mut s = Store::<B3BlockHasher>::open_with_progress(path, &StoreOptions::new().write(true).dedup_window(2), |_, _| true).unwrap();
+        let a = s.put(b"event").unwrap();
+        assert_eq!(s.put(b"event").unwrap(), a);
+        s.put(b"other").unwrap();
+        assert_eq!(s.put(b"event").unwrap(), a);
+        // a falls out of the window
+        s.put(b"third").unwrap();
+        let again = s.put(b"event").unwrap();
+        assert_ne!(again, a);
+        assert_eq!(s.len(), 4);
+
+        // deleted blocks aren't handed out
+        s.delete_many(&[again]).unwrap();
+        assert_ne!(s.put(b"event").unwrap(), again);
+        s.delete_many(&[a]).unwrap();
+        // compaction empties the window
+        s.compact().unwrap();
+        let n = s.len();
+        s.put(b"other").unwrap();
+        assert_eq!(s.len(), n + 1);
+
+        s.set_dedup_window(None);
+        let c = s.put(b"same").unwrap();
+        assert_ne!(s.put(b"same").unwrap(), c);
+    }
+
+    #[test]
+    fn buffer_pool_reuses_read_buffers() {
+        let path = test_file("pool.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        for i in 0..4u8 {
+            s.put(&[i; 100]).unwrap();
+        }
+        s.close().unwrap();
+
+        let mut s = Store::<B3BlockHasher>::open_with_progress(path, &StoreOptions::new().buffer_pool(1 << 20), |_, _| true).unwrap();
+        assert!(s.verify_block(0).unwrap());
+        let first = s.pool_stats().unwrap();
+        assert!(first.misses > 0 && first.held_bytes > 0);
+        for i in 0..4 {
+            assert!(s.verify_block(i).unwrap());
+        }
+        let stats = s.pool_stats().unwrap();
+        assert_eq!(stats.misses, first.misses);
+        assert!(stats.hits > 0);
+
+        // payloads from get leave the pool until recycled
+        let data = s.get(1).unwrap();
+        assert_eq!(data, vec![1; 100]);
+        let held = s.pool_stats().unwrap().held_bytes;
+        s.recycle(data);
+        assert!(s.pool_stats().unwrap().held_bytes > held);
+        let clone = s.try_clone().unwrap();
+        assert_eq!(clone.pool_stats(), s.pool_stats());
+        s.set_buffer_pool(None);
+        assert_eq!(s.pool_stats(), None);
+    }
+
+    #[test]
+    fn aligned_payloads_read_in_place() {
+        let path = test_file("aligned.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        s.put(b"unaligned").unwrap();
+        assert!(s.set_payload_alignment(Some(48)).is_err());
+        s.set_payload_alignment(Some(64)).unwrap();
+        let ids: Vec<BlockId> = (1..5u8).map(|i| s.put(&vec![i; usize::from(i) * 7]).unwrap()).collect();
+        let hsize = s.header_size as u64;
+        for i in &ids {
+            assert_eq!((s.block_address(*i).unwrap() + hsize) % 64, 0);
+        }
+        // scans skip the padding
+        crash(s);
+        // nothing else has the file open, so read_aligned's slices are safe
+        let mut s = Store::<B3BlockHasher>::open_with_progress(path.clone(), &StoreOptions::new().write(true).mmap(true), |_, _| true).unwrap();
+        assert_eq!(s.len(), 5);
+        for (n, i) in ids.iter().enumerate() {
+            let payload = unsafe { s.read_aligned(*i, 64) }.unwrap();
+            assert_eq!(payload.as_ptr() as usize % 64, 0);
+            assert_eq!(payload, &vec![n as u8 + 1; (n + 1) * 7][..]);
+        }
+        // nothing was copied
+        assert!(s.aligned_buf.is_empty() || !cfg!(all(unix, target_pointer_width = "64")));
+        // copied when the file doesn't line up, mapped again as it grows
+        assert_eq!(unsafe { s.read_aligned(0, 4096) }.unwrap(), b"unaligned");
+        assert_eq!(unsafe { s.read_aligned(0, 4096) }.unwrap().as_ptr() as usize % 4096, 0);
+        s.set_payload_alignment(Some(64)).unwrap();
+        let late = s.put(b"late").unwrap();
+        assert_eq!(unsafe { s.read_aligned(late, 64) }.unwrap(), b"late");
+        s.delete_many(&[ids[0]]).unwrap();
+        assert!(unsafe { s.read_aligned(ids[0], 64) }.is_err());
+
+        s.compact().unwrap();
+        for i in 0..s.len() {
+            assert_eq!((s.block_address(i).unwrap() + hsize) % 64, 0);
+        }
+        s.set_mmap(false);
+        assert_eq!(unsafe { s.read_aligned(s.len() - 1, 8) }.unwrap(), b"late");
+    }
+
+    #[test]
+    fn delete_where_deletes_matching_headers() {
+        let path = test_file("delete_where.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        let a = s.put(&[1; 300]).unwrap();
+        let b = s.put(b"bob").unwrap();
+        let c = s.put(&[2; 500]).unwrap();
+        s.delete_many(&[c]).unwrap();
+        let deleted = s.delete_where(|info| info.size > 100).unwrap();
+        assert_eq!(deleted, vec![a]);
+        assert!(s.delete_where(|info| info.size > 100).unwrap().is_empty());
+        assert_eq!(s.len(), 3);
+        crash(s);
+        let opts = StoreOptions::new().write(true);
+        let mut s = Store::<B3BlockHasher>::open_with_progress(path, &opts, |_, _| true).unwrap();
+        assert!(!s.is_live(a).unwrap());
+        assert_eq!(s.get(b).unwrap(), b"bob");
+    }
+
+    #[test]
+    fn truncate_to_drops_the_suffix() {
+        let path = test_file("truncate_to.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        for i in 0..5u8 {
+            s.put(&[i; 32]).unwrap();
+        }
+        s.delete_many(&[1, 3]).unwrap();
+        s.quarantine(4).unwrap();
+        let end = s.block_address(3).unwrap();
+        s.truncate_to(2).unwrap();
+        assert_eq!(s.len(), 3);
+        assert_eq!(s.index().data_end_address, end);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), end);
+        assert!(s.truncate_to(3).is_err());
+        s.truncate_to(2).unwrap();
+        assert_eq!(s.put(b"again").unwrap(), 3);
+        assert!(s.quarantined().is_empty());
+        s.close().unwrap();
+
+        let opts = StoreOptions::new().write(true);
+        let mut s = Store::<B3BlockHasher>::open_with_progress(path.clone(), &opts, |_, _| true).unwrap();
+        assert_eq!(s.len(), 4);
+        assert!(!s.is_live(1).unwrap());
+        assert_eq!(s.get(3).unwrap(), b"again");
+        s.truncate_to(0).unwrap();
+        crash(s);
+        let s = Store::<B3BlockHasher>::open_with_progress(path, &opts, |_, _| true).unwrap();
+        assert_eq!(s.len(), 1);
+    }
+
+    #[test]
+    fn iter_and_reader_skip_dead_payloads_unread() {
+        let mut s = Store::<B3BlockHasher>::create(test_file("as_reader.st")).unwrap();
+        s.put(b"one ").unwrap();
+        let dead = s.put(&vec![7u8; 200_000]).unwrap();
+        s.put(b"two ").unwrap();
+        let big: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        s.put(&big).unwrap();
+        s.delete_many(&[dead]).unwrap();
+
+        s.reset_counters();
+        let ids: Vec<BlockId> = s.iter().map(|r| r.unwrap().0).collect();
+        assert_eq!(ids, vec![0, 2, 3]);
+        assert!(s.io_counters().bytes_read < 150_000);
+
+        s.reset_counters();
+        let mut all = Vec::new();
+        s.as_reader().read_to_end(&mut all).unwrap();
+        assert!(s.io_counters().bytes_read < 150_000);
+        assert_eq!(&all[..8], b"one two ");
+        assert_eq!(&all[8..], &big[..]);
+        s.delete_many(&[0, 2, 3]).unwrap();
+        let mut none = Vec::new();
+        assert_eq!(s.as_reader().read_to_end(&mut none).unwrap(), 0);
+    }
+
+    #[test]
+    fn pinned_blocks_stay_put() {
+        let path = test_file("pin.st");
+        let mut s = Store::<B3BlockHasher>::create(path).unwrap();
+        for i in 0..4u8 {
+            s.put(&[i; 64]).unwrap();
+        }
+        s.delete_many(&[0]).unwrap();
+        assert!(s.pin(0).is_err());
+        let guard = s.pin(2).unwrap();
+        let mut other = s.try_clone().unwrap();
+        let again = other.pin(2).unwrap();
+        assert_eq!(guard.block(), 2);
+        let kind = |e: Box<dyn std::error::Error>| e.downcast_ref::<StoreError>().unwrap().kind();
+        assert_eq!(kind(s.compact().err().unwrap()), StoreErrorKind::Pinned);
+        assert_eq!(kind(s.compact_in_place().err().unwrap()), StoreErrorKind::Pinned);
+        assert_eq!(kind(s.swap(&[(2, &[9; 64])]).err().unwrap()), StoreErrorKind::Pinned);
+        assert_eq!(kind(s.truncate_to(1).err().unwrap()), StoreErrorKind::Pinned);
+        // other blocks can still change, and the pinned one be read
+        s.swap(&[(1, &[8; 64])]).unwrap();
+        s.truncate_to(2).unwrap();
+        assert_eq!(s.get(2).unwrap(), vec![2; 64]);
+
+        drop(guard);
+        assert!(s.is_pinned(2));
+        drop(again);
+        assert!(!s.is_pinned(2));
+        let report = s.compact().unwrap();
+        assert_eq!(report.remap, vec![None, Some(0), Some(1)]);
+        assert!(s.pin(1).is_ok());
+    }
+
+    #[test]
+    fn vacuum_into_copies_live_blocks() {
+        let path = test_file("vacuum_src.st");
+        let copy = test_file("vacuum_copy.st");
+        let _ = std::fs::remove_file(&copy);
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        for i in 0..4u8 {
+            s.put(&[i; 100]).unwrap();
+        }
+        s.delete_many(&[1]).unwrap();
+        s.close().unwrap();
+
+        let mut s = Store::<B3BlockHasher>::new(path).unwrap();
+        let report = s.vacuum_into(&copy).unwrap();
+        assert_eq!(report.remap, vec![Some(0), None, Some(1), Some(2)]);
+        assert!(report.bytes_reclaimed > 100);
+        assert!(s.vacuum_into(&copy).is_err());
+        // the source is untouched and still readable
+        assert_eq!(s.len(), 4);
+        assert_eq!(s.get(3).unwrap(), vec![3; 100]);
+
+        let mut c = Store::<B3BlockHasher>::new(copy).unwrap();
+        assert_eq!(c.len(), 3);
+        assert_eq!(c.get(1).unwrap(), vec![2; 100]);
+        assert!(c.scrub().unwrap().failed.is_empty());
+    }
+
+    /// Blocks get found corrupt, shared with the test
+    struct FailureLog(Arc<RwLock<Vec<BlockId>>>);
+
+    impl StoreObserver for FailureLog {
+        fn on_relocate(&mut self, _old_addr: u64, _new_addr: u64, _block_id: BlockId) {}
+
+        fn on_verify_failure(&mut self, block_id: BlockId) {
+            self.0.write().unwrap().push(block_id);
+        }
+    }
+
+    #[test]
+    fn sampled_verification() {
+        let path = test_file("verify_sample.st");
+        let mut s = Store::<B3BlockHasher>::create(path).unwrap();
+        let good = s.put(&[1; 16]).unwrap();
+        let bad = s.put(&[2; 16]).unwrap();
+        let payload = s.block_address(bad).unwrap() + DataHeader::<B3BlockHasher>::size() as u64;
+        s.file.seek(SeekFrom::Start(payload)).unwrap();
+        s.file.write_all(&[7]).unwrap();
+        let failures = Arc::new(RwLock::new(Vec::new()));
+        s.add_observer(Box::new(FailureLog(Arc::clone(&failures))));
+
+        s.set_verify_sample(Some(0.0));
+        for _ in 0..10 {
+            assert_eq!(s.get(bad).unwrap()[1..], [2; 15]);
+        }
+        assert_eq!(s.verify_stats(), VerifyStats { reads: 10, verified: 0, failures: 0 });
+        s.set_verify_sample(Some(0.5));
+        for _ in 0..1000 {
+            s.get(good).unwrap();
+        }
+        let verified = s.verify_stats().verified;
+        assert!(verified > 350 && verified < 650);
+        s.set_verify_sample(None);
+        assert!(s.get(bad).is_err());
+        assert_eq!(s.verify_stats().failures, 1);
+        assert_eq!(*failures.read().unwrap(), vec![bad]);
+    }
+
+    #[test]
+    fn compaction_shrinks_the_file() {
+        let path = test_file("shrink.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        for i in 0..6u8 {
+            s.put(&[i; 512]).unwrap();
+        }
+        let file_len = || std::fs::metadata(&path).unwrap().len();
+        s.delete_many(&[0, 1]).unwrap();
+        let high = file_len();
+        s.compact_in_place().unwrap();
+        assert_eq!(file_len(), s.index().data_end_address);
+        assert!(file_len() < high);
+        s.delete_many(&[0]).unwrap();
+        s.compact().unwrap();
+        assert_eq!(file_len(), s.index().data_end_address);
+        s.truncate_to(1).unwrap();
+        let end = s.index().data_end_address;
+        assert_eq!(file_len(), end);
+        s.close().unwrap();
+
+        // the footer goes right after the last block, and is found again
+        let mut s = Store::<B3BlockHasher>::new(path.clone()).unwrap();
+        assert!(s.read_index_footer().unwrap());
+        assert_eq!(s.index().data_end_address, end);
+        assert!(file_len() > end);
+        assert_eq!(s.get(1).unwrap(), vec![4; 512]);
+    }
+
+    #[test]
+    fn errors_carry_their_numbers() {
+        let path = test_file("error_context.st");
+        let mut s = Store::<B3BlockHasher>::create(path).unwrap();
+        s.put(b"first").unwrap();
+        s.put(b"second").unwrap();
+        s.delete_block(1).unwrap();
+        let e = s.get(1).err().unwrap();
+        let se = e.downcast_ref::<StoreError>().unwrap();
+        assert_eq!(se.kind(), StoreErrorKind::NotLive);
+        assert_eq!(se.message(), ERROR_FSTORE_NOTLIVE);
+        assert_eq!(se.context().index, Some(1));
+        assert_eq!(e.to_string(), format!("{} (index 1)", ERROR_FSTORE_NOTLIVE));
+
+        let e = s.delete_many(&[0, 7]).err().unwrap();
+        assert_eq!(e.downcast_ref::<StoreError>().unwrap().context().index, Some(7));
+
+        let address = s.block_address(0).unwrap();
+        let payload = address + DataHeader::<B3BlockHasher>::size() as u64;
+        s.file.seek(SeekFrom::Start(payload)).unwrap();
+        s.file.write_all(b"F").unwrap();
+        let e = s.get(0).err().unwrap();
+        let se = e.downcast_ref::<StoreError>().unwrap();
+        assert_eq!(se.context(), ErrorContext { index: Some(0), offset: Some(address), value: None });
+        assert_eq!(e.to_string(), format!("{} (index 0, offset {})", ERROR_FSTORE_CHECKSUM, address));
+
+        s.set_max_block_size(Some(4));
+        let e = s.put(b"too long").err().unwrap();
+        assert_eq!(e.to_string(), format!("{} (8 > 4)", ERROR_FSTORE_TOOLARGE));
+    }
+
+    /// Slow operations seen by an observer, shared with the test
+    struct SlowLog(Arc<RwLock<Vec<SlowOp>>>);
+
+    impl StoreObserver for SlowLog {
+        fn on_relocate(&mut self, _old_addr: u64, _new_addr: u64, _block_id: BlockId) {}
+
+        fn on_slow_op(&mut self, op: &SlowOp) {
+            self.0.write().unwrap().push(*op);
+        }
+    }
+
+    #[test]
+    fn slow_operations_are_reported() {
+        let path = test_file("slow_ops.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        let slow = Arc::new(RwLock::new(Vec::new()));
+        s.add_observer(Box::new(SlowLog(Arc::clone(&slow))));
+        s.put(b"unwatched").unwrap();
+        s.set_slow_threshold(Some(Duration::from_secs(3600)));
+        s.put(b"quick").unwrap();
+        s.get(0).unwrap();
+        assert!(slow.read().unwrap().is_empty());
+
+        // everything takes longer than nothing
+        s.set_slow_threshold(Some(Duration::ZERO));
+        let b = s.put(b"watched").unwrap();
+        s.get(b).unwrap();
+        let mut r = s.try_clone().unwrap();
+        r.add_observer(Box::new(SlowLog(Arc::clone(&slow))));
+        r.reindex().unwrap();
+        let ops = slow.read().unwrap().clone();
+        let kinds: Vec<(SlowOpKind, Option<BlockId>, u64)> = ops.iter().map(|o| (o.kind, o.block_id, o.bytes)).collect();
+        assert_eq!(kinds, vec![(SlowOpKind::Write, Some(b), 7), (SlowOpKind::Read, Some(b), 7), (SlowOpKind::Index, None, 0)]);
+        assert_eq!(ops[0].offset, s.block_address(b));
+        assert!(ops[1].elapsed > Duration::ZERO);
+    }
+
+    #[test]
+    fn compaction_reencodes_blocks() {
+        let path = test_file("reencode.st");
+        let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        s.put(b"old").unwrap();
+        s.put(b"kept").unwrap();
+        s.delete_block(0).unwrap();
+        s.compact_with_codec(Box::new(FieldsHeaderCodec { fields: HEADER_FIELD_CONTENT_TYPE })).unwrap();
+        assert_eq!(s.codec_id(), FieldsHeaderCodec { fields: HEADER_FIELD_CONTENT_TYPE }.id());
+        assert_eq!(s.get(0).unwrap(), b"kept");
+        let typed = s.put_typed(b"{}", ContentType(2)).unwrap();
+        // the content type would be lost
+        let e = s.compact_with_codec(Box::new(BinaryHeaderCodec)).err().unwrap();
+        assert_eq!(e.downcast_ref::<StoreError>().unwrap().context().index, Some(typed));
+        assert_eq!(s.codec_id(), FieldsHeaderCodec { fields: HEADER_FIELD_CONTENT_TYPE }.id());
+        s.close().unwrap();
+        let mut s = Store::<B3BlockHasher>::new(path.clone()).unwrap();
+        assert_eq!(s.content_type(typed).unwrap(), ContentType(2));
+
+        let migrated = test_file("reencode_crc.st");
+        let _ = std::fs::remove_file(&migrated);
+        let report = s.migrate_into::<Crc32BlockHasher>(&migrated, Box::new(FieldsHeaderCodec { fields: HEADER_FIELD_CONTENT_TYPE })).unwrap();
+        assert_eq!(report.remap, vec![Some(0), Some(1)]);
+        let mut m = Store::<Crc32BlockHasher>::new(migrated).unwrap();
+        assert_eq!(m.get(0).unwrap(), b"kept");
+        assert_eq!(m.content_type(1).unwrap(), ContentType(2));
+    }
+
+    #[test]
+    fn write_with_combines_options() {
+        let path = test_file("write_with.st");
+        let mut s = Store::<B3BlockHasher>::create_with_codec(path, Box::new(FieldsHeaderCodec { fields: HEADER_FIELD_CONTENT_TYPE | HEADER_FIELD_ACCESS })).unwrap();
+        let plain = s.write_with(b"plain", &WriteOpts::default()).unwrap();
+        assert_eq!(s.get(plain).unwrap(), b"plain");
+        let opts = WriteOpts { content_type: Some(ContentType(2)), access: Some((7, 0o600)), namespace: Some("docs") };
+        let id = s.write_with(b"{}", &opts).unwrap();
+        assert_eq!(s.content_type(id).unwrap(), ContentType(2));
+        let dh = s.block_header(id).unwrap();
+        assert_eq!((dh.owner(), dh.permissions()), (7, 0o600));
+        assert_eq!(s.cf("docs").unwrap().get(id).unwrap(), b"{}");
+        assert!(s.write_with(b"", &WriteOpts { namespace: Some(""), ..WriteOpts::default() }).is_err());
+
+        let mut s = Store::<B3BlockHasher>::create(test_file("write_with_plain.st")).unwrap();
+        assert!(s.write_with(b"x", &WriteOpts { access: Some((1, 1)), ..WriteOpts::default() }).is_err());
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn read_with_options() {
+        let path = test_file("read_with.st");
+        let mut s = Store::<B3BlockHasher>::create(path).unwrap();
+        let a = s.put(b"alive").unwrap();
+        let b = s.put(b"deleted").unwrap();
+        s.delete_block(b).unwrap();
+        assert_eq!(s.read_with(a, ReadOpts::default()).unwrap(), b"alive");
+        assert!(s.read_with(b, ReadOpts::default()).is_err());
+        let forensic = ReadOpts { include_dead: true, ..ReadOpts::default() };
+        assert_eq!(s.read_with(b, forensic).unwrap(), b"deleted");
+
+        let mut buf = Vec::with_capacity(64);
+        let out = s.read_with(a, ReadOpts { into: Some(&mut buf), ..ReadOpts::default() }).unwrap();
+        assert!(out.is_empty());
+        assert_eq!(buf, b"alive");
+        assert!(buf.capacity() >= 64);
+
+        let payload = s.block_address(a).unwrap() + DataHeader::<B3BlockHasher>::size() as u64;
+        s.file.seek(SeekFrom::Start(payload)).unwrap();
+        s.file.write_all(b"A").unwrap();
+        assert!(s.read_with(a, ReadOpts::default()).is_err());
+        assert_eq!(s.read_with(a, ReadOpts { verify: false, ..ReadOpts::default() }).unwrap(), b"Alive");
+    }
+
+    #[test]
+    fn deterministic_output_is_byte_identical() {
+        let seed = 1_600_000_000;
+        let run = |name: &str| {
+            let path = test_file(name);
+            let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+            s.set_deterministic(Some(seed));
+            s.set_audit_label(Some("build"));
+            s.set_payload_alignment(Some(64)).unwrap();
+            for i in 0..4u8 {
+                s.put(&[i; 100]).unwrap();
+            }
+            s.delete_many(&[1]).unwrap();
+            s.purge_tombstones(Duration::ZERO, Erase::Secure).unwrap();
+            s.compact().unwrap();
+            s.put(b"last").unwrap();
+            assert_eq!(s.append_time(0), Some(UNIX_EPOCH + Duration::from_secs(seed)));
+            s.close().unwrap();
+            std::fs::read(path).unwrap()
+        };
+        assert_eq!(run("deterministic_a.st"), run("deterministic_b.st"));
+    }
+
+    #[test]
+    fn sessions_roll_back_together() {
+        let path = test_file("sessions.st");
+        let mut s = Store::<B3BlockHasher>::create_with_codec(path.clone(), Box::new(FieldsHeaderCodec { fields: HEADER_FIELD_ACCESS | HEADER_FIELD_SESSION })).unwrap();
+        let before = s.put(b"before").unwrap();
+        let first = s.begin_session().unwrap();
+        assert!(s.begin_session().is_err());
+        s.put(b"one").unwrap();
+        s.put_owned(b"two", 7, 0o600).unwrap();
+        assert_eq!(s.end_session(), Some(first));
+        assert_eq!(s.end_session(), None);
+        s.put(b"after").unwrap();
+        s.close().unwrap();
+
+        let mut s = Store::<B3BlockHasher>::open_for_write(path).unwrap();
+        let second = s.begin_session().unwrap();
+        assert!(second > first);
+        let failed = s.put(b"partial").unwrap();
+        s.delete_block(before).unwrap();
+        s.compact().unwrap();
+        let failed = failed - 1;
+        assert_eq!(s.block_header(failed).unwrap().session(), second);
+        let payloads = |s: &mut Store<B3BlockHasher>, id| s.iter_session(id).map(|b| b.unwrap().1).collect::<Vec<_>>();
+        assert_eq!(payloads(&mut s, first), vec![b"one".to_vec(), b"two".to_vec()]);
+        // still open across compaction
+        s.put(b"more").unwrap();
+        assert_eq!(payloads(&mut s, second), vec![b"partial".to_vec(), b"more".to_vec()]);
+
+        assert_eq!(s.delete_session(second).unwrap(), vec![failed, failed + 1]);
+        assert!(s.iter_session(second).next().is_none());
+        assert!(s.delete_session(0).unwrap().is_empty());
+        let left: Vec<Vec<u8>> = s.iter().map(|b| b.unwrap().1).collect();
+        assert_eq!(left, vec![b"one".to_vec(), b"two".to_vec(), b"after".to_vec()]);
+
+        // a copy into a codec that can't record them would lose them
+        let access = FieldsHeaderCodec { fields: HEADER_FIELD_CONTENT_TYPE | HEADER_FIELD_ACCESS };
+        let e = s.migrate_into::<B3BlockHasher>(&test_file("sessions_plain.st"), Box::new(access)).unwrap_err();
+        assert!(e.to_string().contains("write sessions"));
+        let mut plain = Store::<B3BlockHasher>::create(test_file("sessions_none.st")).unwrap();
+        assert!(plain.begin_session().is_err());
+    }
+
+    #[test]
+    fn session_ids_are_never_reused() {
+        let path = test_file("session_ids.st");
+        let codec = FieldsHeaderCodec { fields: HEADER_FIELD_SESSION };
+        let mut s = Store::<B3BlockHasher>::create_with_codec(path.clone(), Box::new(codec)).unwrap();
+        let first = s.begin_session().unwrap();
+        s.put(b"gone").unwrap();
+        s.end_session();
+        // no block records it any more
+        s.delete_session(first).unwrap();
+        s.compact().unwrap();
+        let mut other = s.try_clone().unwrap();
+        let second = s.begin_session().unwrap();
+        let third = other.begin_session().unwrap();
+        assert!(first < second && second < third);
+        drop(other);
+        s.close().unwrap();
+
+        let mut s = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        let fourth = s.begin_session().unwrap();
+        assert!(fourth > third);
+        s.put(b"unsealed").unwrap();
+        crash(s);
+
+        // no footer: the highest session a block records
+        let mut s = Store::<B3BlockHasher>::open_for_write(path).unwrap();
+        assert!(s.begin_session().unwrap() > fourth);
+    }
+
+    #[test]
+    fn version_1_stores_open_and_upgrade() {
+        // written by the original store: four blocks, the last deleted
+        let path = test_file("v1.st");
+        std::fs::write(&path, include_bytes!("../testdata/v1.st")).unwrap();
+        let mut s = Store::<B3BlockHasher>::new(path.clone()).unwrap();
+        assert_eq!(s.version(), 1);
+        assert_eq!(s.len(), 4);
+        assert_eq!(s.get(0).unwrap(), b"first block");
+        assert_eq!(s.get(2).unwrap(), b"third, deleted");
+        assert!(!s.is_live(3).unwrap());
+        drop(s);
+        assert!(Store::<B3BlockHasher>::open_for_write(path.clone()).is_err());
+
+        Store::<B3BlockHasher>::upgrade(&path).unwrap();
+        let mut s = Store::<B3BlockHasher>::open_for_write(path.clone()).unwrap();
+        assert_eq!(s.version(), STORE_VERSIONNUM);
+        assert_eq!(s.get(1).unwrap(), b"second");
+        assert!(!s.is_live(3).unwrap());
+        assert_eq!(s.put(b"fifth").unwrap(), 4);
+        s.close().unwrap();
+        Store::<B3BlockHasher>::upgrade(&path).unwrap();
+        let mut s = Store::<B3BlockHasher>::new(path).unwrap();
+        assert_eq!(s.get(4).unwrap(), b"fifth");
+        assert_eq!(s.read_with(3, ReadOpts { include_dead: true, ..ReadOpts::default() }).unwrap(), [0, 1, 2, 3, 255]);
+    }
+}
@@ -0,0 +1,217 @@
+//Copyright 2021 Matthew Petricone
+//! A store as a durable local buffer for message streams.
+//!
+//! Store::consume_from takes messages from a MessageSource, such as a Kafka
+//! or NATS consumer, and keeps each as a block: MSG_MAGIC, then u32
+//! partition, u64 offset, u16 topic length, the topic, u32 key length or
+//! u32::MAX for none, the key, and the payload. The blocks are synced before
+//! the source is told to commit, so a message is never acknowledged before
+//! it is safe, though after a crash some may be stored twice.
+//! Store::publish_to replays the blocks to a MessageSink, and
+//! Store::last_offsets tells a consumer where to resume.
+//!
+//! The traits are all a client has to implement; no client library is
+//! built in.
+use crate::crypto::BlockHasher;
+use crate::store::{BlockId, Store, StoreIO};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::convert::TryInto;
+
+/// Marks a block holding a message
+pub static MSG_MAGIC: &[u8; 8] = b"FSTMSG01";
+
+static ERROR_MSG_TOPIC: &str = "Topic names are at most 65535 bytes.";
+static ERROR_MSG_KEY: &str = "Message key is too large.";
+
+/// One message of a stream
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Message {
+    pub topic: String,
+    pub partition: u32,
+    /// position in the partition, as the source counts it
+    pub offset: u64,
+    pub key: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    /// The block a message is kept as, see the module docs
+    pub fn encode(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let topic_len = u16::try_from(self.topic.len()).map_err(|_| ERROR_MSG_TOPIC)?;
+        let key_len = match &self.key {
+            Some(k) => u32::try_from(k.len()).ok().filter(|l| *l != u32::MAX).ok_or(ERROR_MSG_KEY)?,
+            None => u32::MAX,
+        };
+        let mut out = MSG_MAGIC.to_vec();
+        out.extend_from_slice(&self.partition.to_le_bytes());
+        out.extend_from_slice(&self.offset.to_le_bytes());
+        out.extend_from_slice(&topic_len.to_le_bytes());
+        out.extend_from_slice(self.topic.as_bytes());
+        out.extend_from_slice(&key_len.to_le_bytes());
+        if let Some(k) = &self.key {
+            out.extend_from_slice(k);
+        }
+        out.extend_from_slice(&self.payload);
+        Ok(out)
+    }
+
+    /// Inverse of encode, None if block isn't a message
+    pub fn decode(block: &[u8]) -> Option<Message> {
+        let rest = block.strip_prefix(&MSG_MAGIC[..])?;
+        let partition = u32::from_le_bytes(rest.get(..4)?.try_into().ok()?);
+        let offset = u64::from_le_bytes(rest.get(4..12)?.try_into().ok()?);
+        let topic_len = u16::from_le_bytes(rest.get(12..14)?.try_into().ok()?) as usize;
+        let topic = String::from_utf8(rest.get(14..14 + topic_len)?.to_vec()).ok()?;
+        let rest = &rest[14 + topic_len..];
+        let key_len = u32::from_le_bytes(rest.get(..4)?.try_into().ok()?);
+        let (key, payload) = if key_len == u32::MAX {
+            (None, &rest[4..])
+        } else {
+            let end = 4usize.checked_add(usize::try_from(key_len).ok()?)?;
+            (Some(rest.get(4..end)?.to_vec()), &rest[end..])
+        };
+        Some(Message { topic, partition, offset, key, payload: payload.to_vec() })
+    }
+}
+
+/// Where Store::consume_from takes messages from, a consumer of some broker
+pub trait MessageSource {
+    /// The next messages available, none if there are none yet
+    fn poll(&mut self) -> Result<Vec<Message>, Box<dyn std::error::Error>>;
+
+    /// The messages poll returned are stored; commit offsets through those given,
+    /// the highest stored per topic and partition
+    fn commit(&mut self, offsets: &[(String, u32, u64)]) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// Where Store::publish_to sends messages, a producer for some broker
+pub trait MessageSink {
+    fn send(&mut self, message: &Message) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Called after a replay, so a buffered producer can wait for delivery
+    fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+}
+
+/// Highest offset per topic and partition among messages
+fn highest_offsets<'a, I: IntoIterator<Item = &'a Message>>(messages: I) -> HashMap<(String, u32), u64> {
+    let mut out: HashMap<(String, u32), u64> = HashMap::new();
+    for m in messages {
+        let e = out.entry((m.topic.clone(), m.partition)).or_insert(m.offset);
+        *e = (*e).max(m.offset);
+    }
+    out
+}
+
+impl<T: BlockHasher> Store<T> {
+    /// Store one poll of source's messages as blocks, sync them, then commit
+    /// their offsets. Returns the blocks, empty if source had nothing.
+    pub fn consume_from<S: MessageSource>(&mut self, source: &mut S) -> Result<Vec<BlockId>, Box<dyn std::error::Error>> {
+        let messages = source.poll()?;
+        if messages.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut ids = Vec::with_capacity(messages.len());
+        for m in &messages {
+            ids.push(self.put(&m.encode()?)?);
+        }
+        self.sync_blocks()?;
+        let mut offsets: Vec<(String, u32, u64)> =
+            highest_offsets(&messages).into_iter().map(|((t, p), o)| (t, p, o)).collect();
+        offsets.sort();
+        source.commit(&offsets)?;
+        Ok(ids)
+    }
+
+    /// Send the messages in live blocks from index from on to sink, in
+    /// order, skipping blocks that aren't messages. Returns the index to
+    /// carry on from next time.
+    pub fn publish_to<K: MessageSink>(&mut self, sink: &mut K, from: BlockId) -> Result<BlockId, Box<dyn std::error::Error>> {
+        let end = self.len();
+        for i in from..end {
+            if !self.is_live(i)? {
+                continue;
+            }
+            if let Some(m) = Message::decode(&self.get(i)?) {
+                sink.send(&m)?;
+            }
+        }
+        sink.flush()?;
+        Ok(end.max(from))
+    }
+
+    /// Highest offset stored per topic and partition, for a consumer to
+    /// resume after
+    pub fn last_offsets(&mut self) -> Result<HashMap<(String, u32), u64>, Box<dyn std::error::Error>> {
+        let mut messages = Vec::new();
+        for block in self.iter() {
+            if let Some(m) = Message::decode(&block?.1) {
+                messages.push(m);
+            }
+        }
+        Ok(highest_offsets(&messages))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+
+    /// A broker partition in memory
+    struct Queue {
+        messages: Vec<Message>,
+        committed: Vec<(String, u32, u64)>,
+    }
+
+    impl MessageSource for Queue {
+        fn poll(&mut self) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+            let n = self.messages.len().min(2);
+            Ok(self.messages.drain(..n).collect())
+        }
+
+        fn commit(&mut self, offsets: &[(String, u32, u64)]) -> Result<(), Box<dyn std::error::Error>> {
+            self.committed.extend_from_slice(offsets);
+            Ok(())
+        }
+    }
+
+    impl MessageSink for Vec<Message> {
+        fn send(&mut self, message: &Message) -> Result<(), Box<dyn std::error::Error>> {
+            self.push(message.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn messages_buffered_and_replayed() {
+        std::fs::create_dir_all("testout").unwrap();
+        let mut s = Store::<B3BlockHasher>::create("testout/streaming.st".to_string()).unwrap();
+        let msg = |partition, offset, key: Option<&[u8]>| Message {
+            topic: "events".to_string(),
+            partition,
+            offset,
+            key: key.map(|k| k.to_vec()),
+            payload: format!("m{}", offset).into_bytes(),
+        };
+        let sent = vec![msg(0, 10, Some(b"k")), msg(1, 4, None), msg(0, 11, Some(b""))];
+        let mut q = Queue { messages: sent.clone(), committed: Vec::new() };
+        assert_eq!(s.consume_from(&mut q).unwrap(), vec![0, 1]);
+        assert_eq!(q.committed, vec![("events".to_string(), 0, 10), ("events".to_string(), 1, 4)]);
+        s.put(b"not a message").unwrap();
+        assert_eq!(s.consume_from(&mut q).unwrap(), vec![3]);
+        assert!(s.consume_from(&mut q).unwrap().is_empty());
+
+        let offsets = s.last_offsets().unwrap();
+        assert_eq!(offsets[&("events".to_string(), 0)], 11);
+        let mut out = Vec::new();
+        assert_eq!(s.publish_to(&mut out, 0).unwrap(), 4);
+        assert_eq!(out, sent);
+        let mut late = Vec::new();
+        assert_eq!(s.publish_to(&mut late, 4).unwrap(), 4);
+        assert!(late.is_empty());
+        assert_eq!(Message::decode(b"FSTMSG01\0"), None);
+    }
+}
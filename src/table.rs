@@ -0,0 +1,121 @@
+//Copyright 2021 Matthew Petricone
+//! Columnar chunks, Arrow IPC and Parquet buffers, kept as blocks.
+//!
+//! Store::put_table checks a buffer is one of the formats and puts it with
+//! its content type, so the store needs a codec that records content types
+//! (FieldsHeaderCodec with HEADER_FIELD_CONTENT_TYPE). Store::read_table
+//! gives the buffer back with its format. Decoding is left to the caller's
+//! arrow or parquet library, so fstore doesn't pick their versions for its
+//! users: the IPC formats open with an IPC reader, a Parquet buffer is a
+//! whole Parquet file, usually one row group.
+use crate::content_type::ContentType;
+use crate::crypto::BlockHasher;
+use crate::store::{BlockId, Store};
+
+static ERROR_TABLE_FORMAT: &str = "Buffer is not Arrow IPC or Parquet.";
+static ERROR_TABLE_NOTTABLE: &str = "Block is not an Arrow or Parquet table.";
+
+/// Opens and closes an Arrow IPC file
+static ARROW_FILE_MAGIC: &[u8; 6] = b"ARROW1";
+/// Opens every message of an Arrow IPC stream
+static ARROW_CONTINUATION: &[u8; 4] = &[0xff; 4];
+/// Opens and closes a Parquet file
+static PARQUET_MAGIC: &[u8; 4] = b"PAR1";
+
+/// Format of a table buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableFormat {
+    /// Arrow IPC file format, random access
+    ArrowFile,
+    /// Arrow IPC streaming format
+    ArrowStream,
+    Parquet,
+}
+
+impl TableFormat {
+    /// Format of buf, judged by its magic bytes, None if it's none of them
+    pub fn detect(buf: &[u8]) -> Option<TableFormat> {
+        let framed = |magic: &[u8]| buf.len() >= 2 * magic.len() && buf.starts_with(magic) && buf.ends_with(magic);
+        if framed(ARROW_FILE_MAGIC) {
+            Some(TableFormat::ArrowFile)
+        } else if framed(PARQUET_MAGIC) {
+            Some(TableFormat::Parquet)
+        } else if buf.starts_with(ARROW_CONTINUATION) {
+            Some(TableFormat::ArrowStream)
+        } else {
+            None
+        }
+    }
+
+    /// Content type blocks of this format are put with
+    pub fn content_type(&self) -> ContentType {
+        match self {
+            TableFormat::ArrowFile => ContentType::ARROW_FILE,
+            TableFormat::ArrowStream => ContentType::ARROW_STREAM,
+            TableFormat::Parquet => ContentType::PARQUET,
+        }
+    }
+
+    /// Format of blocks with content_type, None if it isn't a table type
+    pub fn from_content_type(content_type: ContentType) -> Option<TableFormat> {
+        match content_type {
+            ContentType::ARROW_FILE => Some(TableFormat::ArrowFile),
+            ContentType::ARROW_STREAM => Some(TableFormat::ArrowStream),
+            ContentType::PARQUET => Some(TableFormat::Parquet),
+            _ => None,
+        }
+    }
+}
+
+/// A table buffer read back from a store
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    pub format: TableFormat,
+    pub bytes: Vec<u8>,
+}
+
+impl<T: BlockHasher> Store<T> {
+    /// Put an Arrow IPC or Parquet buffer as a block typed with its format
+    pub fn put_table(&mut self, buf: &[u8]) -> Result<BlockId, Box<dyn std::error::Error>> {
+        let format = TableFormat::detect(buf).ok_or(ERROR_TABLE_FORMAT)?;
+        self.put_typed(buf, format.content_type())
+    }
+
+    /// The table at index, failing for blocks put other than by put_table
+    pub fn read_table(&mut self, index: BlockId) -> Result<Table, Box<dyn std::error::Error>> {
+        let format = TableFormat::from_content_type(self.content_type(index)?)
+            .ok_or_else(|| format!("{} (index {})", ERROR_TABLE_NOTTABLE, index))?;
+        Ok(Table { format, bytes: self.get(index)? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+    use crate::data_header::{FieldsHeaderCodec, HEADER_FIELD_CONTENT_TYPE};
+
+    #[test]
+    fn tables_keep_their_format() {
+        let arrow_file = [&b"ARROW1\0\0"[..], &[1; 24], b"ARROW1"].concat();
+        let arrow_stream = [&[0xff; 4][..], &[8, 0, 0, 0], &[2; 8]].concat();
+        let parquet = [&b"PAR1"[..], &[3; 16], b"PAR1"].concat();
+        assert_eq!(TableFormat::detect(b"PAR1"), None);
+        assert_eq!(TableFormat::detect(b"{}"), None);
+
+        std::fs::create_dir_all("testout").unwrap();
+        let mut s = Store::<B3BlockHasher>::create_with_codec("testout/table.st".to_string(), Box::new(FieldsHeaderCodec { fields: HEADER_FIELD_CONTENT_TYPE })).unwrap();
+        let ids: Vec<BlockId> = [&arrow_file, &arrow_stream, &parquet].iter().map(|b| s.put_table(b).unwrap()).collect();
+        assert!(s.put_table(b"not a table").is_err());
+        let plain = s.put(b"PAR1 PAR1").unwrap();
+
+        let tables: Vec<Table> = ids.iter().map(|i| s.read_table(*i).unwrap()).collect();
+        assert_eq!(
+            tables.iter().map(|t| t.format).collect::<Vec<_>>(),
+            vec![TableFormat::ArrowFile, TableFormat::ArrowStream, TableFormat::Parquet]
+        );
+        assert_eq!(tables[2].bytes, parquet);
+        assert_eq!(s.content_type(ids[2]).unwrap().extension(), Some("parquet"));
+        assert!(s.read_table(plain).is_err());
+    }
+}
@@ -0,0 +1,375 @@
+//Copyright 2021 Matthew Petricone
+//! Two stores used as one: a fast hot store and a large cold store.
+//!
+//! New blocks go to the hot store. Reading a cold block copies it back to the
+//! hot store, and the TierPolicy moves blocks that are too old, or don't fit,
+//! out to the cold store. Block ids never change when a block moves.
+use crate::crypto::BlockHasher;
+use crate::data_header::DataHeader;
+use crate::platform;
+use crate::store::{BlockId, Store, StoreIO};
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Marks a tier map sidecar file
+static TIER_MAP_MAGIC: &[u8; 8] = b"FSTTIR01";
+static ERROR_TIER_MAP_INVALID: &str = "Invalid tier map file.";
+static ERROR_TIER_OUTOFBOUNDS: &str = "Value out of bounds.";
+
+/// Which store a block is in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tier {
+    Hot,
+    Cold,
+}
+
+/// When blocks leave the hot store
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TierPolicy {
+    /// live payload bytes the hot store may hold, least recently used blocks go first
+    pub max_hot_bytes: u64,
+    /// blocks not read for this long are demoted, None to keep them
+    pub max_hot_age: Option<Duration>,
+}
+
+impl Default for TierPolicy {
+    fn default() -> TierPolicy {
+        TierPolicy {
+            max_hot_bytes: 64 * 1024 * 1024,
+            max_hot_age: None,
+        }
+    }
+}
+
+/// Where a block lives
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TierEntry {
+    tier: Tier,
+    id: BlockId,
+    size: u64,
+    deleted: bool,
+    last_access: SystemTime,
+}
+
+/// A hot and a cold Store behind one StoreIO.
+///
+/// The block map is kept in a sidecar next to the hot store, named after it
+/// with ".tiers" appended, and is written on close.
+pub struct TieredStore<T: BlockHasher> {
+    hot: Store<T>,
+    cold: Store<T>,
+    hot_path: String,
+    policy: TierPolicy,
+    blocks: Vec<TierEntry>,
+    /// tier of the last block seeked to, for read and read_data_header
+    current: Tier,
+    closed: bool,
+}
+
+impl<T: BlockHasher> TieredStore<T> {
+    /// Create both stores, replacing anything already there
+    pub fn create(hot_path: String, cold_path: String, policy: TierPolicy) -> Result<TieredStore<T>, Box<dyn std::error::Error>> {
+        let hot = Store::<T>::create(hot_path.clone())?;
+        let cold = Store::<T>::create(cold_path)?;
+        Ok(TieredStore::with_stores(hot, cold, hot_path, policy, Vec::new()))
+    }
+
+    /// Open both stores for writing, with the block map saved by close
+    pub fn open(hot_path: String, cold_path: String, policy: TierPolicy) -> Result<TieredStore<T>, Box<dyn std::error::Error>> {
+        let hot = Store::<T>::open_for_write(hot_path.clone())?;
+        let cold = Store::<T>::open_for_write(cold_path)?;
+        let blocks = TieredStore::<T>::load_map(&hot_path)?;
+        for b in &blocks {
+            let len = match b.tier {
+                Tier::Hot => hot.len(),
+                Tier::Cold => cold.len(),
+            };
+            if b.id >= len {
+                return Err(Box::new(Error::new(ErrorKind::InvalidData, ERROR_TIER_MAP_INVALID)));
+            }
+        }
+        Ok(TieredStore::with_stores(hot, cold, hot_path, policy, blocks))
+    }
+
+    fn with_stores(hot: Store<T>, cold: Store<T>, hot_path: String, policy: TierPolicy, blocks: Vec<TierEntry>) -> TieredStore<T> {
+        TieredStore {
+            hot,
+            cold,
+            hot_path,
+            policy,
+            blocks,
+            current: Tier::Hot,
+            closed: false,
+        }
+    }
+
+    /// Append data to the hot store, returning its id
+    pub fn put(&mut self, data: &[u8]) -> Result<BlockId, Box<dyn std::error::Error>> {
+        let id = self.hot.put(data)?;
+        self.blocks.push(TierEntry {
+            tier: Tier::Hot,
+            id,
+            size: u64::try_from(data.len())?,
+            deleted: false,
+            last_access: SystemTime::now(),
+        });
+        let index = self.blocks.len() - 1;
+        self.apply_policy(Some(index))?;
+        Ok(index)
+    }
+
+    /// Payload of the block at index, promoting it if it is cold
+    pub fn get(&mut self, index: BlockId) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.touch(index)?;
+        let e = self.blocks[index];
+        self.store(e.tier).get(e.id)
+    }
+
+    /// Tier the block at index is in
+    pub fn tier(&self, index: BlockId) -> Option<Tier> {
+        self.blocks.get(index).map(|e| e.tier)
+    }
+
+    /// Live payload bytes in the hot store
+    pub fn hot_bytes(&self) -> u64 {
+        self.blocks
+            .iter()
+            .filter(|e| e.tier == Tier::Hot && !e.deleted)
+            .map(|e| e.size)
+            .sum()
+    }
+
+    /// Demote whatever the policy says no longer belongs in the hot store
+    pub fn apply_policy_now(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.apply_policy(None)
+    }
+
+    /// Save the block map and close both stores
+    pub fn close(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.closed = true;
+        self.save_map()?;
+        self.hot.close_in_place()?;
+        self.cold.close_in_place()
+    }
+
+    fn store(&mut self, tier: Tier) -> &mut Store<T> {
+        match tier {
+            Tier::Hot => &mut self.hot,
+            Tier::Cold => &mut self.cold,
+        }
+    }
+
+    /// Record a read of index, promoting it if it is cold
+    fn touch(&mut self, index: BlockId) -> Result<(), Box<dyn std::error::Error>> {
+        let e = *self
+            .blocks
+            .get(index)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, ERROR_TIER_OUTOFBOUNDS))?;
+        self.blocks[index].last_access = SystemTime::now();
+        if e.tier == Tier::Cold && !e.deleted {
+            self.move_block(index, Tier::Hot)?;
+            self.apply_policy(Some(index))?;
+        }
+        Ok(())
+    }
+
+    /// Copy the block at index to tier and delete the old copy
+    fn move_block(&mut self, index: BlockId, to: Tier) -> Result<(), Box<dyn std::error::Error>> {
+        let e = self.blocks[index];
+        let data = self.store(e.tier).read_block(e.id)?.1;
+        let id = self.store(to).put(&data)?;
+        self.store(e.tier).delete_block(e.id)?;
+        self.blocks[index].tier = to;
+        self.blocks[index].id = id;
+        Ok(())
+    }
+
+    /// Demote old blocks, then least recently used ones until the hot store fits.
+    ///
+    /// keep is the block being read or written, which stays hot regardless.
+    fn apply_policy(&mut self, keep: Option<BlockId>) -> Result<(), Box<dyn std::error::Error>> {
+        let now = SystemTime::now();
+        let mut hot: Vec<BlockId> = (0..self.blocks.len())
+            .filter(|i| Some(*i) != keep && self.blocks[*i].tier == Tier::Hot && !self.blocks[*i].deleted)
+            .collect();
+        hot.sort_by_key(|i| self.blocks[*i].last_access);
+        let mut hot_bytes = self.hot_bytes();
+        for i in hot {
+            let e = self.blocks[i];
+            let too_old = match self.policy.max_hot_age {
+                Some(age) => now.duration_since(e.last_access).map(|d| d > age).unwrap_or(false),
+                None => false,
+            };
+            if !too_old && hot_bytes <= self.policy.max_hot_bytes {
+                break;
+            }
+            self.move_block(i, Tier::Cold)?;
+            hot_bytes -= e.size;
+        }
+        Ok(())
+    }
+
+    /// Read the block map sidecar, empty if there isn't one
+    ///
+    /// Per block: u8 tier, u8 deleted, u64 id, u64 size, u64 last access in unix seconds.
+    fn load_map(hot_path: &str) -> Result<Vec<TierEntry>, Error> {
+        let data = match fs::read(TieredStore::<T>::map_path(hot_path)) {
+            Ok(d) => d,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let invalid = || Error::new(ErrorKind::InvalidData, ERROR_TIER_MAP_INVALID);
+        if data.len() < 16 || &data[0..8] != TIER_MAP_MAGIC {
+            return Err(invalid());
+        }
+        let count = u64::from_le_bytes(data[8..16].try_into().unwrap());
+        if count.checked_mul(26).and_then(|c| c.checked_add(16)) != Some(data.len() as u64) {
+            return Err(invalid());
+        }
+        data[16..]
+            .chunks_exact(26)
+            .map(|c| {
+                let tier = match c[0] {
+                    0 => Tier::Hot,
+                    1 => Tier::Cold,
+                    _ => return Err(invalid()),
+                };
+                Ok(TierEntry {
+                    tier,
+                    deleted: c[1] != 0,
+                    id: usize::try_from(u64::from_le_bytes(c[2..10].try_into().unwrap())).map_err(|_| invalid())?,
+                    size: u64::from_le_bytes(c[10..18].try_into().unwrap()),
+                    last_access: UNIX_EPOCH + Duration::from_secs(u64::from_le_bytes(c[18..26].try_into().unwrap())),
+                })
+            })
+            .collect()
+    }
+
+    /// Write the block map sidecar, replacing the old one in one rename
+    fn save_map(&self) -> Result<(), Error> {
+        let mut out = Vec::with_capacity(16 + self.blocks.len() * 26);
+        out.extend_from_slice(TIER_MAP_MAGIC);
+        out.extend_from_slice(&u64::try_from(self.blocks.len()).unwrap().to_le_bytes());
+        for e in &self.blocks {
+            out.push(match e.tier {
+                Tier::Hot => 0,
+                Tier::Cold => 1,
+            });
+            out.push(u8::from(e.deleted));
+            out.extend_from_slice(&u64::try_from(e.id).unwrap().to_le_bytes());
+            out.extend_from_slice(&e.size.to_le_bytes());
+            let secs = e.last_access.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            out.extend_from_slice(&secs.to_le_bytes());
+        }
+        platform::write_replace(&TieredStore::<T>::map_path(&self.hot_path), &out)
+    }
+
+    /// Sidecar file name for the block map
+    fn map_path(hot_path: &str) -> String {
+        format!("{}.tiers", hot_path)
+    }
+}
+
+impl<T: BlockHasher> Drop for TieredStore<T> {
+    /// Best effort save of the block map, the stores close themselves
+    fn drop(&mut self) {
+        if !self.closed {
+            let _ = self.save_map();
+        }
+    }
+}
+
+impl<T: BlockHasher> StoreIO<T> for TieredStore<T> {
+    fn delete_block(&mut self, index: usize) -> Result<(), Box<dyn std::error::Error>> {
+        let e = *self
+            .blocks
+            .get(index)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, ERROR_TIER_OUTOFBOUNDS))?;
+        self.store(e.tier).delete_block(e.id)?;
+        self.blocks[index].deleted = true;
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Address within whichever store holds the block now
+    fn block_address(&self, index: usize) -> Option<u64> {
+        let e = self.blocks.get(index)?;
+        match e.tier {
+            Tier::Hot => self.hot.block_address(e.id),
+            Tier::Cold => self.cold.block_address(e.id),
+        }
+    }
+
+    fn read_data_header(&mut self, data_header: &mut DataHeader<T>) -> Result<(), Box<dyn std::error::Error>> {
+        self.store(self.current).read_data_header(data_header)
+    }
+
+    fn read(&mut self, data: &mut Vec<u8>) -> Result<usize, Error> {
+        self.store(self.current).read(data)
+    }
+
+    fn read_at_index(&mut self, index: usize, data: &mut Vec<u8>) -> Result<usize, Box<dyn std::error::Error>> {
+        self.touch(index)?;
+        let e = self.blocks[index];
+        self.current = e.tier;
+        self.store(e.tier).read_at_index(e.id, data)
+    }
+
+    /// Promotes the block if it is cold
+    fn seek(&mut self, index: usize) -> Result<u64, Box<dyn std::error::Error>> {
+        self.touch(index)?;
+        let e = self.blocks[index];
+        self.current = e.tier;
+        self.store(e.tier).seek(e.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+
+    #[test]
+    fn blocks_move_between_tiers() {
+        fs::create_dir_all("testout").unwrap();
+        let hot = "testout/tiered_hot.st".to_string();
+        let cold = "testout/tiered_cold.st".to_string();
+        let _ = fs::remove_file(format!("{}.tiers", hot));
+        let policy = TierPolicy {
+            max_hot_bytes: 250,
+            max_hot_age: None,
+        };
+        let mut ts = TieredStore::<B3BlockHasher>::create(hot.clone(), cold.clone(), policy).unwrap();
+        for i in 0..4u8 {
+            assert_eq!(ts.put(&[i; 100]).unwrap(), usize::from(i));
+        }
+        // only two fit, the oldest went cold
+        assert_eq!(ts.tier(0), Some(Tier::Cold));
+        assert_eq!(ts.tier(1), Some(Tier::Cold));
+        assert_eq!(ts.tier(3), Some(Tier::Hot));
+        assert!(ts.hot_bytes() <= 250);
+
+        assert_eq!(ts.get(0).unwrap(), vec![0u8; 100]);
+        assert_eq!(ts.tier(0), Some(Tier::Hot));
+        assert_eq!(ts.tier(2), Some(Tier::Cold));
+        ts.close().unwrap();
+
+        let mut ts = TieredStore::<B3BlockHasher>::open(hot, cold, policy).unwrap();
+        assert_eq!(ts.len(), 4);
+        assert_eq!(ts.tier(2), Some(Tier::Cold));
+        let mut buf = vec![0u8; 10];
+        ts.seek(2).unwrap();
+        assert_eq!(ts.tier(2), Some(Tier::Hot));
+        let mut dh = DataHeader::<B3BlockHasher>::new().unwrap();
+        ts.read_data_header(&mut dh).unwrap();
+        assert_eq!(dh.data_size().unwrap(), 100);
+        ts.read(&mut buf).unwrap();
+        assert_eq!(buf, vec![2u8; 10]);
+    }
+}
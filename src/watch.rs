@@ -0,0 +1,112 @@
+//Copyright 2021 Matthew Petricone
+//! Follow a store another process is appending to, like tail -f.
+//!
+//! The file is polled with Store::reindex, there is no notification from the OS.
+use crate::crypto::BlockHasher;
+use crate::store::{BlockId, Store, StoreIO};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::thread;
+use std::time::Duration;
+
+static ERROR_WATCH_TRUNCATED: &str = "Store was truncated while being watched.";
+
+/// A block index and its payload
+pub type WatchedBlock = (BlockId, Vec<u8>);
+
+/// Blocks appended to a store after the watch started, from Store::watch
+///
+/// As an Iterator it blocks until the next block arrives and never ends;
+/// use poll to check without waiting.
+pub struct Watch<'a, T: BlockHasher> {
+    store: &'a mut Store<T>,
+    next: BlockId,
+    interval: Duration,
+    pending: VecDeque<WatchedBlock>,
+}
+
+impl<T: BlockHasher> Store<T> {
+    /// Watch for blocks appended from now on, checking every interval.
+    ///
+    /// Deleted and quarantined blocks are skipped.
+    pub fn watch(&mut self, interval: Duration) -> Watch<'_, T> {
+        let next = self.len();
+        Watch {
+            store: self,
+            next,
+            interval,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<T: BlockHasher> Watch<'_, T> {
+    /// Blocks appended since the last poll, without waiting.
+    ///
+    /// If the file shrank, watching starts again from its new end and an
+    /// error of kind InvalidData is returned.
+    pub fn poll(&mut self) -> Result<Vec<WatchedBlock>, Box<dyn std::error::Error>> {
+        let mut found: Vec<WatchedBlock> = self.pending.drain(..).collect();
+        if self.store.reindex()?.truncated {
+            self.next = self.store.len();
+            return Err(Box::new(Error::new(ErrorKind::InvalidData, ERROR_WATCH_TRUNCATED)));
+        }
+        while self.next < self.store.len() {
+            let index = self.next;
+            if self.store.is_live(index)? {
+                found.push((index, self.store.get(index)?));
+            }
+            self.next += 1;
+        }
+        Ok(found)
+    }
+}
+
+impl<T: BlockHasher> Iterator for Watch<'_, T> {
+    type Item = Result<WatchedBlock, Box<dyn std::error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(b) = self.pending.pop_front() {
+                return Some(Ok(b));
+            }
+            match self.poll() {
+                Ok(found) if found.is_empty() => thread::sleep(self.interval),
+                Ok(found) => self.pending.extend(found),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+    use std::io::Write;
+
+    #[test]
+    fn watch_follows_writer() {
+        std::fs::create_dir_all("testout").unwrap();
+        let path = "testout/watch.st".to_string();
+        let mut w = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        w.put(&[0]).unwrap();
+        w.flush().unwrap();
+        let mut r = Store::<B3BlockHasher>::new(path).unwrap();
+        let mut watch = r.watch(Duration::from_millis(1));
+        assert!(watch.poll().unwrap().is_empty());
+        w.put(&[1]).unwrap();
+        w.put(&[2]).unwrap();
+        w.flush().unwrap();
+        assert_eq!(watch.next().unwrap().unwrap(), (1, vec![1]));
+        assert_eq!(watch.next().unwrap().unwrap(), (2, vec![2]));
+
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            w.put(&[3]).unwrap();
+            w.close().unwrap();
+        });
+        assert_eq!(watch.next().unwrap().unwrap(), (3, vec![3]));
+        writer.join().unwrap();
+    }
+}
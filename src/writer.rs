@@ -0,0 +1,197 @@
+//Copyright 2021 Matthew Petricone
+use crate::crypto::BlockHasher;
+use crate::store::{BlockId, Store};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::JoinHandle;
+
+static ERROR_WRITER_STOPPED: &str = "Store writer has stopped.";
+
+/// Most puts written between syncs
+const WRITER_MAX_BATCH: usize = 256;
+
+/// Why a put through a StoreWriter didn't complete
+#[derive(Debug)]
+pub enum PutError {
+    /// the block wasn't written
+    Write(Error),
+    /// the block was written with this id but syncing it failed, so it may
+    /// not survive a crash. Putting it again may store it twice.
+    Sync(BlockId, Error),
+}
+
+impl fmt::Display for PutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PutError::Write(e) => write!(f, "{}", e),
+            PutError::Sync(id, e) => write!(f, "block {} written but not synced: {}", id, e),
+        }
+    }
+}
+
+impl std::error::Error for PutError {}
+
+/// A put waiting for the worker
+struct PutRequest {
+    data: Vec<u8>,
+    reply: Sender<Result<BlockId, PutError>>,
+}
+
+/// Result of StoreWriter::put, ready once the block is written and synced
+pub struct PendingPut {
+    receiver: Receiver<Result<BlockId, PutError>>,
+}
+
+impl PendingPut {
+    /// Block until the write is done
+    pub fn wait(self) -> Result<BlockId, PutError> {
+        match self.receiver.recv() {
+            Ok(r) => r,
+            Err(_) => Err(PutError::Write(Error::new(ErrorKind::BrokenPipe, ERROR_WRITER_STOPPED))),
+        }
+    }
+}
+
+/// Single writer for a Store, shared by many threads.
+///
+/// The Store is moved to a worker thread. put queues data and returns
+/// immediately; the worker writes queued blocks in the order they arrived,
+/// syncing them to disk once per batch before any is acknowledged.
+pub struct StoreWriter {
+    sender: Option<Sender<PutRequest>>,
+    worker: Option<JoinHandle<Result<(), Error>>>,
+}
+
+impl StoreWriter {
+    /// Start a worker thread that owns store
+    pub fn new<T: BlockHasher + Send + 'static>(store: Store<T>) -> StoreWriter {
+        let (sender, receiver) = channel();
+        let worker = std::thread::spawn(move || StoreWriter::run(store, receiver));
+        StoreWriter {
+            sender: Some(sender),
+            worker: Some(worker),
+        }
+    }
+
+    /// Queue data to be written as a block
+    pub fn put(&self, data: Vec<u8>) -> PendingPut {
+        let (reply, receiver) = channel();
+        if let Some(sender) = &self.sender {
+            // if the worker is gone the reply sender is dropped and wait reports it
+            let _ = sender.send(PutRequest { data, reply });
+        }
+        PendingPut { receiver }
+    }
+
+    /// Finish queued writes and close the store
+    pub fn close(mut self) -> Result<(), Error> {
+        self.stop()
+    }
+
+    /// Hang up on the worker and wait for it
+    fn stop(&mut self) -> Result<(), Error> {
+        self.sender = None;
+        match self.worker.take() {
+            Some(w) => match w.join() {
+                Ok(r) => r,
+                Err(_) => Err(Error::other(ERROR_WRITER_STOPPED)),
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Worker loop, runs until every sender is gone
+    fn run<T: BlockHasher>(mut store: Store<T>, receiver: Receiver<PutRequest>) -> Result<(), Error> {
+        while let Ok(first) = receiver.recv() {
+            let mut batch = vec![first];
+            while batch.len() < WRITER_MAX_BATCH {
+                match receiver.try_recv() {
+                    Ok(r) => batch.push(r),
+                    Err(_) => break,
+                }
+            }
+            let mut results: Vec<Result<BlockId, PutError>> = batch
+                .iter()
+                .map(|r| store.put(&r.data).map_err(|e| PutError::Write(Error::other(e.to_string()))))
+                .collect();
+            // nothing is acknowledged until it's on disk
+            if let Err(e) = store.sync_blocks() {
+                for r in results.iter_mut() {
+                    if let Ok(id) = r {
+                        *r = Err(PutError::Sync(*id, Error::new(e.kind(), e.to_string())));
+                    }
+                }
+            }
+            for (request, result) in batch.into_iter().zip(results) {
+                let _ = request.reply.send(result);
+            }
+        }
+        store.close().map_err(|e| Error::other(e.to_string()))
+    }
+}
+
+impl Drop for StoreWriter {
+    /// Finish queued writes, errors are ignored
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+    use crate::store::StoreIO;
+    use std::sync::Arc;
+
+    #[test]
+    fn many_threads_put() {
+        std::fs::create_dir_all("testout").unwrap();
+        let path = "testout/writer.st".to_string();
+        let s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+        let writer = Arc::new(StoreWriter::new(s));
+        let handles: Vec<_> = (0..4u8)
+            .map(|t| {
+                let w = Arc::clone(&writer);
+                std::thread::spawn(move || {
+                    (0..25u8).map(|i| (w.put(vec![t, i]).wait().unwrap(), vec![t, i])).collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut written: Vec<(BlockId, Vec<u8>)> = handles.into_iter().flat_map(|h| h.join().unwrap()).collect();
+        Arc::try_unwrap(writer).ok().unwrap().close().unwrap();
+
+        written.sort();
+        let ids: Vec<BlockId> = written.iter().map(|w| w.0).collect();
+        assert_eq!(ids, (0..100).collect::<Vec<_>>());
+        let mut s = Store::<B3BlockHasher>::new(path).unwrap();
+        assert_eq!(s.len(), 100);
+        for (id, data) in written {
+            let mut buf = vec![0u8; 2];
+            s.seek(id).unwrap();
+            let mut dh = crate::data_header::DataHeader::<B3BlockHasher>::new().unwrap();
+            s.read_data_header(&mut dh).unwrap();
+            s.read(&mut buf).unwrap();
+            assert_eq!(buf, data);
+        }
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn sync_failure_keeps_the_block_id() {
+        use crate::fault::{run_with_faults, Fault, FaultPlan};
+        std::fs::create_dir_all("testout").unwrap();
+        let mut probe = Store::<B3BlockHasher>::create("testout/writer_probe.st".to_string()).unwrap();
+        let put_ops = run_with_faults(&mut probe, FaultPlan::new(), |s| s.put(b"x").map(|_| ())).ops;
+        let mut s = Store::<B3BlockHasher>::create("testout/writer_sync.st".to_string()).unwrap();
+        // the sync right after the first put
+        s.inject_faults(FaultPlan::new().at(put_ops, Fault::IoError));
+        let writer = StoreWriter::new(s);
+        assert!(matches!(writer.put(b"x".to_vec()).wait(), Err(PutError::Sync(0, _))));
+        assert_eq!(writer.put(b"y".to_vec()).wait().unwrap(), 1);
+        writer.close().unwrap();
+        let mut s = Store::<B3BlockHasher>::new("testout/writer_sync.st".to_string()).unwrap();
+        assert_eq!(s.get(0).unwrap(), b"x");
+    }
+}
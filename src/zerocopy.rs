@@ -0,0 +1,172 @@
+//Copyright 2021 Matthew Petricone
+//! Stores of fixed layout values read in place, without deserializing.
+//!
+//! A ZeroCopyStore keeps each value, or slice of values, as the bytes it
+//! has in memory, in a block whose payload is aligned for the type (see
+//! Store::set_payload_alignment). Reads hand back references into the
+//! memory mapped file, see Store::read_aligned, so a stored struct is a
+//! checksum away. Values are read on the machine that wrote them: there is
+//! no conversion of byte order or layout.
+//!
+//! As the references point into the map, get and get_slice are unsafe, on
+//! the terms of Store::read_aligned.
+use crate::crypto::BlockHasher;
+use crate::store::{BlockId, Store, StoreIO, StoreOptions};
+use std::marker::PhantomData;
+use std::mem::{align_of, size_of};
+
+static ERROR_ZEROCOPY_SIZE: &str = "Block payload is not a whole number of values.";
+
+/// A type whose values can be stored as their bytes and used in place.
+///
+/// # Safety
+///
+/// Implementors must be #[repr(C)] or #[repr(transparent)] with no padding
+/// bytes, hold no pointers or references, and accept any bit pattern, as the
+/// built in numeric types do.
+pub unsafe trait Archive: Copy + 'static {}
+
+unsafe impl Archive for u8 {}
+unsafe impl Archive for u16 {}
+unsafe impl Archive for u32 {}
+unsafe impl Archive for u64 {}
+unsafe impl Archive for u128 {}
+unsafe impl Archive for i8 {}
+unsafe impl Archive for i16 {}
+unsafe impl Archive for i32 {}
+unsafe impl Archive for i64 {}
+unsafe impl Archive for i128 {}
+unsafe impl Archive for f32 {}
+unsafe impl Archive for f64 {}
+unsafe impl<A: Archive, const N: usize> Archive for [A; N] {}
+
+/// A Store of values of type A, read in place
+pub struct ZeroCopyStore<A: Archive, T: BlockHasher> {
+    store: Store<T>,
+    phantom: PhantomData<A>,
+}
+
+impl<A: Archive, T: BlockHasher> ZeroCopyStore<A, T> {
+    /// Create a store, replacing anything already there
+    pub fn create(filename: String) -> Result<ZeroCopyStore<A, T>, Box<dyn std::error::Error>> {
+        ZeroCopyStore::from_store(Store::<T>::create(filename)?)
+    }
+
+    /// Open a store for writing, with the file mapped for reads
+    pub fn open(filename: String) -> Result<ZeroCopyStore<A, T>, Box<dyn std::error::Error>> {
+        let opts = StoreOptions::new().write(true).mmap(true);
+        ZeroCopyStore::from_store(Store::<T>::open_with_progress(filename, &opts, |_, _| true)?)
+    }
+
+    /// Keep values of type A in store, aligning new payloads for them and
+    /// mapping the file for reads
+    pub fn from_store(mut store: Store<T>) -> Result<ZeroCopyStore<A, T>, Box<dyn std::error::Error>> {
+        let align = align_of::<A>() as u64;
+        store.set_payload_alignment(if align > 1 { Some(align) } else { None })?;
+        store.set_mmap(true);
+        Ok(ZeroCopyStore { store, phantom: PhantomData })
+    }
+
+    /// Append value as a new block, returning its index
+    pub fn put(&mut self, value: &A) -> Result<BlockId, Box<dyn std::error::Error>> {
+        self.put_slice(std::slice::from_ref(value))
+    }
+
+    /// Append values as one block, returning its index
+    pub fn put_slice(&mut self, values: &[A]) -> Result<BlockId, Box<dyn std::error::Error>> {
+        // Archive types have no padding, so every byte is initialized
+        let bytes = unsafe { std::slice::from_raw_parts(values.as_ptr() as *const u8, std::mem::size_of_val(values)) };
+        self.store.put(bytes)
+    }
+
+    /// The value at index, in place, checked as Store::get does.
+    ///
+    /// Fails for blocks that aren't exactly one value.
+    ///
+    /// # Safety
+    ///
+    /// As for get_slice.
+    pub unsafe fn get(&mut self, index: BlockId) -> Result<&A, Box<dyn std::error::Error>> {
+        match self.get_slice(index)? {
+            [value] => Ok(value),
+            _ => Err(format!("{} (index {})", ERROR_ZEROCOPY_SIZE, index).into()),
+        }
+    }
+
+    /// The values at index, in place, checked as Store::get does
+    ///
+    /// # Safety
+    ///
+    /// The values are the mapped file, so the caller must keep it from being
+    /// truncated or the block's payload from being changed in place, by any
+    /// other handle or process, while they are held. See the safety section
+    /// of Store::read_aligned.
+    pub unsafe fn get_slice(&mut self, index: BlockId) -> Result<&[A], Box<dyn std::error::Error>> {
+        let size = size_of::<A>();
+        let bytes = self.store.read_aligned(index, align_of::<A>())?;
+        if size == 0 || bytes.len() % size != 0 {
+            return Err(format!("{} (index {})", ERROR_ZEROCOPY_SIZE, index).into());
+        }
+        // read_aligned aligned it for A, and any bytes are a valid A
+        Ok(std::slice::from_raw_parts(bytes.as_ptr() as *const A, bytes.len() / size))
+    }
+
+    /// Number of blocks, live or not
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    /// true if there are no blocks
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
+    /// The underlying store, for what ZeroCopyStore doesn't wrap
+    pub fn store_mut(&mut self) -> &mut Store<T> {
+        &mut self.store
+    }
+
+    /// The underlying store, for closing or other use
+    pub fn into_inner(self) -> Store<T> {
+        self.store
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::B3BlockHasher;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    #[repr(C)]
+    struct Tick {
+        time: u64,
+        price: f64,
+        volume: u32,
+        venue: u32,
+    }
+
+    unsafe impl Archive for Tick {}
+
+    #[test]
+    fn values_read_in_place() {
+        std::fs::create_dir_all("testout").unwrap();
+        let path = "testout/zerocopy.st".to_string();
+        let mut zs = ZeroCopyStore::<Tick, B3BlockHasher>::create(path.clone()).unwrap();
+        let tick = Tick { time: 1, price: 2.5, volume: 100, venue: 3 };
+        let one = zs.put(&tick).unwrap();
+        let many = zs.put_slice(&[tick, Tick { time: 2, ..tick }]).unwrap();
+        zs.into_inner().close().unwrap();
+
+        // nothing else has the file open, so the references are safe
+        let mut zs = ZeroCopyStore::<Tick, B3BlockHasher>::open(path).unwrap();
+        assert_eq!(zs.len(), 2);
+        let got = unsafe { zs.get(one) }.unwrap();
+        assert_eq!(*got, tick);
+        assert_eq!(got as *const Tick as usize % align_of::<Tick>(), 0);
+        assert_eq!(unsafe { zs.get_slice(many) }.unwrap()[1].time, 2);
+        assert!(unsafe { zs.get(many) }.is_err());
+        zs.store_mut().put(b"odd").unwrap();
+        assert!(unsafe { zs.get_slice(2) }.is_err());
+    }
+}
@@ -0,0 +1,99 @@
+//Copyright 2021 Matthew Petricone
+//! Wiping payloads and hasher state from memory.
+//!
+//! With the secure-memory feature, buffers the store reads payloads into
+//! for itself (verification, scrub, compaction and the like) are wiped
+//! before they are freed, as are the hash values and personalization of the
+//! built in hashers. Payloads handed to callers, by get for one, are theirs
+//! to wipe, with zeroize.
+use crate::pool::{BufferPool, SharedPool};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{compiler_fence, Ordering};
+
+/// Overwrite buf with zeros in a way the compiler won't optimize away
+pub fn zeroize(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        // volatile so the writes aren't dropped as dead stores
+        unsafe { std::ptr::write_volatile(b, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// zeroize, only with the secure-memory feature
+#[inline]
+pub(crate) fn wipe(_buf: &mut [u8]) {
+    #[cfg(feature = "secure-memory")]
+    zeroize(_buf);
+}
+
+/// A buffer the store uses for itself, wiped when dropped with the
+/// secure-memory feature, and given back to the pool it came from if any
+#[derive(Debug, Default)]
+pub(crate) struct Scratch {
+    buf: Vec<u8>,
+    pool: Option<SharedPool>,
+}
+
+impl Scratch {
+    pub(crate) fn new(buf: Vec<u8>) -> Scratch {
+        Scratch { buf, pool: None }
+    }
+
+    /// len zeroed bytes from pool, if there is one
+    pub(crate) fn zeroed(pool: Option<&SharedPool>, len: usize) -> Scratch {
+        match pool {
+            Some(p) => Scratch { buf: BufferPool::lock(p).take(len), pool: Some(p.clone()) },
+            None => Scratch::new(vec![0u8; len]),
+        }
+    }
+
+    /// buf, to go back to pool when dropped
+    pub(crate) fn from_pool(pool: Option<&SharedPool>, buf: Vec<u8>) -> Scratch {
+        Scratch { buf, pool: pool.cloned() }
+    }
+
+    /// The buffer, for handing to a caller, who takes over wiping it
+    pub(crate) fn into_inner(mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+impl Deref for Scratch {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for Scratch {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        wipe(&mut self.buf);
+        if let Some(p) = &self.pool {
+            BufferPool::lock(p).give(std::mem::take(&mut self.buf));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zeroize_clears_and_scratch_hands_over() {
+        let mut secret = b"hunter2".to_vec();
+        zeroize(&mut secret);
+        assert_eq!(secret, vec![0; 7]);
+
+        let mut s = Scratch::new(vec![1, 2, 3]);
+        s.push(4);
+        assert_eq!(&s[..], &[1, 2, 3, 4]);
+        assert_eq!(s.into_inner(), vec![1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,75 @@
+//! fstore verify, run as the binary is
+use fstore::crypto::B3BlockHasher;
+use fstore::store::{Store, StoreIO};
+use std::process::{Command, Output};
+
+fn test_file(name: &str) -> String {
+    format!("{}/{}", env!("CARGO_TARGET_TMPDIR"), name)
+}
+
+fn verify(args: &[&str]) -> (i32, String) {
+    let Output { status, stdout, .. } = Command::new(env!("CARGO_BIN_EXE_fstore")).arg("verify").args(args).output().unwrap();
+    (status.code().unwrap(), String::from_utf8(stdout).unwrap())
+}
+
+/// A store of three blocks, the second deleted
+fn make_store(name: &str) -> String {
+    let path = test_file(name);
+    let mut s = Store::<B3BlockHasher>::create(path.clone()).unwrap();
+    s.put(b"first payload").unwrap();
+    s.put(b"second payload").unwrap();
+    s.put(b"third payload").unwrap();
+    s.delete_block(1).unwrap();
+    s.close().unwrap();
+    path
+}
+
+#[test]
+fn clean_store_verifies() {
+    let path = make_store("verify_clean.st");
+    let (code, out) = verify(&[&path]);
+    assert_eq!(code, 0);
+    assert!(out.contains("blocks, "), "{}", out);
+
+    let (code, out) = verify(&["--json", &path]);
+    assert_eq!(code, 0);
+    let expected = format!(
+        r#"{{"path":"{}","status":"ok","blocks_checked":2,"bytes_checked":26,"failed":[],"blocks":[{{"index":0,"status":"ok"}},{{"index":1,"status":"deleted"}},{{"index":2,"status":"ok"}}]}}"#,
+        path
+    );
+    assert_eq!(out.trim_end(), expected);
+}
+
+#[test]
+fn corrupted_store_fails() {
+    let path = make_store("verify_corrupt.st");
+    let mut bytes = std::fs::read(&path).unwrap();
+    let at = bytes.windows(5).position(|w| w == b"third").unwrap();
+    bytes[at] ^= 0xff;
+    std::fs::write(&path, bytes).unwrap();
+
+    let (code, out) = verify(&["--json", &path]);
+    assert_eq!(code, 1);
+    assert!(out.starts_with(&format!(r#"{{"path":"{}","status":"corrupt","#, path)), "{}", out);
+    assert!(out.contains(r#""failed":[2]"#), "{}", out);
+    assert!(out.contains(r#"{"index":0,"status":"ok"}"#), "{}", out);
+    assert!(out.contains(r#"{"index":2,"status":"corrupt"}"#), "{}", out);
+}
+
+#[test]
+fn missing_store_is_unreadable() {
+    let path = test_file("verify_missing.st");
+    let _ = std::fs::remove_file(&path);
+    let (code, out) = verify(&[&path]);
+    assert_eq!(code, 2);
+    assert!(out.is_empty());
+
+    let (code, out) = verify(&["--json", &path]);
+    assert_eq!(code, 2);
+    assert!(out.starts_with(&format!(r#"{{"path":"{}","status":"unreadable","error":""#, path)), "{}", out);
+}
+
+#[test]
+fn bad_arguments_are_a_usage_error() {
+    assert_eq!(verify(&[]).0, 2);
+}